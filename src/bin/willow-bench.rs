@@ -0,0 +1,220 @@
+//! Runs a TPC-B-like workload through the embedded API and reports throughput, latency
+//! percentiles and the engine's metrics.
+//!
+//! ```text
+//! willow-bench [--threads N] [--txns N] [--scale N] [--dir PATH]
+//! ```
+//!
+//! Each transaction adds a random amount to the balance of an account, of its teller and of its
+//! branch. Per unit of scale there is a branch with 10 tellers and 100000 accounts, each balance
+//! an int slot of a block of the `branches`, `tellers` or `accounts` file. The history table is
+//! left out, as there is no record layer to append to. `--txns` is per thread. The database is
+//! in memory unless `--dir` is given, and other settings are read from `WILLOW_CONFIG`.
+
+use std::{
+    env,
+    path::PathBuf,
+    process, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use willow_db::{BlockId, Config, PageHeader, Transaction, TxnError, WillowDB, WillowError};
+
+const USAGE: &str = "usage: willow-bench [--threads N] [--txns N] [--scale N] [--dir PATH]";
+
+const TELLERS_PER_BRANCH: u64 = 10;
+const ACCOUNTS_PER_BRANCH: u64 = 100_000;
+
+struct Args {
+    threads: usize,
+    txns: usize,
+    scale: u64,
+    dir: Option<PathBuf>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            txns: 1000,
+            scale: 1,
+            dir: None,
+        }
+    }
+}
+
+/// What one thread measured.
+#[derive(Default)]
+struct Run {
+    latencies: Vec<Duration>,
+    retries: u64,
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--threads" => parsed.threads = parse(&arg, &value()?)?,
+            "--txns" => parsed.txns = parse(&arg, &value()?)?,
+            "--scale" => parsed.scale = parse(&arg, &value()?)?,
+            "--dir" => parsed.dir = Some(value()?.into()),
+            _ => return Err(format!("unexpected argument: {arg}")),
+        }
+    }
+    if parsed.threads == 0 || parsed.scale == 0 {
+        return Err("--threads and --scale must be at least 1".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {arg}: {value}"))
+}
+
+fn run(args: Args) -> Result<(), WillowError> {
+    let config_path = env::var_os("WILLOW_CONFIG").map(PathBuf::from);
+    let config = Config::load(config_path.as_deref())?;
+    let builder = WillowDB::builder().config(&config)?;
+    let db = match &args.dir {
+        Some(dir) => builder.open(dir)?,
+        None => builder.open_in_memory()?,
+    };
+    let slots = Slots::new(db.block_size());
+
+    let start = Instant::now();
+    let runs = thread::scope(|s| {
+        let workers: Vec<_> = (0..args.threads)
+            .map(|i| {
+                let (db, slots, args) = (&db, &slots, &args);
+                s.spawn(move || -> Result<Run, WillowError> {
+                    let mut rng = Rng::new(i as u64);
+                    let mut run = Run::default();
+                    for _ in 0..args.txns {
+                        let started = Instant::now();
+                        run.retries += transfer(db, slots, args.scale, &mut rng)?;
+                        run.latencies.push(started.elapsed());
+                    }
+                    Ok(run)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap())
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let retries: u64 = runs.iter().map(|r| r.retries).sum();
+    let mut latencies: Vec<_> = runs.into_iter().flat_map(|r| r.latencies).collect();
+    latencies.sort_unstable();
+    let txns = latencies.len();
+    println!(
+        "{txns} transactions by {} threads in {elapsed:.2?}: {:.0} txn/s, {retries} retries",
+        args.threads,
+        txns as f64 / elapsed.as_secs_f64()
+    );
+    for p in [50, 95, 99] {
+        println!("p{p} latency: {:.2?}", percentile(&latencies, p));
+    }
+    print!("{}", db.metrics().to_prometheus());
+    db.close()
+}
+
+/// Runs one TPC-B transaction, starting over when it gives up waiting for a lock or a buffer.
+/// Returns the number of times it started over.
+fn transfer(db: &WillowDB, slots: &Slots, scale: u64, rng: &mut Rng) -> Result<u64, WillowError> {
+    let branch = rng.below(scale);
+    let teller = branch * TELLERS_PER_BRANCH + rng.below(TELLERS_PER_BRANCH);
+    let account = branch * ACCOUNTS_PER_BRANCH + rng.below(ACCOUNTS_PER_BRANCH);
+    let delta = rng.below(10_001) as i32 - 5000;
+
+    let mut retries = 0;
+    loop {
+        let mut tx = db.new_txn()?;
+        let res = [
+            ("accounts", account),
+            ("tellers", teller),
+            ("branches", branch),
+        ]
+        .into_iter()
+        .try_for_each(|(file, n)| slots.add(&mut tx, file, n, delta));
+        match res.and_then(|()| tx.commit()) {
+            Ok(()) => return Ok(retries),
+            Err(e) if e.is_retryable() => {
+                tx.rollback()?;
+                retries += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Where the balance of a numbered row is kept.
+struct Slots {
+    per_block: u64,
+}
+
+impl Slots {
+    fn new(block_size: usize) -> Self {
+        Self {
+            per_block: ((block_size - PageHeader::SIZE) / 4) as u64,
+        }
+    }
+
+    /// Adds `delta` to the balance of row `n` of `file`.
+    fn add(&self, tx: &mut Transaction, file: &str, n: u64, delta: i32) -> Result<(), TxnError> {
+        let block = BlockId::new(file, n / self.per_block);
+        let offset = PageHeader::SIZE + (n % self.per_block) as usize * 4;
+        tx.pin(&block)?;
+        // locked for the update up front, as transactions of the same branch all update it
+        tx.x_lock(&block)?;
+        let balance = tx.get_int(&block, offset)?;
+        tx.set_int(&block, offset, balance.wrapping_add(delta), true)?;
+        tx.unpin(&block);
+        Ok(())
+    }
+}
+
+/// `p`th percentile of `sorted`.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+/// xorshift64*, good enough to pick rows.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self((now ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
@@ -0,0 +1,95 @@
+//! Prints a database's log, oldest record first.
+//!
+//! ```text
+//! willow-waldump [--txn N] [--block FILE:NUM] [--from LSN] <db dir>
+//! ```
+//!
+//! The block size, log directory and log file name are read from `WILLOW_CONFIG` like the
+//! database does. Each line is the record's LSN as `block/used bytes` followed by the record.
+
+use std::{env, path::PathBuf, process};
+
+use willow_db::{BlockId, Config, Lsn, TxNum, WalError, WillowDB, WillowError};
+
+const USAGE: &str = "usage: willow-waldump [--txn N] [--block FILE:NUM] [--from LSN] <db dir>";
+
+#[derive(Default)]
+struct Args {
+    dir: Option<PathBuf>,
+    txn: Option<TxNum>,
+    block: Option<BlockId>,
+    from: Lsn,
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--txn" => parsed.txn = Some(parse(&arg, &value()?)?),
+            "--from" => parsed.from = parse(&arg, &value()?)?,
+            "--block" => {
+                let value = value()?;
+                let (file, num) = value
+                    .rsplit_once(':')
+                    .ok_or(format!("invalid {arg}: {value}"))?;
+                parsed.block = Some(BlockId::new(file, parse(&arg, num)?));
+            }
+            _ if arg.starts_with('-') || parsed.dir.is_some() => {
+                return Err(format!("unexpected argument: {arg}"))
+            }
+            _ => parsed.dir = Some(arg.into()),
+        }
+    }
+    if parsed.dir.is_none() {
+        return Err("missing the database directory".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {arg}: {value}"))
+}
+
+fn run(args: Args) -> Result<(), WillowError> {
+    let config_path = env::var_os("WILLOW_CONFIG").map(PathBuf::from);
+    let config = Config::load(config_path.as_deref())?;
+    let reader = WillowDB::builder()
+        .config(&config)?
+        .open_log(args.dir.unwrap())?;
+
+    for rec in reader.records(args.from)? {
+        let (lsn, record) = match rec {
+            Ok(rec) => rec,
+            Err(WalError::Undecodable { lsn }) => {
+                eprintln!("{}/{}: undecodable record", lsn >> 32, lsn as u32);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if args.txn.is_some_and(|txn| record.txn_num() != Some(txn))
+            || args
+                .block
+                .as_ref()
+                .is_some_and(|b| record.block() != Some(b))
+        {
+            continue;
+        }
+        println!("{}/{} {}", lsn >> 32, lsn as u32, record);
+    }
+    Ok(())
+}
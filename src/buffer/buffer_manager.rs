@@ -1,41 +1,100 @@
 #![allow(dead_code)]
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock, RwLockWriteGuard},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    panic::Location,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
 };
 
+use thiserror::Error;
+use tracing::{debug, trace, warn};
+
 use crate::{
-    file::{BlockId, FileManager, Page},
-    log::{LogManager, Lsn},
+    file::{BlockId, Page, StorageBackend, StorageError},
+    log::{LogError, LogManager, Lsn},
+    metrics::{BufferManagerStats, Histogram},
     txn::TxNum,
 };
 
-use super::replacer::{EvictionPolicy, Replacer};
+use super::replacer::{AccessHint, EvictionPolicy, Replacer};
+
+/// Default duration a pin waits for a frame to be unpinned before being aborted.
+pub const DEFAULT_PIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The latest LSN at the time each block was last written to disk, since the pool was created.
+type ChangeMap = Mutex<HashMap<BlockId, Lsn>>;
+
+/// The frames holding each transaction's modifications that weren't written yet.
+type DirtyMap = Mutex<HashMap<TxNum, HashSet<BufferId>>>;
+
+#[derive(Debug, Error)]
+pub enum BufferError {
+    #[error("no unpinned buffer became available in time")]
+    Abort,
+    #[error("{0} is still pinned")]
+    BlockPinned(BlockId),
+    #[error("buffer pool is read-only")]
+    ReadOnly,
+    #[error("a buffer pool of {shards} shards needs at least {shards} frames, got {capacity}")]
+    CapacityTooSmall { capacity: usize, shards: usize },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Log(#[from] LogError),
+}
 
 pub struct Buffer {
-    fm: Arc<FileManager>,
+    fm: Arc<dyn StorageBackend>,
     lm: Arc<LogManager>,
+    /// The buffer's frame in the pool.
+    pos: BufferId,
     contents: Page,
     block: Option<BlockId>,
-    /// Some(t) indicates that the page is modified where
-    /// t is the txn_num that made the change.
-    txn_num: Option<TxNum>,
+    /// Whether the page has changes that weren't written yet.
+    modified: bool,
+    /// Every transaction that changed the page since it was last written. Any of them
+    /// committing writes the page.
+    modified_by: Vec<TxNum>,
     /// If page is modified then this holds the LSN of the most recent log record.
     /// None indicates that no log record was generated for the update.
     lsn: Option<Lsn>,
+    /// LSN of the first log record for a change made since the page was last written, the
+    /// page's recLSN.
+    rec_lsn: Option<Lsn>,
+    /// Set for every buffer of a read-only pool.
+    read_only: bool,
+    changes: Arc<ChangeMap>,
+    dirty: Arc<DirtyMap>,
 }
 
 impl Buffer {
-    fn new(fm: Arc<FileManager>, lm: Arc<LogManager>) -> Self {
+    fn new(
+        fm: Arc<dyn StorageBackend>,
+        lm: Arc<LogManager>,
+        pos: BufferId,
+        changes: Arc<ChangeMap>,
+        dirty: Arc<DirtyMap>,
+    ) -> Self {
         let contents = Page::new(fm.block_size());
         Self {
             fm,
             lm,
+            pos,
             contents,
             block: None,
-            txn_num: None,
+            modified: false,
+            modified_by: Vec::new(),
             lsn: None,
+            rec_lsn: None,
+            read_only: false,
+            changes,
+            dirty,
         }
     }
 
@@ -51,252 +110,1508 @@ impl Buffer {
         self.block.as_ref()
     }
 
-    fn modifying_txn(&self) -> Option<TxNum> {
-        self.txn_num
+    fn is_modified(&self) -> bool {
+        self.modified
     }
 
-    pub fn set_modified(&mut self, txn_num: usize, lsn: Option<Lsn>) {
-        self.txn_num = Some(txn_num);
-        if lsn.is_some() {
-            // Lsn won't be present in case no log record is generated for an update.
-            self.lsn = lsn;
+    /// LSN of the last logged change to the page, kept in its
+    /// [`PageHeader`](crate::file::PageHeader). `0` if there was none.
+    pub fn page_lsn(&self) -> Lsn {
+        self.contents.page_lsn()
+    }
+
+    /// Returns [`BufferError::ReadOnly`] if the buffer belongs to a read-only pool.
+    pub fn set_modified(&mut self, txn_num: usize, lsn: Option<Lsn>) -> Result<(), BufferError> {
+        if self.read_only {
+            return Err(BufferError::ReadOnly);
+        }
+        if !self.modified_by.contains(&txn_num) {
+            let mut dirty = self.dirty.lock().unwrap();
+            dirty.entry(txn_num).or_default().insert(self.pos);
+            self.modified_by.push(txn_num);
         }
+        self.modified = true;
+        // Lsn won't be present in case no log record is generated for an update.
+        if let Some(lsn) = lsn {
+            self.lsn = Some(lsn);
+            self.rec_lsn.get_or_insert(lsn);
+            self.contents.set_page_lsn(lsn);
+        }
+        Ok(())
     }
 
-    fn assign_to_block(&mut self, block: &BlockId) {
-        self.flush();
+    /// Returns whether the previous block's modifications had to be written first.
+    fn assign_to_block(&mut self, block: &BlockId) -> Result<bool, BufferError> {
+        let written = self.flush()?;
         self.block = Some(block.clone());
-        self.fm.read(block, &mut self.contents);
+        self.fm.read_block(block, &mut self.contents)?;
+        Ok(written)
+    }
+
+    /// Returns whether the page was modified and had to be written.
+    fn flush(&mut self) -> Result<bool, BufferError> {
+        if !self.modified {
+            return Ok(false);
+        }
+        self.lm.flush(self.lsn)?;
+        self.fm.write_block(self.block().unwrap(), &self.contents)?;
+        self.changes
+            .lock()
+            .unwrap()
+            .insert(self.block.clone().unwrap(), self.lm.latest_lsn());
+        self.mark_clean();
+        Ok(true)
+    }
+
+    /// Forgets the modifications, once they're written or thrown away.
+    fn mark_clean(&mut self) {
+        if !self.modified_by.is_empty() {
+            let mut dirty = self.dirty.lock().unwrap();
+            for txn_num in self.modified_by.drain(..) {
+                if let Some(frames) = dirty.get_mut(&txn_num) {
+                    frames.remove(&self.pos);
+                    if frames.is_empty() {
+                        dirty.remove(&txn_num);
+                    }
+                }
+            }
+        }
+        self.modified = false;
+        self.rec_lsn = None;
+    }
+}
+
+/// A pinned buffer. The pin is released when the guard is dropped, including on early returns
+/// and panics.
+pub struct PinnedBuffer {
+    bm: Arc<BufferManager>,
+    block: BlockId,
+    buf: Arc<RwLock<Buffer>>,
+    /// Key of the pin's [`PinRecord`] when pins are tracked.
+    pin_id: Option<u64>,
+}
+
+impl PinnedBuffer {
+    pub fn block(&self) -> &BlockId {
+        &self.block
     }
+}
 
-    fn flush(&mut self) {
-        if self.txn_num.is_some() {
-            self.lm.flush(self.lsn);
-            self.fm.write(self.block().unwrap(), &self.contents);
-            self.txn_num = None
+impl Deref for PinnedBuffer {
+    type Target = RwLock<Buffer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        if let (Some(id), Some(tracker)) = (self.pin_id, &self.bm.pin_tracker) {
+            tracker.lock().unwrap().pins.remove(&id);
         }
+        self.bm.unpin(&self.block);
     }
 }
 
+/// A pin that wasn't released yet, see [`Builder::track_pins`](crate::Builder::track_pins).
+#[derive(Debug, Clone)]
+pub struct PinRecord {
+    pub block: BlockId,
+    /// Where the pin was taken.
+    pub location: &'static Location<'static>,
+    /// The transaction that took the pin, `None` for a pin taken outside of one.
+    pub txn_num: Option<TxNum>,
+    pub since: Instant,
+}
+
+/// The pins that weren't released yet, in the order they were taken.
+#[derive(Default)]
+struct PinTracker {
+    next_id: u64,
+    pins: BTreeMap<u64, PinRecord>,
+}
+
 /// index in the buffer pool
 type BufferId = usize;
 
-#[derive(Copy, Clone)]
 struct BufferMeta {
     pos: BufferId,
-    pins: usize,
+    /// Atomic so that a cached frame can be pinned and unpinned under the shard's read lock,
+    /// see [`BufferManagerInner::pin_cached`].
+    pins: AtomicUsize,
+    /// Set when the frame is pinned under the read lock, until the replacer is told about the
+    /// access by the next eviction.
+    referenced: AtomicBool,
 }
 
+impl BufferMeta {
+    fn new(pos: BufferId, pins: usize) -> Self {
+        Self {
+            pos,
+            pins: AtomicUsize::new(pins),
+            referenced: AtomicBool::new(false),
+        }
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Acquire) > 0
+    }
+}
+
+/// Counters kept under the pool's lock, see [`BufferManagerStats`].
+#[derive(Default, Clone, Copy)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    dirty_writes: u64,
+}
+
+/// One shard of the pool: a share of the frames and the blocks that hash to it.
 struct BufferManagerInner {
+    fm: Arc<dyn StorageBackend>,
+    lm: Arc<LogManager>,
     buf_table: HashMap<BlockId, BufferMeta>,
     free_list: Vec<BufferId>,
-    pool: Box<[Arc<RwLock<Buffer>>]>,
+    pool: Vec<Arc<RwLock<Buffer>>>,
+    /// Frames given up by [`BufferManager::resize`]. They keep their place so that the other
+    /// frames' positions don't change, but not their page.
+    retired: Vec<BufferId>,
     replacer: Box<dyn Replacer>,
+    stats: Counters,
+    changes: Arc<ChangeMap>,
+    /// Shared with every buffer, which updates it as it's modified and written.
+    dirty: Arc<DirtyMap>,
+    read_only: bool,
 }
 
 impl BufferManagerInner {
     fn new(
-        fm: Arc<FileManager>,
+        fm: Arc<dyn StorageBackend>,
         lm: Arc<LogManager>,
         capacity: usize,
-        eviction_policy: EvictionPolicy,
+        eviction_policy: &EvictionPolicy,
+        changes: Arc<ChangeMap>,
     ) -> Self {
-        let mut v = Vec::new();
-        v.resize_with(capacity, || {
-            Arc::new(RwLock::new(Buffer::new(Arc::clone(&fm), Arc::clone(&lm))))
-        });
-
-        Self {
+        let mut shard = Self {
+            fm,
+            lm,
             buf_table: HashMap::new(),
-            free_list: (0..capacity).collect(),
-            pool: v.into_boxed_slice(),
-            replacer: eviction_policy.into(),
+            free_list: Vec::with_capacity(capacity),
+            pool: Vec::with_capacity(capacity),
+            retired: Vec::new(),
+            replacer: eviction_policy.replacer(capacity),
+            stats: Counters::default(),
+            changes,
+            dirty: Arc::new(DirtyMap::default()),
+            read_only: false,
+        };
+        shard.grow(capacity);
+        shard
+    }
+
+    /// Frames of the shard, not counting the retired ones.
+    fn capacity(&self) -> usize {
+        self.pool.len() - self.retired.len()
+    }
+
+    /// Adds `n` free frames, bringing back retired ones before appending new ones.
+    fn grow(&mut self, n: usize) {
+        for _ in 0..n {
+            let pos = match self.retired.pop() {
+                Some(pos) => {
+                    self.pool[pos].write().unwrap().contents = Page::new(self.fm.block_size());
+                    pos
+                }
+                None => {
+                    let pos = self.pool.len();
+                    let mut buf = Buffer::new(
+                        Arc::clone(&self.fm),
+                        Arc::clone(&self.lm),
+                        pos,
+                        Arc::clone(&self.changes),
+                        Arc::clone(&self.dirty),
+                    );
+                    buf.read_only = self.read_only;
+                    self.pool.push(Arc::new(RwLock::new(buf)));
+                    pos
+                }
+            };
+            self.free_list.push(pos);
         }
     }
 
-    fn pin(&mut self, block: &BlockId) -> Option<Arc<RwLock<Buffer>>> {
+    /// Retires up to `n` free or unpinned frames, writing their modifications first and
+    /// dropping their pages. Returns the number of frames retired.
+    fn shrink(&mut self, n: usize) -> Result<usize, BufferError> {
+        let mut retired = 0;
+        while retired < n {
+            let Some(pos) = self.free_list.pop().or_else(|| self.evict()) else {
+                break;
+            };
+            let mut buf = self.pool[pos].write().unwrap();
+            match buf.flush() {
+                Ok(written) => self.stats.dirty_writes += written as u64,
+                Err(e) => {
+                    drop(buf);
+                    self.free_list.push(pos);
+                    return Err(e);
+                }
+            }
+            buf.block = None;
+            buf.lsn = None;
+            buf.contents = Page::new(0);
+            drop(buf);
+            self.retired.push(pos);
+            retired += 1;
+        }
+        Ok(retired)
+    }
+
+    fn pin(
+        &mut self,
+        block: &BlockId,
+        hint: AccessHint,
+    ) -> Result<Arc<RwLock<Buffer>>, BufferError> {
         // find existing buffer or choose an un-pinned buffer
-        let existing = self.buf_table.get(block).copied().map(|e| e.pos);
+        let existing = self.buf_table.get(block).map(|e| e.pos);
         let pos = existing
             .or_else(|| self.free_list.pop())
-            .or_else(|| self.replacer.evict())?;
+            .or_else(|| {
+                let pos = self.evict()?;
+                trace!(pos, %block, "evicted a frame to load block");
+                Some(pos)
+            })
+            .ok_or_else(|| {
+                trace!(%block, "buffer pool exhausted");
+                BufferError::Abort
+            })?;
 
-        let buf_lock = self.pool.get_mut(pos)?;
-        if existing.is_none() {
-            buf_lock.write().unwrap().assign_to_block(block);
+        let buf_lock = &self.pool[pos];
+        if existing.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            match buf_lock.write().unwrap().assign_to_block(block) {
+                Ok(written) => self.stats.dirty_writes += written as u64,
+                Err(e) => {
+                    // hand the frame back so that it isn't lost
+                    self.free_list.push(pos);
+                    return Err(e);
+                }
+            }
+            self.replacer.record_load(pos, block);
         }
 
         self.buf_table
             .entry(block.to_owned())
             .and_modify(|e| {
-                e.pins += 1;
+                e.pins.fetch_add(1, Ordering::AcqRel);
             })
-            .or_insert(BufferMeta { pos, pins: 1 });
+            .or_insert_with(|| BufferMeta::new(pos, 1));
+
+        match hint {
+            AccessHint::Normal => self.replacer.record_access(pos),
+            AccessHint::Sequential => self.replacer.record_sequential_access(pos),
+        }
+        // the pin count keeps the frame in place, see `evict`
+        self.replacer.set_evictable(pos, true);
+
+        Ok(Arc::clone(&self.pool[pos]))
+    }
+
+    /// Pins every block in `blocks`, reading the ones that aren't cached with a single
+    /// [`StorageBackend::read_blocks`] call so the backend can keep the reads in flight together.
+    fn pin_many(&mut self, blocks: &[BlockId]) -> Result<Vec<Arc<RwLock<Buffer>>>, BufferError> {
+        let mut misses: Vec<(BufferId, BlockId)> = Vec::new();
+        let mut positions = Vec::with_capacity(blocks.len());
+
+        for block in blocks {
+            let cached = self
+                .buf_table
+                .get(block)
+                .map(|e| e.pos)
+                .or_else(|| misses.iter().find(|(_, b)| b == block).map(|(pos, _)| *pos));
+            let pos = match cached.or_else(|| self.free_list.pop()) {
+                Some(pos) => pos,
+                None => match self.evict() {
+                    Some(pos) => pos,
+                    None => {
+                        trace!(%block, "buffer pool exhausted");
+                        // hand back the frames claimed so far
+                        self.free_list.extend(misses.iter().map(|(pos, _)| *pos));
+                        return Err(BufferError::Abort);
+                    }
+                },
+            };
+            if cached.is_none() {
+                misses.push((pos, block.clone()));
+            }
+            positions.push(pos);
+        }
+
+        if let Err(e) = self.read_misses(&misses) {
+            self.free_list.extend(misses.iter().map(|(pos, _)| *pos));
+            return Err(e);
+        }
+        for (pos, block) in &misses {
+            self.replacer.record_load(*pos, block);
+        }
 
-        self.replacer.record_access(pos);
+        for (block, &pos) in blocks.iter().zip(&positions) {
+            self.buf_table
+                .entry(block.to_owned())
+                .and_modify(|e| {
+                    e.pins.fetch_add(1, Ordering::AcqRel);
+                })
+                .or_insert_with(|| BufferMeta::new(pos, 1));
+            self.replacer.record_access(pos);
+            self.replacer.set_evictable(pos, true);
+        }
+        self.stats.misses += misses.len() as u64;
+        self.stats.hits += (blocks.len() - misses.len()) as u64;
 
-        Some(Arc::clone(buf_lock))
+        Ok(positions
+            .into_iter()
+            .map(|pos| Arc::clone(&self.pool[pos]))
+            .collect())
     }
 
-    fn unpin(&mut self, buf: RwLockWriteGuard<Buffer>) {
-        let block = buf.block().unwrap();
-        if let Some(e) = self.buf_table.get_mut(block) {
-            e.pins = e.pins.saturating_sub(1);
-            let is_pinned = e.pins > 0;
-            if !is_pinned {
-                let meta = self.buf_table.remove(block).unwrap();
-                self.replacer.set_evictable(meta.pos, true);
+    /// Reads the blocks of `blocks` that aren't cached into free frames, without pinning them,
+    /// for as long as there are free frames. Returns the number of blocks read.
+    fn prefetch(&mut self, blocks: &[BlockId]) -> Result<usize, BufferError> {
+        let mut loads: Vec<(BufferId, BlockId)> = Vec::new();
+        for block in blocks {
+            if self.buf_table.contains_key(block) || loads.iter().any(|(_, b)| b == block) {
+                continue;
+            }
+            let Some(pos) = self.free_list.pop() else {
+                break;
+            };
+            loads.push((pos, block.clone()));
+        }
+
+        if let Err(e) = self.read_misses(&loads) {
+            self.free_list.extend(loads.iter().map(|(pos, _)| *pos));
+            return Err(e);
+        }
+        for (pos, block) in &loads {
+            self.replacer.record_load(*pos, block);
+            self.replacer.record_access(*pos);
+            self.replacer.set_evictable(*pos, true);
+            self.buf_table
+                .insert(block.clone(), BufferMeta::new(*pos, 0));
+        }
+        Ok(loads.len())
+    }
+
+    fn read_misses(&mut self, misses: &[(BufferId, BlockId)]) -> Result<(), BufferError> {
+        if misses.is_empty() {
+            return Ok(());
+        }
+        let mut bufs = Vec::with_capacity(misses.len());
+        for (pos, _) in misses {
+            let mut buf = self.pool[*pos].write().unwrap();
+            self.stats.dirty_writes += buf.flush()? as u64;
+            bufs.push(buf);
+        }
+
+        let ids: Vec<BlockId> = misses.iter().map(|(_, block)| block.clone()).collect();
+        let mut pages: Vec<&mut Page> = bufs.iter_mut().map(|b| &mut b.contents).collect();
+        self.fm.read_blocks(&ids, &mut pages)?;
+
+        for (buf, block) in bufs.iter_mut().zip(ids) {
+            buf.block = Some(block);
+        }
+        Ok(())
+    }
+
+    /// Picks an unpinned frame to reuse and forgets the block it held.
+    ///
+    /// The replacer sees every loaded frame as evictable and is told which ones are pinned
+    /// here instead, as pins come and go under the read lock. A frame pinned that way since
+    /// the last eviction is passed over once, and its access is recorded then.
+    fn evict(&mut self) -> Option<BufferId> {
+        let (pool, buf_table) = (&self.pool, &self.buf_table);
+        let pos = loop {
+            let mut referenced = Vec::new();
+            let pos = self.replacer.evict_unless(&mut |pos| {
+                // a frame locked by its user is pinned
+                let Ok(buf) = pool[pos].try_read() else {
+                    return true;
+                };
+                let Some(meta) = buf.block().and_then(|block| buf_table.get(block)) else {
+                    return false;
+                };
+                if meta.is_pinned() {
+                    return true;
+                }
+                if meta.referenced.swap(false, Ordering::AcqRel) {
+                    referenced.push(pos);
+                    return true;
+                }
+                false
+            });
+            for &pos in &referenced {
+                self.replacer.record_access(pos);
+                self.replacer.set_evictable(pos, true);
+            }
+            match pos {
+                Some(pos) => break pos,
+                None if referenced.is_empty() => return None,
+                None => {}
             }
         };
+        if let Some(block) = self.pool[pos].read().unwrap().block() {
+            self.buf_table.remove(block);
+        }
+        self.stats.evictions += 1;
+        Some(pos)
     }
 
+    /// Pins `block` if it's cached, which only needs the shard's read lock: evictions take the
+    /// write lock and skip pinned frames. Pinning an unpinned frame is recorded as an access by
+    /// the next eviction, while a frame that's already pinned isn't accessed anew, as that's
+    /// correlated with the pin anyway. A sequential access to an unpinned frame goes through
+    /// `pin` instead, so that the replacer gets the hint.
+    fn pin_cached(&self, block: &BlockId, hint: AccessHint) -> Option<Arc<RwLock<Buffer>>> {
+        let e = self.buf_table.get(block)?;
+        let pins = e
+            .pins
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n > 0 || hint == AccessHint::Normal).then_some(n + 1)
+            })
+            .ok()?;
+        if pins == 0 {
+            e.referenced.store(true, Ordering::Release);
+        }
+        Some(Arc::clone(&self.pool[e.pos]))
+    }
+
+    /// Drops a pin of `block`, which only needs the shard's read lock. Returns whether it was
+    /// the last one. The block stays cached until its frame is evicted.
+    fn release(&self, block: &BlockId) -> bool {
+        // the block may have been discarded in between, the frame is released all the same
+        self.buf_table.get(block).is_some_and(|e| {
+            e.pins
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+                == Ok(1)
+        })
+    }
+
+    /// Free frames and frames holding an unpinned block.
     fn available(&self) -> usize {
-        self.free_list.len() + self.replacer.available()
+        let unpinned = self.buf_table.values().filter(|e| !e.is_pinned()).count();
+        self.free_list.len() + unpinned
     }
 
-    fn flush_all(&mut self, txn_num: TxNum) {
-        for meta in self.buf_table.values() {
-            let matches = {
-                let buf = self.pool.get(meta.pos).unwrap().read().unwrap();
-                buf.modifying_txn().is_some_and(|x| x == txn_num)
-            };
-            if matches {
-                let mut buf = self.pool.get(meta.pos).unwrap().write().unwrap();
-                buf.flush();
+    /// Writes every modified buffer of the blocks `selected` picks in one batch, whichever
+    /// transaction modified it.
+    fn flush_dirty(&mut self, selected: impl Fn(&BlockId) -> bool) -> Result<(), BufferError> {
+        // scan the whole pool rather than buf_table, each buffer knows whether it's modified
+        let dirty: Vec<_> = self
+            .pool
+            .iter()
+            .map(|buf| buf.write().unwrap())
+            .filter(|buf| buf.is_modified() && buf.block().is_some_and(&selected))
+            .collect();
+        self.stats.dirty_writes += write_batch(self.fm.as_ref(), &self.changes, dirty)? as u64;
+        Ok(())
+    }
+
+    /// Writes the buffers modified by `txn_num` in one batch, without looking at the rest of
+    /// the pool.
+    fn flush_all(&mut self, txn_num: TxNum) -> Result<(), BufferError> {
+        let Some(frames) = self.dirty.lock().unwrap().get(&txn_num).cloned() else {
+            return Ok(());
+        };
+        let mut frames: Vec<_> = frames.into_iter().collect();
+        frames.sort_unstable();
+        let dirty: Vec<_> = frames
+            .into_iter()
+            .map(|pos| self.pool[pos].write().unwrap())
+            .filter(|buf| buf.modified_by.contains(&txn_num))
+            .collect();
+        self.stats.dirty_writes += write_batch(self.fm.as_ref(), &self.changes, dirty)? as u64;
+        Ok(())
+    }
+
+    /// Writes the buffers of `frames` that are modified, skipping the ones locked by their
+    /// users. Returns the number of pages written.
+    fn flush_frames(&mut self, frames: &[BufferId]) -> Result<usize, BufferError> {
+        let dirty: Vec<_> = frames
+            .iter()
+            .filter_map(|&pos| self.pool[pos].try_write().ok())
+            .filter(|buf| buf.is_modified())
+            .collect();
+        let written = write_batch(self.fm.as_ref(), &self.changes, dirty)?;
+        self.stats.dirty_writes += written as u64;
+        Ok(written)
+    }
+
+    /// A pinned block among those `doomed` selects, if any.
+    fn pinned(&self, doomed: impl Fn(&BlockId) -> bool) -> Option<BlockId> {
+        self.buf_table
+            .iter()
+            .find(|(b, meta)| doomed(b) && meta.is_pinned())
+            .map(|(b, _)| b.clone())
+    }
+
+    /// Drops the cached copies of the blocks `doomed` selects, including unflushed
+    /// modifications. None of them may be pinned.
+    fn discard(&mut self, doomed: impl Fn(&BlockId) -> bool) {
+        self.buf_table.retain(|b, _| !doomed(b));
+
+        for buf in self.pool.iter() {
+            let mut buf = buf.write().unwrap();
+            if buf.block().is_some_and(&doomed) {
+                buf.mark_clean();
+                buf.block = None;
+                buf.lsn = None;
             }
         }
     }
 }
 
+/// Writes the modified buffers in `dirty` in one batch, after flushing the log up to the newest
+/// of their LSNs. Returns the number of pages written.
+fn write_batch(
+    fm: &dyn StorageBackend,
+    changes: &ChangeMap,
+    mut dirty: Vec<RwLockWriteGuard<Buffer>>,
+) -> Result<usize, BufferError> {
+    let Some(first) = dirty.first() else {
+        return Ok(0);
+    };
+    let lm = Arc::clone(&first.lm);
+
+    lm.flush(dirty.iter().filter_map(|buf| buf.lsn).max())?;
+    let blocks: Vec<BlockId> = dirty.iter().map(|buf| buf.block.clone().unwrap()).collect();
+    let pages: Vec<&Page> = dirty.iter().map(|buf| &buf.contents).collect();
+    fm.write_blocks(&blocks, &pages)?;
+
+    let lsn = lm.latest_lsn();
+    let mut changes = changes.lock().unwrap();
+    for (buf, block) in dirty.iter_mut().zip(&blocks) {
+        buf.mark_clean();
+        changes.insert(block.clone(), lsn);
+    }
+    Ok(blocks.len())
+}
+
+/// Frames a shard should have at least; smaller pools aren't sharded.
+const MIN_SHARD_FRAMES: usize = 64;
+const MAX_SHARDS: usize = 16;
+
+/// Frames of shard `i` out of `n` in a pool of `capacity` frames.
+fn shard_frames(capacity: usize, n: usize, i: usize) -> usize {
+    capacity / n + usize::from(i < capacity % n)
+}
+
+/// A pool of buffers caching disk blocks.
+///
+/// Large pools are split into shards, each with its own frames, block table and replacer, and
+/// each block always goes to the shard its hash picks. Pins of blocks in different shards don't
+/// wait for each other; the flip side is that a pin waits when its shard is full, even if
+/// another shard has unpinned frames.
 pub struct BufferManager {
-    state: RwLock<BufferManagerInner>,
+    shards: Box<[RwLock<BufferManagerInner>]>,
+    fm: Arc<dyn StorageBackend>,
+    /// Shared with every buffer; kept outside the shards so it can be read while writes are
+    /// paused.
+    changes: Arc<ChangeMap>,
+    /// Held by pins waiting for a frame, so that an unpin can't slip in between their last
+    /// attempt and the wait.
+    waiting: Mutex<()>,
+    unpinned: Condvar,
+    pin_timeout: Duration,
+    /// Time spent by pins that had to wait for a frame.
+    pin_wait: Histogram,
+    aborts: AtomicU64,
+    /// Hits served by the read-locked path, which can't update the shards' counters.
+    fast_hits: AtomicU64,
+    pin_tracker: Option<Mutex<PinTracker>>,
 }
 
 impl BufferManager {
     pub fn new(
-        fm: Arc<FileManager>,
+        fm: Arc<dyn StorageBackend>,
         lm: Arc<LogManager>,
         capacity: usize,
         eviction_policy: EvictionPolicy,
     ) -> Self {
+        let changes = Arc::new(ChangeMap::default());
+        let n = (capacity / MIN_SHARD_FRAMES).clamp(1, MAX_SHARDS);
+        let shards = (0..n)
+            .map(|i| {
+                RwLock::new(BufferManagerInner::new(
+                    Arc::clone(&fm),
+                    Arc::clone(&lm),
+                    shard_frames(capacity, n, i),
+                    &eviction_policy,
+                    Arc::clone(&changes),
+                ))
+            })
+            .collect();
         Self {
-            state: RwLock::new(BufferManagerInner::new(fm, lm, capacity, eviction_policy)),
+            shards,
+            fm,
+            changes,
+            waiting: Mutex::new(()),
+            unpinned: Condvar::new(),
+            pin_timeout: DEFAULT_PIN_TIMEOUT,
+            pin_wait: Histogram::default(),
+            aborts: AtomicU64::new(0),
+            fast_hits: AtomicU64::new(0),
+            pin_tracker: None,
         }
     }
 
-    pub fn pin(&self, block: &BlockId) -> Option<Arc<RwLock<Buffer>>> {
-        let mut state = self.state.write().unwrap();
-        state.pin(block)
+    /// How long a pin waits for a frame to be unpinned when every frame is pinned.
+    pub fn with_pin_timeout(mut self, timeout: Duration) -> Self {
+        self.pin_timeout = timeout;
+        self
     }
 
-    pub fn unpin(&self, buf: RwLockWriteGuard<Buffer>) {
-        let mut state = self.state.write().unwrap();
-        state.unpin(buf);
+    /// Records where each pin is taken, and by which transaction, until it's released, to find
+    /// pins that are never released with [`BufferManager::outstanding_pins`]. Every pin and
+    /// unpin then goes through one more lock.
+    pub fn with_pin_tracking(mut self) -> Self {
+        self.pin_tracker = Some(Mutex::default());
+        self
     }
 
-    fn available(&self) -> usize {
-        let state = self.state.read().unwrap();
-        state.free_list.len() + state.replacer.available()
+    /// The pins that weren't released yet, oldest first. Always empty unless pins are tracked.
+    pub fn outstanding_pins(&self) -> Vec<PinRecord> {
+        self.pin_tracker
+            .as_ref()
+            .map(|t| t.lock().unwrap().pins.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Makes every buffer in the pool refuse [`Buffer::set_modified`].
+    pub fn read_only(self) -> Self {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            shard.read_only = true;
+            for buf in shard.pool.iter() {
+                buf.write().unwrap().read_only = true;
+            }
+        }
+        self
+    }
+
+    /// Frames in the pool.
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.read().unwrap().capacity())
+            .sum()
+    }
+
+    /// Grows or shrinks the pool to `capacity` frames while it's in use. Returns the capacity
+    /// it ends up with.
+    ///
+    /// The frames are spread over the shards as when the pool was created, but the number of
+    /// shards doesn't change. Growing adds free frames. Shrinking gives up free frames first and
+    /// then evicts unpinned ones, writing their modifications; pinned frames are kept, so the
+    /// pool can remain larger than `capacity`. Every shard needs a frame, so a `capacity` below
+    /// the number of shards is rejected. If writing a modification fails, the shards resized
+    /// so far keep their new size.
+    pub fn resize(&self, capacity: usize) -> Result<usize, BufferError> {
+        let n = self.shards.len();
+        if capacity < n.max(1) {
+            return Err(BufferError::CapacityTooSmall {
+                capacity,
+                shards: n,
+            });
+        }
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut shard = shard.write().unwrap();
+            let (current, target) = (shard.capacity(), shard_frames(capacity, n, i));
+            if target > current {
+                shard.grow(target - current);
+            } else {
+                shard.shrink(current - target)?;
+            }
+            let frames = shard.capacity();
+            shard.replacer.set_capacity(frames);
+        }
+        {
+            // pins waiting for a frame may find one now
+            let _waiting = self.waiting.lock().unwrap();
+            self.unpinned.notify_all();
+        }
+        let capacity = self.capacity();
+        debug!(capacity, "resized buffer pool");
+        Ok(capacity)
+    }
+
+    fn shard_index(&self, block: &BlockId) -> usize {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+        let mut hasher = DefaultHasher::new();
+        block.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, block: &BlockId) -> &RwLock<BufferManagerInner> {
+        &self.shards[self.shard_index(block)]
+    }
+
+    /// Locks every shard, in order.
+    fn lock_all(&self) -> Vec<RwLockWriteGuard<'_, BufferManagerInner>> {
+        self.shards.iter().map(|s| s.write().unwrap()).collect()
+    }
+
+    /// Pins `block`, waiting for a frame to be unpinned if every frame of its shard is pinned.
+    /// Returns [`BufferError::Abort`] if none was within the pin timeout.
+    #[track_caller]
+    pub fn pin(self: &Arc<Self>, block: &BlockId) -> Result<PinnedBuffer, BufferError> {
+        self.pin_for(block, AccessHint::Normal, None)
+    }
+
+    /// Pins `block` like [`BufferManager::pin`], telling the replacer how it's about to be
+    /// used.
+    #[track_caller]
+    pub fn pin_with_hint(
+        self: &Arc<Self>,
+        block: &BlockId,
+        hint: AccessHint,
+    ) -> Result<PinnedBuffer, BufferError> {
+        self.pin_for(block, hint, None)
+    }
+
+    /// Pins `block` on behalf of transaction `txn_num`, which is recorded if pins are tracked.
+    #[track_caller]
+    pub(crate) fn pin_for(
+        self: &Arc<Self>,
+        block: &BlockId,
+        hint: AccessHint,
+        txn_num: Option<TxNum>,
+    ) -> Result<PinnedBuffer, BufferError> {
+        let location = Location::caller();
+        let shard = self.shard(block);
+        let cached = shard.read().unwrap().pin_cached(block, hint);
+        let buf = match cached {
+            Some(buf) => {
+                self.fast_hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => self.wait_for_frames(|| shard.write().unwrap().pin(block, hint))?,
+        };
+        Ok(self.guard(block, buf, location, txn_num))
+    }
+
+    fn guard(
+        self: &Arc<Self>,
+        block: &BlockId,
+        buf: Arc<RwLock<Buffer>>,
+        location: &'static Location<'static>,
+        txn_num: Option<TxNum>,
+    ) -> PinnedBuffer {
+        let pin_id = self.pin_tracker.as_ref().map(|tracker| {
+            let mut tracker = tracker.lock().unwrap();
+            let id = tracker.next_id;
+            tracker.next_id += 1;
+            tracker.pins.insert(
+                id,
+                PinRecord {
+                    block: block.clone(),
+                    location,
+                    txn_num,
+                    since: Instant::now(),
+                },
+            );
+            id
+        });
+        PinnedBuffer {
+            bm: Arc::clone(self),
+            block: block.clone(),
+            buf,
+            pin_id,
+        }
+    }
+
+    /// Pins several blocks at once. Blocks that have to be read from disk are read as one batch
+    /// per shard. Waits like [`BufferManager::pin`] when there aren't enough unpinned frames.
+    #[track_caller]
+    pub fn pin_many(
+        self: &Arc<Self>,
+        blocks: &[BlockId],
+    ) -> Result<Vec<PinnedBuffer>, BufferError> {
+        let location = Location::caller();
+        let bufs = self.wait_for_frames(|| self.try_pin_many(blocks))?;
+        Ok(blocks
+            .iter()
+            .zip(bufs)
+            .map(|(block, buf)| self.guard(block, buf, location, None))
+            .collect())
+    }
+
+    /// Pins all of `blocks` or none of them.
+    fn try_pin_many(&self, blocks: &[BlockId]) -> Result<Vec<Arc<RwLock<Buffer>>>, BufferError> {
+        let mut by_shard: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, block) in blocks.iter().enumerate() {
+            by_shard.entry(self.shard_index(block)).or_default().push(i);
+        }
+        // every shard involved is locked first, in order, so that nobody sees half of the pins
+        let mut locked: Vec<_> = by_shard
+            .into_iter()
+            .map(|(shard, idx)| (self.shards[shard].write().unwrap(), idx))
+            .collect();
+
+        let mut bufs = vec![None; blocks.len()];
+        for k in 0..locked.len() {
+            let (state, idx) = &mut locked[k];
+            let subset: Vec<BlockId> = idx.iter().map(|&i| blocks[i].clone()).collect();
+            match state.pin_many(&subset) {
+                Ok(pinned) => {
+                    for (&i, buf) in idx.iter().zip(pinned) {
+                        bufs[i] = Some(buf);
+                    }
+                }
+                Err(e) => {
+                    // release what the shards before this one pinned
+                    for (state, idx) in &mut locked[..k] {
+                        for &i in idx.iter() {
+                            state.release(&blocks[i]);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(bufs.into_iter().map(Option::unwrap).collect())
+    }
+    /// Reads the blocks of `blocks` that aren't cached yet into free frames, in one batch per
+    /// shard, so that pinning them later doesn't wait for the disk. Blocks aren't pinned, and
+    /// nothing is evicted for them: the ones that don't fit in the free frames are skipped.
+    ///
+    /// A prefetched block counts as accessed once by the replacer. Returns the number of
+    /// blocks read.
+    pub fn prefetch(&self, blocks: &[BlockId]) -> Result<usize, BufferError> {
+        let mut by_shard: BTreeMap<usize, Vec<BlockId>> = BTreeMap::new();
+        for block in blocks {
+            by_shard
+                .entry(self.shard_index(block))
+                .or_default()
+                .push(block.clone());
+        }
+        let mut read = 0;
+        for (shard, blocks) in by_shard {
+            read += self.shards[shard].write().unwrap().prefetch(&blocks)?;
+        }
+        Ok(read)
+    }
+
+    /// Runs `f` until it finds the frames it needs, waking up on every unpin, for at most the
+    /// pin timeout.
+    fn wait_for_frames<T>(&self, f: impl Fn() -> Result<T, BufferError>) -> Result<T, BufferError> {
+        match f() {
+            Err(BufferError::Abort) => {}
+            res => return res,
+        }
+
+        let start = Instant::now();
+        let mut waiting = self.waiting.lock().unwrap();
+        loop {
+            match f() {
+                Err(BufferError::Abort) if start.elapsed() < self.pin_timeout => {}
+                Err(BufferError::Abort) => {
+                    warn!(waited = ?start.elapsed(), "pin request aborted");
+                    self.pin_wait.observe(start.elapsed());
+                    self.aborts.fetch_add(1, Ordering::Relaxed);
+                    return Err(BufferError::Abort);
+                }
+                res => {
+                    self.pin_wait.observe(start.elapsed());
+                    return res;
+                }
+            }
+            debug!("waiting for an unpinned buffer");
+            let remaining = self.pin_timeout.saturating_sub(start.elapsed());
+            (waiting, _) = self.unpinned.wait_timeout(waiting, remaining).unwrap();
+        }
+    }
+
+    fn unpin(&self, block: &BlockId) {
+        let shard = self.shard(block);
+        let last = shard.read().unwrap().release(block);
+        if last {
+            let _waiting = self.waiting.lock().unwrap();
+            self.unpinned.notify_all();
+        }
+    }
+
+    pub(crate) fn available(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.read().unwrap().available())
+            .sum()
     }
 
-    pub fn flush_all(&self, txn_num: TxNum) {
-        let mut state = self.state.write().unwrap();
-        state.flush_all(txn_num);
+    pub fn flush_all(&self, txn_num: TxNum) -> Result<(), BufferError> {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().flush_all(txn_num)?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BufferManagerStats {
+        let mut stats = BufferManagerStats {
+            hits: self.fast_hits.load(Ordering::Relaxed),
+            aborts: self.aborts.load(Ordering::Relaxed),
+            pin_wait: self.pin_wait.snapshot(),
+            ..Default::default()
+        };
+        for shard in self.shards.iter() {
+            let state = shard.read().unwrap();
+            let c = state.stats;
+            stats.pinned += state.buf_table.values().filter(|e| e.is_pinned()).count();
+            stats.hits += c.hits;
+            stats.misses += c.misses;
+            stats.evictions += c.evictions;
+            stats.dirty_writes += c.dirty_writes;
+        }
+        stats
+    }
+
+    /// The blocks cached in the pool, sorted by file and block number.
+    pub(crate) fn resident_blocks(&self) -> Vec<BlockId> {
+        let mut blocks: Vec<BlockId> = self
+            .shards
+            .iter()
+            .flat_map(|s| {
+                s.read()
+                    .unwrap()
+                    .buf_table
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        blocks.sort_by_key(|b| (b.filename(), b.number()));
+        blocks
+    }
+
+    /// The blocks with logged changes that weren't written to disk yet, with their recLSNs.
+    pub(crate) fn dirty_pages(&self) -> Vec<(BlockId, Lsn)> {
+        let mut pages = Vec::new();
+        for shard in self.shards.iter() {
+            let state = shard.read().unwrap();
+            pages.extend(state.pool.iter().filter_map(|buf| {
+                let buf = buf.read().unwrap();
+                Some((buf.block.clone()?, buf.rec_lsn?))
+            }));
+        }
+        pages
+    }
+
+    /// Writes every modified buffer to disk, regardless of which transaction modified it.
+    pub fn flush_all_dirty(&self) -> Result<(), BufferError> {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().flush_dirty(|_| true)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every modified buffer of `filename` to disk, e.g. before copying the file.
+    pub fn flush_file(&self, filename: &str) -> Result<(), BufferError> {
+        for shard in self.shards.iter() {
+            shard
+                .write()
+                .unwrap()
+                .flush_dirty(|block| block.filename() == filename)?;
+        }
+        Ok(())
+    }
+
+    /// Writes up to `max` modified buffers, oldest changes first, without waiting for buffers
+    /// that are in use. Returns the number of pages written.
+    pub fn flush_oldest(&self, max: usize) -> Result<usize, BufferError> {
+        // (recLSN, shard, frame)
+        let mut oldest = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let state = shard.read().unwrap();
+            oldest.extend(state.pool.iter().enumerate().filter_map(|(pos, buf)| {
+                let buf = buf.try_read().ok()?;
+                buf.is_modified().then_some((buf.rec_lsn, i, pos))
+            }));
+        }
+        oldest.sort_unstable();
+        oldest.truncate(max);
+        oldest.sort_unstable_by_key(|&(_, shard, pos)| (shard, pos));
+
+        let mut written = 0;
+        for frames in oldest.chunk_by(|a, b| a.1 == b.1) {
+            let positions: Vec<BufferId> = frames.iter().map(|&(_, _, pos)| pos).collect();
+            written += self.shards[frames[0].1]
+                .write()
+                .unwrap()
+                .flush_frames(&positions)?;
+        }
+        Ok(written)
+    }
+
+    /// Writes every modified buffer to disk and runs `f` before letting go of the pool,
+    /// so no page reaches disk until `f` returns. Pins, unpins and commits wait meanwhile.
+    pub(crate) fn pause_writes<T>(&self, f: impl FnOnce() -> T) -> Result<T, BufferError> {
+        let mut shards = self.lock_all();
+        for state in shards.iter_mut() {
+            state.flush_dirty(|_| true)?;
+        }
+        Ok(f())
+    }
+
+    /// Blocks written to disk since `lsn` was the latest LSN, as far back as the pool's creation.
+    pub(crate) fn changed_since(&self, lsn: Lsn) -> Vec<BlockId> {
+        let changes = self.changes.lock().unwrap();
+        changes
+            .iter()
+            .filter(|(_, &written)| written >= lsn)
+            .map(|(block, _)| block.clone())
+            .collect()
+    }
+
+    /// Shrinks `filename` to `len` blocks, throwing away cached copies of the removed blocks.
+    /// Returns [`BufferError::BlockPinned`] if one of them is still pinned.
+    pub fn truncate(&self, filename: &str, len: u64) -> Result<(), BufferError> {
+        self.discard(filename, len)?;
+        self.fm.truncate(filename, len)?;
+        Ok(())
+    }
+
+    /// Deletes `filename`, throwing away every cached block of it.
+    /// Returns [`BufferError::BlockPinned`] if one of them is still pinned.
+    pub fn delete(&self, filename: &str) -> Result<(), BufferError> {
+        self.discard(filename, 0)?;
+        self.fm.delete(filename)?;
+        Ok(())
+    }
+
+    /// Drops the cached copies of every block of `filename` numbered `from` or higher,
+    /// including unflushed modifications. Fails if any of those blocks is pinned.
+    fn discard(&self, filename: &str, from: u64) -> Result<(), BufferError> {
+        let doomed = |block: &BlockId| block.filename() == filename && block.number() >= from;
+        let mut shards = self.lock_all();
+        if let Some(block) = shards.iter().find_map(|state| state.pinned(doomed)) {
+            return Err(BufferError::BlockPinned(block));
+        }
+        for state in shards.iter_mut() {
+            state.discard(doomed);
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use std::{
-        env,
-        time::{SystemTime, UNIX_EPOCH},
-    };
+    use crate::file::FileManager;
 
     use super::*;
 
     fn setup(
-        prefix: &str,
         block_size: usize,
         capacity: usize,
-    ) -> (Arc<FileManager>, BufferManager) {
-        let dirname = format!(
-            "{}_{}",
-            prefix,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-        );
-        let dir_path = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname);
-        let fm = Arc::new(FileManager::new(&dir_path, block_size));
-        let lm = Arc::new(LogManager::new(Arc::clone(&fm), "db.log"));
-        (
-            Arc::clone(&fm),
-            BufferManager::new(fm, lm, capacity, EvictionPolicy::default()),
-        )
+        pin_timeout: Duration,
+    ) -> (Arc<FileManager>, Arc<BufferManager>) {
+        let fm = Arc::new(FileManager::in_memory(block_size));
+        let lm = Arc::new(LogManager::new(fm.clone(), "db.log").unwrap());
+        let bm = BufferManager::new(fm.clone(), lm, capacity, EvictionPolicy::default())
+            .with_pin_timeout(pin_timeout);
+        (fm, Arc::new(bm))
+    }
+
+    #[test]
+    fn test_pin_many() {
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
+        for i in 0..3 {
+            let mut p = Page::new(fm.block_size());
+            p.set_int(0, i * 10);
+            fm.write_block(&BlockId::new("testfile", i as u64), &p)
+                .unwrap();
+        }
+
+        let first = bm.pin(&BlockId::new("testfile", 0)).unwrap();
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        let bufs = bm.pin_many(&blocks).unwrap();
+
+        let values: Vec<_> = bufs
+            .iter()
+            .map(|b| b.read().unwrap().contents().get_int(0))
+            .collect();
+        assert_eq!(values, [0, 10, 20]);
+        assert!(std::ptr::eq(&*first, &*bufs[0]));
+        assert_eq!(bufs[2].block(), &blocks[2]);
+        assert_eq!(bm.available(), 0);
+
+        let stats = bm.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+        assert_eq!(stats.pinned, 3);
+
+        // no frame left for block 3; the pool must be unchanged afterwards
+        assert!(matches!(
+            bm.pin_many(&[BlockId::new("testfile", 3)]),
+            Err(BufferError::Abort)
+        ));
+
+        drop(bufs);
+        assert_eq!(bm.available(), 2);
+        drop(first);
+        assert_eq!(bm.available(), 3);
     }
 
     #[test]
     fn test_buffer() {
-        let (fm, bm) = setup("buffertest", 400, 3);
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
         let fname = "testfile";
 
         assert_eq!(bm.available(), 3);
 
         let (bid1, bid2) = (BlockId::new(fname, 1), BlockId::new(fname, 2));
 
-        let buf1_lock = bm.pin(&bid1).unwrap();
-        let mut buf1 = buf1_lock.write().unwrap();
-        let p = buf1.contents_mut();
+        {
+            let buf1_lock = bm.pin(&bid1).unwrap();
+            let mut buf1 = buf1_lock.write().unwrap();
+            let p = buf1.contents_mut();
 
-        let n = p.get_int(80);
-        assert_eq!(n, 0);
+            let n = p.get_int(80);
+            assert_eq!(n, 0);
 
-        // this modification will get written to disk
-        p.set_int(80, n + 1);
-        buf1.set_modified(1, Some(0));
-        bm.unpin(buf1);
+            // this modification will get written to disk
+            p.set_int(80, n + 1);
+            buf1.set_modified(1, Some(0)).unwrap();
+        }
 
         assert_eq!(bm.available(), 3);
 
-        let buf2_lock = bm.pin(&BlockId::new(fname, 2)).unwrap();
-        let buf2 = buf2_lock.write().unwrap();
-
-        bm.pin(&BlockId::new(fname, 3)).unwrap();
-        bm.pin(&BlockId::new(fname, 4)).unwrap();
+        let buf2 = bm.pin(&BlockId::new(fname, 2)).unwrap();
+        let _buf3 = bm.pin(&BlockId::new(fname, 3)).unwrap();
+        let _buf4 = bm.pin(&BlockId::new(fname, 4)).unwrap();
 
         // ^one of these pins should've flushed block1 to disk
-        bm.unpin(buf2);
+        drop(buf2);
         assert_eq!(bm.available(), 1);
+        assert_eq!(bm.stats().dirty_writes, 1);
 
         // verify that block1 was written to disk
 
         let mut p1 = Page::new(fm.block_size());
-        fm.read(&bid1, &mut p1);
+        fm.read_block(&bid1, &mut p1).unwrap();
 
         assert_eq!(p1.get_int(80), 1);
 
-        let buf2_lock = bm.pin(&bid2).unwrap();
-        let mut buf2 = buf2_lock.write().unwrap();
-        let p2 = buf2.contents_mut();
+        {
+            let buf2_lock = bm.pin(&bid2).unwrap();
+            let mut buf2 = buf2_lock.write().unwrap();
+            let p2 = buf2.contents_mut();
 
-        // this modification won't get written to disk
-        p2.set_int(80, 9999);
-        buf2.set_modified(1, Some(0));
-        bm.unpin(buf2);
+            // this modification won't get written to disk
+            p2.set_int(80, 9999);
+            buf2.set_modified(1, Some(0)).unwrap();
+        }
 
         // verify that block2 wasn't written to disk
 
         let mut p2 = Page::new(fm.block_size());
-        fm.read(&bid2, &mut p2);
+        fm.read_block(&bid2, &mut p2).unwrap();
 
         assert_eq!(p2.get_int(80), 0);
     }
 
+    #[test]
+    fn test_flush_all_txn() {
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
+        fm.append_extent("testfile", 3).unwrap();
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        for (block, txn_num) in blocks.iter().zip([1, 2, 1]) {
+            let buf = bm.pin(block).unwrap();
+            let mut buf = buf.write().unwrap();
+            buf.contents_mut().set_int(0, txn_num as i32);
+            buf.set_modified(txn_num, None).unwrap();
+        }
+        // block 2 is modified by both transactions, either one's commit writes it
+        {
+            let buf = bm.pin(&blocks[2]).unwrap();
+            buf.write().unwrap().set_modified(2, None).unwrap();
+        }
+
+        bm.flush_all(1).unwrap();
+        assert_eq!(bm.stats().dirty_writes, 2);
+        let mut p = Page::new(fm.block_size());
+        fm.read_block(&blocks[0], &mut p).unwrap();
+        assert_eq!(p.get_int(0), 1);
+        fm.read_block(&blocks[2], &mut p).unwrap();
+        assert_eq!(p.get_int(0), 1);
+        fm.read_block(&blocks[1], &mut p).unwrap();
+        assert_eq!(p.get_int(0), 0);
+
+        bm.flush_all(2).unwrap();
+        assert_eq!(bm.stats().dirty_writes, 3);
+        bm.flush_all(2).unwrap();
+        assert_eq!(bm.stats().dirty_writes, 3);
+        assert!(bm.shards[0]
+            .read()
+            .unwrap()
+            .dirty
+            .lock()
+            .unwrap()
+            .is_empty());
+
+        // only the blocks of the flushed file are written
+        let other = BlockId::new("otherfile", 0);
+        fm.append_extent("otherfile", 1).unwrap();
+        for block in [&blocks[0], &other] {
+            let buf = bm.pin(block).unwrap();
+            buf.write().unwrap().set_modified(3, None).unwrap();
+        }
+        bm.flush_file("otherfile").unwrap();
+        assert_eq!(bm.stats().dirty_writes, 4);
+        bm.flush_all(3).unwrap();
+        assert_eq!(bm.stats().dirty_writes, 5);
+    }
+
+    #[test]
+    fn test_pin_pinned_block_under_read_lock() {
+        let (_fm, bm) = setup(400, 2, Duration::ZERO);
+        let block = BlockId::new("testfile", 0);
+        let first = bm.pin(&block).unwrap();
+
+        // a writer would wait for `shard` forever
+        let shard = bm.shards[0].read().unwrap();
+        let second = bm.pin(&block).unwrap();
+        assert!(std::ptr::eq(&*first, &*second));
+        drop(second);
+        drop(shard);
+
+        let stats = bm.stats();
+        assert_eq!((stats.hits, stats.misses, stats.pinned), (1, 1, 1));
+        drop(first);
+        assert_eq!(bm.available(), 2);
+        assert_eq!(bm.stats().pinned, 0);
+
+        // so is an unpinned one, and it's unpinned under the read lock as well
+        let shard = bm.shards[0].read().unwrap();
+        let again = bm.pin(&block).unwrap();
+        assert_eq!(bm.available(), 1);
+        drop(again);
+        drop(shard);
+        assert_eq!(bm.available(), 2);
+        assert_eq!(bm.stats().hits, 2);
+    }
+
+    #[test]
+    fn test_evict_skips_frames_pinned_under_read_lock() {
+        let (_fm, bm) = setup(400, 2, Duration::ZERO);
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        drop(bm.pin(&blocks[0]).unwrap());
+        drop(bm.pin(&blocks[1]).unwrap());
+
+        // the replacer doesn't know that block 0 is pinned again
+        let first = bm.pin(&blocks[0]).unwrap();
+        assert_eq!(bm.stats().hits, 1);
+        drop(bm.pin(&blocks[2]).unwrap());
+        assert_eq!(bm.resident_blocks().len(), 2);
+        assert!(bm.resident_blocks().contains(&blocks[0]));
+        assert!(!bm.resident_blocks().contains(&blocks[1]));
+
+        // once unpinned, the access it got counts when picking a victim
+        drop(first);
+        drop(bm.pin(&blocks[1]).unwrap());
+        assert!(bm.resident_blocks().contains(&blocks[0]));
+        assert!(!bm.resident_blocks().contains(&blocks[2]));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
+        for i in 0..4 {
+            let mut p = Page::new(fm.block_size());
+            p.set_int(0, i * 10);
+            fm.write_block(&BlockId::new("testfile", i as u64), &p)
+                .unwrap();
+        }
+        let blocks: Vec<_> = (0..4).map(|i| BlockId::new("testfile", i)).collect();
+
+        let pinned = bm.pin(&blocks[0]).unwrap();
+        // block 0 is cached already and block 3 doesn't fit
+        assert_eq!(bm.prefetch(&blocks).unwrap(), 2);
+        assert_eq!(bm.available(), 2);
+        assert_eq!(bm.stats().pinned, 1);
+
+        let buf = bm.pin(&blocks[2]).unwrap();
+        assert_eq!(buf.read().unwrap().contents().get_int(0), 20);
+        let stats = bm.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        drop((buf, pinned));
+
+        // prefetched blocks are evicted like any other
+        let _buf = bm.pin(&blocks[3]).unwrap();
+        assert_eq!(bm.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
+        fm.append_extent("testfile", 6).unwrap();
+        let blocks: Vec<_> = (0..6).map(|i| BlockId::new("testfile", i)).collect();
+        let bufs = bm.pin_many(&blocks[..3]).unwrap();
+        assert!(matches!(bm.pin(&blocks[3]), Err(BufferError::Abort)));
+
+        assert_eq!(bm.resize(5).unwrap(), 5);
+        let more = bm.pin_many(&blocks[3..5]).unwrap();
+        assert_eq!(bm.available(), 0);
+
+        // blocks 0 and 1 are unpinned but modified; block 2 stays pinned
+        let lm = bm.shards[0].read().unwrap().lm.clone();
+        for buf in &bufs[..2] {
+            let mut buf = buf.write().unwrap();
+            buf.contents_mut().set_int(0, 9);
+            buf.set_modified(1, Some(lm.append(&[0; 8]).unwrap()))
+                .unwrap();
+        }
+        drop(more);
+        let mut bufs = bufs;
+        let pinned = bufs.pop().unwrap();
+        drop(bufs);
+        assert!(matches!(
+            bm.resize(0),
+            Err(BufferError::CapacityTooSmall { .. })
+        ));
+        assert_eq!(bm.resize(1).unwrap(), 1);
+        assert_eq!(bm.available(), 0);
+        assert!(bm.dirty_pages().is_empty());
+        let mut p = Page::new(fm.block_size());
+        fm.read_block(&blocks[1], &mut p).unwrap();
+        assert_eq!(p.get_int(0), 9);
+
+        // retired frames are brought back before new ones are added
+        drop(pinned);
+        assert_eq!(bm.resize(4).unwrap(), 4);
+        assert_eq!(bm.shards[0].read().unwrap().pool.len(), 5);
+        let bufs = bm.pin_many(&blocks[..4]).unwrap();
+        assert_eq!(bufs[1].read().unwrap().contents().get_int(0), 9);
+
+        // every shard keeps at least one frame
+        let (_, bm) = setup(400, 1024, Duration::ZERO);
+        assert_eq!(bm.shards.len(), MAX_SHARDS);
+        assert!(matches!(
+            bm.resize(10),
+            Err(BufferError::CapacityTooSmall {
+                capacity: 10,
+                shards: MAX_SHARDS
+            })
+        ));
+        assert_eq!(bm.capacity(), 1024);
+        assert_eq!(bm.resize(MAX_SHARDS).unwrap(), MAX_SHARDS);
+    }
+
+    #[test]
+    fn test_pin_tracking() {
+        let (_fm, bm) = setup(400, 3, Duration::ZERO);
+        let untracked = bm.pin(&BlockId::new("testfile", 0)).unwrap();
+        assert!(bm.outstanding_pins().is_empty());
+        drop(untracked);
+
+        let bm = Arc::new(Arc::into_inner(bm).unwrap().with_pin_tracking());
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        let (leaked, line) = (bm.pin(&blocks[0]).unwrap(), line!());
+        let bufs = bm.pin_many(&blocks[1..]).unwrap();
+        assert_eq!(bm.outstanding_pins().len(), 3);
+        drop(bufs);
+
+        let pins = bm.outstanding_pins();
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].block, blocks[0]);
+        assert_eq!(pins[0].txn_num, None);
+        assert_eq!(pins[0].location.file(), file!());
+        assert_eq!(pins[0].location.line(), line);
+        drop(leaked);
+        assert!(bm.outstanding_pins().is_empty());
+    }
+
+    #[test]
+    fn test_shards() {
+        let (_fm, bm) = setup(400, 130, Duration::ZERO);
+        let sizes: Vec<_> = bm
+            .shards
+            .iter()
+            .map(|s| s.read().unwrap().pool.len())
+            .collect();
+        assert_eq!(sizes, [65, 65]);
+
+        // blocks of both shards, pinned all at once
+        let blocks: Vec<_> = (0..40).map(|i| BlockId::new("testfile", i)).collect();
+        let bufs = bm.pin_many(&blocks).unwrap();
+        assert_eq!(bm.available(), 90);
+        for (buf, block) in bufs.iter().zip(&blocks) {
+            assert_eq!(buf.read().unwrap().block(), Some(block));
+        }
+
+        // one shard fills up before the pool does; nothing of the batch stays pinned
+        let more: Vec<_> = (40..140).map(|i| BlockId::new("testfile", i)).collect();
+        assert!(matches!(bm.pin_many(&more), Err(BufferError::Abort)));
+        assert_eq!(bm.available(), 90);
+        assert_eq!(bm.stats().pinned, 40);
+
+        let threads: Vec<_> = (0..4u64)
+            .map(|t| {
+                let bm = Arc::clone(&bm);
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let block = BlockId::new("testfile", 1000 + t * 10 + i % 10);
+                        drop(bm.pin(&block).unwrap());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        drop(bufs);
+        assert_eq!(bm.available(), 130);
+    }
+
+    #[test]
+    fn test_truncate_discards_buffers() {
+        let (fm, bm) = setup(400, 3, Duration::ZERO);
+        fm.append_extent("testfile", 3).unwrap();
+
+        for i in 0..3 {
+            let buf_lock = bm.pin(&BlockId::new("testfile", i)).unwrap();
+            let mut buf = buf_lock.write().unwrap();
+            buf.contents_mut().set_int(0, 7);
+            buf.set_modified(1, None).unwrap();
+        }
+
+        let pinned = bm.pin(&BlockId::new("testfile", 0)).unwrap();
+        assert!(matches!(
+            bm.delete("testfile"),
+            Err(BufferError::BlockPinned(_))
+        ));
+
+        bm.truncate("testfile", 1).unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 1);
+        // the discarded modifications must not bring the blocks back
+        bm.flush_all_dirty().unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 1);
+
+        let mut p = Page::new(fm.block_size());
+        fm.read_block(&BlockId::new("testfile", 0), &mut p).unwrap();
+        assert_eq!(p.get_int(0), 7);
+
+        drop(pinned);
+        bm.delete("testfile").unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 0);
+    }
+
     #[test]
     fn test_buffer_manager() {
-        let (_fm, bm) = setup("buffermgrtest", 400, 3);
+        let (_fm, bm) = setup(400, 3, Duration::ZERO);
         let fname = "testfile";
 
         let mut bufv = Vec::new();
@@ -309,24 +1624,49 @@ mod tests {
             BlockId::new(fname, 3),
         );
 
-        bufv[0] = bm.pin(&bid0);
-        bufv[1] = bm.pin(&bid1);
-        bufv[2] = bm.pin(&bid2);
+        bufv[0] = bm.pin(&bid0).ok();
+        bufv[1] = bm.pin(&bid1).ok();
+        bufv[2] = bm.pin(&bid2).ok();
 
-        bm.unpin(bufv[1].as_mut().unwrap().write().unwrap());
         bufv[1] = None;
 
-        bufv[3] = bm.pin(&bid0);
-        bufv[4] = bm.pin(&bid1);
+        bufv[3] = bm.pin(&bid0).ok();
+        bufv[4] = bm.pin(&bid1).ok();
 
         assert_eq!(bm.available(), 0);
-        bufv[5] = bm.pin(&bid3);
-        assert!(bufv[5].is_none());
+        assert!(matches!(bm.pin(&bid3), Err(BufferError::Abort)));
 
-        bm.unpin(bufv[2].as_mut().unwrap().write().unwrap());
         bufv[2] = None;
 
-        bufv[5] = bm.pin(&bid3);
+        bufv[5] = bm.pin(&bid3).ok();
         assert!(bufv[5].is_some());
     }
+
+    #[test]
+    fn test_pin_waits_for_unpin() {
+        let (_fm, bm) = setup(400, 1, Duration::from_secs(5));
+        let (bid0, bid1) = (BlockId::new("testfile", 0), BlockId::new("testfile", 1));
+
+        let buf = bm.pin(&bid0).unwrap();
+        let handle = {
+            let bm = Arc::clone(&bm);
+            let block = bid1.clone();
+            std::thread::spawn(move || bm.pin(&block).unwrap().block().clone())
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        drop(buf);
+        assert_eq!(handle.join().unwrap(), bid1);
+
+        // nothing gets unpinned this time
+        let (_fm, bm) = setup(400, 1, Duration::from_millis(50));
+        let _buf = bm.pin(&bid0).unwrap();
+        let start = Instant::now();
+        assert!(matches!(bm.pin(&bid1), Err(BufferError::Abort)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let stats = bm.stats();
+        assert_eq!((stats.aborts, stats.pin_wait.count), (1, 1));
+        assert!(stats.pin_wait.sum >= Duration::from_millis(50));
+    }
 }
@@ -1,6 +1,16 @@
 mod buffer_manager;
 mod replacer;
+pub(crate) mod warmup;
+mod writer;
 
-pub use buffer_manager::BufferManager;
 pub use buffer_manager::Buffer;
+pub use buffer_manager::BufferError;
+pub use buffer_manager::BufferManager;
+pub use buffer_manager::PinRecord;
+pub use buffer_manager::PinnedBuffer;
+pub(crate) use buffer_manager::DEFAULT_PIN_TIMEOUT;
+pub use replacer::AccessHint;
 pub use replacer::EvictionPolicy;
+pub use replacer::Replacer;
+pub use replacer::ReplacerFactory;
+pub(crate) use writer::BackgroundWriter;
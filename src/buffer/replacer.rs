@@ -1,24 +1,129 @@
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    str::FromStr,
+};
+
+use crate::file::BlockId;
 
-#[derive(Default)]
 pub enum EvictionPolicy {
     Fifo,
+    /// Evicts the frame whose `k`th most recent access is the oldest. A larger `k` resists scans
+    /// better but takes longer to notice that a block stopped being used.
+    ///
+    /// An access within `correlated_period` accesses (to any frame) of the frame's previous one
+    /// is taken as part of that one rather than as a new reference, e.g. when a transaction reads
+    /// a block and then writes it.
+    LruK {
+        k: usize,
+        correlated_period: usize,
+    },
+    /// Adaptive Replacement Cache: balances recently and frequently used blocks by itself, so
+    /// that a sequential scan doesn't wipe out the blocks that are used over and over.
+    Arc,
+    /// A policy supplied by the embedder. The pool may be split into shards, each with its own
+    /// replacer, so this builds one for a given number of frames rather than being one.
+    Custom(ReplacerFactory),
+}
+
+/// Builds the [`Replacer`] of a pool, or of one of its shards, of the given number of frames.
+pub type ReplacerFactory = Box<dyn Fn(usize) -> Box<dyn Replacer> + Send + Sync>;
+
+/// How a block is about to be used, passed along with a pin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessHint {
     #[default]
-    LruK,
+    Normal,
+    /// The block is read once as part of a sequential scan. The replacer keeps it at low
+    /// priority, so that a scan evicts its own blocks rather than the ones used over and over.
+    Sequential,
 }
 
-pub(super) trait Replacer: Send + Sync {
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        Self::LruK {
+            k: 2,
+            correlated_period: 0,
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(Self::Fifo),
+            "lru-k" | "lruk" => Ok(Self::default()),
+            "arc" => Ok(Self::Arc),
+            policy => match policy.strip_prefix("lru-").map(str::parse) {
+                Some(Ok(k)) if k > 0 => Ok(Self::LruK {
+                    k,
+                    correlated_period: 0,
+                }),
+                _ => Err(format!(
+                    "unknown eviction policy {:?} (expected \"fifo\", \"lru-k\", \"lru-<k>\" or \"arc\")",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Picks the frames to reuse when the pool is full, see [`EvictionPolicy::Custom`].
+///
+/// Frames are identified by their position in the pool. A frame takes part once its first
+/// access is recorded, and isn't evictable until [`Replacer::set_evictable`] says so. The pool
+/// doesn't tell the replacer when a frame is pinned or unpinned, which happens without its
+/// lock: loaded frames are kept evictable, and [`Replacer::evict_unless`] is told which ones
+/// are in use when it's time to evict. An evicted frame is forgotten until it's accessed again.
+pub trait Replacer: Send + Sync {
+    /// Called when frame `key` is loaded with `block`, before the access is recorded.
+    fn record_load(&mut self, _key: usize, _block: &BlockId) {}
+    /// Records an access to frame `key`, which also makes it unevictable.
     fn record_access(&mut self, key: usize);
+    /// Records an access by a sequential scan, see [`AccessHint::Sequential`]. Replacers that
+    /// can't tell scans apart record it as any other access.
+    fn record_sequential_access(&mut self, key: usize) {
+        self.record_access(key);
+    }
+    /// Picks an evictable frame and forgets it, `None` if no frame is evictable.
     fn evict(&mut self) -> Option<usize>;
+    /// Like [`Replacer::evict`], but passes over the frames for which `in_use` holds, which
+    /// stay evictable. The default evicts them and records an access to them again.
+    fn evict_unless(&mut self, in_use: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let mut skipped = Vec::new();
+        let key = loop {
+            match self.evict() {
+                Some(key) if in_use(key) => skipped.push(key),
+                key => break key,
+            }
+        };
+        for key in skipped {
+            self.record_access(key);
+            self.set_evictable(key, true);
+        }
+        key
+    }
+    /// Does nothing for a frame that isn't tracked.
     fn set_evictable(&mut self, key: usize, is_evictable: bool);
+    /// Number of evictable frames.
     fn available(&self) -> usize;
+    /// Called when the number of frames changes.
+    fn set_capacity(&mut self, _capacity: usize) {}
 }
 
-impl From<EvictionPolicy> for Box<dyn Replacer> {
-    fn from(policy: EvictionPolicy) -> Self {
-        match policy {
+impl EvictionPolicy {
+    /// The replacer for a pool of `capacity` frames.
+    pub(super) fn replacer(&self, capacity: usize) -> Box<dyn Replacer> {
+        match self {
             EvictionPolicy::Fifo => Box::new(Fifo::default()),
-            EvictionPolicy::LruK => Box::new(LruK::default()),
+            &EvictionPolicy::LruK {
+                k,
+                correlated_period,
+            } => Box::new(LruK::new(k, correlated_period)),
+            EvictionPolicy::Arc => Box::new(AdaptiveCache::new(capacity)),
+            EvictionPolicy::Custom(factory) => factory(capacity),
         }
     }
 }
@@ -37,10 +142,14 @@ impl Replacer for Fifo {
     }
 
     fn evict(&mut self) -> Option<usize> {
+        self.evict_unless(&mut |_| false)
+    }
+
+    fn evict_unless(&mut self, in_use: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
         let key = self
             .store
             .iter()
-            .find(|&(_, evictable)| *evictable)
+            .find(|&(k, evictable)| *evictable && !in_use(*k))
             .map(|(k, _)| *k);
 
         if let Some(k) = key {
@@ -98,21 +207,30 @@ struct LruK {
     /// Latest timestamp is stored in the back.
     store: HashMap<usize, LruKNode>,
     k: usize,
+    /// Accesses within this many timestamps of the previous one are correlated with it.
+    correlated_period: usize,
     current_ts: usize,
     available: usize,
 }
 
-impl Default for LruK {
-    fn default() -> Self {
+impl LruK {
+    fn new(k: usize, correlated_period: usize) -> Self {
         Self {
             store: HashMap::new(),
-            k: 2,
+            k: k.max(1),
+            correlated_period,
             current_ts: 0,
             available: 0,
         }
     }
 }
 
+impl Default for LruK {
+    fn default() -> Self {
+        Self::new(2, 0)
+    }
+}
+
 impl Replacer for LruK {
     fn record_access(&mut self, key: usize) {
         self.current_ts += 1;
@@ -121,31 +239,53 @@ impl Replacer for LruK {
             self.available -= 1;
         }
         entry.is_evictable = false;
+        let correlated = entry
+            .history
+            .back()
+            .is_some_and(|&last| self.current_ts - last <= self.correlated_period);
+        if correlated {
+            // counts as the previous reference, which is now the latest
+            entry.history.pop_back();
+        }
         entry.history.push_back(self.current_ts);
         if entry.history.len() > self.k {
             entry.history.pop_front();
         }
     }
 
+    fn record_sequential_access(&mut self, key: usize) {
+        let entry = self.store.entry(key).or_default();
+        if entry.is_evictable {
+            self.available -= 1;
+        }
+        entry.is_evictable = false;
+        // not a reference: a block only ever scanned looks older than any other, while the
+        // history of a block that's also used otherwise is left alone
+        if entry.history.is_empty() {
+            entry.history.push_back(0);
+        }
+    }
+
     fn evict(&mut self) -> Option<usize> {
-        let mut max_dist = 0;
-        let mut earliest_ts = usize::MAX;
-        let mut key = None;
+        self.evict_unless(&mut |_| false)
+    }
 
-        for (k, node) in &self.store {
-            if node.is_evictable {
+    fn evict_unless(&mut self, in_use: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        // largest distance first, then earliest timestamp
+        let mut candidates: Vec<_> = self
+            .store
+            .iter()
+            .filter(|(_, node)| node.is_evictable)
+            .map(|(k, node)| {
                 let dist = node.backward_k_distance(self.current_ts, self.k);
-                if dist < max_dist {
-                    continue;
-                }
-                let ts = node.least_recent_timestamp();
-                if dist > max_dist || (dist == max_dist && ts < earliest_ts) {
-                    max_dist = dist;
-                    earliest_ts = ts;
-                    key = Some(*k);
-                }
-            }
-        }
+                (Reverse(dist), node.least_recent_timestamp(), *k)
+            })
+            .collect();
+        candidates.sort_unstable();
+        let key = candidates
+            .into_iter()
+            .map(|(_, _, k)| k)
+            .find(|&k| !in_use(k));
 
         if let Some(k) = key {
             self.available -= 1;
@@ -171,6 +311,227 @@ impl Replacer for LruK {
     }
 }
 
+/// The two lists of ARC, each with its ghost list of blocks evicted from it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ArcList {
+    /// Frames accessed once since their block was loaded (T1).
+    Recent,
+    /// Frames accessed more than once (T2).
+    Frequent,
+}
+
+struct ArcFrame {
+    list: ArcList,
+    /// Logical timestamp of the latest access, the frame's position in its list.
+    ts: usize,
+    is_evictable: bool,
+    block: Option<BlockId>,
+    /// Set by a load until the access that goes with it is recorded.
+    loaded: bool,
+}
+
+/// ARC (Megiddo & Modha) keeps the frames seen once and the frames seen again in separate LRU
+/// lists, and remembers the blocks recently evicted from each in a ghost list. A miss on a block
+/// in a ghost list shows that list was evicted from too eagerly, so the target size of the
+/// recent list moves towards it: up for the recent ghosts, down for the frequent ones. Eviction
+/// takes the least recently used evictable frame of the recent list while it's above its
+/// target, and of the frequent list otherwise.
+struct AdaptiveCache {
+    capacity: usize,
+    /// Target number of frames in the recent list (p).
+    target: usize,
+    frames: HashMap<usize, ArcFrame>,
+    /// Timestamp -> frame, least recent first.
+    recent: BTreeMap<usize, usize>,
+    frequent: BTreeMap<usize, usize>,
+    /// Evicted block -> the list it was evicted from and when.
+    ghosts: HashMap<BlockId, (ArcList, usize)>,
+    /// Timestamp -> evicted block, oldest first (B1 and B2).
+    recent_ghosts: BTreeMap<usize, BlockId>,
+    frequent_ghosts: BTreeMap<usize, BlockId>,
+    current_ts: usize,
+    available: usize,
+}
+
+impl AdaptiveCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            target: 0,
+            frames: HashMap::new(),
+            recent: BTreeMap::new(),
+            frequent: BTreeMap::new(),
+            ghosts: HashMap::new(),
+            recent_ghosts: BTreeMap::new(),
+            frequent_ghosts: BTreeMap::new(),
+            current_ts: 0,
+            available: 0,
+        }
+    }
+
+    fn list_mut(&mut self, list: ArcList) -> &mut BTreeMap<usize, usize> {
+        match list {
+            ArcList::Recent => &mut self.recent,
+            ArcList::Frequent => &mut self.frequent,
+        }
+    }
+
+    fn ghost_list_mut(&mut self, list: ArcList) -> &mut BTreeMap<usize, BlockId> {
+        match list {
+            ArcList::Recent => &mut self.recent_ghosts,
+            ArcList::Frequent => &mut self.frequent_ghosts,
+        }
+    }
+
+    /// The least recently used evictable frame in `list` that isn't in use.
+    fn victim(&self, list: ArcList, in_use: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let list = match list {
+            ArcList::Recent => &self.recent,
+            ArcList::Frequent => &self.frequent,
+        };
+        list.values()
+            .copied()
+            .find(|&key| self.frames[&key].is_evictable && !in_use(key))
+    }
+
+    /// Records an access to frame `key`, moving it to the frequent list if it's seen again
+    /// since it was loaded and `promote` is set.
+    fn access(&mut self, key: usize, promote: bool) {
+        self.current_ts += 1;
+        let ts = self.current_ts;
+        let frame = self.frames.entry(key).or_insert(ArcFrame {
+            list: ArcList::Recent,
+            ts: 0,
+            is_evictable: false,
+            block: None,
+            loaded: true,
+        });
+        let old = (frame.list, frame.ts);
+        if !frame.loaded && promote {
+            // seen again since it was loaded
+            frame.list = ArcList::Frequent;
+        }
+        frame.loaded = false;
+        frame.ts = ts;
+        if frame.is_evictable {
+            frame.is_evictable = false;
+            self.available -= 1;
+        }
+        let list = frame.list;
+        self.list_mut(old.0).remove(&old.1);
+        self.list_mut(list).insert(ts, key);
+    }
+
+    /// Forgets the oldest ghosts once the recent list and its ghosts or all the lists together
+    /// outgrow the pool, as ARC bounds them to `capacity` and twice that.
+    fn trim_ghosts(&mut self) {
+        while self.recent.len() + self.recent_ghosts.len() > self.capacity {
+            let Some((_, block)) = self.recent_ghosts.pop_first() else {
+                break;
+            };
+            self.ghosts.remove(&block);
+        }
+        let total = |c: &Self| c.frames.len() + c.recent_ghosts.len() + c.frequent_ghosts.len();
+        while total(self) > 2 * self.capacity {
+            let Some((_, block)) = self.frequent_ghosts.pop_first() else {
+                break;
+            };
+            self.ghosts.remove(&block);
+        }
+    }
+}
+
+impl Replacer for AdaptiveCache {
+    fn record_load(&mut self, key: usize, block: &BlockId) {
+        let mut list = ArcList::Recent;
+        if let Some((ghost, ts)) = self.ghosts.remove(block) {
+            self.ghost_list_mut(ghost).remove(&ts);
+            let (b1, b2) = (
+                self.recent_ghosts.len().max(1),
+                self.frequent_ghosts.len().max(1),
+            );
+            self.target = match ghost {
+                ArcList::Recent => (self.target + (b2 / b1).max(1)).min(self.capacity),
+                ArcList::Frequent => self.target.saturating_sub((b1 / b2).max(1)),
+            };
+            list = ArcList::Frequent;
+        }
+        if let Some(old) = self.frames.remove(&key) {
+            self.list_mut(old.list).remove(&old.ts);
+            if old.is_evictable {
+                self.available -= 1;
+            }
+        }
+        self.frames.insert(
+            key,
+            ArcFrame {
+                list,
+                ts: 0,
+                is_evictable: false,
+                block: Some(block.clone()),
+                loaded: true,
+            },
+        );
+    }
+
+    fn record_access(&mut self, key: usize) {
+        self.access(key, true);
+    }
+
+    fn record_sequential_access(&mut self, key: usize) {
+        // a scan going over a block several times doesn't make it frequent
+        self.access(key, false);
+    }
+
+    fn evict(&mut self) -> Option<usize> {
+        self.evict_unless(&mut |_| false)
+    }
+
+    fn evict_unless(&mut self, in_use: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let (preferred, other) = if self.recent.len() > self.target {
+            (ArcList::Recent, ArcList::Frequent)
+        } else {
+            (ArcList::Frequent, ArcList::Recent)
+        };
+        let key = self
+            .victim(preferred, in_use)
+            .or_else(|| self.victim(other, in_use))?;
+
+        let frame = self.frames.remove(&key).unwrap();
+        self.list_mut(frame.list).remove(&frame.ts);
+        self.available -= 1;
+        if let Some(block) = frame.block {
+            self.current_ts += 1;
+            let ts = self.current_ts;
+            self.ghost_list_mut(frame.list).insert(ts, block.clone());
+            self.ghosts.insert(block, (frame.list, ts));
+            self.trim_ghosts();
+        }
+        Some(key)
+    }
+
+    fn set_evictable(&mut self, key: usize, is_evictable: bool) {
+        if let Some(e) = self.frames.get_mut(&key) {
+            if is_evictable && !e.is_evictable {
+                self.available += 1;
+            } else if !is_evictable && e.is_evictable {
+                self.available -= 1;
+            }
+            e.is_evictable = is_evictable
+        }
+    }
+
+    fn available(&self) -> usize {
+        self.available
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.target = self.target.min(self.capacity);
+        self.trim_ghosts();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -268,4 +629,184 @@ mod tests {
         lruk.set_evictable(9, true); // should increase again
         assert_eq!(lruk.available(), 1);
     }
+
+    #[test]
+    fn test_lruk_parameters() {
+        // with k = 3, two accesses still leave an infinite distance
+
+        let mut lruk = LruK::new(3, 0);
+        lruk.record_access(1); // ts=1
+        lruk.record_access(1); // ts=2
+        lruk.record_access(2); // ts=3
+        lruk.record_access(2); // ts=4
+        lruk.record_access(2); // ts=5
+        lruk.set_evictable(1, true);
+        lruk.set_evictable(2, true);
+        assert_eq!(lruk.evict(), Some(1));
+
+        // an access right after the previous one doesn't count as a second reference
+
+        let mut lruk = LruK::new(2, 1);
+        lruk.record_access(1); // ts=1
+        lruk.record_access(1); // ts=2, correlated -> 1 has [2]
+        lruk.record_access(2); // ts=3
+        lruk.record_access(3); // ts=4
+        lruk.record_access(2); // ts=5 -> 2 has [3,5]
+        for key in 1..=3 {
+            lruk.set_evictable(key, true);
+        }
+        assert_eq!(lruk.evict(), Some(1));
+        assert_eq!(lruk.evict(), Some(3));
+        assert_eq!(lruk.evict(), Some(2));
+
+        assert!(matches!(
+            "lru-3".parse(),
+            Ok(EvictionPolicy::LruK {
+                k: 3,
+                correlated_period: 0
+            })
+        ));
+        assert!("lru-0".parse::<EvictionPolicy>().is_err());
+    }
+
+    /// Pins and unpins `blocks` one after the other in a pool of `capacity` frames whose
+    /// replacement is left to `replacer`. Returns the blocks in the pool at the end.
+    fn run(replacer: &mut dyn Replacer, capacity: usize, blocks: &[u64]) -> Vec<u64> {
+        run_hinted(replacer, capacity, blocks, |_| false)
+    }
+
+    /// Like `run`, with the blocks `scanned` selects accessed sequentially.
+    fn run_hinted(
+        replacer: &mut dyn Replacer,
+        capacity: usize,
+        blocks: &[u64],
+        scanned: impl Fn(u64) -> bool,
+    ) -> Vec<u64> {
+        let mut frames: Vec<Option<u64>> = vec![None; capacity];
+        for &b in blocks {
+            let key = match frames.iter().position(|f| *f == Some(b)) {
+                Some(key) => key,
+                None => {
+                    let key = frames
+                        .iter()
+                        .position(Option::is_none)
+                        .or_else(|| replacer.evict())
+                        .unwrap();
+                    replacer.record_load(key, &BlockId::new("testfile", b));
+                    frames[key] = Some(b);
+                    key
+                }
+            };
+            if scanned(b) {
+                replacer.record_sequential_access(key);
+            } else {
+                replacer.record_access(key);
+            }
+            replacer.set_evictable(key, true);
+        }
+        frames.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn test_sequential_access() {
+        let scanned = |b| b >= 10;
+
+        // LRU-K: blocks seen once are kept over the blocks of a scan
+        let blocks = [[1, 2].as_slice(), &(10..30).collect::<Vec<_>>()].concat();
+        let resident = run(&mut LruK::default(), 4, &blocks);
+        assert!(!resident.contains(&1) && !resident.contains(&2));
+        let resident = run_hinted(&mut LruK::default(), 4, &blocks, scanned);
+        assert!(resident.contains(&1) && resident.contains(&2));
+
+        // ARC: a scan reading each block twice doesn't take over the frequent list
+        let scan: Vec<u64> = (10..30).flat_map(|b| [b, b]).collect();
+        let blocks = [[1, 2, 1, 2].as_slice(), &scan].concat();
+        let resident = run(&mut AdaptiveCache::new(4), 4, &blocks);
+        assert!(!resident.contains(&1) && !resident.contains(&2));
+        let resident = run_hinted(&mut AdaptiveCache::new(4), 4, &blocks, scanned);
+        assert!(resident.contains(&1) && resident.contains(&2));
+    }
+
+    #[test]
+    fn test_arc() {
+        // a scan doesn't push out blocks used more than once
+
+        let mut arc = AdaptiveCache::new(4);
+        let scan: Vec<u64> = (10..30).collect();
+        let resident = run(&mut arc, 4, &[[1, 2, 1, 2].as_slice(), &scan].concat());
+        assert!(resident.contains(&1) && resident.contains(&2));
+        assert_eq!(arc.available(), 4);
+        assert_eq!(arc.target, 0);
+
+        // a miss on a block recently evicted from the recent list grows its target
+
+        run(&mut arc, 4, &[27]);
+        assert_eq!(arc.target, 1);
+        let key = arc.frequent.values().last().unwrap();
+        assert_eq!(arc.frames[key].block, Some(BlockId::new("testfile", 27)));
+
+        // pinned frames are skipped
+
+        let mut arc = AdaptiveCache::new(2);
+        arc.record_load(0, &BlockId::new("testfile", 0));
+        arc.record_access(0);
+        arc.record_load(1, &BlockId::new("testfile", 1));
+        arc.record_access(1);
+        arc.set_evictable(1, true);
+        assert_eq!(arc.evict(), Some(1));
+        assert_eq!(arc.evict(), None);
+    }
+
+    #[test]
+    fn test_custom() {
+        /// Evicts the most recently accessed frame.
+        #[derive(Default)]
+        struct Mru {
+            order: Vec<usize>,
+            evictable: Vec<usize>,
+        }
+
+        impl Replacer for Mru {
+            fn record_access(&mut self, key: usize) {
+                self.order.retain(|&k| k != key);
+                self.order.push(key);
+                self.set_evictable(key, false);
+            }
+
+            fn evict(&mut self) -> Option<usize> {
+                let pos = self
+                    .order
+                    .iter()
+                    .rposition(|k| self.evictable.contains(k))?;
+                let key = self.order.remove(pos);
+                self.evictable.retain(|&k| k != key);
+                Some(key)
+            }
+
+            fn set_evictable(&mut self, key: usize, is_evictable: bool) {
+                self.evictable.retain(|&k| k != key);
+                if is_evictable && self.order.contains(&key) {
+                    self.evictable.push(key);
+                }
+            }
+
+            fn available(&self) -> usize {
+                self.evictable.len()
+            }
+        }
+
+        let policy = EvictionPolicy::Custom(Box::new(|capacity| {
+            assert_eq!(capacity, 3);
+            Box::new(Mru::default())
+        }));
+        let mut replacer = policy.replacer(3);
+        let mut resident = run(replacer.as_mut(), 3, &[1, 2, 3, 4, 5]);
+        resident.sort_unstable();
+        assert_eq!(resident, [1, 2, 5]);
+        assert_eq!(replacer.available(), 3);
+
+        // frames in use are passed over and stay evictable
+        assert_eq!(replacer.evict_unless(&mut |key| key != 1), Some(1));
+        assert_eq!(replacer.available(), 2);
+    }
 }
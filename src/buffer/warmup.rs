@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use tracing::{debug, warn};
+
+use super::BufferManager;
+use crate::file::BlockId;
+
+/// Blocks read per call to [`BufferManager::prefetch`].
+const BATCH_SIZE: usize = 32;
+
+/// Reads the blocks that were cached when the database was last closed back into the pool
+/// from a background thread, so that a restart doesn't begin with a cold cache.
+///
+/// Blocks only go to free frames: nothing loaded by the running workload is evicted for them.
+/// The thread stops when every block is read or the warmup is dropped.
+pub(crate) struct Warmup {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Warmup {
+    pub fn start(bm: Arc<BufferManager>, blocks: Vec<BlockId>) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::Builder::new()
+            .name("willow-warmup".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || run(&bm, &blocks, &stop)
+            })?;
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+fn run(bm: &BufferManager, blocks: &[BlockId], stop: &AtomicBool) {
+    let start = Instant::now();
+    let mut read = 0;
+    for batch in blocks.chunks(BATCH_SIZE) {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        match bm.prefetch(batch) {
+            Ok(n) => read += n,
+            Err(e) => {
+                warn!(error = %e, "buffer pool warmup failed");
+                return;
+            }
+        }
+    }
+    debug!(blocks = read, elapsed = ?start.elapsed(), "buffer pool warmed up");
+}
+
+impl Drop for Warmup {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Writes `blocks` to `path`, one `<number> <filename>` line per block.
+pub(crate) fn save(path: &Path, blocks: &[BlockId]) -> io::Result<()> {
+    let mut f = io::BufWriter::new(fs::File::create(path)?);
+    for block in blocks {
+        writeln!(f, "{} {}", block.number(), block.filename())?;
+    }
+    f.into_inner()?.sync_all()
+}
+
+/// Reads the blocks written by [`save`], skipping lines that don't parse.
+pub(crate) fn load(path: &Path) -> io::Result<Vec<BlockId>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| {
+            let (number, filename) = line.split_once(' ')?;
+            Some(BlockId::new(filename, number.parse().ok()?))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use crate::{
+        buffer::EvictionPolicy,
+        file::{FileManager, StorageBackend},
+        log::LogManager,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_warmup() {
+        let dir = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(format!(
+            "warmuptest_{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("warmup");
+        let blocks: Vec<_> = (0..5).map(|i| BlockId::new("my file", i)).collect();
+        save(&path, &blocks).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"garbage\n")
+            .unwrap();
+        assert_eq!(load(&path).unwrap(), blocks);
+
+        let fm = Arc::new(FileManager::in_memory(400));
+        fm.append_extent("my file", 5).unwrap();
+        let lm = Arc::new(LogManager::new(fm.clone(), "db.log").unwrap());
+        let bm = Arc::new(BufferManager::new(fm, lm, 3, EvictionPolicy::default()));
+
+        // only as many blocks as there are free frames are read
+        let warmup = Warmup::start(Arc::clone(&bm), blocks).unwrap();
+        let start = Instant::now();
+        while bm.resident_blocks().len() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(warmup);
+        assert_eq!(bm.available(), 3);
+        let _buf = bm.pin(&BlockId::new("my file", 0)).unwrap();
+        assert_eq!(bm.stats().misses, 0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tracing::{trace, warn};
+
+use super::BufferManager;
+
+/// Writes the oldest modified buffers in the background, so that pages reach disk steadily
+/// rather than in bursts at commit, and evicting a frame rarely has to write it first.
+///
+/// The log is flushed up to a page's LSN before the page is written, as it is for any other
+/// write. The thread stops when the writer is dropped.
+pub(crate) struct BackgroundWriter {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    /// Starts writing up to `max_pages` buffers every `interval`.
+    pub fn start(bm: Arc<BufferManager>, interval: Duration, max_pages: usize) -> io::Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = thread::Builder::new()
+            .name("willow-bg-writer".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || run(&bm, &stop, interval, max_pages)
+            })?;
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+fn run(bm: &BufferManager, stop: &(Mutex<bool>, Condvar), interval: Duration, max_pages: usize) {
+    let (lock, cvar) = stop;
+    let mut stopped = lock.lock().unwrap();
+    loop {
+        (stopped, _) = cvar
+            .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+            .unwrap();
+        if *stopped {
+            return;
+        }
+        match bm.flush_oldest(max_pages) {
+            Ok(0) => {}
+            Ok(pages) => trace!(pages, "background writer wrote modified pages"),
+            Err(e) => warn!(error = %e, "background writer failed to write modified pages"),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{
+        buffer::EvictionPolicy,
+        file::{BlockId, FileManager, Page, StorageBackend},
+        log::LogManager,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_background_writer() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let lm = Arc::new(LogManager::new(fm.clone(), "db.log").unwrap());
+        let bm = Arc::new(BufferManager::new(
+            fm.clone(),
+            Arc::clone(&lm),
+            3,
+            EvictionPolicy::default(),
+        ));
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        for block in &blocks {
+            let buf = bm.pin(block).unwrap();
+            let mut buf = buf.write().unwrap();
+            buf.contents_mut().set_int(0, 7);
+            let lsn = lm.append(&[0; 8]).unwrap();
+            buf.set_modified(1, Some(lsn)).unwrap();
+        }
+        // pinned buffers are written too
+        let _pinned = bm.pin(&blocks[0]).unwrap();
+
+        let writer = BackgroundWriter::start(Arc::clone(&bm), Duration::from_millis(5), 2).unwrap();
+        let start = Instant::now();
+        while bm.stats().dirty_writes < 3 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(writer);
+
+        assert!(bm.dirty_pages().is_empty());
+        let mut p = Page::new(fm.block_size());
+        for block in &blocks {
+            fm.read_block(block, &mut p).unwrap();
+            assert_eq!(p.get_int(0), 7);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{
+    file::{BlockId, FileManager, StorageError},
+    log::Lsn,
+    wal::{WalError, WalReader},
+};
+
+/// What [`Builder::check`](crate::Builder::check) found in a database, e.g. for the
+/// `willow-check` binary.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Number of data files whose blocks were checked.
+    pub files: usize,
+    /// Blocks of the data files whose contents don't match their checksum.
+    pub bad_blocks: Vec<BlockId>,
+    /// Number of log records read.
+    pub records: usize,
+    /// Log records that can't be decoded, including the ones that don't match their CRC.
+    pub bad_records: Vec<Lsn>,
+    /// The error that stopped the walk of the log, e.g. a block whose fragments don't match
+    /// their CRC.
+    pub log_error: Option<WalError>,
+}
+
+impl CheckReport {
+    /// Checks the blocks of `files` against their checksums and walks the whole log.
+    pub(crate) fn run(
+        fm: &FileManager,
+        files: &[String],
+        log: &WalReader,
+    ) -> Result<Self, StorageError> {
+        let mut report = Self {
+            files: files.len(),
+            ..Default::default()
+        };
+        for filename in files {
+            report.bad_blocks.extend(fm.scan_file(filename)?);
+        }
+
+        let records = match log.records(0) {
+            Ok(records) => records,
+            Err(e) => {
+                report.log_error = Some(e);
+                return Ok(report);
+            }
+        };
+        for rec in records {
+            match rec {
+                Ok(_) => {}
+                Err(WalError::Undecodable { lsn }) => report.bad_records.push(lsn),
+                Err(e) => {
+                    report.log_error = Some(e);
+                    break;
+                }
+            }
+            report.records += 1;
+        }
+        Ok(report)
+    }
+
+    /// Whether nothing is wrong.
+    pub fn is_ok(&self) -> bool {
+        self.bad_blocks.is_empty() && self.bad_records.is_empty() && self.log_error.is_none()
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    buffer::EvictionPolicy,
+    file::{Durability, DIRECT_IO_ALIGNMENT},
+};
+
+/// Smallest block size that leaves room for a log boundary and a few records.
+const MIN_BLOCK_SIZE: usize = 64;
+
+const ENV_PREFIX: &str = "WILLOW_";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config {}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("failed to parse config {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid `{key}`: {reason}")]
+    Invalid { key: &'static str, reason: String },
+}
+
+/// Engine options loaded from a TOML file and/or `WILLOW_*` environment variables.
+///
+/// Every field is optional; anything left unset falls back to the builder's default.
+///
+/// ```toml
+/// block_size = 4096
+/// buffer_capacity = 1024
+/// eviction = "lru-k"
+/// pin_timeout_ms = 5000
+/// lock_timeout_ms = 5000
+/// log_dir = "/var/lib/willow/wal"
+/// log_file = "willowdb.log"
+/// direct_io = false
+/// read_only = false
+/// max_open_files = 256
+/// checksums = true
+/// durability = "on-commit"
+/// group_commit_delay_ms = 2
+/// log_buffer_pages = 8
+/// log_compression = true
+/// archive_dir = "/var/lib/willow/archive"
+/// warmup = true
+/// standby = false
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub block_size: Option<usize>,
+    pub buffer_capacity: Option<usize>,
+    pub eviction: Option<String>,
+    pub pin_timeout_ms: Option<u64>,
+    pub lock_timeout_ms: Option<u64>,
+    pub log_dir: Option<PathBuf>,
+    pub log_file: Option<String>,
+    pub direct_io: Option<bool>,
+    pub read_only: Option<bool>,
+    pub max_open_files: Option<usize>,
+    pub checksums: Option<bool>,
+    pub durability: Option<String>,
+    pub group_commit_delay_ms: Option<u64>,
+    pub log_buffer_pages: Option<usize>,
+    pub log_compression: Option<bool>,
+    pub archive_dir: Option<PathBuf>,
+    pub warmup: Option<bool>,
+    pub standby: Option<bool>,
+}
+
+impl Config {
+    /// Reads the config file at `path` (if any), applies environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let config = match path {
+            Some(p) => Self::from_file(p)?,
+            None => Self::default(),
+        };
+        let config = config.with_overrides(env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Overrides fields with `WILLOW_<FIELD>` variables, e.g. `WILLOW_BLOCK_SIZE=4096`.
+    /// Any other `WILLOW_` variable but `WILLOW_CONFIG` is rejected, like unknown keys in a file.
+    pub fn with_overrides(
+        mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        for (key, val) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match name {
+                "BLOCK_SIZE" => self.block_size = Some(parse_var("block_size", &val)?),
+                "BUFFER_CAPACITY" => {
+                    self.buffer_capacity = Some(parse_var("buffer_capacity", &val)?)
+                }
+                "EVICTION" => self.eviction = Some(val),
+                "PIN_TIMEOUT_MS" => self.pin_timeout_ms = Some(parse_var("pin_timeout_ms", &val)?),
+                "LOCK_TIMEOUT_MS" => {
+                    self.lock_timeout_ms = Some(parse_var("lock_timeout_ms", &val)?)
+                }
+                "LOG_DIR" => self.log_dir = Some(PathBuf::from(val)),
+                "LOG_FILE" => self.log_file = Some(val),
+                "DIRECT_IO" => self.direct_io = Some(parse_var("direct_io", &val)?),
+                "READ_ONLY" => self.read_only = Some(parse_var("read_only", &val)?),
+                "MAX_OPEN_FILES" => self.max_open_files = Some(parse_var("max_open_files", &val)?),
+                "CHECKSUMS" => self.checksums = Some(parse_var("checksums", &val)?),
+                "DURABILITY" => self.durability = Some(val),
+                "ARCHIVE_DIR" => self.archive_dir = Some(PathBuf::from(val)),
+                "GROUP_COMMIT_DELAY_MS" => {
+                    self.group_commit_delay_ms = Some(parse_var("group_commit_delay_ms", &val)?)
+                }
+                "LOG_BUFFER_PAGES" => {
+                    self.log_buffer_pages = Some(parse_var("log_buffer_pages", &val)?)
+                }
+                "LOG_COMPRESSION" => {
+                    self.log_compression = Some(parse_var("log_compression", &val)?)
+                }
+                "WARMUP" => self.warmup = Some(parse_var("warmup", &val)?),
+                "STANDBY" => self.standby = Some(parse_var("standby", &val)?),
+                // the path of the config file itself
+                "CONFIG" => {}
+                _ => return Err(invalid("environment", format!("unknown variable `{key}`"))),
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(n) = self.block_size {
+            if n < MIN_BLOCK_SIZE {
+                return Err(invalid(
+                    "block_size",
+                    format!("must be at least {} bytes, got {}", MIN_BLOCK_SIZE, n),
+                ));
+            }
+        }
+        if self.buffer_capacity == Some(0) {
+            return Err(invalid("buffer_capacity", "must be greater than 0".into()));
+        }
+        if self.max_open_files == Some(0) {
+            return Err(invalid("max_open_files", "must be greater than 0".into()));
+        }
+        if let Some(n @ 0..2) = self.log_buffer_pages {
+            return Err(invalid(
+                "log_buffer_pages",
+                format!("must be at least 2, got {}", n),
+            ));
+        }
+        if let (Some(true), Some(n)) = (self.direct_io, self.block_size) {
+            if !n.is_multiple_of(DIRECT_IO_ALIGNMENT) {
+                return Err(invalid(
+                    "block_size",
+                    format!(
+                        "must be a multiple of {} with direct_io, got {}",
+                        DIRECT_IO_ALIGNMENT, n
+                    ),
+                ));
+            }
+        }
+        self.eviction_policy()?;
+        self.durability()?;
+        if let Some(f) = &self.log_file {
+            if f.is_empty() || f.contains(['/', '\\']) {
+                return Err(invalid(
+                    "log_file",
+                    format!("must be a plain file name, got {:?}", f),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn eviction_policy(&self) -> Result<Option<EvictionPolicy>, ConfigError> {
+        self.eviction
+            .as_deref()
+            .map(|s| EvictionPolicy::from_str(s).map_err(|e| invalid("eviction", e)))
+            .transpose()
+    }
+
+    pub(crate) fn durability(&self) -> Result<Option<Durability>, ConfigError> {
+        self.durability
+            .as_deref()
+            .map(|s| Durability::from_str(s).map_err(|e| invalid("durability", e)))
+            .transpose()
+    }
+
+    pub(crate) fn pin_timeout(&self) -> Option<Duration> {
+        self.pin_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub(crate) fn lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub(crate) fn group_commit_delay(&self) -> Option<Duration> {
+        self.group_commit_delay_ms.map(Duration::from_millis)
+    }
+}
+
+fn parse_var<T: FromStr>(key: &'static str, val: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    val.trim()
+        .parse()
+        .map_err(|e| invalid(key, format!("{:?}: {}", val, e)))
+}
+
+fn invalid(key: &'static str, reason: String) -> ConfigError {
+    ConfigError::Invalid { key, reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            r#"
+            block_size = 4096
+            buffer_capacity = 64
+            eviction = "fifo"
+            log_dir = "wal"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.block_size, Some(4096));
+        assert!(matches!(
+            config.eviction_policy(),
+            Ok(Some(EvictionPolicy::Fifo))
+        ));
+
+        // env overrides
+
+        let config = config
+            .with_overrides(vars(&[
+                ("WILLOW_BUFFER_CAPACITY", "128"),
+                ("WILLOW_LOCK_TIMEOUT_MS", "250"),
+                ("WILLOW_PIN_TIMEOUT_MS", "100"),
+                ("WILLOW_DURABILITY", "every-100ms"),
+                ("WILLOW_GROUP_COMMIT_DELAY_MS", "3"),
+                ("WILLOW_LOG_BUFFER_PAGES", "8"),
+                ("WILLOW_LOG_COMPRESSION", "true"),
+                ("WILLOW_CHECKSUMS", "true"),
+                ("WILLOW_CONFIG", "willow.toml"),
+                ("UNRELATED", "1"),
+            ]))
+            .unwrap();
+        assert_eq!(config.buffer_capacity, Some(128));
+        assert_eq!(
+            config.durability().unwrap(),
+            Some(Durability::Every(Duration::from_millis(100)))
+        );
+        assert_eq!(config.lock_timeout(), Some(Duration::from_millis(250)));
+        assert_eq!(config.pin_timeout(), Some(Duration::from_millis(100)));
+        assert_eq!(config.group_commit_delay(), Some(Duration::from_millis(3)));
+        assert_eq!(config.log_buffer_pages, Some(8));
+        assert_eq!(config.log_compression, Some(true));
+        assert_eq!(config.checksums, Some(true));
+        assert_eq!(config.block_size, Some(4096));
+
+        // validation
+
+        let err = Config::default()
+            .with_overrides(vars(&[("WILLOW_CHECKSUM", "true")]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                key: "environment",
+                ..
+            }
+        ));
+
+        let err = Config::default()
+            .with_overrides(vars(&[("WILLOW_BLOCK_SIZE", "abc")]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                key: "block_size",
+                ..
+            }
+        ));
+
+        let config: Config = toml::from_str("eviction = \"clock\"").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("durability = \"every-second\"").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("buffer_capacity = 0").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("max_open_files = 0").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("log_buffer_pages = 1").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("block_size = 1000\ndirect_io = true").unwrap();
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("blok_size = 10").is_err());
+    }
+}
@@ -1,3 +1,7 @@
 use std::mem;
 
-pub const SIZE_OF_INT : usize = mem::size_of::<i32>();
+pub const SIZE_OF_INT: usize = mem::size_of::<i32>();
+pub const SIZE_OF_LONG: usize = mem::size_of::<i64>();
+pub const SIZE_OF_DOUBLE: usize = mem::size_of::<f64>();
+/// Longest varint encoding: 64 bits in 7-bit groups.
+pub const MAX_VARINT_LEN: usize = 10;
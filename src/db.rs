@@ -0,0 +1,1815 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    buffer::{
+        warmup::{self, Warmup},
+        BackgroundWriter, BufferManager, EvictionPolicy, PinRecord, DEFAULT_PIN_TIMEOUT,
+    },
+    config::{Config, ConfigError},
+    error::WillowError,
+    file::{
+        pio, BlockId, CountingStorage, DirOptions, Durability, FileManager, IoStats,
+        StorageBackend, StorageError, DEFAULT_MAX_OPEN_FILES, LOCK_FILE, MAP_SUFFIX,
+    },
+    log::{dir_archiver, LogArchiver, LogManager, Lsn, DEFAULT_LOG_BUFFER_PAGES},
+    metrics::{BufferManagerStats, FileManagerStats, MetricsSnapshot},
+    replication::{Applier, LogReceiver, LogShipper, Replay, ReplicationError},
+    txn::{
+        RecoveryObserver, RecoveryProgress, RecoveryTarget, Transaction, TransactionManager,
+        DEFAULT_LOCK_TIMEOUT,
+    },
+    wal::WalReader,
+    CheckReport,
+};
+
+const DEFAULT_BLOCK_SIZE: usize = 1000;
+const DEFAULT_BUFFER_CAPACITY: usize = 400;
+const DEFAULT_LOG_FILE: &str = "willowdb.log";
+
+/// Created by [`WillowDB::close`] and removed on the next open.
+/// Its absence in an existing database means the previous run didn't shut down cleanly.
+const CLEAN_SHUTDOWN_MARKER: &str = "CLEAN_SHUTDOWN";
+
+/// Blocks cached when the database was last closed, written by [`WillowDB::close`] and read
+/// back on open by [`Builder::warmup`].
+const WARMUP_FILE: &str = "WARMUP";
+
+/// Created when a database is opened as a standby and removed once it's promoted.
+/// Its data files weren't written in step with the checkpoints in its log, so a database
+/// that still has it is recovered from the whole log when opened as a primary.
+const STANDBY_MARKER: &str = "STANDBY";
+
+/// Configures and opens a [`WillowDB`].
+///
+/// ```no_run
+/// use willow_db::{EvictionPolicy, WillowDB};
+///
+/// let db = WillowDB::builder()
+///     .block_size(4096)
+///     .buffer_capacity(1024)
+///     .eviction(EvictionPolicy::LruK { k: 2, correlated_period: 0 })
+///     .log_file("willowdb.log")
+///     .open("testdb")?;
+/// # Ok::<(), willow_db::WillowError>(())
+/// ```
+pub struct Builder {
+    block_size: usize,
+    buffer_capacity: usize,
+    eviction: EvictionPolicy,
+    pin_timeout: Duration,
+    background_writer: Option<(Duration, usize)>,
+    warmup: bool,
+    track_pins: bool,
+    lock_timeout: Duration,
+    log_dir: Option<PathBuf>,
+    log_file: String,
+    direct_io: bool,
+    read_only: bool,
+    standby: bool,
+    force: bool,
+    max_open_files: usize,
+    checksums: bool,
+    durability: Durability,
+    group_commit_delay: Duration,
+    log_buffer_pages: usize,
+    log_compression: bool,
+    archiver: Option<LogArchiver>,
+    compressed_files: Vec<String>,
+    recovery_target: Option<RecoveryTarget>,
+    recovery_observer: Option<RecoveryObserver>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            eviction: EvictionPolicy::default(),
+            pin_timeout: DEFAULT_PIN_TIMEOUT,
+            background_writer: None,
+            warmup: false,
+            track_pins: false,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            log_dir: None,
+            log_file: DEFAULT_LOG_FILE.to_owned(),
+            direct_io: false,
+            read_only: false,
+            standby: false,
+            force: false,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            checksums: false,
+            durability: Durability::default(),
+            group_commit_delay: Duration::ZERO,
+            log_buffer_pages: DEFAULT_LOG_BUFFER_PAGES,
+            log_compression: false,
+            archiver: None,
+            compressed_files: Vec::new(),
+            recovery_target: None,
+            recovery_observer: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Size of a disk block (and of every page) in bytes.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Number of frames in the buffer pool.
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.buffer_capacity = capacity;
+        self
+    }
+
+    /// Policy the buffer pool uses to pick a frame to evict, see [`EvictionPolicy`].
+    pub fn eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// How long a pin waits for a buffer to be unpinned when the whole pool is pinned.
+    pub fn pin_timeout(mut self, timeout: Duration) -> Self {
+        self.pin_timeout = timeout;
+        self
+    }
+
+    /// Writes up to `max_pages` modified buffers, oldest changes first, every `interval` from a
+    /// background thread. Off by default, pages are then written when their frame is reused or
+    /// when their transaction commits.
+    pub fn background_writer(mut self, interval: Duration, max_pages: usize) -> Self {
+        self.background_writer = Some((interval, max_pages));
+        self
+    }
+
+    /// Reads the blocks that were cached when the database was last closed back into the pool
+    /// from a background thread after opening it, into frames the workload hasn't taken yet.
+    /// Off by default.
+    pub fn warmup(mut self, enabled: bool) -> Self {
+        self.warmup = enabled;
+        self
+    }
+
+    /// Records where every buffer pin is taken until it's released, to find pins that are never
+    /// released: see [`WillowDB::outstanding_pins`]. [`WillowDB::close`] logs the ones left.
+    /// Off by default, as it slows every pin down.
+    pub fn track_pins(mut self, enabled: bool) -> Self {
+        self.track_pins = enabled;
+        self
+    }
+
+    /// How long a transaction waits for a conflicting lock before giving up.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Directory holding the log file. Defaults to the database directory.
+    pub fn log_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.log_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Name of the log file inside the log directory.
+    pub fn log_file(mut self, filename: &str) -> Self {
+        self.log_file = filename.to_owned();
+        self
+    }
+
+    /// Bypasses the OS page cache for data and log files, since the buffer pool already caches pages.
+    ///
+    /// Requires a block size that is a multiple of [`crate::DIRECT_IO_ALIGNMENT`]. Linux only.
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Opens an existing database without write access, e.g. to run analytics against a
+    /// snapshot while another process owns the primary copy.
+    ///
+    /// Recovery is skipped, so changes of transactions that were in flight when the snapshot
+    /// was taken stay visible. Transactions refuse every modification. `direct_io` is ignored.
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Opens the database as a standby of a primary. It appends the primary's log records
+    /// received by its [`WillowDB::log_receiver`] to its own log, applies them to its data files
+    /// in the background and only runs read-only transactions, until [`WillowDB::promote`]
+    /// makes it a primary.
+    ///
+    /// The standby must start out empty or as a backup of the primary that was never opened
+    /// (see [`WillowDB::backup`]), with the same block size, checksum and compression settings.
+    /// Recovery is skipped and the log is applied from its first record on every open. A
+    /// standby opened without this option is promoted on open. Ignored for a read-only database.
+    pub fn standby(mut self, enabled: bool) -> Self {
+        self.standby = enabled;
+        self
+    }
+
+    /// Number of data files kept open at once. Beyond that, the least recently used
+    /// file is closed and reopened on demand.
+    pub fn max_open_files(mut self, n: usize) -> Self {
+        self.max_open_files = n;
+        self
+    }
+
+    /// Stores a checksum in the header of every data and log block and verifies it on each
+    /// read. A database must always be opened with the same setting.
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Sets when data blocks are synced to disk. The log is synced on every flush regardless.
+    ///
+    /// See [`Durability`] for what each policy risks on a crash.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// How long a committer waits for others to share its log sync. Zero (the default) only
+    /// batches committers that arrive while a sync is already in progress.
+    ///
+    /// Raises commit latency by up to `delay` in exchange for fewer syncs under concurrent load.
+    pub fn group_commit_delay(mut self, delay: Duration) -> Self {
+        self.group_commit_delay = delay;
+        self
+    }
+
+    /// Number of log pages buffered in memory. Appends only wait for the log to be written
+    /// once every page is full, so more pages absorb bigger bursts. At least two are used.
+    pub fn log_buffer_pages(mut self, pages: usize) -> Self {
+        self.log_buffer_pages = pages;
+        self
+    }
+
+    /// Compresses log records with lz4 when that makes them smaller, e.g. for updates of long
+    /// strings. Logs written either way can be opened with or without it.
+    pub fn log_compression(mut self, enabled: bool) -> Self {
+        self.log_compression = enabled;
+        self
+    }
+
+    /// Keeps a copy of every completed log block in `dir`, in a file named like the log.
+    pub fn archive_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.archiver = Some(dir_archiver(dir.as_ref().to_owned()));
+        self
+    }
+
+    /// Calls `archiver` with every log block once it's full and synced, like PostgreSQL's
+    /// `archive_command`. Replaces [`Builder::archive_dir`].
+    ///
+    /// The block is archived by the log writer, so an error is returned by the next append or
+    /// flush, and the block is handed over again afterwards.
+    pub fn log_archiver(
+        mut self,
+        archiver: impl Fn(&BlockId, &[u8]) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.archiver = Some(Box::new(archiver));
+        self
+    }
+
+    /// Stores the data file `filename` lz4-compressed when it's created. Can be called once per file.
+    ///
+    /// See [`FileManager::compress`].
+    pub fn compress_file(mut self, filename: &str) -> Self {
+        self.compressed_files.push(filename.to_owned());
+        self
+    }
+
+    /// Recovers the database to `target` on open, e.g. to undo a bad bulk delete by going back
+    /// to five minutes ago.
+    ///
+    /// Meant for a database restored from a backup taken before the target, with its log
+    /// replaced by the archived one (see [`Builder::archive_dir`]). The log records past the
+    /// target are dropped for good, and every transaction that hadn't committed by then is
+    /// rolled back. Recovery replays the whole log that's left, whether or not the database
+    /// was shut down cleanly. Ignored for a read-only or new database.
+    pub fn recovery_target(mut self, target: RecoveryTarget) -> Self {
+        self.recovery_target = Some(target);
+        self
+    }
+
+    /// Calls `observer` after every log record recovery handles on open, with the phase it's
+    /// in, how many records it handled so far and the LSN of the last one, e.g. to show a
+    /// progress bar while a large log is recovered.
+    pub fn recovery_observer(
+        mut self,
+        observer: impl Fn(&RecoveryProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.recovery_observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Opens the database even if another process holds its directory lock.
+    ///
+    /// Only meant for recovering from a lock that is known to be stale, e.g. on a network
+    /// filesystem; two processes writing the same database will corrupt it.
+    pub fn force(mut self, enabled: bool) -> Self {
+        self.force = enabled;
+        self
+    }
+
+    /// Applies every option set in `config`, leaving the rest untouched.
+    pub fn config(mut self, config: &Config) -> Result<Self, ConfigError> {
+        config.validate()?;
+        if let Some(n) = config.block_size {
+            self.block_size = n;
+        }
+        if let Some(n) = config.buffer_capacity {
+            self.buffer_capacity = n;
+        }
+        if let Some(policy) = config.eviction_policy()? {
+            self.eviction = policy;
+        }
+        if let Some(timeout) = config.pin_timeout() {
+            self.pin_timeout = timeout;
+        }
+        if let Some(timeout) = config.lock_timeout() {
+            self.lock_timeout = timeout;
+        }
+        if let Some(dir) = &config.log_dir {
+            self.log_dir = Some(dir.clone());
+        }
+        if let Some(f) = &config.log_file {
+            self.log_file = f.clone();
+        }
+        if let Some(enabled) = config.direct_io {
+            self.direct_io = enabled;
+        }
+        if let Some(enabled) = config.read_only {
+            self.read_only = enabled;
+        }
+        if let Some(n) = config.max_open_files {
+            self.max_open_files = n;
+        }
+        if let Some(enabled) = config.checksums {
+            self.checksums = enabled;
+        }
+        if let Some(durability) = config.durability()? {
+            self.durability = durability;
+        }
+        if let Some(delay) = config.group_commit_delay() {
+            self.group_commit_delay = delay;
+        }
+        if let Some(n) = config.log_buffer_pages {
+            self.log_buffer_pages = n;
+        }
+        if let Some(enabled) = config.log_compression {
+            self.log_compression = enabled;
+        }
+        if let Some(dir) = &config.archive_dir {
+            self.archiver = Some(dir_archiver(dir.clone()));
+        }
+        if let Some(enabled) = config.warmup {
+            self.warmup = enabled;
+        }
+        if let Some(enabled) = config.standby {
+            self.standby = enabled;
+        }
+        Ok(self)
+    }
+
+    /// Opens the database in `path`, creating the directory if it doesn't exist.
+    ///
+    /// Recovery is run for an existing database unless it was shut down with [`WillowDB::close`].
+    /// Fails if another process has the database open, unless [`Builder::force`] is set.
+    pub fn open(self, path: impl AsRef<Path>) -> Result<WillowDB, WillowError> {
+        let fm = Arc::new(self.file_manager(path.as_ref())?);
+        let is_new = fm.is_new;
+        let log_fm: Arc<dyn StorageBackend> = match &self.log_dir {
+            // a second FileManager on the same directory would trip over its lock
+            Some(dir) if dir != path.as_ref() => Arc::new(self.file_manager(dir)?),
+            _ => Arc::clone(&fm) as _,
+        };
+        self.open_with(fm, log_fm, Some(path.as_ref().to_owned()), is_new)
+    }
+
+    /// Opens the log of the database in `path` for inspection, see [`WalReader`].
+    ///
+    /// The log is opened read-only, so this fails while another process has the database open
+    /// unless [`Builder::force`] is set.
+    pub fn open_log(mut self, path: impl AsRef<Path>) -> Result<WalReader, WillowError> {
+        self.read_only = true;
+        let dir = self.log_dir.as_deref().unwrap_or(path.as_ref());
+        let fm = Arc::new(self.file_manager(dir)?);
+        Ok(WalReader::new(fm, &self.log_file)?)
+    }
+
+    /// Checks the database in `path` without opening it: the blocks of every data file against
+    /// their checksums, if [`Builder::checksums`] is set, and every log record against its CRC.
+    ///
+    /// Like [`Builder::open_log`], this fails while another process has the database open
+    /// unless [`Builder::force`] is set.
+    pub fn check(mut self, path: impl AsRef<Path>) -> Result<CheckReport, WillowError> {
+        self.read_only = true;
+        let path = path.as_ref();
+        let fm = Arc::new(self.file_manager(path)?);
+        let log_fm: Arc<dyn StorageBackend> = match &self.log_dir {
+            Some(dir) if dir != path => Arc::new(self.file_manager(dir)?),
+            _ => Arc::clone(&fm) as _,
+        };
+        let mut files = data_files(path, &self.log_file)?;
+        // the map of a compressed file is checked along with it
+        files.retain(|name| name != STANDBY_MARKER && !name.ends_with(MAP_SUFFIX));
+        files.sort_unstable();
+        let log = WalReader::new(log_fm, &self.log_file)?;
+        Ok(CheckReport::run(&fm, &files, &log)?)
+    }
+
+    fn file_manager(&self, dir: &Path) -> Result<FileManager, StorageError> {
+        let opts = DirOptions {
+            direct_io: self.direct_io && !self.read_only,
+            read_only: self.read_only,
+            force: self.force,
+        };
+        let fm = FileManager::open(dir, self.block_size, opts)?
+            .with_max_open_files(self.max_open_files)
+            .with_durability(self.durability);
+        for filename in &self.compressed_files {
+            fm.compress(filename);
+        }
+        Ok(if self.checksums {
+            fm.with_checksums()
+        } else {
+            fm
+        })
+    }
+
+    /// Opens a database that lives entirely in memory and is discarded when dropped.
+    ///
+    /// `log_dir` is ignored: the log is kept in memory as well.
+    pub fn open_in_memory(self) -> Result<WillowDB, WillowError> {
+        let mut fm = FileManager::in_memory(self.block_size);
+        if self.checksums {
+            fm = fm.with_checksums();
+        }
+        let fm = Arc::new(fm);
+        self.open_with(Arc::clone(&fm) as _, fm, None, true)
+    }
+
+    /// Opens a database on top of a custom storage backend, which holds both the data and the log.
+    ///
+    /// The backend's block size overrides [`Builder::block_size`], and `log_dir` is ignored.
+    /// Recovery runs whenever the backend already contains a log.
+    pub fn open_with_storage(
+        self,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<WillowDB, WillowError> {
+        let is_new = storage.length(&self.log_file)? == 0;
+        self.open_with(Arc::clone(&storage), storage, None, is_new)
+    }
+
+    fn open_with(
+        self,
+        storage: Arc<dyn StorageBackend>,
+        log_storage: Arc<dyn StorageBackend>,
+        dir: Option<PathBuf>,
+        is_new: bool,
+    ) -> Result<WillowDB, WillowError> {
+        let separate_log = !Arc::ptr_eq(&storage, &log_storage);
+        let io_stats = Arc::new(IoStats::default());
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(CountingStorage::new(storage, Arc::clone(&io_stats)));
+        let log_storage: Arc<dyn StorageBackend> =
+            Arc::new(CountingStorage::new(log_storage, Arc::clone(&io_stats)));
+
+        let mut lm = LogManager::new(Arc::clone(&log_storage), &self.log_file)?
+            .with_group_commit(self.group_commit_delay)
+            .with_buffer_pages(self.log_buffer_pages)
+            .with_record_compression(self.log_compression);
+        if let Some(archiver) = self.archiver {
+            lm = lm.with_archiver(archiver);
+        }
+        let lm = Arc::new(lm);
+        let opened_lsn = lm.latest_lsn();
+        let mut bm = BufferManager::new(
+            Arc::clone(&storage),
+            Arc::clone(&lm),
+            self.buffer_capacity,
+            self.eviction,
+        )
+        .with_pin_timeout(self.pin_timeout);
+        if self.read_only {
+            bm = bm.read_only();
+        }
+        if self.track_pins {
+            bm = bm.with_pin_tracking();
+        }
+        let bm = Arc::new(bm);
+        let mut tm = TransactionManager::new(
+            Arc::clone(&storage),
+            Arc::clone(&lm),
+            Arc::clone(&bm),
+            self.lock_timeout,
+        );
+        if let Some(observer) = self.recovery_observer {
+            tm = tm.with_recovery_observer(observer);
+        }
+
+        let standby = self.standby && !self.read_only;
+        if self.read_only {
+            tm = tm.read_only();
+            if dir
+                .as_ref()
+                .is_some_and(|d| !d.join(CLEAN_SHUTDOWN_MARKER).exists())
+            {
+                warn!(
+                    "opening a database that wasn't shut down cleanly read-only; skipping recovery"
+                );
+            }
+        } else if standby {
+            if let Some(dir) = &dir {
+                let marker = dir.join(CLEAN_SHUTDOWN_MARKER);
+                if marker.exists() {
+                    fs::remove_file(marker)?;
+                }
+                fs::File::create(dir.join(STANDBY_MARKER))?.sync_all()?;
+            }
+        } else if !is_new {
+            let marker = dir
+                .as_ref()
+                .map(|d| d.join(CLEAN_SHUTDOWN_MARKER))
+                .filter(|m| m.exists());
+            if let Some(marker) = &marker {
+                fs::remove_file(marker)?;
+            }
+            let was_standby = dir
+                .as_ref()
+                .map(|d| d.join(STANDBY_MARKER))
+                .filter(|m| m.exists());
+            match (self.recovery_target, marker, &was_standby) {
+                (Some(target), _, _) => tm.recover_to(target)?,
+                (None, _, Some(_)) => tm.recover_whole_log()?,
+                (None, None, None) => tm.recover()?,
+                (None, Some(_), None) => {}
+            }
+            if let Some(marker) = was_standby {
+                fs::remove_file(marker)?;
+            }
+        }
+        let tm = Arc::new(tm);
+        let applier = if standby {
+            let replay = Replay::new(Arc::clone(&tm), Arc::clone(&lm));
+            Some(Applier::start(replay)?)
+        } else {
+            None
+        };
+
+        let bg_writer = match self.background_writer {
+            Some((interval, max_pages)) if !self.read_only => Some(BackgroundWriter::start(
+                Arc::clone(&bm),
+                interval,
+                max_pages,
+            )?),
+            _ => None,
+        };
+        let warmup = match &dir {
+            Some(dir) => start_warmup(dir, self.warmup, self.read_only, &storage, &bm)?,
+            None => None,
+        };
+
+        let log_dir = dir
+            .as_ref()
+            .map(|d| self.log_dir.clone().unwrap_or_else(|| d.clone()));
+        Ok(WillowDB {
+            applier,
+            warmup,
+            bg_writer,
+            storage,
+            log_storage,
+            io_stats,
+            separate_log,
+            log_dir,
+            log_file: self.log_file,
+            disk_block_size: self.block_size,
+            opened_lsn,
+            dir,
+            is_new,
+            read_only: self.read_only,
+            standby: Arc::new(AtomicBool::new(standby)),
+            lm,
+            bm,
+            tm,
+        })
+    }
+}
+
+/// Alias for embedders that prefer `Database::open(path, options)`.
+pub type Database = WillowDB;
+
+/// An open database: owns the file, log, buffer and transaction managers.
+///
+/// ```no_run
+/// use willow_db::{BlockId, Database, WillowDB};
+///
+/// let db = Database::open("testdb", WillowDB::builder().block_size(4096))?;
+/// let block = BlockId::new("accounts", 0);
+///
+/// let mut txn = db.new_txn()?;
+/// txn.pin(&block)?;
+/// txn.set_int(&block, 80, 100, true)?;
+/// txn.commit()?;
+///
+/// db.close()?;
+/// # Ok::<(), willow_db::WillowError>(())
+/// ```
+pub struct WillowDB {
+    /// Declared first so that the threads stop before anything they use is dropped.
+    applier: Option<Applier>,
+    warmup: Option<Warmup>,
+    bg_writer: Option<BackgroundWriter>,
+    storage: Arc<dyn StorageBackend>,
+    log_storage: Arc<dyn StorageBackend>,
+    io_stats: Arc<IoStats>,
+    /// Whether the log lives in a different backend than the data.
+    separate_log: bool,
+    /// `None` unless the database lives in a directory.
+    dir: Option<PathBuf>,
+    /// Directory of the log file; `None` whenever `dir` is.
+    log_dir: Option<PathBuf>,
+    log_file: String,
+    /// Size of a block in the files on disk.
+    disk_block_size: usize,
+    /// Latest LSN when the database was opened; written blocks are tracked from then on.
+    opened_lsn: Lsn,
+    is_new: bool,
+    read_only: bool,
+    /// Set until a standby is promoted; shared with its log receivers.
+    standby: Arc<AtomicBool>,
+    lm: Arc<LogManager>,
+    bm: Arc<BufferManager>,
+    tm: Arc<TransactionManager>,
+}
+
+impl WillowDB {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Opens the database in `path` with the given options. Same as [`Builder::open`].
+    pub fn open(path: impl AsRef<Path>, options: Builder) -> Result<Self, WillowError> {
+        options.open(path)
+    }
+
+    /// Starts a new transaction, which is read-only on a standby.
+    pub fn new_txn(&self) -> Result<Transaction, WillowError> {
+        if self.is_standby() {
+            return Ok(self.tm.create_read_only_txn()?);
+        }
+        Ok(self.tm.create_txn()?)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.storage.block_size()
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the database was opened with [`Builder::standby`] and hasn't been promoted.
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    /// Turns a standby into a primary: applies the records received so far, rolls back the
+    /// primary's transactions that hadn't finished and starts accepting writes. Log receivers
+    /// refuse records from then on, so receiving should be stopped first.
+    ///
+    /// The transactions started on the standby must be finished first, since rolling back takes
+    /// locks on the blocks it restores. If applying the received records fails, the database
+    /// stays a standby. If rolling back fails, the database should be reopened, which promotes
+    /// it again.
+    pub fn promote(&mut self) -> Result<(), WillowError> {
+        let Some(applier) = self.applier.take() else {
+            return Err(ReplicationError::NotStandby.into());
+        };
+        self.standby.store(false, Ordering::SeqCst);
+        let mut replay = applier
+            .stop()
+            .unwrap_or_else(|| Replay::new(Arc::clone(&self.tm), Arc::clone(&self.lm)));
+        if let Err(e) = replay.apply() {
+            self.applier = Some(Applier::start(replay)?);
+            self.standby.store(true, Ordering::SeqCst);
+            return Err(e.into());
+        }
+        replay.finish_all()?;
+        // the data files weren't written in step with the primary's checkpoints
+        self.tm.recover_whole_log()?;
+        if let Some(dir) = &self.dir {
+            fs::remove_file(dir.join(STANDBY_MARKER))?;
+        }
+        info!("standby promoted");
+        Ok(())
+    }
+
+    /// Returns a shipper that streams this database's log to a standby, starting from its
+    /// first record.
+    pub fn log_shipper(&self) -> LogShipper {
+        LogShipper::new(Arc::clone(&self.lm))
+    }
+
+    /// Returns a receiver that appends the records of a primary's [`LogShipper`] to this
+    /// database's log. It refuses them unless the database is a standby.
+    pub fn log_receiver(&self) -> LogReceiver {
+        LogReceiver::new(Arc::clone(&self.lm), Arc::clone(&self.standby))
+    }
+
+    /// Collects the current values of the engine's counters and latency histograms.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let buffer = self.bm.stats();
+        let txn = self.tm.stats();
+        MetricsSnapshot {
+            buffer_hits: buffer.hits,
+            buffer_misses: buffer.misses,
+            buffer_evictions: buffer.evictions,
+            buffer_dirty_writes: buffer.dirty_writes,
+            blocks_read: self.io_stats.blocks_read.load(Ordering::SeqCst),
+            blocks_written: self.io_stats.blocks_written.load(Ordering::SeqCst),
+            active_txns: txn.active.load(Ordering::SeqCst),
+            lock_wait: txn.lock_wait.snapshot(),
+            pin_wait: buffer.pin_wait,
+            log_flush: self.lm.flush_latency(),
+        }
+    }
+
+    /// Activity of the buffer pool since the database was opened, to help size it.
+    pub fn buffer_stats(&self) -> BufferManagerStats {
+        self.bm.stats()
+    }
+
+    /// Grows or shrinks the buffer pool to `capacity` frames without reopening the database.
+    /// Shrinking writes and gives up unpinned frames but keeps the pinned ones, so the pool
+    /// can remain larger than asked; returns the capacity it ends up with. Large pools are split
+    /// into shards of their own frames, and a `capacity` below their number is rejected.
+    pub fn resize_buffer_pool(&self, capacity: usize) -> Result<usize, WillowError> {
+        Ok(self.bm.resize(capacity)?)
+    }
+
+    /// The buffer pins that weren't released yet, oldest first, with where they were taken and
+    /// by which transaction. Always empty unless [`Builder::track_pins`] is set.
+    pub fn outstanding_pins(&self) -> Vec<PinRecord> {
+        self.bm.outstanding_pins()
+    }
+
+    /// Per-file reads, writes and fsyncs of the data and log files, if the storage backend
+    /// keeps track of them ([`FileManager`] does).
+    pub fn file_stats(&self) -> Option<FileManagerStats> {
+        let stats = self.storage.stats();
+        if !self.separate_log {
+            return stats;
+        }
+        match (stats, self.log_storage.stats()) {
+            (Some(data), Some(log)) => Some(data.merge(log)),
+            (data, log) => data.or(log),
+        }
+    }
+
+    /// Restarts the counters reported by [`WillowDB::file_stats`] from zero.
+    pub fn reset_file_stats(&self) {
+        self.storage.reset_stats();
+        if self.separate_log {
+            self.log_storage.reset_stats();
+        }
+    }
+
+    /// Copies the database into `dest`, which must not exist yet, while it stays open.
+    /// Returns the LSN the copy is consistent with, for [`WillowDB::backup_incremental`].
+    ///
+    /// Every modified page is flushed first. The data files and then the log are copied while
+    /// no page or log record can be written, so transactions stall for the duration of the copy.
+    /// The copy lacks the clean-shutdown marker: opening it runs recovery, which rolls back the
+    /// transactions that were in flight. It must be opened with the same block size, checksum
+    /// and compression settings, and holds the log in `dest` itself even if a `log_dir` is used.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<Lsn, WillowError> {
+        let (dir, log_dir) = self.backup_dirs()?;
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest.parent().unwrap_or(Path::new("")))?;
+        fs::create_dir(dest)?;
+
+        let lsn = self.bm.pause_writes(|| -> Result<Lsn, WillowError> {
+            for name in data_files(dir, &self.log_file)? {
+                copy_file(&dir.join(&name), &dest.join(&name))?;
+            }
+            let log = (log_dir.join(&self.log_file), dest.join(&self.log_file));
+            Ok(self
+                .lm
+                .pause(|lsn| copy_file(&log.0, &log.1).map(|_| lsn))??)
+        })??;
+        fs::File::open(dest)?.sync_all()?;
+        info!(dest = %dest.display(), lsn, "backup finished");
+        Ok(lsn)
+    }
+
+    /// Brings the backup in `dest` up to date, copying only the blocks written since `since_lsn`:
+    /// the LSN returned when `dest` was last backed up to. Returns the LSN to pass next time.
+    ///
+    /// Written blocks are tracked in memory from the moment the database is opened, so `since_lsn`
+    /// must come from a backup taken since then; an older one is rejected. Opening a backup runs
+    /// recovery and changes it, so `dest` mustn't have been opened in the meantime. Compressed files are copied whole, and
+    /// files that no longer exist are removed from `dest`. See [`WillowDB::backup`] for the rest.
+    pub fn backup_incremental(
+        &self,
+        dest: impl AsRef<Path>,
+        since_lsn: Lsn,
+    ) -> Result<Lsn, WillowError> {
+        let (dir, log_dir) = self.backup_dirs()?;
+        let dest = dest.as_ref();
+        if !dest.join(&self.log_file).exists() {
+            let msg = format!("{} doesn't hold a backup", dest.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+        if since_lsn > self.lm.latest_lsn() {
+            let msg = format!("LSN {since_lsn} is past the end of the log");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+        }
+        if since_lsn < self.opened_lsn {
+            let msg =
+                format!("LSN {since_lsn} predates the database being opened; take a full backup");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+        }
+
+        let bs = self.disk_block_size;
+        let lsn = self.bm.pause_writes(|| -> Result<Lsn, WillowError> {
+            let mut changed: HashMap<String, Vec<u64>> = HashMap::new();
+            for block in self.bm.changed_since(since_lsn) {
+                let blocks = changed.entry(block.filename().to_owned()).or_default();
+                blocks.push(block.number());
+            }
+
+            let files = data_files(dir, &self.log_file)?;
+            for name in &files {
+                let (from, to) = (dir.join(name), dest.join(name));
+                // a compressed file's blocks don't sit at fixed offsets
+                let compressed =
+                    name.ends_with(MAP_SUFFIX) || files.contains(&format!("{name}{MAP_SUFFIX}"));
+                if compressed || !to.exists() {
+                    copy_file(&from, &to)?;
+                } else {
+                    let blocks = changed.remove(name).unwrap_or_default();
+                    copy_blocks(&from, &to, bs, blocks)?;
+                }
+            }
+            for name in data_files(dest, &self.log_file)? {
+                if !files.contains(&name) {
+                    fs::remove_file(dest.join(name))?;
+                }
+            }
+
+            let (from, to) = (log_dir.join(&self.log_file), dest.join(&self.log_file));
+            Ok(self.lm.pause(|lsn| -> io::Result<Lsn> {
+                // log blocks are only ever appended to, so the backup is only missing the
+                // blocks past its last one and whatever was added to that one
+                let copied = fs::metadata(&to)?.len() / bs as u64;
+                let total = fs::metadata(&from)?.len().div_ceil(bs as u64);
+                copy_blocks(&from, &to, bs, copied.saturating_sub(1)..total)?;
+                Ok(lsn)
+            })??)
+        })??;
+        info!(dest = %dest.display(), since_lsn, lsn, "incremental backup finished");
+        Ok(lsn)
+    }
+
+    /// The data and log directories, or an error for a database that doesn't live on disk.
+    fn backup_dirs(&self) -> Result<(&Path, &Path), WillowError> {
+        match (&self.dir, &self.log_dir) {
+            (Some(dir), Some(log_dir)) => Ok((dir, log_dir)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only a database in a directory can be backed up",
+            )
+            .into()),
+        }
+    }
+
+    /// Shuts the database down: flushes all dirty buffers, writes a checkpoint,
+    /// syncs the data files and leaves a marker so the next open skips recovery.
+    /// The blocks in the buffer pool are recorded for [`Builder::warmup`].
+    ///
+    /// All transactions must be committed or rolled back before calling this.
+    /// A read-only database has nothing to write and is simply dropped. A standby writes no
+    /// checkpoint or marker, since its log must stay a copy of the primary's.
+    /// The directory lock is released once every transaction has been dropped as well.
+    pub fn close(mut self) -> Result<(), WillowError> {
+        for pin in self.bm.outstanding_pins() {
+            warn!(
+                block = %pin.block,
+                location = %pin.location,
+                txn_num = ?pin.txn_num,
+                held = ?pin.since.elapsed(),
+                "buffer still pinned at close"
+            );
+        }
+        if self.read_only {
+            return Ok(());
+        }
+        let standby = self.applier.is_some();
+        drop(self.applier.take());
+        drop(self.warmup.take());
+        drop(self.bg_writer.take());
+        self.bm.flush_all_dirty()?;
+        // a standby's log must stay a copy of the primary's
+        if !standby {
+            self.tm.checkpoint()?;
+        }
+        self.storage.sync_all()?;
+        self.log_storage.sync_all()?;
+        if let Some(dir) = &self.dir {
+            warmup::save(&dir.join(WARMUP_FILE), &self.bm.resident_blocks())?;
+            if !standby {
+                fs::File::create(dir.join(CLEAN_SHUTDOWN_MARKER))?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts reading the blocks listed in `dir`'s warmup file if `enabled`, skipping those of
+/// files that no longer exist or are now shorter. The file is removed unless read-only.
+fn start_warmup(
+    dir: &Path,
+    enabled: bool,
+    read_only: bool,
+    storage: &Arc<dyn StorageBackend>,
+    bm: &Arc<BufferManager>,
+) -> Result<Option<Warmup>, WillowError> {
+    let path = dir.join(WARMUP_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let blocks = if enabled {
+        warmup::load(&path)?
+    } else {
+        Vec::new()
+    };
+    if !read_only {
+        fs::remove_file(&path)?;
+    }
+    let mut lengths = HashMap::new();
+    let mut present = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let name = block.filename();
+        let len = match lengths.get(name) {
+            Some(&len) => len,
+            None => {
+                // asking for the length of a missing file would create it
+                let len = if dir.join(name).exists() {
+                    storage.length(name)?
+                } else {
+                    0
+                };
+                lengths.insert(name, len);
+                len
+            }
+        };
+        if block.number() < len {
+            present.push(block);
+        }
+    }
+    if present.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Warmup::start(Arc::clone(bm), present)?))
+}
+
+/// Names of the files in `dir` that belong in a backup, apart from the log `log_file`.
+fn data_files(dir: &Path, log_file: &str) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let skip = [LOCK_FILE, CLEAN_SHUTDOWN_MARKER, WARMUP_FILE, log_file];
+        if entry.file_type()?.is_file() && !skip.contains(&name.as_str()) {
+            files.push(name);
+        }
+    }
+    Ok(files)
+}
+
+/// Copies `from` to `to` and makes the copy durable.
+fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)?;
+    fs::File::open(to)?.sync_all()
+}
+
+/// Resizes `to` to the length of `from`, copies the given blocks of `from` over and makes the
+/// result durable. Blocks past the end of `from` are skipped.
+fn copy_blocks(
+    from: &Path,
+    to: &Path,
+    block_size: usize,
+    blocks: impl IntoIterator<Item = u64>,
+) -> io::Result<()> {
+    let src = fs::File::open(from)?;
+    let dst = fs::OpenOptions::new().write(true).open(to)?;
+    let len = src.metadata()?.len();
+    dst.set_len(len)?;
+
+    let mut buf = vec![0; block_size];
+    for n in blocks {
+        let offset = n * block_size as u64;
+        if offset >= len {
+            continue;
+        }
+        let read = pio::read_at(&src, &mut buf, offset)?;
+        pio::write_all_at(&dst, &buf[..read], offset)?;
+    }
+    dst.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, io,
+        sync::{atomic::AtomicBool, Mutex},
+        thread,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use crate::{
+        file::{BlockId, Page},
+        txn::{RecoveryPhase, TxnError},
+    };
+
+    use super::*;
+
+    fn test_dir(prefix: &str) -> PathBuf {
+        let dirname = format!(
+            "{}_{}",
+            prefix,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname)
+    }
+
+    #[test]
+    fn test_builder() {
+        let dir_path = test_dir("dbtest");
+
+        let db = WillowDB::builder()
+            .block_size(512)
+            .buffer_capacity(8)
+            .eviction(EvictionPolicy::Fifo)
+            .log_file("test.log")
+            .open(&dir_path)
+            .unwrap();
+
+        assert!(db.is_new());
+        assert_eq!(db.block_size(), 512);
+        assert_eq!(db.bm.available(), 8);
+        assert_eq!(db.storage.length("test.log").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_builder_config() {
+        let dir_path = test_dir("dbconfigtest");
+        let log_dir = dir_path.join("wal");
+
+        let config = Config {
+            block_size: Some(256),
+            buffer_capacity: Some(4),
+            log_dir: Some(log_dir.clone()),
+            ..Default::default()
+        };
+        let db = WillowDB::builder()
+            .config(&config)
+            .unwrap()
+            .open(&dir_path)
+            .unwrap();
+
+        assert_eq!(db.block_size(), 256);
+        assert_eq!(db.bm.available(), 4);
+        assert!(log_dir.join(DEFAULT_LOG_FILE).exists());
+        assert!(!dir_path.join(DEFAULT_LOG_FILE).exists());
+    }
+
+    #[test]
+    fn test_close() {
+        let dir_path = test_dir("dbclosetest");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+
+        let blk = BlockId::new("testfile", 0);
+        let buf_lock = db.bm.pin(&blk).unwrap();
+        let mut buf = buf_lock.write().unwrap();
+        buf.contents_mut().set_int(80, 42);
+        buf.set_modified(1, None).unwrap();
+        drop(buf);
+        // the buffer keeps the FileManager (and its directory lock) alive
+        drop(buf_lock);
+
+        db.close().unwrap();
+
+        assert!(dir_path.join(CLEAN_SHUTDOWN_MARKER).exists());
+
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        assert!(!db.is_new());
+        assert!(!dir_path.join(CLEAN_SHUTDOWN_MARKER).exists());
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 42);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_warmup() {
+        let dir_path = test_dir("dbwarmuptest");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        db.storage.append_extent("testfile", 3).unwrap();
+        db.storage.append_extent("dropped", 1).unwrap();
+        for block in [("testfile", 2), ("dropped", 0), ("testfile", 0)] {
+            drop(db.bm.pin(&BlockId::new(block.0, block.1)).unwrap());
+        }
+        db.close().unwrap();
+        assert!(dir_path.join(WARMUP_FILE).exists());
+        fs::remove_file(dir_path.join("dropped")).unwrap();
+
+        let db = WillowDB::builder()
+            .block_size(400)
+            .warmup(true)
+            .open(&dir_path)
+            .unwrap();
+        assert!(!dir_path.join(WARMUP_FILE).exists());
+        let start = std::time::Instant::now();
+        while db.bm.resident_blocks().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        db.close().unwrap();
+        // blocks of files that are gone are skipped rather than bringing the files back
+        assert!(!dir_path.join("dropped").exists());
+        assert_eq!(
+            warmup::load(&dir_path.join(WARMUP_FILE)).unwrap(),
+            [BlockId::new("testfile", 0), BlockId::new("testfile", 2)]
+        );
+    }
+
+    #[test]
+    fn test_track_pins() {
+        let db = WillowDB::builder()
+            .block_size(400)
+            .track_pins(true)
+            .open_in_memory()
+            .unwrap();
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        let line = line!() - 1;
+
+        let pins = db.outstanding_pins();
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].block, blk);
+        assert_eq!(pins[0].txn_num, Some(tx.txn_num()));
+        assert_eq!(
+            (pins[0].location.file(), pins[0].location.line()),
+            (file!(), line)
+        );
+        tx.commit().unwrap();
+        assert!(db.outstanding_pins().is_empty());
+    }
+
+    #[test]
+    fn test_recover_on_reopen() {
+        let dir_path = test_dir("dbrecovertest");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        let blk = BlockId::new("testfile", 0);
+
+        let mut tx1 = db.new_txn().unwrap();
+        tx1.pin(&blk).unwrap();
+        tx1.set_int(&blk, 80, 1, true).unwrap();
+        tx1.commit().unwrap();
+
+        let mut tx2 = db.new_txn().unwrap();
+        tx2.pin(&blk).unwrap();
+        tx2.set_int(&blk, 80, 2, true).unwrap();
+        // the uncommitted change reaches the disk, then the process "crashes"
+        db.bm.flush_all_dirty().unwrap();
+        tx2.crash();
+        drop(tx1);
+        drop(db);
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let db = WillowDB::builder()
+            .block_size(400)
+            .recovery_observer({
+                let progress = Arc::clone(&progress);
+                move |p| progress.lock().unwrap().push(*p)
+            })
+            .open(&dir_path)
+            .unwrap();
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 1);
+        tx.commit().unwrap();
+
+        let progress = progress.lock().unwrap();
+        let phases: Vec<_> = progress.iter().map(|p| p.phase).collect();
+        assert!(phases.is_sorted_by_key(|phase| *phase as u8));
+        for phase in [
+            RecoveryPhase::Analysis,
+            RecoveryPhase::Redo,
+            RecoveryPhase::Undo,
+        ] {
+            let last = progress.iter().rfind(|p| p.phase == phase).unwrap();
+            assert_eq!(
+                last.records,
+                progress.iter().filter(|p| p.phase == phase).count()
+            );
+        }
+        let last = progress.last().unwrap();
+        assert_eq!(Some(last.records), last.total);
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir_path = test_dir("dbbackuptest");
+        let backup_path = test_dir("dbbackuptest_copy");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        let blk = BlockId::new("testfile", 0);
+
+        let mut tx1 = db.new_txn().unwrap();
+        tx1.pin(&blk).unwrap();
+        tx1.set_int(&blk, 80, 1, true).unwrap();
+        tx1.commit().unwrap();
+
+        // still in flight while the backup is taken
+        let mut tx2 = db.new_txn().unwrap();
+        tx2.pin(&blk).unwrap();
+        tx2.set_int(&blk, 80, 2, true).unwrap();
+        db.backup(&backup_path).unwrap();
+        assert!(db.backup(&backup_path).is_err());
+        tx2.commit().unwrap();
+
+        let backup = WillowDB::builder()
+            .block_size(400)
+            .open(&backup_path)
+            .unwrap();
+        assert!(!backup.is_new());
+        let mut tx = backup.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 1);
+        tx.commit().unwrap();
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 2);
+        tx.commit().unwrap();
+
+        let db = WillowDB::builder().open_in_memory().unwrap();
+        assert!(db.backup(test_dir("dbbackupmemtest")).is_err());
+    }
+
+    #[test]
+    fn test_backup_incremental() {
+        let dir_path = test_dir("dbincbackuptest");
+        let backup_path = test_dir("dbincbackuptest_copy");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        let (blk1, blk2) = (BlockId::new("file1", 0), BlockId::new("file2", 3));
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk1).unwrap();
+        tx.set_int(&blk1, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+        assert!(db.backup_incremental(&backup_path, 0).is_err());
+        let lsn = db.backup(&backup_path).unwrap();
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk1).unwrap();
+        tx.pin(&blk2).unwrap();
+        tx.set_int(&blk1, 80, 2, true).unwrap();
+        tx.set_int(&blk2, 80, 3, true).unwrap();
+        tx.commit().unwrap();
+        let next = db.backup_incremental(&backup_path, lsn).unwrap();
+        assert!(next > lsn);
+        assert!(db.backup_incremental(&backup_path, next + 100).is_err());
+
+        let backup = WillowDB::builder()
+            .block_size(400)
+            .open(&backup_path)
+            .unwrap();
+        let mut tx = backup.new_txn().unwrap();
+        tx.pin(&blk1).unwrap();
+        tx.pin(&blk2).unwrap();
+        assert_eq!(tx.get_int(&blk1, 80).unwrap(), 2);
+        assert_eq!(tx.get_int(&blk2, 80).unwrap(), 3);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_backup_incremental_after_reopen() {
+        let dir_path = test_dir("dbincreopentest");
+        let backup_path = test_dir("dbincreopentest_copy");
+        let lsn = {
+            let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+            let blk = BlockId::new("testfile", 0);
+            let mut tx = db.new_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            tx.set_int(&blk, 80, 1, true).unwrap();
+            tx.commit().unwrap();
+            let lsn = db.backup(&backup_path).unwrap();
+            let mut tx = db.new_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            tx.set_int(&blk, 80, 2, true).unwrap();
+            tx.commit().unwrap();
+            lsn
+        };
+
+        // LSNs survive a restart, but the blocks written before it aren't known
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        assert!(db.lm.latest_lsn() > lsn);
+        assert!(db.backup_incremental(&backup_path, lsn).is_err());
+    }
+
+    #[test]
+    fn test_archive_dir() {
+        let dir_path = test_dir("dbarchivetest");
+        let archive_path = test_dir("dbarchivetest_wal");
+        let db = WillowDB::builder()
+            .block_size(400)
+            .archive_dir(&archive_path)
+            .open(&dir_path)
+            .unwrap();
+        let blk = BlockId::new("testfile", 0);
+        for i in 0..20 {
+            let mut tx = db.new_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            tx.set_string(&blk, 80, &"x".repeat(50 + i), true).unwrap();
+            tx.commit().unwrap();
+        }
+
+        // every block but the current one has been archived
+        let log = fs::read(dir_path.join(DEFAULT_LOG_FILE)).unwrap();
+        let archived = fs::read(archive_path.join(DEFAULT_LOG_FILE)).unwrap();
+        assert!(!archived.is_empty());
+        assert_eq!(archived.len(), log.len() - 400);
+        assert_eq!(archived, log[..archived.len()]);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let dir_path = test_dir("dbchecksumtest");
+        let builder = || WillowDB::builder().block_size(400).checksums(true);
+        let db = builder().open(&dir_path).unwrap();
+        assert_eq!(db.block_size(), 400);
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 7, true).unwrap();
+        tx.commit().unwrap();
+        drop(tx);
+        db.close().unwrap();
+
+        let db = builder().open(&dir_path).unwrap();
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 7);
+        tx.commit().unwrap();
+        drop(tx);
+        db.close().unwrap();
+        let report = builder().check(&dir_path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files, 1);
+        assert!(report.records > 0);
+
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .open(dir_path.join("testfile"))
+            .unwrap();
+        pio::write_all_at(&f, &[0xff], 82).unwrap();
+        let report = builder().check(&dir_path).unwrap();
+        assert_eq!(report.bad_blocks, std::slice::from_ref(&blk));
+        assert!(report.log_error.is_none());
+
+        // a record of the log
+        let log = fs::OpenOptions::new()
+            .write(true)
+            .open(dir_path.join(DEFAULT_LOG_FILE))
+            .unwrap();
+        pio::write_all_at(&log, &[0xff], 390).unwrap();
+        let report = builder().check(&dir_path).unwrap();
+        assert!(report.log_error.is_some());
+
+        let db = builder().open(&dir_path).unwrap();
+        let mut tx = db.new_txn().unwrap();
+        assert!(matches!(
+            tx.pin(&blk),
+            Err(TxnError::Buffer(crate::buffer::BufferError::Storage(
+                StorageError::ChecksumMismatch(_)
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir_path = test_dir("dbreadonlytest");
+        assert!(WillowDB::builder().read_only(true).open(&dir_path).is_err());
+
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 7, true).unwrap();
+        tx.commit().unwrap();
+        drop(tx);
+        db.close().unwrap();
+        let log_len = fs::metadata(dir_path.join(DEFAULT_LOG_FILE)).unwrap().len();
+
+        let db = WillowDB::builder()
+            .block_size(400)
+            .read_only(true)
+            .open(&dir_path)
+            .unwrap();
+        assert!(db.is_read_only());
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 7);
+        assert!(matches!(
+            tx.set_int(&blk, 80, 8, false),
+            Err(TxnError::ReadOnly)
+        ));
+        tx.commit().unwrap();
+        db.close().unwrap();
+
+        // nothing was logged and the marker is still in place
+        let new_len = fs::metadata(dir_path.join(DEFAULT_LOG_FILE)).unwrap().len();
+        assert_eq!(new_len, log_len);
+        assert!(dir_path.join(CLEAN_SHUTDOWN_MARKER).exists());
+    }
+
+    /// Reads the int at `offset` of `blk` in a transaction of its own.
+    fn read_int(db: &WillowDB, blk: &BlockId, offset: usize) -> Result<i32, WillowError> {
+        let mut tx = db.new_txn()?;
+        tx.pin(blk)?;
+        let n = tx.get_int(blk, offset)?;
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /// Waits for a standby to apply the change that writes `n` at `offset` of `blk`.
+    fn wait_for_int(db: &WillowDB, blk: &BlockId, offset: usize, n: i32) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while read_int(db, blk, offset).ok() != Some(n) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "change wasn't applied"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_standby() {
+        let primary_path = test_dir("dbstandbyprimary");
+        let standby_path = test_dir("dbstandbytest");
+        let standby_builder = || {
+            WillowDB::builder()
+                .block_size(400)
+                .lock_timeout(Duration::from_millis(50))
+                .standby(true)
+        };
+        let primary = WillowDB::builder()
+            .block_size(400)
+            .open(&primary_path)
+            .unwrap();
+        let standby = standby_builder().open(&standby_path).unwrap();
+        assert!(standby.is_standby());
+
+        let (blk0, blk1) = (BlockId::new("testfile", 0), BlockId::new("testfile", 1));
+        let mut tx1 = primary.new_txn().unwrap();
+        tx1.pin(&blk0).unwrap();
+        tx1.set_int(&blk0, 80, 1, true).unwrap();
+        tx1.commit().unwrap();
+        let mut tx2 = primary.new_txn().unwrap();
+        tx2.pin(&blk1).unwrap();
+        tx2.set_int(&blk1, 80, 2, true).unwrap();
+
+        let mut shipper = primary.log_shipper();
+        let mut stream = Vec::new();
+        shipper.ship(&mut stream).unwrap();
+        assert!(matches!(
+            primary.log_receiver().receive(&stream[..]),
+            Err(ReplicationError::NotStandby)
+        ));
+        assert!(standby.log_receiver().receive(&stream[..]).unwrap() > 0);
+        wait_for_int(&standby, &blk0, 80, 1);
+
+        let mut tx = standby.new_txn().unwrap();
+        tx.pin(&blk0).unwrap();
+        assert!(matches!(
+            tx.set_int(&blk0, 80, 3, true),
+            Err(TxnError::ReadOnly)
+        ));
+        tx.commit().unwrap();
+        drop(tx);
+        // tx2 hasn't committed, so its change stays locked
+        let err = read_int(&standby, &blk1, 80).unwrap_err();
+        assert!(err.is_retryable());
+
+        // reopening applies the log from the start again
+        standby.close().unwrap();
+        assert!(!standby_path.join(CLEAN_SHUTDOWN_MARKER).exists());
+        let mut standby = standby_builder().open(&standby_path).unwrap();
+        assert_eq!(standby.log_receiver().receive(&stream[..]).unwrap(), 0);
+        wait_for_int(&standby, &blk0, 80, 1);
+
+        // promotion rolls back tx2, which is still in flight on the primary
+        standby.promote().unwrap();
+        assert!(!standby.is_standby());
+        assert!(!standby_path.join(STANDBY_MARKER).exists());
+        assert!(matches!(
+            standby.log_receiver().receive(&stream[..]),
+            Err(ReplicationError::NotStandby)
+        ));
+        assert!(matches!(
+            standby.promote(),
+            Err(WillowError::Replication(ReplicationError::NotStandby))
+        ));
+        assert_eq!(read_int(&standby, &blk0, 80).unwrap(), 1);
+        assert_eq!(read_int(&standby, &blk1, 80).unwrap(), 0);
+        let mut tx = standby.new_txn().unwrap();
+        tx.pin(&blk1).unwrap();
+        tx.set_int(&blk1, 80, 4, true).unwrap();
+        tx.commit().unwrap();
+        drop(tx);
+        standby.close().unwrap();
+
+        let db = WillowDB::builder()
+            .block_size(400)
+            .open(&standby_path)
+            .unwrap();
+        assert_eq!(read_int(&db, &blk0, 80).unwrap(), 1);
+        assert_eq!(read_int(&db, &blk1, 80).unwrap(), 4);
+        db.close().unwrap();
+
+        tx2.rollback().unwrap();
+        drop(tx2);
+        primary.close().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_target() {
+        let dir_path = test_dir("dbpitrtest");
+        let backups = [test_dir("dbpitrtest_lsn"), test_dir("dbpitrtest_time")];
+        let blk = BlockId::new("testfile", 0);
+        let get = |db: &WillowDB| {
+            let mut tx = db.new_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            let n = tx.get_int(&blk, 80).unwrap();
+            tx.commit().unwrap();
+            n
+        };
+
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        for n in [1, 2] {
+            let mut tx = db.new_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            tx.set_int(&blk, 80, n, true).unwrap();
+            tx.commit().unwrap();
+            if n == 1 {
+                for backup in &backups {
+                    db.backup(backup).unwrap();
+                }
+            }
+        }
+        db.close().unwrap();
+
+        // reuses the number of the first transaction before the reopen
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 3, true).unwrap();
+        let (lsn, time) = (db.lm.latest_lsn(), SystemTime::now());
+        thread::sleep(Duration::from_millis(2));
+        tx.commit().unwrap();
+        db.close().unwrap();
+
+        let targets = [RecoveryTarget::Lsn(lsn), RecoveryTarget::Time(time)];
+        for (backup, target) in backups.iter().zip(targets) {
+            // the archived log
+            copy_file(
+                &dir_path.join(DEFAULT_LOG_FILE),
+                &backup.join(DEFAULT_LOG_FILE),
+            )
+            .unwrap();
+            let db = WillowDB::builder()
+                .block_size(400)
+                .recovery_target(target)
+                .open(backup)
+                .unwrap();
+            assert_eq!(get(&db), 2, "recovering to {target:?}");
+            db.close().unwrap();
+
+            // the records past the target are gone for good
+            let db = WillowDB::builder().block_size(400).open(backup).unwrap();
+            assert_eq!(get(&db), 2);
+        }
+    }
+
+    #[test]
+    fn test_dir_lock() {
+        let dir_path = test_dir("dblocktest");
+        let db = WillowDB::builder().open(&dir_path).unwrap();
+        assert!(matches!(
+            WillowDB::builder().open(&dir_path),
+            Err(WillowError::Storage(StorageError::Locked(_)))
+        ));
+
+        let forced = WillowDB::builder().force(true).open(&dir_path).unwrap();
+        drop(forced);
+        drop(db);
+        WillowDB::builder().open(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_file_stats() {
+        let dir_path = test_dir("dbfilestatstest");
+        let log_dir = test_dir("dbfilestatslogtest");
+        let db = WillowDB::builder()
+            .block_size(400)
+            .log_dir(&log_dir)
+            .open(&dir_path)
+            .unwrap();
+
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+        // commit leaves the page in the pool
+        assert_eq!(db.file_stats().unwrap().per_file["testfile"].writes, 0);
+        db.bm.flush_all_dirty().unwrap();
+
+        let stats = db.file_stats().unwrap();
+        let data = stats.per_file["testfile"];
+        assert_eq!((data.reads, data.writes, data.fsyncs), (1, 1, 1));
+        assert!(stats.per_file[DEFAULT_LOG_FILE].writes > 0);
+        assert_eq!(
+            stats.writes,
+            data.writes + stats.per_file[DEFAULT_LOG_FILE].writes
+        );
+
+        db.reset_file_stats();
+        let stats = db.file_stats().unwrap();
+        assert_eq!((stats.reads, stats.writes, stats.fsyncs), (0, 0, 0));
+        assert!(stats.per_file.is_empty());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let dir_path = test_dir("dbmetricstest");
+        let db = WillowDB::builder().block_size(400).open(&dir_path).unwrap();
+
+        let blk = BlockId::new("testfile", 0);
+        drop(db.bm.pin(&blk).unwrap());
+        drop(db.bm.pin(&blk).unwrap());
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.buffer_hits, 1);
+        assert_eq!(metrics.buffer_misses, 1);
+        assert_eq!(metrics.blocks_read, 1);
+        assert_eq!(metrics.active_txns, 0);
+        let stats = db.buffer_stats();
+        assert_eq!((stats.pinned, stats.hit_ratio()), (0, 0.5));
+        assert!(metrics
+            .to_prometheus()
+            .contains("willow_buffer_misses_total 1\n"));
+    }
+
+    #[test]
+    fn test_new_txn() {
+        let dir_path = test_dir("dbtxntest");
+        let db = Database::open(&dir_path, WillowDB::builder().block_size(400)).unwrap();
+        let blk = BlockId::new("testfile", 0);
+
+        let mut tx1 = db.new_txn().unwrap();
+        tx1.pin(&blk).unwrap();
+        tx1.set_int(&blk, 80, 7, true).unwrap();
+        tx1.set_string(&blk, 40, "seven", true).unwrap();
+        assert_eq!(db.metrics().active_txns, 1);
+        tx1.commit().unwrap();
+
+        let mut tx2 = db.new_txn().unwrap();
+        tx2.pin(&blk).unwrap();
+        assert_eq!(tx2.get_int(&blk, 80).unwrap(), 7);
+        assert_eq!(tx2.get_string(&blk, 40).unwrap(), "seven");
+        tx2.commit().unwrap();
+
+        assert_eq!(db.metrics().active_txns, 0);
+    }
+
+    #[test]
+    fn test_open_in_memory() {
+        let db = WillowDB::builder()
+            .block_size(400)
+            .open_in_memory()
+            .unwrap();
+        let blk = BlockId::new("testfile", 0);
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 5, true).unwrap();
+        tx.rollback().unwrap();
+
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 0);
+        tx.commit().unwrap();
+
+        assert!(db.is_new());
+        db.close().unwrap();
+    }
+
+    /// In-memory backend whose writes can be made to fail.
+    struct FlakyStorage {
+        inner: FileManager,
+        fail_writes: AtomicBool,
+    }
+
+    impl StorageBackend for FlakyStorage {
+        fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+            self.inner.read_block(block, p)
+        }
+
+        fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+            if self.fail_writes.load(Ordering::SeqCst) {
+                return Err(StorageError::Write {
+                    block: block.clone(),
+                    source: io::Error::other("disk full"),
+                });
+            }
+            self.inner.write_block(block, p)
+        }
+
+        fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+            self.inner.append(filename)
+        }
+
+        fn length(&self, filename: &str) -> Result<u64, StorageError> {
+            self.inner.length(filename)
+        }
+
+        fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+            self.inner.truncate(filename, len)
+        }
+
+        fn delete(&self, filename: &str) -> Result<(), StorageError> {
+            self.inner.delete(filename)
+        }
+
+        fn block_size(&self) -> usize {
+            self.inner.block_size()
+        }
+    }
+
+    #[test]
+    fn test_open_with_storage() {
+        let storage = Arc::new(FlakyStorage {
+            inner: FileManager::in_memory(400),
+            fail_writes: AtomicBool::new(false),
+        });
+        let db = WillowDB::builder()
+            .open_with_storage(Arc::clone(&storage) as _)
+            .unwrap();
+        assert!(db.is_new());
+        assert_eq!(db.block_size(), 400);
+
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 3, true).unwrap();
+
+        storage.fail_writes.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            tx.commit(),
+            Err(TxnError::Buffer(_) | TxnError::Log(_))
+        ));
+        assert!(db.metrics().blocks_written > 0);
+    }
+}
@@ -0,0 +1,41 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{
+    buffer::BufferError, config::ConfigError, file::StorageError, log::LogError,
+    replication::ReplicationError, txn::TxnError, wal::WalError,
+};
+
+/// Top-level error returned by the public API.
+#[derive(Debug, Error)]
+pub enum WillowError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Log(#[from] LogError),
+    #[error(transparent)]
+    Buffer(#[from] BufferError),
+    #[error(transparent)]
+    Txn(#[from] TxnError),
+    #[error(transparent)]
+    Replication(#[from] ReplicationError),
+    #[error(transparent)]
+    Wal(#[from] WalError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl WillowError {
+    /// Whether the operation gave up waiting for a lock or a buffer and may succeed if the
+    /// transaction is rolled back and run again, see [`TxnError::is_retryable`].
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Buffer(e) => matches!(e, BufferError::Abort),
+            Self::Txn(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+}
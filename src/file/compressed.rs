@@ -0,0 +1,133 @@
+use std::{fs::File, io};
+
+use super::pio;
+
+/// Suffix of the file holding a compressed file's block map.
+pub(crate) const MAP_SUFFIX: &str = ".map";
+
+/// Size of a block map entry: the frame's offset (u64) and length (u32).
+const ENTRY_SIZE: usize = 12;
+
+/// Where a block's compressed bytes live in the data file. A zero length marks a block
+/// that was never written and reads back as zeros.
+#[derive(Clone, Copy, Default)]
+struct Frame {
+    offset: u64,
+    len: u32,
+}
+
+/// A file whose blocks are stored lz4-compressed.
+///
+/// Compressed blocks vary in size, so the data file is a heap of frames and a separate map
+/// file records the frame of each block. A rewritten block reuses its old frame when the new
+/// one fits and is appended to the data file otherwise; the space of abandoned frames isn't
+/// reclaimed until the file is deleted.
+pub(super) struct CompressedFile {
+    data: File,
+    map: File,
+    block_size: usize,
+    frames: Vec<Frame>,
+    /// End of the data file, where new frames are appended.
+    end: u64,
+}
+
+impl CompressedFile {
+    pub fn new(data: File, map: File, block_size: usize) -> io::Result<Self> {
+        let mut raw = vec![0; map.metadata()?.len() as usize];
+        let n = pio::read_at(&map, &mut raw, 0)?;
+        let frames = raw[..n]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| Frame {
+                offset: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                len: u32::from_le_bytes(entry[8..].try_into().unwrap()),
+            })
+            .collect();
+        let end = data.metadata()?.len();
+        Ok(Self {
+            data,
+            map,
+            block_size,
+            frames,
+            end,
+        })
+    }
+
+    /// Reads whole blocks starting at the block-aligned `offset`.
+    /// Returns the number of bytes read, which is short past the last block.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let first = (offset / self.block_size as u64) as usize;
+        let mut total = 0;
+        for (i, chunk) in buf.chunks_mut(self.block_size).enumerate() {
+            let Some(frame) = self.frames.get(first + i) else {
+                break;
+            };
+            if frame.len == 0 {
+                chunk.fill(0);
+            } else {
+                let mut compressed = vec![0; frame.len as usize];
+                pio::read_at(&self.data, &mut compressed, frame.offset)?;
+                lz4_flex::block::decompress_into(&compressed, chunk)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            total += chunk.len();
+        }
+        Ok(total)
+    }
+
+    /// Writes whole blocks starting at the block-aligned `offset`.
+    pub fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let first = (offset / self.block_size as u64) as usize;
+        for (i, chunk) in buf.chunks(self.block_size).enumerate() {
+            let idx = first + i;
+            let compressed = lz4_flex::block::compress(chunk);
+            let old = self.frames.get(idx).copied().unwrap_or_default();
+            let frame = if old.len as usize >= compressed.len() {
+                Frame {
+                    offset: old.offset,
+                    len: compressed.len() as u32,
+                }
+            } else {
+                let frame = Frame {
+                    offset: self.end,
+                    len: compressed.len() as u32,
+                };
+                self.end += compressed.len() as u64;
+                frame
+            };
+
+            pio::write_all_at(&self.data, &compressed, frame.offset)?;
+            if self.frames.len() <= idx {
+                self.frames.resize(idx + 1, Frame::default());
+            }
+            self.frames[idx] = frame;
+            self.write_entry(idx)?;
+        }
+        Ok(())
+    }
+
+    /// Logical length in bytes.
+    pub fn len(&self) -> u64 {
+        (self.frames.len() * self.block_size) as u64
+    }
+
+    /// Resizes to `len` bytes worth of blocks; new blocks read back as zeros.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let n = (len / self.block_size as u64) as usize;
+        self.frames.resize(n, Frame::default());
+        self.map.set_len((n * ENTRY_SIZE) as u64)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        // frames first, then the map entries that point at them
+        self.data.sync_all()?;
+        self.map.sync_all()
+    }
+
+    fn write_entry(&self, idx: usize) -> io::Result<()> {
+        let frame = self.frames[idx];
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..8].copy_from_slice(&frame.offset.to_le_bytes());
+        entry[8..].copy_from_slice(&frame.len.to_le_bytes());
+        pio::write_all_at(&self.map, &entry, (idx * ENTRY_SIZE) as u64)
+    }
+}
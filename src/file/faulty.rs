@@ -0,0 +1,200 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::metrics::FileManagerStats;
+
+use super::{BlockId, Page, StorageBackend, StorageError};
+
+/// No fault is planned.
+const NO_FAULT: u64 = u64::MAX;
+
+/// A [`StorageBackend`] that passes everything on to another one until it's told to fail, to
+/// test how the engine copes with I/O errors and crashes.
+///
+/// Once it fails, every write, append, truncation, deletion and sync returns an error until
+/// [`FaultyStorage::heal`] is called, while reads still go through. What was written before
+/// stays in the wrapped backend, as after the process crashes: a database opened on it again
+/// runs recovery.
+pub struct FaultyStorage {
+    inner: Arc<dyn StorageBackend>,
+    /// Changes let through before failing, [`NO_FAULT`] if they all are.
+    remaining: AtomicU64,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            inner,
+            remaining: AtomicU64::new(NO_FAULT),
+        }
+    }
+
+    /// Lets `n` more changes through and fails the ones after. A write of several blocks
+    /// counts as one change.
+    pub fn fail_after(&self, n: u64) {
+        self.remaining.store(n.min(NO_FAULT - 1), Ordering::SeqCst);
+    }
+
+    /// Lets every change through again.
+    pub fn heal(&self) {
+        self.remaining.store(NO_FAULT, Ordering::SeqCst);
+    }
+
+    /// Whether changes fail.
+    pub fn is_failing(&self) -> bool {
+        self.remaining.load(Ordering::SeqCst) == 0
+    }
+
+    /// Counts a change, failing if none is left.
+    fn change(&self) -> io::Result<()> {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| match n {
+                NO_FAULT => Some(NO_FAULT),
+                0 => None,
+                n => Some(n - 1),
+            })
+            .map(|_| ())
+            .map_err(|_| io::Error::other("injected fault"))
+    }
+}
+
+impl StorageBackend for FaultyStorage {
+    fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+        self.inner.read_block(block, p)
+    }
+
+    fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+        self.change().map_err(|source| StorageError::Write {
+            block: block.clone(),
+            source,
+        })?;
+        self.inner.write_block(block, p)
+    }
+
+    fn read_blocks(&self, blocks: &[BlockId], pages: &mut [&mut Page]) -> Result<(), StorageError> {
+        self.inner.read_blocks(blocks, pages)
+    }
+
+    fn write_blocks(&self, blocks: &[BlockId], pages: &[&Page]) -> Result<(), StorageError> {
+        if let Some(block) = blocks.first() {
+            self.change().map_err(|source| StorageError::Write {
+                block: block.clone(),
+                source,
+            })?;
+        }
+        self.inner.write_blocks(blocks, pages)
+    }
+
+    fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+        self.append_extent(filename, 1)
+    }
+
+    fn append_extent(&self, filename: &str, count: usize) -> Result<BlockId, StorageError> {
+        if let Err(source) = self.change() {
+            let block = BlockId::new(filename, self.inner.length(filename)?);
+            return Err(StorageError::Write { block, source });
+        }
+        self.inner.append_extent(filename, count)
+    }
+
+    fn length(&self, filename: &str) -> Result<u64, StorageError> {
+        self.inner.length(filename)
+    }
+
+    fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+        self.change().map_err(|source| StorageError::Truncate {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        self.inner.truncate(filename, len)
+    }
+
+    fn delete(&self, filename: &str) -> Result<(), StorageError> {
+        self.change().map_err(|source| StorageError::Delete {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        self.inner.delete(filename)
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn sync_file(&self, filename: &str) -> Result<(), StorageError> {
+        self.change().map_err(|source| StorageError::Sync {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        self.inner.sync_file(filename)
+    }
+
+    fn sync_all(&self) -> Result<(), StorageError> {
+        self.change().map_err(|source| StorageError::Sync {
+            filename: String::new(),
+            source,
+        })?;
+        self.inner.sync_all()
+    }
+
+    fn stats(&self) -> Option<FileManagerStats> {
+        self.inner.stats()
+    }
+
+    fn reset_stats(&self) {
+        self.inner.reset_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{file::FileManager, WillowDB};
+
+    use super::*;
+
+    #[test]
+    fn test_crash_recovery() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&fm) as _));
+        let db = WillowDB::builder()
+            .open_with_storage(Arc::clone(&faulty) as _)
+            .unwrap();
+        let blk = BlockId::new("testfile", 0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        // the commit can't reach the log
+        faulty.fail_after(0);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 2, true).unwrap();
+        assert!(tx.commit().is_err());
+        assert!(faulty.is_failing());
+        drop(tx);
+        drop(db);
+
+        let db = WillowDB::builder()
+            .open_with_storage(Arc::clone(&fm) as _)
+            .unwrap();
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        assert_eq!(tx.get_int(&blk, 80).unwrap(), 1);
+        tx.commit().unwrap();
+
+        faulty.heal();
+        assert!(faulty.append("testfile").is_ok());
+        faulty.fail_after(1);
+        assert!(faulty.append("testfile").is_ok());
+        assert!(matches!(
+            faulty.append("testfile"),
+            Err(StorageError::Write { block, .. }) if block.number() == 3
+        ));
+    }
+}
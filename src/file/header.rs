@@ -0,0 +1,207 @@
+use std::fmt;
+
+use super::{Page, PageError};
+use crate::{
+    constants::{SIZE_OF_INT, SIZE_OF_LONG},
+    log::Lsn,
+};
+
+const TYPE_POS: usize = 0;
+const LSN_POS: usize = TYPE_POS + SIZE_OF_INT;
+const CHECKSUM_POS: usize = LSN_POS + SIZE_OF_LONG;
+const FREE_SPACE_POS: usize = CHECKSUM_POS + SIZE_OF_INT;
+
+/// What a block holds, recorded in its [`PageHeader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
+    /// A block that was never formatted, e.g. one that reads back as zeros.
+    Empty = 0,
+    /// A log block. Records grow down from the end of the block towards the free-space pointer.
+    Log = 1,
+    /// A data block.
+    Data = 2,
+}
+
+impl TryFrom<i32> for PageType {
+    type Error = PageError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Empty),
+            1 => Ok(Self::Log),
+            2 => Ok(Self::Data),
+            n => Err(PageError::UnknownPageType(n)),
+        }
+    }
+}
+
+impl fmt::Display for PageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Empty => "empty",
+            Self::Log => "log",
+            Self::Data => "data",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The header at the start of a formatted block. Log pages are formatted when they're created,
+/// data pages on the first change made to them by a transaction.
+///
+/// Layout: `[ page type (i32) | page LSN (i64) | checksum (u32) | free-space pointer (i32) ]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub page_type: PageType,
+    /// LSN of the last log record describing a change to the block.
+    pub lsn: Lsn,
+    /// CRC32 of the page without this field, see [`Page::update_checksum`]. Always kept for log
+    /// pages, and for every block by a [`FileManager`](super::FileManager) with checksums.
+    pub checksum: u32,
+    /// Offset of the boundary between used and free space.
+    pub free_space: usize,
+}
+
+impl PageHeader {
+    /// Bytes the header takes up at the start of the page.
+    pub const SIZE: usize = FREE_SPACE_POS + SIZE_OF_INT;
+
+    pub fn new(page_type: PageType, free_space: usize) -> Self {
+        Self {
+            page_type,
+            lsn: 0,
+            checksum: 0,
+            free_space,
+        }
+    }
+}
+
+impl Page {
+    pub fn try_header(&self) -> Result<PageHeader, PageError> {
+        Ok(PageHeader {
+            page_type: self.try_page_type()?,
+            lsn: self.try_get_long(LSN_POS)? as Lsn,
+            checksum: self.try_get_int(CHECKSUM_POS)? as u32,
+            free_space: self.try_free_space()?,
+        })
+    }
+
+    pub fn try_set_header(&mut self, header: &PageHeader) -> Result<(), PageError> {
+        // check the whole range first so that a failed write leaves the page untouched
+        self.try_get_raw(0, PageHeader::SIZE)?;
+        self.try_set_int(TYPE_POS, header.page_type as i32)?;
+        self.try_set_long(LSN_POS, header.lsn as i64)?;
+        self.try_set_int(CHECKSUM_POS, header.checksum as i32)?;
+        self.try_set_int(FREE_SPACE_POS, header.free_space as i32)
+    }
+
+    pub fn try_page_type(&self) -> Result<PageType, PageError> {
+        PageType::try_from(self.try_get_int(TYPE_POS)?)
+    }
+
+    pub fn try_free_space(&self) -> Result<usize, PageError> {
+        // a negative pointer becomes one past any page
+        Ok(self.try_get_int(FREE_SPACE_POS)? as u32 as usize)
+    }
+
+    pub fn header(&self) -> PageHeader {
+        self.try_header().expect("valid header in bound")
+    }
+
+    pub fn set_header(&mut self, header: &PageHeader) {
+        self.try_set_header(header).expect("range to be in bound")
+    }
+
+    pub fn page_lsn(&self) -> Lsn {
+        self.get_long(LSN_POS) as Lsn
+    }
+
+    pub fn set_page_lsn(&mut self, lsn: Lsn) {
+        self.set_long(LSN_POS, lsn as i64);
+    }
+
+    pub fn free_space(&self) -> usize {
+        self.try_free_space().expect("in bound")
+    }
+
+    pub fn set_free_space(&mut self, offset: usize) {
+        self.set_int(FREE_SPACE_POS, offset as i32);
+    }
+
+    /// CRC32 of the page, skipping the header's checksum field.
+    pub fn compute_checksum(&self) -> u32 {
+        checksum(self.contents())
+    }
+
+    /// Stores the page's checksum in its header. Call after the last change before writing.
+    pub fn update_checksum(&mut self) {
+        store_checksum(self.contents_mut());
+    }
+
+    /// Checks the checksum stored in the header against the page's contents.
+    pub fn verify_checksum(&self) -> Result<(), PageError> {
+        let stored = self.try_get_int(CHECKSUM_POS)? as u32;
+        let computed = self.compute_checksum();
+        if stored != computed {
+            return Err(PageError::ChecksumMismatch { stored, computed });
+        }
+        Ok(())
+    }
+}
+
+/// CRC32 of a block's bytes, skipping the header's checksum field.
+fn checksum(contents: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&contents[..CHECKSUM_POS]);
+    hasher.update(&contents[CHECKSUM_POS + SIZE_OF_INT..]);
+    hasher.finalize()
+}
+
+/// Stores the checksum of a block's bytes in its header.
+pub(super) fn store_checksum(contents: &mut [u8]) {
+    let checksum = checksum(contents);
+    contents[CHECKSUM_POS..CHECKSUM_POS + SIZE_OF_INT].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Whether the checksum in a block's header matches its bytes.
+pub(super) fn checksum_matches(contents: &[u8]) -> bool {
+    let stored = &contents[CHECKSUM_POS..CHECKSUM_POS + SIZE_OF_INT];
+    u32::from_le_bytes(stored.try_into().unwrap()) == checksum(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_header() {
+        let mut p = Page::new(100);
+        assert_eq!(p.header(), PageHeader::new(PageType::Empty, 0));
+
+        let header = PageHeader {
+            page_type: PageType::Data,
+            lsn: 42,
+            checksum: 0,
+            free_space: PageHeader::SIZE,
+        };
+        p.set_header(&header);
+        assert_eq!(p.header(), header);
+        p.set_page_lsn(43);
+        p.set_free_space(60);
+        assert_eq!((p.page_lsn(), p.free_space()), (43, 60));
+
+        p.set_int(80, 7);
+        assert!(p.verify_checksum().is_err());
+        p.update_checksum();
+        assert!(p.verify_checksum().is_ok());
+        p.set_int(80, 8);
+        assert!(matches!(
+            p.verify_checksum(),
+            Err(PageError::ChecksumMismatch { .. })
+        ));
+
+        p.set_int(0, 9);
+        assert!(matches!(p.try_header(), Err(PageError::UnknownPageType(9))));
+        assert!(Page::new(10).try_set_header(&header).is_err());
+    }
+}
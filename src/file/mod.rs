@@ -0,0 +1,2283 @@
+#![allow(dead_code)]
+
+use std::{
+    alloc::{self, Layout},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    ptr::NonNull,
+    slice,
+    str::{self, FromStr, Utf8Error},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::{info, trace, warn};
+
+use crate::{
+    constants::{MAX_VARINT_LEN, SIZE_OF_DOUBLE, SIZE_OF_INT, SIZE_OF_LONG},
+    metrics::{FileIoStats, FileManagerStats},
+};
+
+use compressed::CompressedFile;
+pub(crate) use compressed::MAP_SUFFIX;
+pub use faulty::FaultyStorage;
+pub use header::{PageHeader, PageType};
+pub use page_serde::{from_page, to_page, PageDeserializer, PageSerializer, SerdeError};
+
+mod compressed;
+mod faulty;
+mod header;
+mod page_serde;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::UringFileManager;
+
+/// When a [`FileManager`] syncs the blocks it writes to disk.
+///
+/// The log manager syncs the log itself on every flush, whatever the policy. Recovery only
+/// undoes changes, so committed changes that weren't synced yet are lost if the machine crashes
+/// with [`Durability::Every`] or [`Durability::Never`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync after every block write.
+    #[default]
+    Always,
+    /// Sync when the buffer pool writes a batch of pages, which it does when a transaction
+    /// commits or rolls back and at checkpoints. Pages written on eviction aren't synced.
+    OnCommit,
+    /// Sync every modified file at most once per interval. Checked whenever blocks are written.
+    Every(Duration),
+    /// Only sync when explicitly asked to, e.g. when the database is closed.
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses `always`, `on-commit`, `never` or `every-<n>ms`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let interval = s
+            .strip_prefix("every-")
+            .and_then(|ms| ms.strip_suffix("ms"))
+            .and_then(|ms| ms.parse().ok());
+        match s.as_str() {
+            "always" => Ok(Self::Always),
+            "on-commit" => Ok(Self::OnCommit),
+            "never" => Ok(Self::Never),
+            _ => interval.map(|ms| Self::Every(Duration::from_millis(ms))).ok_or_else(|| {
+                format!(
+                    "unknown durability {:?} (expected \"always\", \"on-commit\", \"never\" or \"every-<n>ms\")",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Lock file that keeps two processes from opening the same database directory.
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// Default cap on the number of file handles a FileManager keeps open.
+pub(crate) const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// Number of blocks that disk space is reserved for at a time when a file grows.
+const EXTENT_BLOCKS: u64 = 64;
+
+/// Alignment required for the offsets, lengths and memory of direct I/O.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Interned file name: copying, comparing and hashing one never touches the name itself.
+///
+/// Names are registered process-wide the first time they are seen so that a [`BlockId`] can be
+/// built without a [`FileManager`] at hand. They are never freed; a database only ever uses a
+/// bounded set of file names.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+#[derive(Default)]
+struct FileNames {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, FileId>,
+}
+
+static FILE_NAMES: LazyLock<RwLock<FileNames>> = LazyLock::new(Default::default);
+
+impl FileId {
+    /// Returns the id of `filename`, registering it if it's new.
+    pub fn new(filename: &str) -> Self {
+        if let Some(&id) = FILE_NAMES.read().unwrap().ids.get(filename) {
+            return id;
+        }
+        let mut registry = FILE_NAMES.write().unwrap();
+        // another thread may have registered it in the meantime
+        if let Some(&id) = registry.ids.get(filename) {
+            return id;
+        }
+        let name: &'static str = Box::leak(filename.into());
+        let id = FileId(registry.names.len() as u32);
+        registry.names.push(name);
+        registry.ids.insert(name, id);
+        id
+    }
+
+    pub fn name(self) -> &'static str {
+        FILE_NAMES.read().unwrap().names[self.0 as usize]
+    }
+}
+
+impl fmt::Debug for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FileId").field(&self.name()).finish()
+    }
+}
+
+/// (file, block number)
+#[derive(Clone, PartialEq, Hash, Eq)]
+pub struct BlockId(FileId, u64);
+
+impl BlockId {
+    pub fn new(filename: &str, block_num: u64) -> Self {
+        BlockId(FileId::new(filename), block_num)
+    }
+
+    /// Same as [`BlockId::new`] without looking the file name up.
+    pub fn with_file(file: FileId, block_num: u64) -> Self {
+        BlockId(file, block_num)
+    }
+
+    pub fn number(&self) -> u64 {
+        self.1
+    }
+
+    pub fn file(&self) -> FileId {
+        self.0
+    }
+
+    pub fn filename(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn hash_code(&self) -> u64 {
+        let mut hasher = DefaultHasher::default();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl fmt::Debug for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BlockId")
+            .field(&self.filename())
+            .field(&self.1)
+            .finish()
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[file {} block {}]", self.filename(), self.1)
+    }
+}
+
+/// Zeroed heap buffer with a caller-chosen alignment.
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: AlignedBuf owns its allocation, just like a Box<[u8]>.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    fn zeroed(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("alignment is a power of two");
+        let ptr = if len == 0 {
+            NonNull::dangling()
+        } else {
+            // SAFETY: the layout has a non-zero size
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+        Self { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr points to layout.size() initialized bytes (or is dangling with len 0)
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: same as deref, and &mut self guarantees exclusive access
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            // SAFETY: allocated in `zeroed` with the same layout
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+        }
+    }
+}
+
+pub struct Page {
+    byte_buf: AlignedBuf,
+}
+
+impl From<Box<[u8]>> for Page {
+    fn from(b: Box<[u8]>) -> Self {
+        let mut p = Page::new(b.len());
+        p.byte_buf.copy_from_slice(&b);
+        p
+    }
+}
+
+impl Page {
+    /// Creates a zeroed page. Pages whose size is a multiple of [`DIRECT_IO_ALIGNMENT`]
+    /// are aligned to it, so they can be used for direct I/O.
+    pub fn new(size: usize) -> Self {
+        let align = if size > 0 && size.is_multiple_of(DIRECT_IO_ALIGNMENT) {
+            DIRECT_IO_ALIGNMENT
+        } else {
+            1
+        };
+        Self {
+            byte_buf: AlignedBuf::zeroed(size, align),
+        }
+    }
+
+    /// The `len` bytes at `offset`, or an error if they run past the end of the page.
+    fn slice(&self, offset: usize, len: usize) -> Result<&[u8], PageError> {
+        let size = self.byte_buf.len();
+        offset
+            .checked_add(len)
+            .and_then(|end| self.byte_buf.get(offset..end))
+            .ok_or(PageError::OutOfBounds { offset, len, size })
+    }
+
+    fn slice_mut(&mut self, offset: usize, len: usize) -> Result<&mut [u8], PageError> {
+        let size = self.byte_buf.len();
+        offset
+            .checked_add(len)
+            .and_then(|end| self.byte_buf.get_mut(offset..end))
+            .ok_or(PageError::OutOfBounds { offset, len, size })
+    }
+
+    pub fn try_get_int(&self, offset: usize) -> Result<i32, PageError> {
+        let bytes = self.slice(offset, SIZE_OF_INT)?;
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn try_set_int(&mut self, offset: usize, n: i32) -> Result<(), PageError> {
+        self.slice_mut(offset, SIZE_OF_INT)?
+            .copy_from_slice(&n.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn try_get_long(&self, offset: usize) -> Result<i64, PageError> {
+        let bytes = self.slice(offset, SIZE_OF_LONG)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn try_set_long(&mut self, offset: usize, n: i64) -> Result<(), PageError> {
+        self.slice_mut(offset, SIZE_OF_LONG)?
+            .copy_from_slice(&n.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn try_get_double(&self, offset: usize) -> Result<f64, PageError> {
+        let bytes = self.slice(offset, SIZE_OF_DOUBLE)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn try_set_double(&mut self, offset: usize, n: f64) -> Result<(), PageError> {
+        self.slice_mut(offset, SIZE_OF_DOUBLE)?
+            .copy_from_slice(&n.to_le_bytes());
+        Ok(())
+    }
+
+    /// Reads a date stored as the number of days since the Unix epoch.
+    pub fn try_get_date(&self, offset: usize) -> Result<i32, PageError> {
+        self.try_get_int(offset)
+    }
+
+    pub fn try_set_date(&mut self, offset: usize, days: i32) -> Result<(), PageError> {
+        self.try_set_int(offset, days)
+    }
+
+    /// Reads a timestamp stored as the number of microseconds since the Unix epoch.
+    pub fn try_get_timestamp(&self, offset: usize) -> Result<i64, PageError> {
+        self.try_get_long(offset)
+    }
+
+    pub fn try_set_timestamp(&mut self, offset: usize, micros: i64) -> Result<(), PageError> {
+        self.try_set_long(offset, micros)
+    }
+
+    pub fn try_get_bool(&self, offset: usize) -> Result<bool, PageError> {
+        Ok(self.slice(offset, 1)?[0] != 0)
+    }
+
+    pub fn try_set_bool(&mut self, offset: usize, b: bool) -> Result<(), PageError> {
+        self.slice_mut(offset, 1)?[0] = b as u8;
+        Ok(())
+    }
+
+    /// Reads `len` bytes. Unlike [`Page::try_get_bytes`], no length is stored with them.
+    pub fn try_get_raw(&self, offset: usize, len: usize) -> Result<&[u8], PageError> {
+        self.slice(offset, len)
+    }
+
+    pub fn try_set_raw(&mut self, offset: usize, bytes: &[u8]) -> Result<(), PageError> {
+        self.slice_mut(offset, bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reads bytes stored after their length. A corrupt length yields an error.
+    pub fn try_get_bytes(&self, offset: usize) -> Result<&[u8], PageError> {
+        // a negative length becomes one that can't fit
+        let len = self.try_get_int(offset)? as u32 as usize;
+        self.slice(offset + SIZE_OF_INT, len)
+    }
+
+    pub fn try_set_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), PageError> {
+        // check the whole range first so that a failed write leaves the page untouched
+        self.slice(offset, SIZE_OF_INT + bytes.len())?;
+        self.try_set_int(offset, bytes.len() as i32)?;
+        self.try_set_raw(offset + SIZE_OF_INT, bytes)
+    }
+
+    /// Reads a string, replacing invalid UTF-8 with U+FFFD.
+    pub fn try_get_string(&self, offset: usize) -> Result<Cow<'_, str>, PageError> {
+        Ok(String::from_utf8_lossy(self.try_get_bytes(offset)?))
+    }
+
+    /// Reads a string, returning an error instead of replacing invalid UTF-8.
+    pub fn try_get_str(&self, offset: usize) -> Result<&str, PageError> {
+        str::from_utf8(self.try_get_bytes(offset)?)
+            .map_err(|source| PageError::InvalidUtf8 { offset, source })
+    }
+
+    pub fn try_set_string(&mut self, offset: usize, s: &str) -> Result<(), PageError> {
+        self.try_set_bytes(offset, s.as_bytes())
+    }
+
+    /// Reads a `CHAR(n)` string written by [`Page::try_set_fixed_string`], without its padding.
+    pub fn try_get_fixed_string(&self, offset: usize, n: usize) -> Result<&str, PageError> {
+        let s = str::from_utf8(self.slice(offset, n)?)
+            .map_err(|source| PageError::InvalidUtf8 { offset, source })?;
+        Ok(s.trim_end_matches(' '))
+    }
+
+    /// Writes `s` into exactly `n` bytes, padding it with spaces or truncating it at the last
+    /// character that fits. Like SQL's `CHAR(n)`, trailing spaces don't survive a round trip.
+    pub fn try_set_fixed_string(
+        &mut self,
+        offset: usize,
+        s: &str,
+        n: usize,
+    ) -> Result<(), PageError> {
+        let s = &s[..s.floor_char_boundary(n)];
+        let dest = self.slice_mut(offset, n)?;
+        dest[..s.len()].copy_from_slice(s.as_bytes());
+        dest[s.len()..].fill(b' ');
+        Ok(())
+    }
+
+    /// Reads a zigzag LEB128 varint, which takes one byte for values in -64..64.
+    /// Occupies [`Page::varint_size`] bytes of the page.
+    pub fn try_get_varint(&self, offset: usize) -> Result<i64, PageError> {
+        let mut n = 0u64;
+        for i in 0..MAX_VARINT_LEN {
+            let b = self.slice(offset + i, 1)?[0];
+            // the last group only has room for one bit, and a trailing zero group would
+            // make the encoding longer than `varint_size` says
+            if (i == MAX_VARINT_LEN - 1 && b > 1) || (i > 0 && b == 0) {
+                break;
+            }
+            n |= u64::from(b & 0x7f) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(((n >> 1) as i64) ^ -((n & 1) as i64));
+            }
+        }
+        Err(PageError::InvalidVarint { offset })
+    }
+
+    pub fn try_set_varint(&mut self, offset: usize, n: i64) -> Result<(), PageError> {
+        let mut zigzag = ((n << 1) ^ (n >> 63)) as u64;
+        let mut buf = [0; MAX_VARINT_LEN];
+        let mut len = 0;
+        loop {
+            buf[len] = (zigzag & 0x7f) as u8;
+            zigzag >>= 7;
+            len += 1;
+            if zigzag == 0 {
+                break;
+            }
+            buf[len - 1] |= 0x80;
+        }
+        self.try_set_raw(offset, &buf[..len])
+    }
+
+    /// Reads a string stored after its length as a varint. Invalid UTF-8 yields an error.
+    pub fn try_get_varstr(&self, offset: usize) -> Result<&str, PageError> {
+        let len = self.try_get_varint(offset)?;
+        let len = usize::try_from(len).map_err(|_| PageError::InvalidVarint { offset })?;
+        let bytes = self.slice(offset + Self::varint_size(len as i64), len)?;
+        str::from_utf8(bytes).map_err(|source| PageError::InvalidUtf8 { offset, source })
+    }
+
+    pub fn try_set_varstr(&mut self, offset: usize, s: &str) -> Result<(), PageError> {
+        // check the whole range first so that a failed write leaves the page untouched
+        self.slice(offset, Self::varstr_size(s))?;
+        self.try_set_varint(offset, s.len() as i64)?;
+        self.try_set_raw(offset + Self::varint_size(s.len() as i64), s.as_bytes())
+    }
+
+    // The accessors below panic where their `try_` counterparts return an error.
+
+    pub fn get_int(&self, offset: usize) -> i32 {
+        self.try_get_int(offset).expect("in bound")
+    }
+
+    pub fn set_int(&mut self, offset: usize, n: i32) {
+        self.try_set_int(offset, n).expect("in bound")
+    }
+
+    pub fn get_long(&self, offset: usize) -> i64 {
+        self.try_get_long(offset).expect("in bound")
+    }
+
+    pub fn set_long(&mut self, offset: usize, n: i64) {
+        self.try_set_long(offset, n).expect("in bound")
+    }
+
+    pub fn get_double(&self, offset: usize) -> f64 {
+        self.try_get_double(offset).expect("in bound")
+    }
+
+    pub fn set_double(&mut self, offset: usize, n: f64) {
+        self.try_set_double(offset, n).expect("in bound")
+    }
+
+    pub fn get_date(&self, offset: usize) -> i32 {
+        self.try_get_date(offset).expect("in bound")
+    }
+
+    pub fn set_date(&mut self, offset: usize, days: i32) {
+        self.try_set_date(offset, days).expect("in bound")
+    }
+
+    pub fn get_timestamp(&self, offset: usize) -> i64 {
+        self.try_get_timestamp(offset).expect("in bound")
+    }
+
+    pub fn set_timestamp(&mut self, offset: usize, micros: i64) {
+        self.try_set_timestamp(offset, micros).expect("in bound")
+    }
+
+    pub fn get_bool(&self, offset: usize) -> bool {
+        self.try_get_bool(offset).expect("in bound")
+    }
+
+    pub fn set_bool(&mut self, offset: usize, b: bool) {
+        self.try_set_bool(offset, b).expect("in bound")
+    }
+
+    pub fn get_raw(&self, offset: usize, len: usize) -> &[u8] {
+        self.try_get_raw(offset, len).expect("range to be in bound")
+    }
+
+    pub fn set_raw(&mut self, offset: usize, bytes: &[u8]) {
+        self.try_set_raw(offset, bytes)
+            .expect("range to be in bound")
+    }
+
+    pub fn get_bytes(&self, offset: usize) -> &[u8] {
+        self.try_get_bytes(offset).expect("range to be in bound")
+    }
+
+    pub fn set_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        self.try_set_bytes(offset, bytes)
+            .expect("range to be in bound")
+    }
+
+    pub fn get_string(&self, offset: usize) -> Cow<'_, str> {
+        self.try_get_string(offset).expect("range to be in bound")
+    }
+
+    /// Like [`Page::get_string`] but rejects invalid UTF-8 instead of replacing it.
+    pub fn get_str(&self, offset: usize) -> Result<&str, Utf8Error> {
+        str::from_utf8(self.get_bytes(offset))
+    }
+
+    pub fn set_string(&mut self, offset: usize, s: &str) {
+        self.try_set_string(offset, s)
+            .expect("range to be in bound")
+    }
+
+    pub fn get_fixed_string(&self, offset: usize, n: usize) -> &str {
+        self.try_get_fixed_string(offset, n)
+            .expect("valid string in bound")
+    }
+
+    pub fn set_fixed_string(&mut self, offset: usize, s: &str, n: usize) {
+        self.try_set_fixed_string(offset, s, n)
+            .expect("range to be in bound")
+    }
+
+    pub fn get_varint(&self, offset: usize) -> i64 {
+        self.try_get_varint(offset).expect("valid varint in bound")
+    }
+
+    pub fn set_varint(&mut self, offset: usize, n: i64) {
+        self.try_set_varint(offset, n)
+            .expect("range to be in bound")
+    }
+
+    pub fn get_varstr(&self, offset: usize) -> &str {
+        self.try_get_varstr(offset).expect("valid string in bound")
+    }
+
+    pub fn set_varstr(&mut self, offset: usize, s: &str) {
+        self.try_set_varstr(offset, s)
+            .expect("range to be in bound")
+    }
+
+    pub fn str_size(s: &str) -> usize {
+        SIZE_OF_INT + s.len()
+    }
+
+    /// Bytes taken by `n` encoded with [`Page::set_varint`].
+    pub fn varint_size(n: i64) -> usize {
+        let zigzag = ((n << 1) ^ (n >> 63)) as u64;
+        (64 - zigzag.leading_zeros() as usize).div_ceil(7).max(1)
+    }
+
+    /// Bytes taken by `s` encoded with [`Page::set_varstr`].
+    pub fn varstr_size(s: &str) -> usize {
+        Self::varint_size(s.len() as i64) + s.len()
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.byte_buf
+    }
+
+    pub fn contents_mut(&mut self) -> &mut [u8] {
+        &mut self.byte_buf
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PageError {
+    #[error("{len} bytes at offset {offset} run past the end of a {size}-byte page")]
+    OutOfBounds {
+        offset: usize,
+        len: usize,
+        size: usize,
+    },
+    #[error("string at offset {offset} is not valid UTF-8")]
+    InvalidUtf8 {
+        offset: usize,
+        #[source]
+        source: Utf8Error,
+    },
+    #[error("malformed varint at offset {offset}")]
+    InvalidVarint { offset: usize },
+    #[error("offset {0} is inside the page header")]
+    InHeader(usize),
+    #[error("unknown page type {0}")]
+    UnknownPageType(i32),
+    #[error("expected a {expected} page, found a {found} page")]
+    WrongPageType { expected: PageType, found: PageType },
+    #[error("page checksum {stored:#x} doesn't match contents ({computed:#x})")]
+    ChecksumMismatch { stored: u32, computed: u32 },
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("{} is not a directory", .0.display())]
+    NotADirectory(PathBuf),
+    #[error("failed to create directory {}", .path.display())]
+    CreateDir { path: PathBuf, source: io::Error },
+    #[error("failed to open {}", .path.display())]
+    Open { path: PathBuf, source: io::Error },
+    #[error("failed to read {block}")]
+    Read { block: BlockId, source: io::Error },
+    #[error("failed to write {block}")]
+    Write { block: BlockId, source: io::Error },
+    #[error("failed to sync file {filename}")]
+    Sync { filename: String, source: io::Error },
+    #[error("failed to get length of file {filename}")]
+    Length { filename: String, source: io::Error },
+    #[error("failed to truncate file {filename}")]
+    Truncate { filename: String, source: io::Error },
+    #[error("failed to delete file {filename}")]
+    Delete { filename: String, source: io::Error },
+    #[error("direct I/O needs a block size that is a multiple of {DIRECT_IO_ALIGNMENT}, got {0}")]
+    UnalignedBlockSize(usize),
+    #[error("direct I/O isn't supported on this platform")]
+    DirectIoUnsupported,
+    #[error("failed to set up io_uring")]
+    RingSetup(#[source] io::Error),
+    #[error("can't modify {0}: storage is read-only")]
+    ReadOnly(String),
+    #[error("database directory {} is in use by another process", .0.display())]
+    Locked(PathBuf),
+    #[error("checksum mismatch in {0}")]
+    ChecksumMismatch(BlockId),
+}
+
+/// An open file: either on disk or a growable in-memory segment.
+enum DataFile {
+    Disk(File),
+    Memory(Vec<u8>),
+    Compressed(CompressedFile),
+}
+
+impl DataFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            DataFile::Disk(f) => pio::read_at(f, buf, offset),
+            DataFile::Compressed(f) => f.read_at(buf, offset),
+            DataFile::Memory(v) => {
+                let start = v.len().min(offset as usize);
+                let n = buf.len().min(v.len() - start);
+                buf[..n].copy_from_slice(&v[start..start + n]);
+                Ok(n)
+            }
+        }
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            DataFile::Disk(f) => pio::write_all_at(f, buf, offset),
+            DataFile::Compressed(f) => f.write_all_at(buf, offset),
+            DataFile::Memory(v) => {
+                let start = offset as usize;
+                let end = start + buf.len();
+                if v.len() < end {
+                    v.resize(end, 0);
+                }
+                v[start..end].copy_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            DataFile::Disk(f) => Ok(f.metadata()?.len()),
+            DataFile::Compressed(f) => Ok(f.len()),
+            DataFile::Memory(v) => Ok(v.len() as u64),
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self {
+            DataFile::Disk(f) => f.sync_all(),
+            DataFile::Compressed(f) => f.sync(),
+            DataFile::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Resizes the file to `len` bytes, filling any new space with zeros.
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            DataFile::Disk(f) => f.set_len(len),
+            DataFile::Compressed(f) => f.set_len(len),
+            DataFile::Memory(v) => {
+                v.resize(len as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reserves space for the first `len` bytes without changing the file's length.
+    /// Best effort: filesystems that can't preallocate are left alone.
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            DataFile::Disk(f) => {
+                use std::os::fd::AsRawFd;
+                // SAFETY: the fd is owned by `f` and stays open for the call
+                let res = unsafe {
+                    libc::fallocate(f.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as i64)
+                };
+                if res == 0 {
+                    return Ok(());
+                }
+                match io::Error::last_os_error() {
+                    e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+                    e => Err(e),
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            DataFile::Disk(_) => Ok(()),
+            // frames vary in size, so there's nothing sensible to reserve
+            DataFile::Compressed(_) => Ok(()),
+            DataFile::Memory(v) => {
+                v.reserve((len as usize).saturating_sub(v.len()));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Block-level storage underneath the buffer and log managers.
+///
+/// [`FileManager`] is the default implementation. Other backends (object stores,
+/// encrypted files, test doubles) can be plugged in with [`crate::Builder::open_with_storage`].
+pub trait StorageBackend: Send + Sync {
+    /// Fills `p` with the contents of `block`. Bytes past the end of the file are left untouched.
+    fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError>;
+
+    fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError>;
+
+    /// Reads `blocks[i]` into `pages[i]`. Backends that can keep several reads in flight
+    /// override this; the default reads one block at a time.
+    fn read_blocks(&self, blocks: &[BlockId], pages: &mut [&mut Page]) -> Result<(), StorageError> {
+        for (block, p) in blocks.iter().zip(pages.iter_mut()) {
+            self.read_block(block, p)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `pages[i]` to `blocks[i]`. The default writes one block at a time.
+    fn write_blocks(&self, blocks: &[BlockId], pages: &[&Page]) -> Result<(), StorageError> {
+        for (block, p) in blocks.iter().zip(pages) {
+            self.write_block(block, p)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a zeroed block to the end of `filename` and returns its id.
+    fn append(&self, filename: &str) -> Result<BlockId, StorageError>;
+
+    /// Adds `count` zeroed blocks to the end of `filename` and returns the id of the first one.
+    /// Meant for bulk loads; the default appends one block at a time.
+    fn append_extent(&self, filename: &str, count: usize) -> Result<BlockId, StorageError> {
+        let first = BlockId::new(filename, self.length(filename)?);
+        for _ in 0..count {
+            self.append(filename)?;
+        }
+        Ok(first)
+    }
+
+    /// Number of blocks in `filename`. A file that doesn't exist yet has 0 blocks.
+    fn length(&self, filename: &str) -> Result<u64, StorageError>;
+
+    /// Shrinks `filename` to its first `len` blocks. Does nothing if the file is already shorter.
+    fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError>;
+
+    /// Removes `filename`. Deleting a file that doesn't exist is not an error.
+    fn delete(&self, filename: &str) -> Result<(), StorageError>;
+
+    fn block_size(&self) -> usize;
+
+    /// Makes every completed write to `filename` durable, whatever the durability policy.
+    /// The default syncs everything.
+    fn sync_file(&self, filename: &str) -> Result<(), StorageError> {
+        let _ = filename;
+        self.sync_all()
+    }
+
+    /// Makes every completed write durable.
+    fn sync_all(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// I/O counters, for backends that keep them.
+    fn stats(&self) -> Option<FileManagerStats> {
+        None
+    }
+
+    fn reset_stats(&self) {}
+}
+
+pub struct FileManager {
+    /// `None` for an in-memory FileManager.
+    db_directory: Option<PathBuf>,
+    block_size: usize,
+    pub is_new: bool,
+    open_files: Arc<RwLock<HashMap<String, OpenFile>>>,
+    /// Upper bound on `open_files`; the least recently used file is closed to make room.
+    max_open_files: usize,
+    /// Logical clock for [`OpenFile::last_used`].
+    clock: AtomicU64,
+    /// Files that didn't exist before this FileManager opened them.
+    created_files: RwLock<HashSet<String>>,
+    /// Open files with `O_DIRECT`, bypassing the OS page cache.
+    direct_io: bool,
+    /// Open files without write access and reject every modification.
+    read_only: bool,
+    /// Keep a CRC32 of each block in its [`PageHeader`].
+    checksums: bool,
+    /// Files to create in the compressed format.
+    compressed_files: RwLock<HashSet<String>>,
+    durability: Durability,
+    /// Files with writes that haven't been synced yet.
+    unsynced: Mutex<HashSet<String>>,
+    /// When [`Durability::Every`] last synced.
+    last_sync: Mutex<Instant>,
+    /// Reads, writes and fsyncs per file since creation or the last [`FileManager::reset_stats`].
+    io_stats: Mutex<HashMap<String, FileIoStats>>,
+    /// Per-file high-water mark: the number of blocks that disk space has been reserved for.
+    reserved: Mutex<HashMap<FileId, u64>>,
+    /// Holds the lock on the directory's `LOCK` file; dropping it releases the lock.
+    _dir_lock: Option<File>,
+}
+
+struct OpenFile {
+    file: Arc<Mutex<DataFile>>,
+    last_used: AtomicU64,
+}
+
+/// How [`FileManager::open`] treats a database directory.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct DirOptions {
+    pub direct_io: bool,
+    pub read_only: bool,
+    /// Open even if another process holds the directory lock.
+    pub force: bool,
+}
+
+impl FileManager {
+    /// Opens (or creates) the database in `db_directory`.
+    ///
+    /// Takes an exclusive lock on the directory's `LOCK` file for as long as the FileManager
+    /// lives, failing with [`StorageError::Locked`] if another process has the database open.
+    pub fn new(db_directory: &Path, block_size: usize) -> Result<Self, StorageError> {
+        Self::open(db_directory, block_size, DirOptions::default())
+    }
+
+    /// Like [`FileManager::new`], but reads and writes bypass the OS page cache.
+    ///
+    /// The block size must be a multiple of [`DIRECT_IO_ALIGNMENT`]. Only supported on Linux.
+    pub fn with_direct_io(db_directory: &Path, block_size: usize) -> Result<Self, StorageError> {
+        let opts = DirOptions {
+            direct_io: true,
+            ..Default::default()
+        };
+        Self::open(db_directory, block_size, opts)
+    }
+
+    /// Opens an existing database directory without write access.
+    ///
+    /// Files are never created, and every write, append, truncation or deletion
+    /// fails with [`StorageError::ReadOnly`]. Several read-only FileManagers can share
+    /// a directory, but not with a writable one.
+    pub fn read_only(db_directory: &Path, block_size: usize) -> Result<Self, StorageError> {
+        let opts = DirOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        Self::open(db_directory, block_size, opts)
+    }
+
+    pub(crate) fn open(
+        db_directory: &Path,
+        block_size: usize,
+        opts: DirOptions,
+    ) -> Result<Self, StorageError> {
+        if opts.direct_io && !cfg!(target_os = "linux") {
+            return Err(StorageError::DirectIoUnsupported);
+        }
+        if opts.direct_io && (block_size == 0 || !block_size.is_multiple_of(DIRECT_IO_ALIGNMENT)) {
+            return Err(StorageError::UnalignedBlockSize(block_size));
+        }
+
+        let path_exists = db_directory
+            .try_exists()
+            .map_err(|source| StorageError::Open {
+                path: db_directory.to_owned(),
+                source,
+            })?;
+        if (path_exists || opts.read_only) && !db_directory.is_dir() {
+            return Err(StorageError::NotADirectory(db_directory.to_owned()));
+        }
+        if !path_exists {
+            info!(dir = %db_directory.display(), "creating database directory");
+            fs::create_dir_all(db_directory).map_err(|source| StorageError::CreateDir {
+                path: db_directory.to_owned(),
+                source,
+            })?;
+        }
+
+        let dir_lock = if opts.force {
+            warn!(dir = %db_directory.display(), "opening without the directory lock");
+            None
+        } else {
+            lock_dir(db_directory, opts.read_only)?
+        };
+
+        Ok(Self {
+            db_directory: Some(db_directory.to_owned()),
+            block_size,
+            is_new: !path_exists,
+            open_files: Arc::new(RwLock::new(HashMap::new())),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            clock: AtomicU64::new(0),
+            created_files: RwLock::new(HashSet::new()),
+            direct_io: opts.direct_io,
+            read_only: opts.read_only,
+            checksums: false,
+            compressed_files: RwLock::new(HashSet::new()),
+            durability: Durability::default(),
+            unsynced: Mutex::new(HashSet::new()),
+            last_sync: Mutex::new(Instant::now()),
+            io_stats: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(HashMap::new()),
+            _dir_lock: dir_lock,
+        })
+    }
+
+    /// Creates a FileManager that keeps every file in memory. Its contents are lost when it's dropped.
+    pub fn in_memory(block_size: usize) -> Self {
+        Self {
+            db_directory: None,
+            block_size,
+            is_new: true,
+            open_files: Arc::new(RwLock::new(HashMap::new())),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            clock: AtomicU64::new(0),
+            created_files: RwLock::new(HashSet::new()),
+            direct_io: false,
+            read_only: false,
+            checksums: false,
+            compressed_files: RwLock::new(HashSet::new()),
+            durability: Durability::default(),
+            unsynced: Mutex::new(HashSet::new()),
+            last_sync: Mutex::new(Instant::now()),
+            io_stats: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(HashMap::new()),
+            _dir_lock: None,
+        }
+    }
+
+    /// Caps the number of open file handles. Files closed to stay under the cap are
+    /// synced first and reopened transparently on their next use.
+    pub fn with_max_open_files(mut self, n: usize) -> Self {
+        self.max_open_files = n.max(1);
+        self
+    }
+
+    /// Stores a checksum in every block and verifies it on each read, failing with
+    /// [`StorageError::ChecksumMismatch`] instead of returning corrupted bytes.
+    ///
+    /// The checksum goes in the checksum field of the block's [`PageHeader`], so every block
+    /// written must start with a header, as log and data pages do. A database must always be
+    /// opened with the same setting.
+    pub fn with_checksums(mut self) -> Self {
+        self.checksums = true;
+        self
+    }
+
+    /// Sets when written blocks are synced to disk. See [`Durability`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Syncs `f` after a write if the durability policy asks for it, and otherwise remembers
+    /// that it still has to be synced. `batch` marks the writes of a buffer pool flush.
+    fn after_write(&self, filename: &str, f: &DataFile, batch: bool) -> Result<(), StorageError> {
+        let sync_now = match self.durability {
+            Durability::Always => true,
+            Durability::OnCommit => batch,
+            Durability::Every(_) | Durability::Never => false,
+        };
+        if sync_now {
+            self.sync_one(filename, f)
+        } else {
+            self.mark_unsynced(filename);
+            Ok(())
+        }
+    }
+
+    fn mark_unsynced(&self, filename: &str) {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        if !unsynced.contains(filename) {
+            unsynced.insert(filename.to_owned());
+        }
+    }
+
+    fn sync_one(&self, filename: &str, f: &DataFile) -> Result<(), StorageError> {
+        f.sync().map_err(|source| StorageError::Sync {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        self.record_io(filename, |s| s.fsyncs += 1);
+        self.unsynced.lock().unwrap().remove(filename);
+        Ok(())
+    }
+
+    /// With [`Durability::Every`], syncs everything once the interval has passed.
+    /// Must not be called while holding a file's lock.
+    fn sync_if_due(&self) -> Result<(), StorageError> {
+        let Durability::Every(interval) = self.durability else {
+            return Ok(());
+        };
+        {
+            let mut last = self.last_sync.lock().unwrap();
+            if last.elapsed() < interval {
+                return Ok(());
+            }
+            *last = Instant::now();
+        }
+        self.sync_all()
+    }
+
+    /// Snapshot of the I/O performed so far, in blocks and fsync calls.
+    pub fn stats(&self) -> FileManagerStats {
+        let per_file = self.io_stats.lock().unwrap().clone();
+        let mut stats = FileManagerStats::default();
+        for s in per_file.values() {
+            stats.reads += s.reads;
+            stats.writes += s.writes;
+            stats.fsyncs += s.fsyncs;
+        }
+        stats.per_file = per_file;
+        stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.io_stats.lock().unwrap().clear();
+    }
+
+    fn record_io(&self, filename: &str, update: impl FnOnce(&mut FileIoStats)) {
+        let mut stats = self.io_stats.lock().unwrap();
+        match stats.get_mut(filename) {
+            Some(s) => update(s),
+            None => update(stats.entry(filename.to_owned()).or_default()),
+        }
+    }
+
+    /// Stores `filename` lz4-compressed if it's created from now on. Files that already
+    /// exist keep their format. Has no effect on in-memory files.
+    ///
+    /// Compression suits cold, string-heavy tables: reads and writes pay for the (de)compression,
+    /// and space freed by rewritten blocks is only reclaimed when the file is deleted.
+    pub fn compress(&self, filename: &str) {
+        self.compressed_files
+            .write()
+            .unwrap()
+            .insert(filename.to_owned());
+    }
+
+    /// Lists the blocks of `filename` whose contents don't match their checksum.
+    /// Always empty if checksums aren't enabled.
+    pub fn scan_file(&self, filename: &str) -> Result<Vec<BlockId>, StorageError> {
+        let mut bad = Vec::new();
+        if !self.checksums {
+            return Ok(bad);
+        }
+        let mut raw = Page::new(self.block_size);
+        for i in 0..self.length(filename)? {
+            let block = BlockId::new(filename, i);
+            let n = self.read_raw(&block, &mut raw)?;
+            if !self.checksum_ok(&raw.byte_buf[..n]) {
+                bad.push(block);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// Byte offset of block `block_num` in its file.
+    fn offset(&self, block_num: u64) -> u64 {
+        block_num * self.block_size as u64
+    }
+
+    /// Whether a block read from disk matches its checksum. Blocks that were never written
+    /// (all zeros, or cut short by the end of the file) are accepted as is.
+    fn checksum_ok(&self, raw: &[u8]) -> bool {
+        if !self.checksums || raw.len() < self.block_size {
+            return true;
+        }
+        header::checksum_matches(raw) || raw.iter().all(|&b| b == 0)
+    }
+
+    /// Copies a page into its on-disk block, with its checksum in the header.
+    fn seal(&self, page: &[u8], raw: &mut [u8]) {
+        raw.copy_from_slice(page);
+        if self.checksums {
+            header::store_checksum(raw);
+        }
+    }
+
+    /// Verifies a block read from disk and copies its data into `page`.
+    fn unseal(&self, block: &BlockId, raw: &[u8], page: &mut [u8]) -> Result<(), StorageError> {
+        if !self.checksum_ok(raw) {
+            return Err(StorageError::ChecksumMismatch(block.clone()));
+        }
+        let n = raw.len().min(page.len());
+        page[..n].copy_from_slice(&raw[..n]);
+        Ok(())
+    }
+
+    /// Reads a whole block into `raw`. Returns the number of bytes read.
+    fn read_raw(&self, block: &BlockId, raw: &mut Page) -> Result<usize, StorageError> {
+        let f_ptr = self.get_file(block.filename())?;
+        let f = f_ptr.lock().unwrap();
+        let offset = self.offset(block.number());
+
+        let n = f
+            .read_at(&mut raw.byte_buf, offset)
+            .map_err(|source| StorageError::Read {
+                block: block.clone(),
+                source,
+            })?;
+        self.record_io(block.filename(), |s| s.reads += 1);
+        Ok(n)
+    }
+
+    fn get_file(&self, filename: &str) -> Result<Arc<Mutex<DataFile>>, StorageError> {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(f) = self.open_files.read().unwrap().get(filename) {
+            f.last_used.store(tick, Ordering::Relaxed);
+            return Ok(Arc::clone(&f.file));
+        }
+        let mut map = self.open_files.write().unwrap();
+        // another thread could have inserted it meanwhile
+        if let Some(f) = map.get(filename) {
+            f.last_used.store(tick, Ordering::Relaxed);
+            return Ok(Arc::clone(&f.file));
+        }
+
+        let table = match &self.db_directory {
+            Some(dir) => {
+                if map.len() >= self.max_open_files {
+                    self.close_lru(&mut map)?;
+                }
+                self.open_disk_file(dir, filename)?
+            }
+            None => {
+                self.created_files
+                    .write()
+                    .unwrap()
+                    .insert(filename.to_owned());
+                DataFile::Memory(Vec::new())
+            }
+        };
+
+        let f = Arc::new(Mutex::new(table));
+        let entry = OpenFile {
+            file: Arc::clone(&f),
+            last_used: AtomicU64::new(tick),
+        };
+        map.insert(filename.to_owned(), entry);
+
+        Ok(f)
+    }
+
+    /// Syncs and forgets the least recently used file. The handle is closed as soon as
+    /// nobody is using it anymore.
+    fn close_lru(&self, map: &mut HashMap<String, OpenFile>) -> Result<(), StorageError> {
+        let Some(filename) = map
+            .iter()
+            .min_by_key(|(_, f)| f.last_used.load(Ordering::Relaxed))
+            .map(|(name, _)| name.clone())
+        else {
+            return Ok(());
+        };
+        // sync_all only sees open files, so pending writes must be made durable now
+        self.sync_one(&filename, &map[&filename].file.lock().unwrap())?;
+        trace!(filename, "closing least recently used file");
+        map.remove(&filename);
+        Ok(())
+    }
+
+    /// Opens a file in the database directory, in the compressed format if it has a block
+    /// map or is new and marked with [`FileManager::compress`].
+    fn open_disk_file(&self, dir: &Path, filename: &str) -> Result<DataFile, StorageError> {
+        let path = dir.join(filename);
+        let map_path = dir.join(format!("{filename}{MAP_SUFFIX}"));
+        let compress = map_path.exists()
+            || (self.compressed_files.read().unwrap().contains(filename) && !path.exists());
+        if !compress {
+            return Ok(DataFile::Disk(self.open_or_create(
+                &path,
+                filename,
+                self.direct_io,
+            )?));
+        }
+
+        // frames aren't aligned, so compressed files never use direct I/O
+        let data = self.open_or_create(&path, filename, false)?;
+        let map = self.open_or_create(&map_path, filename, false)?;
+        let f = CompressedFile::new(data, map, self.block_size)
+            .map_err(|source| StorageError::Open { path, source })?;
+        Ok(DataFile::Compressed(f))
+    }
+
+    fn open_or_create(
+        &self,
+        path: &Path,
+        filename: &str,
+        direct_io: bool,
+    ) -> Result<File, StorageError> {
+        let to_err = |source| StorageError::Open {
+            path: path.to_owned(),
+            source,
+        };
+        if self.read_only {
+            return File::open(path).map_err(to_err);
+        }
+
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(true);
+        #[cfg(target_os = "linux")]
+        if direct_io {
+            std::os::unix::fs::OpenOptionsExt::custom_flags(&mut opts, libc::O_DIRECT);
+        }
+        // try creating first so we know whether the file is new without racing another process
+        match opts.clone().create_new(true).open(path) {
+            Ok(f) => {
+                self.created_files
+                    .write()
+                    .unwrap()
+                    .insert(filename.to_owned());
+                Ok(f)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => opts.open(path),
+            Err(e) => Err(e),
+        }
+        .map_err(to_err)
+    }
+
+    fn check_writable(&self, filename: &str) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly(filename.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Makes sure disk space is reserved for `count` blocks starting at `first`, growing
+    /// the reservation a whole extent at a time so files don't fragment block by block.
+    fn reserve(&self, f: &mut DataFile, first: &BlockId, count: usize) -> Result<(), StorageError> {
+        let needed = first.number() + count as u64;
+        let mut reserved = self.reserved.lock().unwrap();
+        let hwm = reserved.entry(first.file()).or_insert(0);
+        if needed <= *hwm {
+            return Ok(());
+        }
+
+        let target = needed.div_ceil(EXTENT_BLOCKS) * EXTENT_BLOCKS;
+        f.preallocate(target * self.block_size as u64)
+            .map_err(|source| StorageError::Write {
+                block: first.clone(),
+                source,
+            })?;
+        *hwm = target;
+        Ok(())
+    }
+
+    /// Returns true if `filename` didn't exist before this FileManager first opened it.
+    pub fn is_new_file(&self, filename: &str) -> Result<bool, StorageError> {
+        self.get_file(filename)?;
+        Ok(self.created_files.read().unwrap().contains(filename))
+    }
+
+    /// The database directory, or `None` if the files are kept in memory.
+    pub fn directory(&self) -> Option<&Path> {
+        self.db_directory.as_deref()
+    }
+}
+
+impl StorageBackend for FileManager {
+    fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+        if !self.checksums {
+            self.read_raw(block, p)?;
+            return Ok(());
+        }
+        let mut raw = Page::new(self.block_size);
+        let n = self.read_raw(block, &mut raw)?;
+        self.unseal(block, &raw.byte_buf[..n], &mut p.byte_buf)
+    }
+
+    fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+        self.check_writable(block.filename())?;
+        let sealed;
+        let raw = if self.checksums {
+            let mut raw = Page::new(self.block_size);
+            self.seal(&p.byte_buf, &mut raw.byte_buf);
+            sealed = raw;
+            &sealed
+        } else {
+            p
+        };
+
+        let f_ptr = self.get_file(block.filename())?;
+        {
+            let mut f = f_ptr.lock().unwrap();
+            let offset = self.offset(block.number());
+
+            f.write_all_at(&raw.byte_buf, offset)
+                .map_err(|source| StorageError::Write {
+                    block: block.clone(),
+                    source,
+                })?;
+            self.record_io(block.filename(), |s| s.writes += 1);
+            self.after_write(block.filename(), &f, false)?;
+        }
+        self.sync_if_due()
+    }
+
+    /// Reads each run of adjacent blocks with a single call.
+    fn read_blocks(&self, blocks: &[BlockId], pages: &mut [&mut Page]) -> Result<(), StorageError> {
+        for run in adjacent_runs(blocks) {
+            let first = &blocks[run[0]];
+            if run.len() == 1 {
+                self.read_block(first, pages[run[0]])?;
+                continue;
+            }
+
+            let mut buf = Page::new(run.len() * self.block_size);
+            let offset = self.offset(first.number());
+            let n = self
+                .get_file(first.filename())?
+                .lock()
+                .unwrap()
+                .read_at(&mut buf.byte_buf, offset)
+                .map_err(|source| StorageError::Read {
+                    block: first.clone(),
+                    source,
+                })?;
+            self.record_io(first.filename(), |s| s.reads += run.len() as u64);
+            for (j, &i) in run.iter().enumerate() {
+                let start = j * self.block_size;
+                let end = n.clamp(start, start + self.block_size);
+                self.unseal(
+                    &blocks[i],
+                    &buf.byte_buf[start..end],
+                    &mut pages[i].byte_buf,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes each run of adjacent blocks with a single call. Unless the durability policy
+    /// says otherwise, every file is synced once at the end.
+    fn write_blocks(&self, blocks: &[BlockId], pages: &[&Page]) -> Result<(), StorageError> {
+        if let Some(block) = blocks.first() {
+            self.check_writable(block.filename())?;
+        }
+        let mut touched: HashMap<&str, Arc<Mutex<DataFile>>> = HashMap::new();
+        for run in adjacent_runs(blocks) {
+            let first = &blocks[run[0]];
+            let mut buf = Page::new(run.len() * self.block_size);
+            for (chunk, &i) in buf.byte_buf.chunks_mut(self.block_size).zip(&run) {
+                self.seal(&pages[i].byte_buf, chunk);
+            }
+
+            let f_ptr = self.get_file(first.filename())?;
+            let offset = self.offset(first.number());
+            f_ptr
+                .lock()
+                .unwrap()
+                .write_all_at(&buf.byte_buf, offset)
+                .map_err(|source| StorageError::Write {
+                    block: first.clone(),
+                    source,
+                })?;
+            self.record_io(first.filename(), |s| s.writes += run.len() as u64);
+            touched.insert(first.filename(), f_ptr);
+        }
+
+        for (filename, f) in touched {
+            self.after_write(filename, &f.lock().unwrap(), true)?;
+        }
+        self.sync_if_due()
+    }
+
+    fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+        self.check_writable(filename)?;
+        let block = BlockId::new(filename, self.length(filename)?);
+        // a page rather than a Vec so that the buffer is aligned for direct I/O
+        let page = Page::new(self.block_size);
+
+        let f_ptr = self.get_file(filename)?;
+        let mut f = f_ptr.lock().unwrap();
+        let offset = self.offset(block.number());
+
+        self.reserve(&mut f, &block, 1)?;
+        f.write_all_at(page.contents(), offset)
+            .map_err(|source| StorageError::Write {
+                block: block.clone(),
+                source,
+            })?;
+        self.record_io(filename, |s| s.writes += 1);
+        self.mark_unsynced(filename);
+
+        Ok(block)
+    }
+
+    fn append_extent(&self, filename: &str, count: usize) -> Result<BlockId, StorageError> {
+        self.check_writable(filename)?;
+        let f_ptr = self.get_file(filename)?;
+        let mut f = f_ptr.lock().unwrap();
+        let len = f.len().map_err(|source| StorageError::Length {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        let first = BlockId::new(filename, len / self.block_size as u64);
+        if count == 0 {
+            return Ok(first);
+        }
+
+        self.reserve(&mut f, &first, count)?;
+        let end = self.offset(first.number() + count as u64);
+        f.set_len(end).map_err(|source| StorageError::Write {
+            block: first.clone(),
+            source,
+        })?;
+        self.mark_unsynced(filename);
+
+        Ok(first)
+    }
+
+    fn length(&self, filename: &str) -> Result<u64, StorageError> {
+        let f_ptr = match self.get_file(filename) {
+            // read-only storage never creates files, so a missing one is simply empty
+            Err(StorageError::Open { source, .. })
+                if self.read_only && source.kind() == io::ErrorKind::NotFound =>
+            {
+                return Ok(0);
+            }
+            res => res?,
+        };
+        let f = f_ptr.lock().unwrap();
+
+        let len = f.len().map_err(|source| StorageError::Length {
+            filename: filename.to_owned(),
+            source,
+        })?;
+        Ok(len / (self.block_size as u64))
+    }
+
+    fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+        self.check_writable(filename)?;
+        let f_ptr = self.get_file(filename)?;
+        let mut f = f_ptr.lock().unwrap();
+        let to_err = |source| StorageError::Truncate {
+            filename: filename.to_owned(),
+            source,
+        };
+
+        let new_len = self.offset(len);
+        if f.len().map_err(to_err)? > new_len {
+            f.set_len(new_len).map_err(to_err)?;
+            self.mark_unsynced(filename);
+            // shrinking also gives back the space reserved past the end
+            self.reserved.lock().unwrap().remove(&FileId::new(filename));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, filename: &str) -> Result<(), StorageError> {
+        self.check_writable(filename)?;
+        self.open_files.write().unwrap().remove(filename);
+        self.created_files.write().unwrap().remove(filename);
+        self.reserved.lock().unwrap().remove(&FileId::new(filename));
+        self.unsynced.lock().unwrap().remove(filename);
+
+        let Some(dir) = &self.db_directory else {
+            return Ok(());
+        };
+        for path in [
+            dir.join(filename),
+            dir.join(format!("{filename}{MAP_SUFFIX}")),
+        ] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(StorageError::Delete {
+                        filename: filename.to_owned(),
+                        source: e,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_file(&self, filename: &str) -> Result<(), StorageError> {
+        if !self.unsynced.lock().unwrap().contains(filename) {
+            return Ok(());
+        }
+        let f_ptr = self.get_file(filename)?;
+        let f = f_ptr.lock().unwrap();
+        self.sync_one(filename, &f)
+    }
+
+    /// Flushes the contents of every file with unsynced writes to disk.
+    fn sync_all(&self) -> Result<(), StorageError> {
+        let pending: Vec<String> = self.unsynced.lock().unwrap().iter().cloned().collect();
+        for filename in pending {
+            self.sync_file(&filename)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<FileManagerStats> {
+        Some(FileManager::stats(self))
+    }
+
+    fn reset_stats(&self) {
+        FileManager::reset_stats(self)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+/// Groups the indexes of `blocks` into runs of consecutive blocks of the same file,
+/// ordered by file name and block number.
+fn adjacent_runs(blocks: &[BlockId]) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..blocks.len()).collect();
+    order.sort_by_key(|&i| (blocks[i].filename(), blocks[i].number()));
+
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    for i in order {
+        let extends_run = runs.last().is_some_and(|run| {
+            let last = &blocks[run[run.len() - 1]];
+            last.filename() == blocks[i].filename() && last.number() + 1 == blocks[i].number()
+        });
+        match runs.last_mut() {
+            Some(run) if extends_run => run.push(i),
+            _ => runs.push(vec![i]),
+        }
+    }
+    runs
+}
+
+/// Counts the blocks read and written through the wrapped backend.
+pub(crate) struct CountingStorage {
+    inner: Arc<dyn StorageBackend>,
+    stats: Arc<IoStats>,
+}
+
+#[derive(Default)]
+pub(crate) struct IoStats {
+    pub blocks_read: AtomicU64,
+    pub blocks_written: AtomicU64,
+}
+
+impl CountingStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, stats: Arc<IoStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl StorageBackend for CountingStorage {
+    fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+        self.inner.read_block(block, p)?;
+        self.stats.blocks_read.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+        self.inner.write_block(block, p)?;
+        self.stats.blocks_written.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn read_blocks(&self, blocks: &[BlockId], pages: &mut [&mut Page]) -> Result<(), StorageError> {
+        self.inner.read_blocks(blocks, pages)?;
+        self.stats
+            .blocks_read
+            .fetch_add(blocks.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn write_blocks(&self, blocks: &[BlockId], pages: &[&Page]) -> Result<(), StorageError> {
+        self.inner.write_blocks(blocks, pages)?;
+        self.stats
+            .blocks_written
+            .fetch_add(blocks.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+        self.inner.append(filename)
+    }
+
+    fn append_extent(&self, filename: &str, count: usize) -> Result<BlockId, StorageError> {
+        self.inner.append_extent(filename, count)
+    }
+
+    fn length(&self, filename: &str) -> Result<u64, StorageError> {
+        self.inner.length(filename)
+    }
+
+    fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+        self.inner.truncate(filename, len)
+    }
+
+    fn delete(&self, filename: &str) -> Result<(), StorageError> {
+        self.inner.delete(filename)
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn sync_file(&self, filename: &str) -> Result<(), StorageError> {
+        self.inner.sync_file(filename)
+    }
+
+    fn sync_all(&self) -> Result<(), StorageError> {
+        self.inner.sync_all()
+    }
+
+    fn stats(&self) -> Option<FileManagerStats> {
+        self.inner.stats()
+    }
+
+    fn reset_stats(&self) {
+        self.inner.reset_stats()
+    }
+}
+
+/// Locks `dir/LOCK`: shared for readers, exclusive otherwise.
+/// The lock is advisory and released by the OS when the process exits.
+fn lock_dir(dir: &Path, shared: bool) -> Result<Option<File>, StorageError> {
+    let path = dir.join(LOCK_FILE);
+    let to_err = |source| StorageError::Open {
+        path: path.clone(),
+        source,
+    };
+    let file = if shared {
+        match File::open(&path) {
+            Ok(f) => f,
+            // a database that was never opened for writing has no lock file, and a
+            // read-only open shouldn't create one
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(to_err(e)),
+        }
+    } else {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(to_err)?
+    };
+
+    let res = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match res {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(StorageError::Locked(dir.to_owned())),
+        Err(TryLockError::Error(e)) => Err(to_err(e)),
+    }
+}
+
+/// Positioned reads and writes: `pread`/`pwrite` on Unix, `seek_read`/`seek_write` on Windows.
+pub(crate) mod pio {
+    use std::{fs::File, io};
+
+    #[cfg(unix)]
+    fn read_once(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(f, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_once(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(f, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_once(f: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(f, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_once(f: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(f, buf, offset)
+    }
+
+    /// Reads until `buf` is full or the end of the file is reached.
+    /// Returns the number of bytes read.
+    pub fn read_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+        while !buf.is_empty() {
+            match read_once(f, buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                    total += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(total)
+    }
+
+    pub fn write_all_at(f: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match write_once(f, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    fn setup(prefix: &str, block_size: usize) -> FileManager {
+        let dirname = format!(
+            "{}_{}",
+            prefix,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        let dir_path = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname);
+        FileManager::new(&dir_path, block_size).unwrap()
+    }
+
+    #[test]
+    fn test_file_manager() {
+        let fm = setup("filetest", 400);
+        let fname = "testfile";
+
+        let block = BlockId::new(fname, 2);
+        let mut p1 = Page::new(fm.block_size());
+
+        let pos1 = 88;
+        let test_str = "abcdefg";
+        p1.set_string(pos1, test_str);
+
+        let size = Page::str_size(test_str);
+        let pos2 = pos1 + size;
+        let test_int = 345;
+        p1.set_int(pos2, test_int);
+
+        fm.write_block(&block, &p1).unwrap();
+
+        let mut p2 = Page::new(fm.block_size());
+        fm.read_block(&block, &mut p2).unwrap();
+
+        assert_eq!(p2.get_int(pos2), test_int);
+        assert_eq!(p2.get_string(pos1), test_str);
+
+        assert_eq!(fm.length(fname).unwrap(), 3); // page was added at start offset of block 2; (0, 1, 2) => 3 blocks so far
+
+        let appended_block = fm.append(fname).unwrap();
+        assert_eq!(appended_block.number(), 3);
+        assert_eq!(fm.length(fname).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_file_manager_errors() {
+        let fm = setup("fileerrtest", 400);
+        let file_path = fm.directory().unwrap().join("notadir");
+        fs::write(&file_path, b"").unwrap();
+
+        assert!(matches!(
+            FileManager::new(&file_path, 400),
+            Err(StorageError::NotADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let fm = setup("filereopentest", 400);
+        let fname = "testfile";
+        let block = BlockId::new(fname, 1);
+
+        let mut p1 = Page::new(fm.block_size());
+        p1.set_int(12, 99);
+        fm.write_block(&block, &p1).unwrap();
+        assert!(fm.is_new_file(fname).unwrap());
+
+        let dir = fm.directory().unwrap().to_owned();
+        assert!(matches!(
+            FileManager::new(&dir, 400),
+            Err(StorageError::Locked(_))
+        ));
+        assert!(matches!(
+            FileManager::read_only(&dir, 400),
+            Err(StorageError::Locked(_))
+        ));
+        drop(fm);
+
+        let fm = FileManager::new(&dir, 400).unwrap();
+        assert!(!fm.is_new);
+        assert!(!fm.is_new_file(fname).unwrap());
+        assert!(fm.is_new_file("otherfile").unwrap());
+
+        let mut p2 = Page::new(fm.block_size());
+        fm.read_block(&block, &mut p2).unwrap();
+        assert_eq!(p2.get_int(12), 99);
+        assert_eq!(fm.length(fname).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let dir_path = setup("filedirecttest", 400).directory().unwrap().to_owned();
+        assert!(matches!(
+            FileManager::with_direct_io(&dir_path, 1000),
+            Err(StorageError::UnalignedBlockSize(1000))
+        ));
+
+        let p = Page::new(2 * DIRECT_IO_ALIGNMENT);
+        assert_eq!(p.contents().as_ptr() as usize % DIRECT_IO_ALIGNMENT, 0);
+
+        if cfg!(target_os = "linux") {
+            let fm = FileManager::with_direct_io(&dir_path, DIRECT_IO_ALIGNMENT).unwrap();
+            let block = fm.append("testfile").unwrap();
+            let mut p1 = Page::new(fm.block_size());
+            p1.set_int(100, 7);
+            fm.write_block(&block, &p1).unwrap();
+
+            let mut p2 = Page::new(fm.block_size());
+            fm.read_block(&block, &mut p2).unwrap();
+            assert_eq!(p2.get_int(100), 7);
+        }
+    }
+
+    #[test]
+    fn test_truncate_and_delete() {
+        let fm = setup("filedeletetest", 400);
+        fm.append_extent("testfile", 5).unwrap();
+
+        fm.truncate("testfile", 2).unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 2);
+        // growing isn't truncate's job
+        fm.truncate("testfile", 4).unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 2);
+
+        let path = fm.directory().unwrap().join("testfile");
+        assert!(path.exists());
+        fm.delete("testfile").unwrap();
+        assert!(!path.exists());
+        fm.delete("testfile").unwrap();
+
+        // the next access starts a fresh file
+        assert_eq!(fm.length("testfile").unwrap(), 0);
+        assert!(fm.is_new_file("testfile").unwrap());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let fm = setup("filereadonlytest", 400);
+        let mut p = Page::new(fm.block_size());
+        p.set_int(0, 42);
+        fm.write_block(&BlockId::new("testfile", 0), &p).unwrap();
+        let dir = fm.directory().unwrap().to_owned();
+        drop(fm);
+
+        let fm = FileManager::read_only(&dir, 400).unwrap();
+        let mut p = Page::new(fm.block_size());
+        fm.read_block(&BlockId::new("testfile", 0), &mut p).unwrap();
+        assert_eq!(p.get_int(0), 42);
+        assert!(matches!(
+            fm.write_block(&BlockId::new("testfile", 0), &p),
+            Err(StorageError::ReadOnly(_))
+        ));
+        assert!(matches!(
+            fm.append("testfile"),
+            Err(StorageError::ReadOnly(_))
+        ));
+
+        assert_eq!(fm.length("missing").unwrap(), 0);
+        assert!(!dir.join("missing").exists());
+        assert!(matches!(
+            FileManager::read_only(&dir.join("missing"), 400),
+            Err(StorageError::NotADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_open_files() {
+        let fm = setup("filemaxopentest", 400).with_max_open_files(2);
+        let mut p = Page::new(fm.block_size());
+        for (i, fname) in ["file0", "file1", "file2"].iter().enumerate() {
+            p.set_int(0, i as i32);
+            fm.write_block(&BlockId::new(fname, 0), &p).unwrap();
+        }
+        assert_eq!(fm.open_files.read().unwrap().len(), 2);
+        assert!(!fm.open_files.read().unwrap().contains_key("file0"));
+
+        // file0 is reopened, closing file1, which is now the least recently used
+        fm.read_block(&BlockId::new("file0", 0), &mut p).unwrap();
+        assert_eq!(p.get_int(0), 0);
+        assert!(!fm.open_files.read().unwrap().contains_key("file1"));
+        fm.read_block(&BlockId::new("file1", 0), &mut p).unwrap();
+        assert_eq!(p.get_int(0), 1);
+    }
+
+    #[test]
+    fn test_durability() {
+        let fsyncs = |fm: &FileManager| fm.stats().per_file.get("testfile").map_or(0, |s| s.fsyncs);
+        let block = BlockId::new("testfile", 0);
+        let p = Page::new(400);
+
+        let fm = setup("filedurabilityalwaystest", 400);
+        fm.write_block(&block, &p).unwrap();
+        assert_eq!(fsyncs(&fm), 1);
+
+        let fm = setup("filedurabilitycommittest", 400).with_durability(Durability::OnCommit);
+        fm.write_block(&block, &p).unwrap();
+        assert_eq!(fsyncs(&fm), 0);
+        fm.write_blocks(std::slice::from_ref(&block), &[&p])
+            .unwrap();
+        assert_eq!(fsyncs(&fm), 1);
+        // nothing left to sync
+        fm.sync_file("testfile").unwrap();
+        assert_eq!(fsyncs(&fm), 1);
+
+        let fm = setup("filedurabilitynevertest", 400).with_durability(Durability::Never);
+        fm.write_block(&block, &p).unwrap();
+        fm.write_blocks(std::slice::from_ref(&block), &[&p])
+            .unwrap();
+        assert_eq!(fsyncs(&fm), 0);
+        fm.sync_all().unwrap();
+        assert_eq!(fsyncs(&fm), 1);
+
+        let fm = setup("filedurabilityeverytest", 400)
+            .with_durability(Durability::Every(Duration::ZERO));
+        fm.write_block(&block, &p).unwrap();
+        assert_eq!(fsyncs(&fm), 1);
+
+        assert_eq!("on-commit".parse(), Ok(Durability::OnCommit));
+        assert_eq!(
+            "every-250ms".parse(),
+            Ok(Durability::Every(Duration::from_millis(250)))
+        );
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn test_checksums() {
+        let fm = setup("filechecksumtest", 400).with_checksums();
+        assert_eq!(fm.block_size(), 400);
+
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        let mut p = Page::new(fm.block_size());
+        p.set_header(&PageHeader::new(PageType::Data, PageHeader::SIZE));
+        p.set_string(fm.block_size() - 20, "tail");
+        fm.write_block(&blocks[0], &p).unwrap();
+        fm.write_blocks(&blocks[1..], &[&p, &p]).unwrap();
+        fm.append("testfile").unwrap();
+
+        let mut pages: Vec<_> = (0..4).map(|_| Page::new(fm.block_size())).collect();
+        let mut refs: Vec<_> = pages.iter_mut().collect();
+        let all: Vec<_> = (0..4).map(|i| BlockId::new("testfile", i)).collect();
+        fm.read_blocks(&all, &mut refs).unwrap();
+        assert_eq!(pages[2].get_string(fm.block_size() - 20), "tail");
+        // the checksum is the one in the page header
+        assert!(pages[2].verify_checksum().is_ok());
+        assert!(fm.scan_file("testfile").unwrap().is_empty());
+
+        // flip a byte of block 1 behind the FileManager's back
+        let path = fm.directory().unwrap().join("testfile");
+        let f = OpenOptions::new().write(true).open(path).unwrap();
+        pio::write_all_at(&f, &[0xff], 400 + 10).unwrap();
+
+        assert!(matches!(
+            fm.read_block(&blocks[1], &mut p),
+            Err(StorageError::ChecksumMismatch(b)) if b == blocks[1]
+        ));
+        let mut refs: Vec<_> = pages.iter_mut().collect();
+        assert!(fm.read_blocks(&all, &mut refs).is_err());
+        assert_eq!(fm.scan_file("testfile").unwrap(), [blocks[1].clone()]);
+    }
+
+    #[test]
+    fn test_compression() {
+        let fm = setup("filecompresstest", 400);
+        fm.compress("cold");
+        let dir = fm.directory().unwrap().to_owned();
+
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("cold", i)).collect();
+        let mut p = Page::new(fm.block_size());
+        for (i, block) in blocks.iter().enumerate() {
+            p.set_string(0, &"ab".repeat(50 + i));
+            fm.write_block(block, &p).unwrap();
+        }
+        assert_eq!(fm.length("cold").unwrap(), 3);
+        assert!(fs::metadata(dir.join("cold")).unwrap().len() < 400);
+
+        // a block that doesn't compress as well no longer fits its frame
+        let noisy: String = (0..150)
+            .map(|i| (b'!' + (i * 37 % 90) as u8) as char)
+            .collect();
+        p.set_string(0, &noisy);
+        fm.write_block(&blocks[1], &p).unwrap();
+        assert_eq!(fm.append("cold").unwrap().number(), 3);
+
+        let mut pages: Vec<_> = (0..4).map(|_| Page::new(fm.block_size())).collect();
+        let mut refs: Vec<_> = pages.iter_mut().collect();
+        let all: Vec<_> = (0..4).map(|i| BlockId::new("cold", i)).collect();
+        fm.read_blocks(&all, &mut refs).unwrap();
+        assert_eq!(pages[0].get_string(0), "ab".repeat(50));
+        assert_eq!(pages[1].get_string(0), noisy);
+        assert_eq!(pages[2].get_string(0), "ab".repeat(52));
+        assert_eq!(pages[3].get_int(0), 0);
+
+        fm.truncate("cold", 2).unwrap();
+        drop(fm);
+
+        // the block map decides the format, whether or not the file is marked
+        let fm = FileManager::new(&dir, 400).unwrap();
+        assert_eq!(fm.length("cold").unwrap(), 2);
+        fm.read_block(&blocks[1], &mut p).unwrap();
+        assert_eq!(p.get_string(0), noisy);
+
+        fm.delete("cold").unwrap();
+        assert!(!dir.join("cold").exists());
+        assert!(!dir.join(format!("cold{MAP_SUFFIX}")).exists());
+    }
+
+    #[test]
+    fn test_multi_block_io() {
+        let fm = setup("filemultitest", 400);
+        let blocks: Vec<_> = [3, 1, 2, 7]
+            .into_iter()
+            .map(|n| BlockId::new("testfile", n))
+            .chain([BlockId::new("otherfile", 0)])
+            .collect();
+        let pages: Vec<_> = (0..blocks.len())
+            .map(|i| {
+                let mut p = Page::new(fm.block_size());
+                p.set_int(4, i as i32);
+                p
+            })
+            .collect();
+        let refs: Vec<_> = pages.iter().collect();
+        fm.write_blocks(&blocks, &refs).unwrap();
+
+        assert_eq!(
+            adjacent_runs(&blocks),
+            vec![vec![4], vec![1, 2, 0], vec![3]]
+        );
+        assert_eq!(fm.length("testfile").unwrap(), 8);
+
+        let mut read: Vec<_> = (0..blocks.len())
+            .map(|_| Page::new(fm.block_size()))
+            .collect();
+        let mut refs: Vec<_> = read.iter_mut().collect();
+        fm.read_blocks(&blocks, &mut refs).unwrap();
+        let values: Vec<_> = read.iter().map(|p| p.get_int(4)).collect();
+        assert_eq!(values, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_file_id() {
+        let id = FileId::new("fileidtest");
+        assert_eq!(FileId::new("fileidtest"), id);
+        assert_ne!(FileId::new("fileidtest2"), id);
+        assert_eq!(id.name(), "fileidtest");
+
+        let block = BlockId::with_file(id, 3);
+        assert_eq!(block, BlockId::new("fileidtest", 3));
+        assert_eq!(block.filename(), "fileidtest");
+        assert_eq!(format!("{:?}", block), "BlockId(\"fileidtest\", 3)");
+    }
+
+    #[test]
+    fn test_page_bounds() {
+        let mut p = Page::new(16);
+        assert!(p.try_set_int(12, 7).is_ok());
+        assert_eq!(p.try_get_int(12).unwrap(), 7);
+        assert!(matches!(
+            p.try_get_int(13),
+            Err(PageError::OutOfBounds {
+                offset: 13,
+                len: 4,
+                size: 16
+            })
+        ));
+        assert!(p.try_get_long(usize::MAX).is_err());
+
+        // a corrupt length prefix
+        p.set_int(0, 100);
+        assert!(p.try_get_bytes(0).is_err());
+        p.set_int(0, -1);
+        assert!(p.try_get_string(0).is_err());
+
+        // invalid UTF-8 is replaced by the lossy accessors and rejected by the strict ones
+        p.set_bytes(0, &[b'a', 0xff]);
+        assert_eq!(p.get_string(0), "a\u{fffd}");
+        assert!(p.get_str(0).is_err());
+        assert!(matches!(
+            p.try_get_str(0),
+            Err(PageError::InvalidUtf8 { offset: 0, .. })
+        ));
+        p.set_string(0, "ok");
+        assert_eq!(p.get_str(0).unwrap(), "ok");
+
+        // a failed write leaves the page untouched
+        let before = p.contents().to_vec();
+        assert!(p.try_set_bytes(8, &[1; 8]).is_err());
+        assert_eq!(p.contents(), before);
+    }
+
+    #[test]
+    fn test_fixed_string() {
+        let mut p = Page::new(32);
+        p.set_int(10, -1);
+        p.set_fixed_string(2, "abc", 8);
+        assert_eq!(p.get_fixed_string(2, 8), "abc");
+        assert_eq!(&p.contents()[2..10], b"abc     ");
+        // the neighbouring value is untouched
+        assert_eq!(p.get_int(10), -1);
+
+        p.set_fixed_string(2, "willow-db", 4);
+        assert_eq!(p.get_fixed_string(2, 4), "will");
+        // truncation doesn't split a character
+        p.set_fixed_string(2, "añb", 2);
+        assert_eq!(p.get_fixed_string(2, 2), "a");
+
+        assert!(p.try_set_fixed_string(30, "abc", 4).is_err());
+        p.set_raw(0, &[0xff]);
+        assert!(p.try_get_fixed_string(0, 2).is_err());
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut p = Page::new(64);
+        for n in [
+            0,
+            1,
+            -1,
+            63,
+            -64,
+            64,
+            300,
+            i32::MIN as i64,
+            i64::MAX,
+            i64::MIN,
+        ] {
+            p.set_varint(3, n);
+            assert_eq!(p.get_varint(3), n);
+            let size = Page::varint_size(n);
+            assert_eq!(
+                p.contents()[3 + size - 1] & 0x80,
+                0,
+                "{n} ends after {size} bytes"
+            );
+        }
+        assert_eq!(Page::varint_size(-64), 1);
+        assert_eq!(Page::varint_size(64), 2);
+        assert_eq!(Page::varint_size(i64::MIN), 10);
+
+        p.set_varstr(0, "willow");
+        assert_eq!(p.get_varstr(0), "willow");
+        assert_eq!(Page::varstr_size("willow"), 7);
+        assert!(p.try_set_varstr(60, "willow").is_err());
+
+        // an unterminated or overlong encoding is rejected
+        p.set_raw(0, &[0xff; 11]);
+        assert!(matches!(
+            p.try_get_varint(0),
+            Err(PageError::InvalidVarint { offset: 0 })
+        ));
+        p.set_raw(0, &[0x80, 0x00]);
+        assert!(p.try_get_varint(0).is_err());
+        p.set_raw(63, &[0x80]);
+        assert!(p.try_get_varint(63).is_err());
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let fm = FileManager::in_memory(400);
+        let block = BlockId::new("testfile", 2);
+
+        let mut p1 = Page::new(fm.block_size());
+        p1.set_string(20, "abc");
+        fm.write_block(&block, &p1).unwrap();
+        assert_eq!(fm.length("testfile").unwrap(), 3);
+        assert!(fm.directory().is_none());
+
+        let mut p2 = Page::new(fm.block_size());
+        fm.read_block(&block, &mut p2).unwrap();
+        assert_eq!(p2.get_string(20), "abc");
+
+        assert_eq!(fm.append("testfile").unwrap().number(), 3);
+        assert_eq!(fm.length("testfile").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_append_extent() {
+        let fm = setup("fileextenttest", 400);
+
+        assert_eq!(fm.append_extent("testfile", 10).unwrap().number(), 0);
+        assert_eq!(fm.length("testfile").unwrap(), 10);
+        assert_eq!(
+            fm.reserved.lock().unwrap()[&FileId::new("testfile")],
+            EXTENT_BLOCKS
+        );
+
+        // appends inside the reserved extent don't grow the reservation
+        assert_eq!(fm.append("testfile").unwrap().number(), 10);
+        assert_eq!(fm.length("testfile").unwrap(), 11);
+        assert_eq!(
+            fm.reserved.lock().unwrap()[&FileId::new("testfile")],
+            EXTENT_BLOCKS
+        );
+
+        let first = fm
+            .append_extent("testfile", EXTENT_BLOCKS as usize)
+            .unwrap();
+        assert_eq!(first.number(), 11);
+        assert_eq!(
+            fm.reserved.lock().unwrap()[&FileId::new("testfile")],
+            2 * EXTENT_BLOCKS
+        );
+
+        let mut p = Page::new(fm.block_size());
+        p.set_int(0, 7);
+        fm.write_block(&BlockId::new("testfile", 70), &p).unwrap();
+        fm.read_block(&BlockId::new("testfile", 74), &mut p)
+            .unwrap();
+        assert_eq!(p.get_int(0), 0);
+        assert_eq!(fm.append_extent("testfile", 0).unwrap().number(), 75);
+    }
+}
@@ -0,0 +1,755 @@
+use std::fmt;
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, SeqAccess, VariantAccess, Visitor},
+    ser::{self, Serialize},
+    Deserialize,
+};
+use thiserror::Error;
+
+use super::{Page, PageError};
+use crate::constants::SIZE_OF_INT;
+
+#[derive(Debug, Error)]
+pub enum SerdeError {
+    #[error(transparent)]
+    Page(#[from] PageError),
+    #[error("sequences and maps must know their length up front")]
+    UnknownLength,
+    #[error("the page format isn't self-describing; {0} needs a type hint")]
+    NotSelfDescribing(&'static str),
+    #[error("invalid {what} tag {tag} at offset {offset}")]
+    InvalidTag {
+        what: &'static str,
+        tag: u32,
+        offset: usize,
+    },
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+/// Serializes `value` into `page` starting at `offset`.
+/// Returns the number of bytes written.
+///
+/// A value that doesn't fit fails with [`PageError::OutOfBounds`] and may leave the fields
+/// before the failing one written.
+pub fn to_page<T: Serialize + ?Sized>(
+    page: &mut Page,
+    offset: usize,
+    value: &T,
+) -> Result<usize, SerdeError> {
+    let mut ser = PageSerializer::new(page, offset);
+    value.serialize(&mut ser)?;
+    Ok(ser.offset() - offset)
+}
+
+/// Deserializes a value written by [`to_page`] at `offset`.
+pub fn from_page<'de, T: Deserialize<'de>>(
+    page: &'de Page,
+    offset: usize,
+) -> Result<T, SerdeError> {
+    T::deserialize(&mut PageDeserializer::new(page, offset))
+}
+
+/// A [`serde::Serializer`] that writes a value into a [`Page`].
+///
+/// Fields are written back to back in declaration order with no padding:
+/// - integers, floats and `char`s as little-endian fixed-width values, bools as one byte;
+/// - strings and byte arrays in the length-prefixed format of [`Page::set_bytes`];
+/// - options as a one-byte tag followed by the value;
+/// - sequences and maps as a 4-byte length followed by their elements;
+/// - enums as a 4-byte variant index followed by the variant's fields.
+///
+/// The format doesn't describe itself, so values must be read back as the same type.
+pub struct PageSerializer<'a> {
+    page: &'a mut Page,
+    offset: usize,
+}
+
+impl<'a> PageSerializer<'a> {
+    pub fn new(page: &'a mut Page, offset: usize) -> Self {
+        Self { page, offset }
+    }
+
+    /// Offset just past the last byte written.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), SerdeError> {
+        self.page.try_set_raw(self.offset, bytes)?;
+        self.offset += bytes.len();
+        Ok(())
+    }
+
+    fn put_len(&mut self, len: Option<usize>) -> Result<(), SerdeError> {
+        let len = len.ok_or(SerdeError::UnknownLength)?;
+        self.put(&(len as u32).to_le_bytes())
+    }
+}
+
+impl<'a> ser::Serializer for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerdeError> {
+        self.put(&[v as u8])
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerdeError> {
+        self.put(&[v])
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerdeError> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerdeError> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerdeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerdeError> {
+        self.page.try_set_bytes(self.offset, v)?;
+        self.offset += SIZE_OF_INT + v.len();
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), SerdeError> {
+        self.put(&[0])
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerdeError> {
+        self.put(&[1])?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), SerdeError> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, SerdeError> {
+        self.put_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerdeError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, SerdeError> {
+        self.put_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerdeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerdeError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a> ser::SerializeSeq for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTuple for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStruct for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for &mut PageSerializer<'a> {
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+}
+
+/// A [`serde::Deserializer`] that reads a value written by [`PageSerializer`].
+///
+/// Strings and byte arrays are borrowed from the page. Since the format isn't
+/// self-describing, `deserialize_any` (and so untagged enums and `#[serde(flatten)]`)
+/// isn't supported.
+pub struct PageDeserializer<'de> {
+    page: &'de Page,
+    offset: usize,
+}
+
+impl<'de> PageDeserializer<'de> {
+    pub fn new(page: &'de Page, offset: usize) -> Self {
+        Self { page, offset }
+    }
+
+    /// Offset just past the last byte read.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], SerdeError> {
+        let bytes = self.page.try_get_raw(self.offset, N)?;
+        self.offset += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn take_u32(&mut self) -> Result<u32, SerdeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn take_bytes(&mut self) -> Result<&'de [u8], SerdeError> {
+        let bytes = self.page.try_get_bytes(self.offset)?;
+        self.offset += SIZE_OF_INT + bytes.len();
+        Ok(bytes)
+    }
+
+    fn take_tag(&mut self, what: &'static str) -> Result<bool, SerdeError> {
+        let offset = self.offset;
+        match self.take::<1>()?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(SerdeError::InvalidTag {
+                what,
+                tag: tag.into(),
+                offset,
+            }),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut PageDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SerdeError> {
+        Err(SerdeError::NotSelfDescribing("deserialize_any"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_bool(self.take_tag("bool")?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i8(i8::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i16(i16::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i32(i32::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_i64(i64::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u8(self.take::<1>()?[0])
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u16(u16::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u64(u64::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f32(f32::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_f64(f64::from_le_bytes(self.take()?))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let offset = self.offset;
+        let tag = self.take_u32()?;
+        let c = char::from_u32(tag).ok_or(SerdeError::InvalidTag {
+            what: "char",
+            tag,
+            offset,
+        })?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let s = self.page.try_get_str(self.offset)?;
+        self.offset += Page::str_size(s);
+        visitor.visit_borrowed_str(s)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_borrowed_bytes(self.take_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        if self.take_tag("option")? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let len = self.take_u32()? as usize;
+        visitor.visit_seq(Elements {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(Elements {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        let len = self.take_u32()? as usize;
+        visitor.visit_map(Elements {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SerdeError> {
+        Err(SerdeError::NotSelfDescribing("deserialize_ignored_any"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Elements of a sequence, tuple, struct or map with a known count.
+struct Elements<'a, 'de> {
+    de: &'a mut PageDeserializer<'de>,
+    left: usize,
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, SerdeError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, SerdeError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, SerdeError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut PageDeserializer<'de> {
+    type Error = SerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), SerdeError> {
+        let index = self.take_u32()?;
+        let value = seed.deserialize(IntoDeserializer::<SerdeError>::into_deserializer(index))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for &mut PageDeserializer<'de> {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: u16, h: u16 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item<'a> {
+        id: u64,
+        name: &'a str,
+        tags: Vec<String>,
+        parent: Option<i32>,
+        active: bool,
+        initial: char,
+        shapes: (Shape, Shape, Shape),
+        counts: BTreeMap<String, i8>,
+    }
+
+    #[test]
+    fn test_page_serde() {
+        let item = Item {
+            id: 1 << 40,
+            name: "willow",
+            tags: vec!["a".into(), "bc".into()],
+            parent: Some(-3),
+            active: true,
+            initial: 'w',
+            shapes: (Shape::Point, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }),
+            counts: [("x".to_string(), -1), ("y".to_string(), 2)].into(),
+        };
+
+        let mut p = Page::new(400);
+        let len = to_page(&mut p, 10, &item).unwrap();
+        let read: Item = from_page(&p, 10).unwrap();
+        assert_eq!(read, item);
+
+        // the next value starts right after the first one
+        to_page(&mut p, 10 + len, &None::<u8>).unwrap();
+        assert_eq!(from_page::<Option<u8>>(&p, 10 + len).unwrap(), None);
+
+        assert!(matches!(
+            to_page(&mut p, 390, &item),
+            Err(SerdeError::Page(PageError::OutOfBounds { .. }))
+        ));
+
+        // a corrupt option tag
+        p.set_raw(0, &[7]);
+        assert!(matches!(
+            from_page::<Option<u8>>(&p, 0),
+            Err(SerdeError::InvalidTag {
+                what: "option",
+                tag: 7,
+                offset: 0
+            })
+        ));
+    }
+}
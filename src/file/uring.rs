@@ -0,0 +1,232 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use super::{BlockId, DataFile, FileManager, Page, StorageBackend, StorageError};
+
+/// Submission queue size. Larger batches are split into chunks of this many entries.
+const QUEUE_DEPTH: u32 = 64;
+
+/// A [`FileManager`] that submits block reads and writes through io_uring.
+///
+/// [`StorageBackend::read_blocks`] keeps the whole batch in flight at once instead of
+/// issuing one `pread` per block. Opening, appending and length checks still go through
+/// the wrapped FileManager.
+pub struct UringFileManager {
+    fm: FileManager,
+    ring: Mutex<IoUring>,
+}
+
+impl UringFileManager {
+    pub fn new(db_directory: &Path, block_size: usize) -> Result<Self, StorageError> {
+        let ring = IoUring::new(QUEUE_DEPTH).map_err(StorageError::RingSetup)?;
+        Ok(Self {
+            fm: FileManager::new(db_directory, block_size)?,
+            ring: Mutex::new(ring),
+        })
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.fm.is_new
+    }
+
+    /// Returns the open file (which must be kept alive while the fd is in use) and its fd.
+    fn fd(&self, filename: &str) -> Result<(Arc<Mutex<DataFile>>, RawFd), StorageError> {
+        let f = self.fm.get_file(filename)?;
+        let fd = match &*f.lock().unwrap() {
+            DataFile::Disk(file) => file.as_raw_fd(),
+            DataFile::Memory(_) => unreachable!("UringFileManager always has a directory"),
+            DataFile::Compressed(_) => {
+                return Err(StorageError::Open {
+                    path: filename.into(),
+                    source: io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "compressed files can't be accessed through io_uring",
+                    ),
+                })
+            }
+        };
+        Ok((f, fd))
+    }
+
+    /// Submits `entries` and waits for all of them to complete.
+    /// Returns the result of each entry in submission order.
+    ///
+    /// # Safety
+    ///
+    /// Every buffer and fd referenced by `entries` must stay valid until this returns.
+    unsafe fn run(&self, entries: Vec<squeue::Entry>) -> io::Result<Vec<i32>> {
+        let mut ring = self.ring.lock().unwrap();
+        let mut results = vec![0; entries.len()];
+
+        for (chunk_idx, chunk) in entries.chunks(QUEUE_DEPTH as usize).enumerate() {
+            for (i, entry) in chunk.iter().enumerate() {
+                let idx = chunk_idx * QUEUE_DEPTH as usize + i;
+                let entry = entry.clone().user_data(idx as u64);
+                ring.submission()
+                    .push(&entry)
+                    .expect("a chunk fits in the submission queue");
+            }
+
+            let mut completed = 0;
+            while completed < chunk.len() {
+                match ring.submit_and_wait(chunk.len() - completed) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+                for cqe in ring.completion() {
+                    results[cqe.user_data() as usize] = cqe.result();
+                    completed += 1;
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+fn check(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+impl StorageBackend for UringFileManager {
+    fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+        self.read_blocks(std::slice::from_ref(block), &mut [p])
+    }
+
+    fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+        let (_file, fd) = self.fd(block.filename())?;
+        let offset = self.fm.offset(block.number());
+        let buf = p.contents();
+        let entries = vec![
+            opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build()
+                .flags(squeue::Flags::IO_LINK),
+            opcode::Fsync::new(types::Fd(fd)).build(),
+        ];
+
+        let to_err = |source| StorageError::Write {
+            block: block.clone(),
+            source,
+        };
+        // SAFETY: `p` and `_file` outlive the call
+        let results = unsafe { self.run(entries) }.map_err(to_err)?;
+        if check(results[0]).map_err(to_err)? != buf.len() {
+            return Err(to_err(io::ErrorKind::WriteZero.into()));
+        }
+        check(results[1]).map_err(to_err)?;
+        Ok(())
+    }
+
+    fn read_blocks(&self, blocks: &[BlockId], pages: &mut [&mut Page]) -> Result<(), StorageError> {
+        let mut files = Vec::with_capacity(blocks.len());
+        let mut entries = Vec::with_capacity(blocks.len());
+        for (block, p) in blocks.iter().zip(pages.iter_mut()) {
+            let (file, fd) = self.fd(block.filename())?;
+            files.push(file);
+            let offset = self.fm.offset(block.number());
+            let buf = p.contents_mut();
+            entries.push(
+                opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(offset)
+                    .build(),
+            );
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // SAFETY: `pages` and `files` outlive the call
+        let results = unsafe { self.run(entries) }.map_err(|source| StorageError::Read {
+            block: blocks[0].clone(),
+            source,
+        })?;
+        for (block, res) in blocks.iter().zip(results) {
+            // a short read means the block is (partly) past the end of the file
+            check(res).map_err(|source| StorageError::Read {
+                block: block.clone(),
+                source,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+        self.fm.append(filename)
+    }
+
+    fn append_extent(&self, filename: &str, count: usize) -> Result<BlockId, StorageError> {
+        self.fm.append_extent(filename, count)
+    }
+
+    fn length(&self, filename: &str) -> Result<u64, StorageError> {
+        self.fm.length(filename)
+    }
+
+    fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+        self.fm.truncate(filename, len)
+    }
+
+    fn delete(&self, filename: &str) -> Result<(), StorageError> {
+        self.fm.delete(filename)
+    }
+
+    fn block_size(&self) -> usize {
+        self.fm.block_size
+    }
+
+    fn sync_file(&self, filename: &str) -> Result<(), StorageError> {
+        self.fm.sync_file(filename)
+    }
+
+    fn sync_all(&self) -> Result<(), StorageError> {
+        self.fm.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_uring_file_manager() {
+        let dirname = format!(
+            "uringtest_{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        let dir_path = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname);
+        let fm = UringFileManager::new(&dir_path, 400).unwrap();
+
+        let blocks: Vec<_> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let mut p = Page::new(fm.block_size());
+            p.set_int(8, i as i32 * 10);
+            fm.write_block(block, &p).unwrap();
+        }
+        assert_eq!(fm.length("testfile").unwrap(), 3);
+
+        let mut pages: Vec<_> = (0..3).map(|_| Page::new(fm.block_size())).collect();
+        let mut refs: Vec<_> = pages.iter_mut().collect();
+        fm.read_blocks(&blocks, &mut refs).unwrap();
+        let values: Vec<_> = pages.iter().map(|p| p.get_int(8)).collect();
+        assert_eq!(values, [0, 10, 20]);
+    }
+}
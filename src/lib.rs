@@ -0,0 +1,36 @@
+mod buffer;
+mod check;
+mod config;
+mod constants;
+mod db;
+mod error;
+mod file;
+mod log;
+mod metrics;
+mod replication;
+mod txn;
+mod wal;
+
+pub use buffer::{AccessHint, EvictionPolicy, PinRecord, Replacer, ReplacerFactory};
+pub use check::CheckReport;
+pub use config::{Config, ConfigError};
+pub use db::{Builder, Database, WillowDB};
+pub use error::WillowError;
+pub use file::{
+    from_page, to_page, BlockId, Durability, FaultyStorage, FileId, FileManager, Page,
+    PageDeserializer, PageError, PageHeader, PageSerializer, PageType, SerdeError, StorageBackend,
+    StorageError, DIRECT_IO_ALIGNMENT,
+};
+pub use log::Lsn;
+pub use metrics::{
+    BufferManagerStats, FileIoStats, FileManagerStats, HistogramSnapshot, MetricsSnapshot,
+};
+pub use replication::{LogReceiver, LogShipper, ReplicationError};
+pub use txn::{
+    LogRecord, RecoveryPhase, RecoveryProgress, RecoveryTarget, Transaction, TxNum, TxnError,
+    UpdateValue,
+};
+pub use wal::{WalError, WalReader, WalRecords};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use file::UringFileManager;
@@ -1,179 +1,977 @@
 #![allow(dead_code)]
 
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io, mem,
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::{trace, warn};
 
 use crate::{
     constants::SIZE_OF_INT,
-    file::{BlockId, FileManager, Page},
+    file::{pio, BlockId, Page, PageError, PageHeader, PageType, StorageBackend, StorageError},
+    metrics::{Histogram, HistogramSnapshot},
 };
 
-/// Log Sequence Number
-pub type Lsn = u32;
+/// Log Sequence Number: where a record is in the log, stable across restarts.
+///
+/// The upper 32 bits are the number of the log block holding the record and the lower 32 bits
+/// how many bytes of the block are used up to and including the record. Records are placed
+/// right -> left in a block, so LSNs grow in append order.
+pub type Lsn = u64;
+
+/// LSN of the record that ends `used` bytes into log block `block`.
+fn to_lsn(block: u64, used: usize) -> Lsn {
+    (block << 32) | used as u64
+}
+
+/// Number of the log block holding the record `lsn`.
+pub(crate) fn lsn_block(lsn: Lsn) -> u64 {
+    lsn >> 32
+}
+
+/// Bytes a fragment takes up besides the record bytes it holds: its kind, checksum and length.
+const FRAGMENT_OVERHEAD: usize = 1 + 2 * SIZE_OF_INT;
+
+/// Which part of a record a fragment stored in a log block holds. A record that doesn't fit in
+/// a block is stored as a first fragment, any number of middle ones and a last one, each in its
+/// own block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fragment {
+    Full = 0,
+    First = 1,
+    Middle = 2,
+    Last = 3,
+}
+
+// Fragment: [ kind (u8) | bytes | crc32 of kind and bytes (u32) | length of bytes (u32) ]
+// The length comes last so that a block's fragments can be walked from its end, oldest first.
+impl Fragment {
+    /// Stores a fragment of `kind` holding `bytes` so that it ends at `end` of a log page.
+    /// Returns the offset it starts at.
+    fn write(page: &mut Page, end: usize, kind: Self, bytes: &[u8]) -> usize {
+        let start = end - FRAGMENT_OVERHEAD - bytes.len();
+        let crc_pos = end - 2 * SIZE_OF_INT;
+        let contents = page.contents_mut();
+        contents[start] = kind as u8;
+        contents[start + 1..crc_pos].copy_from_slice(bytes);
+        let crc = crc32fast::hash(&contents[start..crc_pos]);
+        contents[crc_pos..crc_pos + SIZE_OF_INT].copy_from_slice(&crc.to_le_bytes());
+        contents[crc_pos + SIZE_OF_INT..end].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        start
+    }
+
+    /// Reads the fragment ending at `end` of a log page and checks its checksum. Returns the
+    /// offset it starts at, its kind and the record bytes it holds.
+    fn read<'a>(
+        page: &'a Page,
+        block: &BlockId,
+        end: usize,
+    ) -> Result<(usize, Self, &'a [u8]), LogError> {
+        let invalid = || LogError::InvalidFragment {
+            block: block.clone(),
+            offset: end,
+        };
+        let contents = page.contents();
+        if end > contents.len() || end < PageHeader::SIZE + FRAGMENT_OVERHEAD {
+            return Err(invalid());
+        }
+        let crc_pos = end - 2 * SIZE_OF_INT;
+        let int_at = |pos: usize| {
+            let bytes = contents[pos..pos + SIZE_OF_INT].try_into().unwrap();
+            u32::from_le_bytes(bytes)
+        };
+        let len = int_at(crc_pos + SIZE_OF_INT) as usize;
+        let start = end
+            .checked_sub(FRAGMENT_OVERHEAD + len)
+            .filter(|&start| start >= PageHeader::SIZE)
+            .ok_or_else(invalid)?;
+        if crc32fast::hash(&contents[start..crc_pos]) != int_at(crc_pos) {
+            return Err(invalid());
+        }
+        let kind = match contents[start] {
+            0 => Self::Full,
+            1 => Self::First,
+            2 => Self::Middle,
+            3 => Self::Last,
+            _ => return Err(invalid()),
+        };
+        Ok((start, kind, &contents[start + 1..crc_pos]))
+    }
+
+    /// Offsets at which the fragments of a log page end, oldest first, walking from the end of
+    /// the page down to `boundary`.
+    fn ends(page: &Page, block: &BlockId, boundary: usize) -> Result<Vec<usize>, LogError> {
+        let mut ends = Vec::new();
+        let mut end = page.contents().len();
+        while end > boundary {
+            ends.push(end);
+            end = Self::read(page, block, end)?.0;
+        }
+        if end != boundary {
+            return Err(LogError::InvalidFragment {
+                block: block.clone(),
+                offset: end,
+            });
+        }
+        Ok(ends)
+    }
+
+    /// Where the intact fragments at the end of a log page start: walking from the end of the
+    /// page, the start of the last fragment before the first one that fails its checks.
+    fn intact_boundary(page: &Page, block: &BlockId) -> usize {
+        let mut end = page.contents().len();
+        while let Ok((start, _, _)) = Self::read(page, block, end) {
+            end = start;
+        }
+        end
+    }
+}
+
+/// Called with each log block once it's full and synced, e.g. to keep the log history
+/// for point-in-time recovery or to ship it to a replica.
+pub type LogArchiver = Box<dyn Fn(&BlockId, &[u8]) -> io::Result<()> + Send + Sync>;
+
+/// Returns an archiver that copies every completed log block into a file of the same name
+/// in `dir`, at the block's offset.
+pub(crate) fn dir_archiver(dir: PathBuf) -> LogArchiver {
+    Box::new(move |block, contents| {
+        fs::create_dir_all(&dir)?;
+        let f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(dir.join(block.filename()))?;
+        pio::write_all_at(&f, contents, block.number() * contents.len() as u64)?;
+        f.sync_data()
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum LogError {
+    #[error("log block {block} is corrupt")]
+    Corrupt {
+        block: BlockId,
+        #[source]
+        source: PageError,
+    },
+    #[error("log block {block} holds an invalid record fragment at offset {offset}")]
+    InvalidFragment { block: BlockId, offset: usize },
+    #[error("failed to archive log block {block}")]
+    Archive {
+        block: BlockId,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to start the log writer")]
+    Writer {
+        #[source]
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Log pages kept in memory by default: the one being filled and the full ones waiting for
+/// the writer.
+pub(crate) const DEFAULT_LOG_BUFFER_PAGES: usize = 4;
 
 struct LogManagerInner {
-    fm: Arc<FileManager>,
+    fm: Arc<dyn StorageBackend>,
     logfile: String,
     logpage: Page,
     current_block: BlockId,
     latest_lsn: Lsn,
     last_saved_lsn: Lsn,
+    /// Pages that filled up and haven't been written yet, oldest first.
+    full_pages: VecDeque<(BlockId, Page)>,
+    /// Written pages ready to be filled again, so that the buffer's pages are reused.
+    spare_pages: Vec<Page>,
+    /// Most pages kept in memory, counting the current one.
+    buffer_pages: usize,
+    /// Highest LSN a caller of [`LogManager::flush`] waits for.
+    requested_lsn: Lsn,
+    /// Why the writer's last round failed; handed to the next caller that waits on it.
+    error: Option<LogError>,
+    /// How long the writer waits for more committers before flushing for a requested LSN.
+    group_commit_delay: Duration,
+    flush_latency: Histogram,
+    archiver: Option<Arc<LogArchiver>>,
+    shutdown: bool,
 }
 
 impl LogManagerInner {
-    fn new(fm: Arc<FileManager>, logfile: &str) -> Self {
+    fn new(fm: Arc<dyn StorageBackend>, logfile: &str) -> Result<Self, LogError> {
         let mut logpage = Page::new(fm.block_size());
-        let logsize = fm.length(logfile);
+        let logsize = fm.length(logfile)?;
         let current_block = if logsize == 0 {
-            let block = fm.append(logfile);
-            logpage.set_int(0, fm.block_size() as i32);
-            fm.write(&block, &logpage);
+            let block = fm.append(logfile)?;
+            logpage.set_header(&PageHeader::new(PageType::Log, fm.block_size()));
+            logpage.update_checksum();
+            fm.write_block(&block, &logpage)?;
             block
         } else {
-            let block = BlockId::new(logfile, logsize as usize - 1);
-            fm.read(&block, &mut logpage);
-            block
+            let block = BlockId::new(logfile, logsize - 1);
+            let torn = match fm.read_block(&block, &mut logpage) {
+                Err(StorageError::ChecksumMismatch(_)) => true,
+                res => {
+                    res?;
+                    LogIterator::check_page(&logpage).is_err()
+                }
+            };
+            if torn {
+                Self::repair_torn(&fm, block, &mut logpage)?
+            } else {
+                block
+            }
         };
+        // continue from the last record written before the restart
+        let used = fm.block_size().saturating_sub(logpage.free_space());
+        let latest_lsn = to_lsn(current_block.number(), used);
 
-        Self {
+        Ok(Self {
             fm,
             logfile: logfile.to_owned(),
             logpage,
             current_block,
-            latest_lsn: 0,
-            last_saved_lsn: 0,
+            latest_lsn,
+            last_saved_lsn: latest_lsn,
+            full_pages: VecDeque::new(),
+            spare_pages: Vec::new(),
+            buffer_pages: DEFAULT_LOG_BUFFER_PAGES,
+            requested_lsn: latest_lsn,
+            error: None,
+            group_commit_delay: Duration::ZERO,
+            flush_latency: Histogram::default(),
+            archiver: None,
+            shutdown: false,
+        })
+    }
+
+    /// Handles a last block that fails its checks, which is what a crash in the middle of
+    /// rewriting it leaves behind. The write only damaged the header and the fragments it
+    /// added: the ones synced before sit unchanged at the end of the block. The fragments are
+    /// kept from the end of the block up to the first one that fails its checksum, and appends
+    /// go on in a new block, keeping LSNs above any handed out before the crash.
+    /// Returns the block to append to, with `logpage` set up for it.
+    fn repair_torn(
+        fm: &Arc<dyn StorageBackend>,
+        block: BlockId,
+        logpage: &mut Page,
+    ) -> Result<BlockId, LogError> {
+        let boundary = Fragment::intact_boundary(logpage, &block);
+        warn!(%block, kept = fm.block_size() - boundary, "repairing a torn log block");
+        logpage.contents_mut()[..boundary].fill(0);
+        logpage.set_header(&PageHeader::new(PageType::Log, boundary));
+        logpage.set_page_lsn(to_lsn(block.number(), fm.block_size() - boundary));
+        logpage.update_checksum();
+        match fm.write_block(&block, logpage) {
+            // leave the log as it is for readers; iterators stop at the torn block
+            Err(StorageError::ReadOnly(_)) => return Ok(block),
+            res => res?,
         }
+        let next = fm.append(block.filename())?;
+        logpage.contents_mut().fill(0);
+        logpage.set_header(&PageHeader::new(PageType::Log, fm.block_size()));
+        logpage.update_checksum();
+        fm.write_block(&next, logpage)?;
+        fm.sync_file(block.filename())?;
+        Ok(next)
     }
 
-    fn append(&mut self, record: &[u8]) -> Lsn {
-        let mut boundary = self.logpage.get_int(0);
-        let record_size = record.len();
-        let bytes_needed = record_size + SIZE_OF_INT;
+    /// Bytes of record data that still fit in the current block, if a fragment fits at all.
+    fn room(&self) -> Option<usize> {
+        self.logpage
+            .free_space()
+            .checked_sub(PageHeader::SIZE + FRAGMENT_OVERHEAD)
+    }
 
-        assert!(bytes_needed + SIZE_OF_INT <= self.fm.block_size());
+    /// Bytes of record data that fit in an empty block.
+    fn block_room(&self) -> usize {
+        self.fm.block_size() - PageHeader::SIZE - FRAGMENT_OVERHEAD
+    }
 
-        if boundary - (bytes_needed as i32) < SIZE_OF_INT as i32 {
-            // doesn't fit so move to the next block
-            self.flush();
-            self.current_block = self.append_new_block();
-            boundary = self.logpage.get_int(0);
-        }
+    /// Queues the current page for the writer and moves on to the next block.
+    fn start_new_block(&mut self) {
+        let mut page = match self.spare_pages.pop() {
+            Some(mut page) => {
+                page.contents_mut().fill(0);
+                page
+            }
+            None => Page::new(self.fm.block_size()),
+        };
+        page.set_header(&PageHeader::new(PageType::Log, self.fm.block_size()));
+        let full = mem::replace(&mut self.logpage, page);
+        let next = BlockId::new(&self.logfile, self.current_block.number() + 1);
+        let block = mem::replace(&mut self.current_block, next);
+        self.full_pages.push_back((block, full));
+    }
+
+    /// Stores a fragment of `kind` holding `bytes`, which must fit in the current block.
+    fn put_fragment(&mut self, kind: Fragment, bytes: &[u8]) -> Lsn {
+        let boundary = self.logpage.free_space();
 
         // records are placed right -> left
-        // boundary value is written to the start of the page
+        // the boundary is the free-space pointer in the page header
         // this allow the log itr. to read records in reverse order (i.e. left -> right)
         //
-        // Page: [ boundary | gap | record n | ... | record1 ]
+        // Page: [ header | gap | record n | ... | record1 ]
         // gap -> optional, in case everything doesn't fit exactly
         // 1..n -> order in which the log was written (record1 was written first and so on..)
+        let record_pos = Fragment::write(&mut self.logpage, boundary, kind, bytes);
+        self.logpage.set_free_space(record_pos);
 
-        let record_pos = boundary as usize - bytes_needed;
-        self.logpage.set_bytes(record_pos, record);
-        self.logpage.set_int(0, record_pos as i32);
-
-        self.latest_lsn += 1;
+        self.latest_lsn = to_lsn(
+            self.current_block.number(),
+            self.fm.block_size() - record_pos,
+        );
+        self.logpage.set_page_lsn(self.latest_lsn);
         self.latest_lsn
     }
 
-    fn append_new_block(&mut self) -> BlockId {
-        let block = self.fm.append(&self.logfile);
-        self.logpage.set_int(0, self.fm.block_size() as i32);
-        self.fm.write(&block, &self.logpage);
-        block
+    fn has_work(&self) -> bool {
+        self.error.is_none()
+            && (self.requested_lsn > self.last_saved_lsn || !self.full_pages.is_empty())
     }
 
-    fn flush(&mut self) {
-        self.fm.write(&self.current_block, &self.logpage);
-        self.last_saved_lsn = self.latest_lsn;
+    /// Takes the full pages, plus a copy of the current one if a flush is due.
+    fn take_round(&mut self) -> WriteRound {
+        let mut pages: Vec<_> = self.full_pages.drain(..).collect();
+        let full = pages.len();
+        let mut lsn = pages.last().map(|(_, p)| p.page_lsn());
+        if self.requested_lsn > self.last_saved_lsn || self.shutdown {
+            let copy = Page::from(Box::<[u8]>::from(self.logpage.contents()));
+            pages.push((self.current_block.clone(), copy));
+            lsn = Some(self.latest_lsn);
+        }
+        WriteRound {
+            fm: Arc::clone(&self.fm),
+            logfile: self.logfile.clone(),
+            pages,
+            full,
+            lsn: lsn.unwrap_or(self.last_saved_lsn),
+            archiver: self.archiver.clone(),
+        }
+    }
+
+    fn finish_round(&mut self, mut round: WriteRound, res: RoundResult) {
+        round.pages.truncate(round.full);
+        match res {
+            Ok(elapsed) => {
+                self.flush_latency.observe(elapsed);
+                self.last_saved_lsn = self.last_saved_lsn.max(round.lsn);
+            }
+            Err(RoundError::Write(e)) => {
+                self.requeue(mem::take(&mut round.pages));
+                self.error = Some(e);
+            }
+            Err(RoundError::Archive(archived, e)) => {
+                // the pages are durable, only archiving them has to be retried
+                self.last_saved_lsn = self.last_saved_lsn.max(round.lsn);
+                let failed = round.pages.split_off(archived);
+                self.requeue(failed);
+                self.error = Some(e);
+            }
+        }
+        let spares = self.buffer_pages - 1;
+        for (_, page) in round.pages {
+            if self.spare_pages.len() < spares {
+                self.spare_pages.push(page);
+            }
+        }
+    }
+
+    fn requeue(&mut self, pages: Vec<(BlockId, Page)>) {
+        for page in pages.into_iter().rev() {
+            self.full_pages.push_front(page);
+        }
     }
 }
 
+/// Pages the log writer writes and syncs in one go.
+struct WriteRound {
+    fm: Arc<dyn StorageBackend>,
+    logfile: String,
+    /// The full pages in block order, then possibly a copy of the current page.
+    pages: Vec<(BlockId, Page)>,
+    /// How many of `pages` are full.
+    full: usize,
+    /// LSN up to which the log is durable once the round succeeds.
+    lsn: Lsn,
+    archiver: Option<Arc<LogArchiver>>,
+}
+
+enum RoundError {
+    Write(LogError),
+    /// The pages were written, but only the given number of full pages were archived.
+    Archive(usize, LogError),
+}
+
+type RoundResult = Result<Duration, RoundError>;
+
+impl WriteRound {
+    fn run(&mut self) -> RoundResult {
+        trace!(
+            pages = self.pages.len(),
+            lsn = self.lsn,
+            "writing log pages"
+        );
+        let start = Instant::now();
+        self.write().map_err(RoundError::Write)?;
+        let elapsed = start.elapsed();
+
+        if let Some(archive) = &self.archiver {
+            for (i, (block, page)) in self.pages[..self.full].iter().enumerate() {
+                archive(block, page.contents()).map_err(|source| {
+                    RoundError::Archive(
+                        i,
+                        LogError::Archive {
+                            block: block.clone(),
+                            source,
+                        },
+                    )
+                })?;
+            }
+        }
+        Ok(elapsed)
+    }
+
+    fn write(&mut self) -> Result<(), LogError> {
+        let mut len = self.fm.length(&self.logfile)?;
+        for (block, page) in &mut self.pages {
+            while len <= block.number() {
+                self.fm.append(&self.logfile)?;
+                len += 1;
+            }
+            page.update_checksum();
+            self.fm.write_block(block, page)?;
+        }
+        // the log is synced whatever the data files' durability policy is
+        self.fm.sync_file(&self.logfile)?;
+        Ok(())
+    }
+}
+
+struct Shared {
+    state: Mutex<LogManagerInner>,
+    /// Wakes the writer when there's something to write.
+    work: Condvar,
+    /// Signalled after each round of the writer.
+    written: Condvar,
+}
+
+impl Shared {
+    /// Body of the log writer thread.
+    fn run_writer(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while !state.shutdown && !state.has_work() {
+                state = self.work.wait(state).unwrap();
+            }
+            let shutdown = state.shutdown;
+            if shutdown && state.latest_lsn == state.last_saved_lsn && state.full_pages.is_empty() {
+                return;
+            }
+            let delay = state.group_commit_delay;
+            if !shutdown && !delay.is_zero() && state.requested_lsn > state.last_saved_lsn {
+                // let more committers join this flush
+                drop(state);
+                thread::sleep(delay);
+                state = self.state.lock().unwrap();
+            }
+
+            let mut round = state.take_round();
+            drop(state);
+            let res = round.run();
+            state = self.state.lock().unwrap();
+            state.finish_round(round, res);
+            self.written.notify_all();
+
+            if shutdown {
+                if let Some(e) = state.error.take() {
+                    warn!(error = %e, "failed to write the log on shutdown");
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Appends records to the log, which a dedicated writer thread writes out.
+///
+/// Appending only copies the record into the current log page. The writer writes pages as they
+/// fill up and whenever a caller of [`LogManager::flush`] waits for its records to be durable.
+///
+/// The log buffer is a ring of pages: when the current page is full, appends move on to the
+/// next one while the writer catches up, and only wait once every page is full.
 pub struct LogManager {
-    inner: RwLock<LogManagerInner>,
+    shared: Arc<Shared>,
+    /// Held for the whole of an append, which may wait for the writer between fragments.
+    appending: Mutex<()>,
+    writer: Option<JoinHandle<()>>,
+    compress_records: bool,
 }
 
 impl LogManager {
-    pub fn new(fm: Arc<FileManager>, logfile: &str) -> Self {
-        Self {
-            inner: RwLock::new(LogManagerInner::new(fm, logfile)),
+    pub fn new(fm: Arc<dyn StorageBackend>, logfile: &str) -> Result<Self, LogError> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(LogManagerInner::new(fm, logfile)?),
+            work: Condvar::new(),
+            written: Condvar::new(),
+        });
+        let writer = thread::Builder::new()
+            .name("willow-log-writer".to_owned())
+            .spawn({
+                let shared = Arc::clone(&shared);
+                move || shared.run_writer()
+            })
+            .map_err(|source| LogError::Writer { source })?;
+        Ok(Self {
+            shared,
+            appending: Mutex::new(()),
+            writer: Some(writer),
+            compress_records: false,
+        })
+    }
+
+    /// Makes the writer wait up to `delay` before flushing for a committer, so that the records
+    /// appended by other committers in the meantime are covered by the same sync.
+    ///
+    /// Committers arriving while a flush is in progress always wait for it and share the next one.
+    pub fn with_group_commit(self, delay: Duration) -> Self {
+        self.shared.state.lock().unwrap().group_commit_delay = delay;
+        self
+    }
+
+    /// Makes the transaction layer lz4-compress the log records it writes when that makes them
+    /// smaller. Compressed and uncompressed records can be read either way.
+    pub fn with_record_compression(mut self, enabled: bool) -> Self {
+        self.compress_records = enabled;
+        self
+    }
+
+    pub(crate) fn compresses_records(&self) -> bool {
+        self.compress_records
+    }
+
+    /// Keeps up to `pages` log pages in memory, see [`LogManager`]. At least two are kept: the
+    /// one being filled and one being written.
+    pub fn with_buffer_pages(self, pages: usize) -> Self {
+        self.shared.state.lock().unwrap().buffer_pages = pages.max(2);
+        self
+    }
+
+    /// Hands every log block to `archiver` once it's full and synced. An archiver error is
+    /// returned by the next append or flush, and archiving the block is retried afterwards.
+    pub fn with_archiver(self, archiver: LogArchiver) -> Self {
+        self.shared.state.lock().unwrap().archiver = Some(Arc::new(archiver));
+        self
+    }
+
+    /// Copies `record` into the log buffer and returns its LSN.
+    ///
+    /// A record that doesn't fit in the rest of the current block goes into the next one, and
+    /// one that doesn't fit in a block at all is spread over as many as it needs; its LSN is
+    /// then that of its last fragment. Only waits for the writer if every page of the log buffer
+    /// is full.
+    pub fn append(&self, record: &[u8]) -> Result<Lsn, LogError> {
+        // the fragments of a record must not be interleaved with another one's
+        let _appending = self.appending.lock().unwrap();
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+
+        let mut rest = record;
+        let mut first = true;
+        loop {
+            let room = state.room().unwrap_or(0);
+            if state.room().is_some_and(|room| rest.len() <= room) {
+                let kind = if first {
+                    Fragment::Full
+                } else {
+                    Fragment::Last
+                };
+                return Ok(state.put_fragment(kind, rest));
+            }
+            // a record that fits in a block isn't split
+            if room > 0 && !(first && rest.len() <= state.block_room()) {
+                let kind = if first {
+                    Fragment::First
+                } else {
+                    Fragment::Middle
+                };
+                let (bytes, tail) = rest.split_at(room);
+                state.put_fragment(kind, bytes);
+                rest = tail;
+                first = false;
+            }
+            state = self.next_block(state)?;
         }
     }
 
-    pub fn append(&self, record: &[u8]) -> Lsn {
-        let mut state = self.inner.write().unwrap();
-        state.append(record)
+    /// Moves on to the next block once the log buffer has room for it.
+    fn next_block<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, LogManagerInner>,
+    ) -> Result<MutexGuard<'a, LogManagerInner>, LogError> {
+        while state.full_pages.len() + 1 >= state.buffer_pages {
+            self.shared.work.notify_one();
+            state = self.shared.written.wait(state).unwrap();
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+        }
+        state.start_new_block();
+        self.shared.work.notify_one();
+        Ok(state)
     }
 
-    /// Ensures that the content of the log are flushed at least till `lsn`.
-    pub fn flush(&self, lsn: Option<Lsn>) {
+    /// Waits until the log is durable at least till `lsn`.
+    ///
+    /// The writer flushes for every waiting caller at once, so callers arriving while a flush is
+    /// in progress share the next one.
+    pub fn flush(&self, lsn: Option<Lsn>) -> Result<(), LogError> {
         let Some(lsn) = lsn else {
-            return;
+            return Ok(());
         };
 
-        let last_saved_lsn = {
-            let state = self.inner.read().unwrap();
-            state.last_saved_lsn
-        };
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.last_saved_lsn >= lsn {
+                return Ok(());
+            }
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            // also wakes the writer to retry a request that failed before
+            state.requested_lsn = state.requested_lsn.max(lsn);
+            self.shared.work.notify_one();
+            state = self.shared.written.wait(state).unwrap();
+        }
+    }
 
-        if lsn > last_saved_lsn {
-            let mut state = self.inner.write().unwrap();
-            state.flush();
+    /// Waits until every appended record is durable and returns the state still locked,
+    /// so that nothing can be appended in the meantime.
+    fn lock_flushed(&self) -> Result<MutexGuard<'_, LogManagerInner>, LogError> {
+        loop {
+            self.flush(Some(self.latest_lsn()))?;
+            let state = self.shared.state.lock().unwrap();
+            if state.last_saved_lsn >= state.latest_lsn {
+                return Ok(state);
+            }
         }
     }
 
+    /// Writes out the current log page and runs `f` with the latest LSN while no record can be appended.
+    pub(crate) fn pause<T>(&self, f: impl FnOnce(Lsn) -> T) -> Result<T, LogError> {
+        let state = self.lock_flushed()?;
+        Ok(f(state.latest_lsn))
+    }
+
+    /// Drops every record after `lsn`, the LSN of a record or 0 to drop them all. Appends carry
+    /// on right after it, handing out the dropped records' LSNs again.
+    pub(crate) fn truncate(&self, lsn: Lsn) -> Result<(), LogError> {
+        let _appending = self.appending.lock().unwrap();
+        let mut state = self.lock_flushed()?;
+        if lsn >= state.latest_lsn {
+            return Ok(());
+        }
+        let fm = Arc::clone(&state.fm);
+        let block = BlockId::new(&state.logfile, lsn_block(lsn));
+        if block != state.current_block {
+            fm.read_block(&block, &mut state.logpage)?;
+        }
+        let page = &mut state.logpage;
+        let boundary = fm.block_size() - (lsn & u32::MAX as u64) as usize;
+        let free = page.free_space();
+        page.contents_mut()[free..boundary].fill(0);
+        page.set_free_space(boundary);
+        page.set_page_lsn(lsn);
+        page.update_checksum();
+        fm.write_block(&block, page)?;
+        fm.truncate(&state.logfile, block.number() + 1)?;
+        fm.sync_file(&state.logfile)?;
+
+        state.current_block = block;
+        state.latest_lsn = lsn;
+        state.last_saved_lsn = lsn;
+        state.requested_lsn = lsn;
+        Ok(())
+    }
+
+    /// LSN of the most recently appended record.
+    pub(crate) fn latest_lsn(&self) -> Lsn {
+        self.shared.state.lock().unwrap().latest_lsn
+    }
+
+    pub(crate) fn flush_latency(&self) -> HistogramSnapshot {
+        self.shared.state.lock().unwrap().flush_latency.snapshot()
+    }
+
     /// Starts at the first (latest) record in the last block and iterates from the latest -> oldest record.
-    pub fn iterator(&self) -> impl Iterator<Item = Box<[u8]>> {
+    pub fn iterator(&self) -> Result<impl Iterator<Item = Result<Box<[u8]>, LogError>>, LogError> {
+        Ok(self.records()?.map(|rec| rec.map(|(_, bytes)| bytes)))
+    }
+
+    /// Like [`LogManager::iterator`] but yields each record with its LSN.
+    pub(crate) fn records(&self) -> Result<LogIterator, LogError> {
         let (fm, block) = {
-            let mut state = self.inner.write().unwrap();
-            state.flush();
+            let state = self.lock_flushed()?;
             (Arc::clone(&state.fm), state.current_block.clone())
         };
 
         LogIterator::new(fm, block)
     }
+
+    /// Iterates from the record `from` (or the first one after it) to the latest record.
+    /// Pass `0` to start at the oldest record.
+    pub fn forward_iterator(
+        &self,
+        from: Lsn,
+    ) -> Result<impl Iterator<Item = Result<Box<[u8]>, LogError>>, LogError> {
+        Ok(self
+            .forward_records(from)?
+            .map(|rec| rec.map(|(_, bytes)| bytes)))
+    }
+
+    /// Like [`LogManager::forward_iterator`] but yields each record with its LSN.
+    pub(crate) fn forward_records(&self, from: Lsn) -> Result<ForwardLogIterator, LogError> {
+        let (fm, last) = {
+            let state = self.lock_flushed()?;
+            (Arc::clone(&state.fm), state.current_block.clone())
+        };
+
+        let block = BlockId::new(last.filename(), lsn_block(from));
+        Ok(ForwardLogIterator {
+            page: Page::new(fm.block_size()),
+            fm,
+            loaded: block.clone(),
+            block,
+            from,
+            last: last.number(),
+            positions: Vec::new(),
+            partial: None,
+            seeking: true,
+            failed: false,
+        })
+    }
+}
+
+impl Drop for LogManager {
+    fn drop(&mut self) {
+        // the writer writes out what's left before it stops
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.work.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
 }
 
-struct LogIterator {
-    fm: Arc<FileManager>,
+pub(crate) struct LogIterator {
+    fm: Arc<dyn StorageBackend>,
     block: BlockId,
     page: Page,
-    current_pos: usize,
-    boundary: usize,
+    /// Offsets at which the fragments in `page` that haven't been read end, oldest first.
+    ends: Vec<usize>,
+    /// Set after a block couldn't be read or parsed; the iterator yields nothing afterwards.
+    failed: bool,
 }
 
 impl LogIterator {
-    fn new(fm: Arc<FileManager>, block: BlockId) -> Self {
+    fn new(fm: Arc<dyn StorageBackend>, block: BlockId) -> Result<Self, LogError> {
         let page = Page::new(fm.block_size());
         let mut itr = Self {
             fm,
             block: block.clone(),
             page,
-            current_pos: 0,
-            boundary: 0,
+            ends: Vec::new(),
+            failed: false,
+        };
+        itr.move_to_block(&block)?;
+        Ok(itr)
+    }
+
+    fn move_to_block(&mut self, block: &BlockId) -> Result<(), LogError> {
+        self.fm.read_block(block, &mut self.page)?;
+        let boundary = Self::check_page(&self.page).map_err(|source| LogError::Corrupt {
+            block: block.clone(),
+            source,
+        })?;
+        self.ends = Fragment::ends(&self.page, block, boundary)?;
+        Ok(())
+    }
+
+    /// Validates a log page's header and returns its boundary.
+    fn check_page(page: &Page) -> Result<usize, PageError> {
+        let header = page.try_header()?;
+        if header.page_type != PageType::Log {
+            return Err(PageError::WrongPageType {
+                expected: PageType::Log,
+                found: header.page_type,
+            });
+        }
+        page.verify_checksum()?;
+        Ok(header.free_space)
+    }
+}
+
+/// Walks the log oldest -> latest. Records within a block are laid out latest -> oldest, so
+/// each block's record offsets are collected first and then yielded in reverse.
+pub(crate) struct ForwardLogIterator {
+    fm: Arc<dyn StorageBackend>,
+    /// Next block to load.
+    block: BlockId,
+    /// Records before this LSN are skipped.
+    from: Lsn,
+    /// Number of the last block to visit.
+    last: u64,
+    page: Page,
+    /// Block held by `page`.
+    loaded: BlockId,
+    /// Offsets at which the fragments in the loaded block that haven't been read end, latest first.
+    positions: Vec<usize>,
+    /// Fragments read so far of a record spilled over several blocks.
+    partial: Option<Vec<u8>>,
+    /// Whether the block holding the start of the first record hasn't been found yet.
+    seeking: bool,
+    /// Set after a block couldn't be read or parsed; the iterator yields nothing afterwards.
+    failed: bool,
+}
+
+impl ForwardLogIterator {
+    /// Reads the next block and collects the offsets of its fragments.
+    fn load_block(&mut self) -> Result<(), LogError> {
+        self.fm.read_block(&self.block, &mut self.page)?;
+        let boundary = LogIterator::check_page(&self.page).map_err(|source| LogError::Corrupt {
+            block: self.block.clone(),
+            source,
+        })?;
+        self.positions = Fragment::ends(&self.page, &self.block, boundary)?;
+        let oldest = match self.positions.first() {
+            Some(&end) => Some(Fragment::read(&self.page, &self.block, end)?.1),
+            None => None,
         };
-        itr.move_to_block(&block);
-        itr
+        self.positions.reverse();
+        self.loaded = self.block.clone();
+
+        // a record spilled into the first block visited starts in an earlier one
+        let continued = matches!(oldest, Some(Fragment::Middle | Fragment::Last));
+        if self.seeking && continued && self.loaded.number() > 0 {
+            self.positions.clear();
+            self.block = BlockId::new(self.loaded.filename(), self.loaded.number() - 1);
+            return Ok(());
+        }
+        self.seeking = false;
+        self.block = BlockId::new(self.loaded.filename(), self.loaded.number() + 1);
+        Ok(())
     }
+}
 
-    fn move_to_block(&mut self, block: &BlockId) {
-        self.fm.read(block, &mut self.page);
-        self.boundary = self.page.get_int(0) as usize;
-        self.current_pos = self.boundary;
+impl Iterator for ForwardLogIterator {
+    type Item = Result<(Lsn, Box<[u8]>), LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let Some(pos) = self.positions.pop() else {
+                if self.block.number() > self.last {
+                    return None;
+                }
+                if let Err(e) = self.load_block() {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+                continue;
+            };
+            let (start, kind, bytes) = match Fragment::read(&self.page, &self.loaded, pos) {
+                Ok(fragment) => fragment,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            let lsn = to_lsn(self.loaded.number(), self.fm.block_size() - start);
+            match kind {
+                Fragment::Full => {
+                    self.partial = None;
+                    if lsn >= self.from {
+                        return Some(Ok((lsn, bytes.into())));
+                    }
+                }
+                Fragment::First => self.partial = Some(bytes.to_vec()),
+                Fragment::Middle => {
+                    if let Some(record) = &mut self.partial {
+                        record.extend_from_slice(bytes);
+                    }
+                }
+                Fragment::Last => {
+                    if let Some(mut record) = self.partial.take() {
+                        record.extend_from_slice(bytes);
+                        if lsn >= self.from {
+                            return Some(Ok((lsn, record.into())));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl LogIterator {
+    /// Reads the next fragment, latest -> oldest.
+    /// Moves past the next fragment and returns where it ends, or `None` past the oldest one.
+    fn next_fragment(&mut self) -> Result<Option<usize>, LogError> {
+        // skip the blocks without records left, e.g. one appended after repairing a torn one
+        while self.ends.is_empty() && self.block.number() > 0 {
+            let block = BlockId::new(self.block.filename(), self.block.number() - 1);
+            self.move_to_block(&block)?;
+            self.block = block;
+        }
+        Ok(self.ends.pop())
     }
 }
 
 impl Iterator for LogIterator {
-    type Item = Box<[u8]>;
+    type Item = Result<(Lsn, Box<[u8]>), LogError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_pos < self.fm.block_size() || self.block.number() > 0 {
-            if self.current_pos == self.fm.block_size() {
-                let block = BlockId::new(self.block.filename(), self.block.number() - 1);
-                self.move_to_block(&block);
-                self.block = block;
+        // fragments of a spilled record, latest first, and the LSN of its last one
+        let mut parts: Vec<Box<[u8]>> = Vec::new();
+        let mut lsn = 0;
+        while !self.failed {
+            let pos = match self.next_fragment() {
+                Ok(pos) => pos?,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            let (start, kind, bytes) = Fragment::read(&self.page, &self.block, pos)
+                .expect("checked when the block was loaded");
+            let fragment_lsn = to_lsn(self.block.number(), self.fm.block_size() - start);
+            match kind {
+                Fragment::Full => return Some(Ok((fragment_lsn, bytes.into()))),
+                Fragment::Last => {
+                    parts = vec![bytes.into()];
+                    lsn = fragment_lsn;
+                }
+                Fragment::Middle if !parts.is_empty() => parts.push(bytes.into()),
+                Fragment::First if !parts.is_empty() => {
+                    parts.push(bytes.into());
+                    parts.reverse();
+                    return Some(Ok((lsn, parts.concat().into())));
+                }
+                // the rest of the record was lost, e.g. in a torn block
+                Fragment::Middle | Fragment::First => {}
             }
-            let record = self.page.get_bytes(self.current_pos);
-            self.current_pos += SIZE_OF_INT + record.len();
-            return Some(record.into());
         }
         None
     }
@@ -184,19 +982,22 @@ mod tests {
 
     use std::{
         env,
+        sync::Barrier,
         time::{SystemTime, UNIX_EPOCH},
     };
 
-    use crate::file::Page;
+    use crate::file::FileManager;
 
     use super::*;
 
     impl LogManager {
-        fn create_records(&mut self, start: i32, end: i32) {
-            for i in start..=end {
-                let record = Self::create_log_record(&format!("record{}", i), i + 100);
-                self.append(&record);
-            }
+        fn create_records(&mut self, start: i32, end: i32) -> Vec<Lsn> {
+            (start..=end)
+                .map(|i| {
+                    let record = Self::create_log_record(&format!("record{}", i), i + 100);
+                    self.append(&record).unwrap()
+                })
+                .collect()
         }
 
         fn create_log_record(s: &str, n: i32) -> Box<[u8]> {
@@ -209,38 +1010,37 @@ mod tests {
 
         fn get_flushed_records(&self) -> Vec<Box<[u8]>> {
             let (fm, block) = {
-                let state = self.inner.read().unwrap();
+                let state = self.shared.state.lock().unwrap();
                 (Arc::clone(&state.fm), state.current_block.clone())
             };
 
-            LogIterator::new(fm, block).collect()
+            LogIterator::new(fm, block)
+                .unwrap()
+                .map(|rec| rec.map(|(_, bytes)| bytes))
+                .collect::<Result<_, _>>()
+                .unwrap()
         }
     }
 
     fn setup(block_size: usize) -> LogManager {
-        let dirname = format!(
-            "logtest_{}",
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-        );
-        let dir_path = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname);
-        let fm = Arc::new(FileManager::new(&dir_path, block_size));
-        LogManager::new(fm, "db.log")
+        let fm = Arc::new(FileManager::in_memory(block_size));
+        LogManager::new(fm, "db.log").unwrap()
     }
 
     #[test]
     fn test_log_manager() {
         let mut lm = setup(400);
 
-        lm.create_records(1, 35);
+        let lsns = lm.create_records(1, 35);
+        lm.flush(Some(lsns[20 - 1])).unwrap();
 
+        // the flush writes the whole current page
         let records = lm.get_flushed_records();
-        assert_eq!(records.len(), 20);
+        assert_eq!(records.len(), 35);
 
-        lm.create_records(36, 70);
-        lm.flush(Some(65));
+        let lsns = lm.create_records(36, 70);
+        // LSN of record65
+        lm.flush(Some(lsns[65 - 36])).unwrap();
 
         let records = lm.get_flushed_records();
         assert_eq!(records.len(), 70);
@@ -257,4 +1057,267 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_forward_iterator() {
+        let mut lm = setup(400);
+        lm.create_records(1, 70);
+
+        let names = |from_block| -> Vec<String> {
+            lm.forward_iterator(from_block)
+                .unwrap()
+                .map(|rec| Page::from(rec.unwrap()).get_string(0).into_owned())
+                .collect()
+        };
+        let all = names(0);
+        let expected: Vec<String> = (1..=70).map(|i| format!("record{}", i)).collect();
+        assert_eq!(all, expected);
+
+        // starting at the second block skips the first block's records
+        let rest = names(1 << 32);
+        assert_eq!(rest, expected[all.len() - rest.len()..]);
+        assert!(rest.len() < all.len());
+        assert_eq!(names(lm.latest_lsn()), ["record70"]);
+        assert!(names(lm.latest_lsn() + 1).is_empty());
+        assert!(names(100 << 32).is_empty());
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let dir = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(format!(
+            "groupcommittest_{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        ));
+        let fm = Arc::new(FileManager::new(&dir, 400).unwrap());
+        let lm = LogManager::new(fm.clone(), "db.log")
+            .unwrap()
+            .with_group_commit(Duration::from_millis(100));
+        let writes_before = fm.stats().writes;
+
+        let barrier = Barrier::new(8);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (lm, barrier) = (&lm, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    let lsn = lm.append(&[i; 10]).unwrap();
+                    lm.flush(Some(lsn)).unwrap();
+                    assert!(lm.shared.state.lock().unwrap().last_saved_lsn >= lsn);
+                });
+            }
+        });
+        // the committers that appended while the first one waited share its flush
+        assert!(fm.stats().writes - writes_before < 8);
+    }
+
+    #[test]
+    fn test_log_writer() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let mut lm = LogManager::new(fm.clone(), "db.log").unwrap();
+        let writes_before = fm.stats().writes;
+
+        // appends only fill the buffer
+        let lsns = lm.create_records(1, 5);
+        assert_eq!(fm.stats().writes, writes_before);
+        lm.flush(Some(lsns[2])).unwrap();
+        assert_eq!(fm.stats().writes, writes_before + 1);
+        assert!(lm.shared.state.lock().unwrap().last_saved_lsn >= lsns[4]);
+
+        // the writer writes what's left when the log manager goes away
+        let lsns = lm.create_records(6, 30);
+        drop(lm);
+        let lm = LogManager::new(fm, "db.log").unwrap();
+        assert_eq!(lm.latest_lsn(), *lsns.last().unwrap());
+        assert_eq!(lm.forward_iterator(0).unwrap().count(), 30);
+    }
+
+    /// In-memory backend whose writes block while `stall` is held.
+    struct StalledStorage {
+        inner: FileManager,
+        stall: Mutex<()>,
+    }
+
+    impl StorageBackend for StalledStorage {
+        fn read_block(&self, block: &BlockId, p: &mut Page) -> Result<(), StorageError> {
+            self.inner.read_block(block, p)
+        }
+
+        fn write_block(&self, block: &BlockId, p: &Page) -> Result<(), StorageError> {
+            let _stall = self.stall.lock().unwrap();
+            self.inner.write_block(block, p)
+        }
+
+        fn append(&self, filename: &str) -> Result<BlockId, StorageError> {
+            self.inner.append(filename)
+        }
+
+        fn length(&self, filename: &str) -> Result<u64, StorageError> {
+            self.inner.length(filename)
+        }
+
+        fn truncate(&self, filename: &str, len: u64) -> Result<(), StorageError> {
+            self.inner.truncate(filename, len)
+        }
+
+        fn delete(&self, filename: &str) -> Result<(), StorageError> {
+            self.inner.delete(filename)
+        }
+
+        fn block_size(&self) -> usize {
+            self.inner.block_size()
+        }
+    }
+
+    #[test]
+    fn test_log_buffer() {
+        let storage = Arc::new(StalledStorage {
+            inner: FileManager::in_memory(400),
+            stall: Mutex::new(()),
+        });
+        let mut lm = LogManager::new(storage.clone(), "db.log")
+            .unwrap()
+            .with_buffer_pages(4);
+
+        // appends go on filling pages while the writer is stuck on the first one
+        let stall = storage.stall.lock().unwrap();
+        let lsns = lm.create_records(1, 40);
+        assert_eq!(lsn_block(*lsns.last().unwrap()), 2);
+        drop(stall);
+
+        lm.flush(lsns.last().copied()).unwrap();
+        assert_eq!(storage.length("db.log").unwrap(), 3);
+        assert_eq!(lm.forward_iterator(0).unwrap().count(), 40);
+
+        // pages are reused once written
+        let lsns = lm.create_records(41, 120);
+        lm.flush(lsns.last().copied()).unwrap();
+        assert!(lm.shared.state.lock().unwrap().spare_pages.len() <= 3);
+        let records: Vec<_> = lm
+            .forward_iterator(0)
+            .unwrap()
+            .map(|rec| Page::from(rec.unwrap()).get_string(0).into_owned())
+            .collect();
+        let expected: Vec<String> = (1..=120).map(|i| format!("record{}", i)).collect();
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn test_torn_block() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let mut lm = LogManager::new(fm.clone(), "db.log").unwrap();
+        let lsns = lm.create_records(1, 25);
+        lm.flush(lsns.last().copied()).unwrap();
+        let lsns = [lsns, lm.create_records(26, 30)].concat();
+        drop(lm);
+        assert_eq!(lsn_block(lsns[24]), 1);
+        assert_eq!(lsn_block(lsns[29]), 1);
+
+        // the rewrite adding records 26-30 got as far as the first bytes of record 28
+        let torn = BlockId::new("db.log", 1);
+        let mut p = Page::new(400);
+        fm.read_block(&torn, &mut p).unwrap();
+        let start = |i: usize| 400 - (lsns[i - 1] & u32::MAX as u64) as usize;
+        p.contents_mut()[start(30)..start(28) + 3].fill(0xab);
+        fm.write_block(&torn, &p).unwrap();
+        assert!(matches!(
+            LogIterator::new(fm.clone(), torn.clone()),
+            Err(LogError::Corrupt { .. })
+        ));
+
+        // the records synced before, and those of the rewrite that are intact, are kept
+        let lm = LogManager::new(fm.clone(), "db.log").unwrap();
+        assert!(lm.latest_lsn() > lsns[29]);
+        let lsn = lm.append(b"next").unwrap();
+        assert_eq!(lsn_block(lsn), 2);
+        let records: Vec<_> = lm
+            .forward_records(0)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 28);
+        assert_eq!(records[26].0, lsns[26]);
+        assert_eq!(
+            records[26].1,
+            LogManager::create_log_record("record27", 127)
+        );
+        assert_eq!(&*records[27].1, b"next");
+        assert_eq!(lm.iterator().unwrap().count(), 28);
+    }
+
+    #[test]
+    fn test_lsn_survives_reopen() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let mut lm = LogManager::new(fm.clone(), "db.log").unwrap();
+        lm.create_records(1, 25);
+        let lsn = lm.latest_lsn();
+        assert_eq!(lsn_block(lsn), 1);
+        lm.flush(Some(lsn)).unwrap();
+
+        let lm = LogManager::new(fm, "db.log").unwrap();
+        assert_eq!(lm.latest_lsn(), lsn);
+        assert!(lm.append(b"next").unwrap() > lsn);
+        let records: Vec<_> = lm
+            .forward_iterator(lsn)
+            .unwrap()
+            .map(|rec| rec.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&*records[1], b"next");
+    }
+
+    #[test]
+    fn test_archiver() {
+        let archived = Arc::new(Mutex::new(Vec::new()));
+        let fm = Arc::new(FileManager::in_memory(400));
+        let mut lm = LogManager::new(fm.clone(), "db.log")
+            .unwrap()
+            .with_archiver({
+                let archived = Arc::clone(&archived);
+                Box::new(move |block, contents| {
+                    archived
+                        .lock()
+                        .unwrap()
+                        .push((block.number(), contents.to_vec()));
+                    Ok(())
+                })
+            });
+        lm.create_records(1, 45);
+        lm.flush(Some(lm.latest_lsn())).unwrap();
+
+        // the first two blocks are full, the third one is current
+        let archived = archived.lock().unwrap();
+        assert_eq!(archived.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [0, 1]);
+        let mut p = Page::new(400);
+        fm.read_block(&BlockId::new("db.log", 1), &mut p).unwrap();
+        assert_eq!(archived[1].1, p.contents());
+    }
+
+    #[test]
+    fn test_spilled_records() {
+        let lm = setup(400);
+
+        // bigger than the log buffer's four pages
+        let big: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let small = lm.append(b"before").unwrap();
+        let lsn = lm.append(&big).unwrap();
+        assert!(lsn_block(lsn) >= 8);
+        lm.append(b"after").unwrap();
+
+        let records: Vec<_> = lm.iterator().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records, [&b"after"[..], &big, b"before"].map(Box::from));
+
+        let records: Vec<_> = lm
+            .forward_records(small + 1)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records[0], (lsn, big.clone().into()));
+        assert_eq!(&*records[1].1, b"after");
+        // starting at the record's LSN finds its first fragment blocks before
+        assert_eq!(lm.forward_iterator(lsn).unwrap().count(), 2);
+        assert_eq!(lm.forward_iterator(lsn + 1).unwrap().count(), 1);
+    }
 }
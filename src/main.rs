@@ -1,17 +1,23 @@
-use std::{path::Path, sync::Arc};
+use std::{env, path::PathBuf, process};
 
-use buffer::{BufferManager, EvictionPolicy};
-use file::FileManager;
-use log::LogManager;
-
-mod buffer;
-mod constants;
-mod file;
-mod log;
-mod txn;
+use tracing_subscriber::EnvFilter;
+use willow_db::{Config, WillowDB, WillowError};
 
 fn main() {
-    let fm = Arc::new(FileManager::new(Path::new("testdb"), 1000));
-    let lm = Arc::new(LogManager::new(Arc::clone(&fm), "willowdb.log"));
-    let _bm = BufferManager::new(Arc::clone(&fm), lm, 400, EvictionPolicy::default());
+    // e.g. RUST_LOG=willow_db::txn=debug,willow_db::buffer=trace
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), WillowError> {
+    let config_path = env::var_os("WILLOW_CONFIG").map(PathBuf::from);
+    let config = Config::load(config_path.as_deref())?;
+    let _db = WillowDB::builder().config(&config)?.open("testdb")?;
+    Ok(())
 }
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds (in microseconds) of the latency histogram buckets.
+const BUCKET_BOUNDS_MICROS: [u64; 7] = [10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+/// Latency histogram with fixed exponential buckets.
+#[derive(Default)]
+pub(crate) struct Histogram {
+    /// Non-cumulative counts; the last slot holds observations above every bound.
+    buckets: [AtomicU64; BUCKET_BOUNDS_MICROS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let micros = d.as_micros() as u64;
+        let idx = BUCKET_BOUNDS_MICROS
+            .iter()
+            .position(|&b| micros <= b)
+            .unwrap_or(BUCKET_BOUNDS_MICROS.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = BUCKET_BOUNDS_MICROS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, n)| {
+                cumulative += n.load(Ordering::Relaxed);
+                (Duration::from_micros(bound), cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HistogramSnapshot {
+    /// (upper bound, number of observations <= bound)
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+}
+
+/// I/O performed on one file. Reads and writes count blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileIoStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub fsyncs: u64,
+}
+
+/// I/O performed by a [`crate::FileManager`], returned by [`crate::FileManager::stats`].
+#[derive(Debug, Clone, Default)]
+pub struct FileManagerStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub fsyncs: u64,
+    pub per_file: HashMap<String, FileIoStats>,
+}
+
+impl FileManagerStats {
+    /// Adds the counters of `other`, e.g. a separate log directory's, to these.
+    pub(crate) fn merge(mut self, other: FileManagerStats) -> Self {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.fsyncs += other.fsyncs;
+        for (filename, s) in other.per_file {
+            let entry = self.per_file.entry(filename).or_default();
+            entry.reads += s.reads;
+            entry.writes += s.writes;
+            entry.fsyncs += s.fsyncs;
+        }
+        self
+    }
+}
+
+/// Buffer pool activity, returned by [`crate::WillowDB::buffer_stats`].
+#[derive(Debug, Clone, Default)]
+pub struct BufferManagerStats {
+    /// Frames pinned at the time of the snapshot.
+    pub pinned: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Modified pages written to disk, to reuse their frame or by a flush.
+    pub dirty_writes: u64,
+    /// Pins given up after waiting the whole pin timeout for a frame.
+    pub aborts: u64,
+    /// Time spent by pins that found every frame pinned.
+    pub pin_wait: HistogramSnapshot,
+}
+
+impl BufferManagerStats {
+    /// Fraction of pins served without reading from disk.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+/// Point-in-time view of the engine's counters, returned by [`crate::WillowDB::metrics`].
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub buffer_hits: u64,
+    pub buffer_misses: u64,
+    pub buffer_evictions: u64,
+    pub buffer_dirty_writes: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub active_txns: u64,
+    pub lock_wait: HistogramSnapshot,
+    pub pin_wait: HistogramSnapshot,
+    pub log_flush: HistogramSnapshot,
+}
+
+impl MetricsSnapshot {
+    /// Fraction of pins served without reading from disk.
+    pub fn buffer_hit_ratio(&self) -> f64 {
+        let total = self.buffer_hits + self.buffer_misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.buffer_hits as f64 / total as f64
+    }
+
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "willow_buffer_hits_total",
+                "Pins served from the buffer pool.",
+                self.buffer_hits,
+            ),
+            (
+                "willow_buffer_misses_total",
+                "Pins that had to read the block from disk.",
+                self.buffer_misses,
+            ),
+            (
+                "willow_buffer_evictions_total",
+                "Frames reclaimed by the replacer.",
+                self.buffer_evictions,
+            ),
+            (
+                "willow_buffer_dirty_writes_total",
+                "Modified pages written from the buffer pool.",
+                self.buffer_dirty_writes,
+            ),
+            (
+                "willow_blocks_read_total",
+                "Blocks read from disk.",
+                self.blocks_read,
+            ),
+            (
+                "willow_blocks_written_total",
+                "Blocks written to disk.",
+                self.blocks_written,
+            ),
+        ];
+        for (name, help, val) in counters {
+            writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {val}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "# HELP willow_active_transactions Transactions that have started but not finished.\n\
+             # TYPE willow_active_transactions gauge\n\
+             willow_active_transactions {}",
+            self.active_txns
+        )
+        .unwrap();
+        write_histogram(
+            &mut out,
+            "willow_lock_wait_seconds",
+            "Time spent acquiring block locks.",
+            &self.lock_wait,
+        );
+        write_histogram(
+            &mut out,
+            "willow_pin_wait_seconds",
+            "Time spent waiting for a buffer to be unpinned.",
+            &self.pin_wait,
+        );
+        write_histogram(
+            &mut out,
+            "willow_log_flush_seconds",
+            "Time spent writing log pages to disk.",
+            &self.log_flush,
+        );
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, h: &HistogramSnapshot) {
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram").unwrap();
+    for (bound, n) in &h.buckets {
+        writeln!(out, "{name}_bucket{{le=\"{}\"}} {n}", bound.as_secs_f64()).unwrap();
+    }
+    writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", h.count).unwrap();
+    writeln!(out, "{name}_sum {}", h.sum.as_secs_f64()).unwrap();
+    writeln!(out, "{name}_count {}", h.count).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::default();
+        h.observe(Duration::from_micros(5));
+        h.observe(Duration::from_micros(50));
+        h.observe(Duration::from_millis(5));
+        h.observe(Duration::from_secs(20));
+
+        let snap = h.snapshot();
+        assert_eq!(snap.count, 4);
+        assert_eq!(snap.buckets[0], (Duration::from_micros(10), 1));
+        assert_eq!(snap.buckets[1], (Duration::from_micros(100), 2));
+        assert_eq!(snap.buckets[3], (Duration::from_millis(10), 3));
+        // the 20s observation only shows up in the +Inf bucket
+        assert_eq!(snap.buckets.last().unwrap().1, 3);
+
+        let metrics = MetricsSnapshot {
+            buffer_hits: 3,
+            buffer_misses: 1,
+            log_flush: snap,
+            ..Default::default()
+        };
+        assert_eq!(metrics.buffer_hit_ratio(), 0.75);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("willow_buffer_hits_total 3\n"));
+        assert!(text.contains("willow_log_flush_seconds_bucket{le=\"0.00001\"} 1\n"));
+        assert!(text.contains("willow_log_flush_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("willow_log_flush_seconds_count 4\n"));
+    }
+}
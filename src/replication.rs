@@ -0,0 +1,366 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    log::{LogError, LogManager, Lsn},
+    txn::{LogRecord, Transaction, TransactionManager, TxNum, TxnError},
+};
+
+/// How often a standby looks for received records to apply.
+const APPLY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    #[error("replication stream failed")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Log(#[from] LogError),
+    #[error("the database isn't a standby")]
+    NotStandby,
+    #[error("log record shipped for LSN {expected} was appended at LSN {found}")]
+    Diverged { expected: Lsn, found: Lsn },
+}
+
+/// Streams the records of a primary's log to a [`LogReceiver`].
+///
+/// Records are sent in append order, each framed by its length as a little-endian u32 and its
+/// LSN as a little-endian u64. The shipper remembers how far it got, so each call to
+/// [`LogShipper::ship`] only sends what was appended since the previous one.
+pub struct LogShipper {
+    lm: Arc<LogManager>,
+    /// LSN from which records haven't been sent yet.
+    next: Lsn,
+}
+
+impl LogShipper {
+    pub(crate) fn new(lm: Arc<LogManager>) -> Self {
+        Self { lm, next: 0 }
+    }
+
+    /// Writes the records appended since the last call to `w`. Returns the number of records sent.
+    pub fn ship(&mut self, w: &mut impl Write) -> Result<usize, ReplicationError> {
+        let mut sent = 0;
+        for rec in self.lm.forward_records(self.next)? {
+            let (lsn, bytes) = rec?;
+            w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            w.write_all(&lsn.to_le_bytes())?;
+            w.write_all(&bytes)?;
+            self.next = lsn + 1;
+            sent += 1;
+        }
+        w.flush()?;
+        Ok(sent)
+    }
+
+    /// Connects to `addr` and ships new records every `interval` until `stop` is set or the
+    /// connection fails.
+    pub fn stream_to(
+        mut self,
+        addr: impl ToSocketAddrs,
+        interval: Duration,
+        stop: &AtomicBool,
+    ) -> Result<(), ReplicationError> {
+        let stream = TcpStream::connect(addr)?;
+        info!(peer = ?stream.peer_addr().ok(), "log shipping started");
+        let mut w = BufWriter::new(stream);
+        while !stop.load(Ordering::Relaxed) {
+            let n = self.ship(&mut w)?;
+            if n > 0 {
+                debug!(records = n, "shipped log records");
+            }
+            thread::sleep(interval);
+        }
+        Ok(())
+    }
+}
+
+/// Appends the records sent by a [`LogShipper`] to a standby's log.
+///
+/// The standby's log mirrors the primary's, LSN for LSN, so it can take over its history.
+/// Records the standby already has are skipped, so a shipper may start over from the first
+/// record, e.g. after the primary restarted. The receiver refuses records once the standby is
+/// promoted, see [`WillowDB::promote`](crate::WillowDB::promote).
+pub struct LogReceiver {
+    lm: Arc<LogManager>,
+    /// Cleared when the database is promoted.
+    standby: Arc<AtomicBool>,
+}
+
+impl LogReceiver {
+    pub(crate) fn new(lm: Arc<LogManager>, standby: Arc<AtomicBool>) -> Self {
+        Self { lm, standby }
+    }
+
+    /// Appends every record read from `r` until it's exhausted and flushes the log after
+    /// each batch. Returns the number of records received.
+    ///
+    /// Fails if a record doesn't land at the LSN it had on the primary, e.g. because the
+    /// standby wasn't created empty or from a backup of the primary, or uses another block size.
+    pub fn receive(&self, r: impl Read) -> Result<usize, ReplicationError> {
+        let mut r = BufReader::new(r);
+        let mut received = 0;
+        let mut last = None;
+        loop {
+            let mut len = [0; 4];
+            match r.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let mut lsn = [0; 8];
+            r.read_exact(&mut lsn)?;
+            let lsn = Lsn::from_le_bytes(lsn);
+            let mut record = vec![0; u32::from_le_bytes(len) as usize];
+            r.read_exact(&mut record)?;
+            if !self.standby.load(Ordering::SeqCst) {
+                return Err(ReplicationError::NotStandby);
+            }
+            if lsn <= self.lm.latest_lsn() {
+                continue;
+            }
+            let found = self.lm.append(&record)?;
+            if found != lsn {
+                return Err(ReplicationError::Diverged {
+                    expected: lsn,
+                    found,
+                });
+            }
+            last = Some(found);
+            received += 1;
+            if r.buffer().is_empty() {
+                // the shipper has nothing more for now
+                self.lm.flush(last)?;
+            }
+        }
+        self.lm.flush(last)?;
+        Ok(received)
+    }
+
+    /// Accepts a single shipper on `listener` and receives its records until it disconnects.
+    pub fn listen(&self, listener: &TcpListener) -> Result<usize, ReplicationError> {
+        let (stream, peer) = listener.accept()?;
+        info!(%peer, "receiving shipped log records");
+        self.receive(stream)
+    }
+}
+
+/// Applies the records a standby receives to its data files.
+///
+/// Each of the primary's transactions is replayed by a transaction of its own, which keeps the
+/// locks of the blocks it changed until the primary's commit or rollback record is applied.
+/// Readers on the standby thus wait for the primary's transactions to finish, as they would on
+/// the primary, and never see uncommitted changes. Records are redone whatever the outcome of
+/// their transaction, like rollbacks' compensation records, and page LSNs make redoing a
+/// record twice harmless, so replay starts from the first record on every open.
+pub(crate) struct Replay {
+    tm: Arc<TransactionManager>,
+    lm: Arc<LogManager>,
+    /// LSN from which records haven't been applied yet.
+    next: Lsn,
+    /// The transactions replaying the primary's unfinished ones, by the primary's number.
+    txns: HashMap<TxNum, Transaction>,
+}
+
+impl Replay {
+    pub fn new(tm: Arc<TransactionManager>, lm: Arc<LogManager>) -> Self {
+        Self {
+            tm,
+            lm,
+            next: 0,
+            txns: HashMap::new(),
+        }
+    }
+
+    /// Applies the records received since the last call. Returns the number of records applied.
+    ///
+    /// Stops at the first record that can't be applied, e.g. because a reader holds a lock on
+    /// its block for longer than the lock timeout; the next call starts over from it.
+    pub fn apply(&mut self) -> Result<usize, TxnError> {
+        let mut applied = 0;
+        for rec in self.lm.forward_records(self.next)? {
+            let (lsn, bytes) = rec?;
+            let record = LogRecord::new(&bytes).ok_or(TxnError::CorruptLogRecord)?;
+            match &record {
+                // transaction numbers start over when the primary is reopened
+                LogRecord::Start { txn_num }
+                | LogRecord::Commit { txn_num, .. }
+                | LogRecord::Rollback { txn_num } => self.finish(*txn_num)?,
+                // written after recovery, which finished whatever was left
+                LogRecord::Checkpoint {} => self.finish_all()?,
+                LogRecord::CheckpointBegin { .. } | LogRecord::CheckpointEnd {} => {}
+                LogRecord::Update { txn_num, .. }
+                | LogRecord::Compensation { txn_num, .. }
+                | LogRecord::Operation { txn_num, .. }
+                | LogRecord::OperationCompensation { txn_num, .. } => {
+                    let txn = match self.txns.entry(*txn_num) {
+                        Entry::Occupied(e) => e.into_mut(),
+                        Entry::Vacant(e) => e.insert(self.tm.create_replica_txn()?),
+                    };
+                    // even if the change is on the page already, e.g. in a backup
+                    if let Some(block) = record.block() {
+                        txn.x_lock(block)?;
+                    }
+                    record.redo(lsn, txn)?;
+                }
+            }
+            self.next = lsn + 1;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Releases the locks of the transaction replaying the primary's `txn_num`, if any.
+    fn finish(&mut self, txn_num: TxNum) -> Result<(), TxnError> {
+        match self.txns.remove(&txn_num) {
+            Some(mut txn) => txn.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Releases the locks of every replaying transaction, e.g. before the standby is promoted
+    /// and recovery rolls back the primary's unfinished transactions.
+    pub fn finish_all(&mut self) -> Result<(), TxnError> {
+        for (_, mut txn) in self.txns.drain() {
+            txn.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a [`Replay`] on a background thread, applying received records every
+/// [`APPLY_INTERVAL`]. The thread stops when the applier is dropped.
+pub(crate) struct Applier {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<Replay>>,
+}
+
+impl Applier {
+    pub fn start(replay: Replay) -> io::Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let handle = thread::Builder::new()
+            .name("willow-apply".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || run(replay, &stop)
+            })?;
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stops the thread and hands back its replay, or `None` if the thread panicked.
+    pub fn stop(mut self) -> Option<Replay> {
+        self.signal_stop();
+        self.handle.take()?.join().ok()
+    }
+
+    fn signal_stop(&self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+}
+
+fn run(mut replay: Replay, stop: &(Mutex<bool>, Condvar)) -> Replay {
+    let (lock, cvar) = stop;
+    let mut stopped = lock.lock().unwrap();
+    loop {
+        (stopped, _) = cvar
+            .wait_timeout_while(stopped, APPLY_INTERVAL, |stopped| !*stopped)
+            .unwrap();
+        if *stopped {
+            return replay;
+        }
+        match replay.apply() {
+            Ok(0) => {}
+            Ok(records) => trace!(records, "applied received log records"),
+            Err(e) if e.is_retryable() => {
+                debug!(error = %e, "applying log records will be retried")
+            }
+            Err(e) => warn!(error = %e, "failed to apply received log records"),
+        }
+    }
+}
+
+impl Drop for Applier {
+    fn drop(&mut self) {
+        self.signal_stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::FileManager;
+
+    use super::*;
+
+    #[test]
+    fn test_log_shipping() {
+        let primary = Arc::new(
+            LogManager::new(Arc::new(FileManager::in_memory(400)), "primary.log").unwrap(),
+        );
+        let standby = Arc::new(
+            LogManager::new(Arc::new(FileManager::in_memory(400)), "standby.log").unwrap(),
+        );
+        let mut shipper = LogShipper::new(Arc::clone(&primary));
+        let is_standby = Arc::new(AtomicBool::new(true));
+        let receiver = LogReceiver::new(Arc::clone(&standby), Arc::clone(&is_standby));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || receiver.listen(&listener).unwrap());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for i in 0..30u8 {
+            primary.append(&[i; 20]).unwrap();
+        }
+        assert_eq!(shipper.ship(&mut stream).unwrap(), 30);
+        assert_eq!(shipper.ship(&mut stream).unwrap(), 0);
+        for i in 30..50u8 {
+            primary.append(&[i; 20]).unwrap();
+        }
+        assert_eq!(shipper.ship(&mut stream).unwrap(), 20);
+        drop(stream);
+        assert_eq!(handle.join().unwrap(), 50);
+
+        let shipped: Vec<_> = standby
+            .forward_iterator(0)
+            .unwrap()
+            .map(|rec| rec.unwrap())
+            .collect();
+        let expected: Vec<Box<[u8]>> = (0..50u8).map(|i| vec![i; 20].into()).collect();
+        assert_eq!(shipped, expected);
+
+        // shipping everything again only adds what the standby is missing
+        primary.append(&[50; 20]).unwrap();
+        let mut stream = Vec::new();
+        LogShipper::new(Arc::clone(&primary))
+            .ship(&mut stream)
+            .unwrap();
+        let receiver = LogReceiver::new(Arc::clone(&standby), Arc::clone(&is_standby));
+        assert_eq!(receiver.receive(&stream[..]).unwrap(), 1);
+        assert_eq!(standby.latest_lsn(), primary.latest_lsn());
+
+        is_standby.store(false, Ordering::SeqCst);
+        assert!(matches!(
+            receiver.receive(&stream[..]),
+            Err(ReplicationError::NotStandby)
+        ));
+    }
+}
@@ -1,8 +1,6 @@
-#![allow(dead_code)]
+use std::{collections::HashMap, sync::Arc};
 
-use std::collections::HashMap;
-
-use super::{lock_table::LockTable, TxNum};
+use super::{lock_table::LockTable, transaction::TxnError, TxNum};
 use crate::file::BlockId;
 
 enum LockType {
@@ -10,58 +8,53 @@ enum LockType {
     S,
 }
 
+/// The locks of one transaction. Requests it already holds a lock for don't go to the shared
+/// [`LockTable`].
 pub(super) struct ConcurrencyManager {
-    lock_tbl: LockTable,
-    locks: HashMap<TxNum, HashMap<BlockId, LockType>>,
+    txn_num: TxNum,
+    lock_tbl: Arc<LockTable>,
+    locks: HashMap<BlockId, LockType>,
 }
 
 impl ConcurrencyManager {
-    pub fn new() -> Self {
+    pub fn new(txn_num: TxNum, lock_tbl: Arc<LockTable>) -> Self {
         Self {
-            lock_tbl: LockTable::new(),
+            txn_num,
+            lock_tbl,
             locks: HashMap::new(),
         }
     }
 
     /// Acquires a shared lock on the block if no lock is already present.
-    pub fn s_lock(&mut self, txn_num: TxNum, block: &BlockId) {
-        let entry = self.locks.entry(txn_num).or_default();
-        if !entry.contains_key(block) {
-            self.lock_tbl
-                .s_lock(txn_num, block)
-                .expect("slock to be acquired");
-            entry.insert(block.to_owned(), LockType::S);
+    pub fn s_lock(&mut self, block: &BlockId) -> Result<(), TxnError> {
+        if !self.locks.contains_key(block) {
+            self.lock_tbl.s_lock(self.txn_num, block)?;
+            self.locks.insert(block.to_owned(), LockType::S);
         }
+        Ok(())
     }
 
-    /// Acquires an exclusive lock on the block if no exclusive lock is already present.
-    pub fn x_lock(&mut self, txn_num: TxNum, block: &BlockId) {
-        if !self.has_x_lock(txn_num, block) {
-            self.s_lock(txn_num, block);
-            self.lock_tbl
-                .x_lock(txn_num, block)
-                .expect("xlock to be acquired");
-            self.locks
-                .entry(txn_num)
-                .or_default()
-                .insert(block.to_owned(), LockType::X);
+    /// Acquires an exclusive lock on the block if no exclusive lock is already present, upgrading
+    /// the shared lock if there is one.
+    pub fn x_lock(&mut self, block: &BlockId) -> Result<(), TxnError> {
+        if !self.has_x_lock(block) {
+            // without a shared lock first, or two transactions could both hold one and wait
+            // for each other to give it up
+            self.lock_tbl.x_lock(self.txn_num, block)?;
+            self.locks.insert(block.to_owned(), LockType::X);
         };
+        Ok(())
     }
 
     /// Releases all locks held by the transaction.
-    pub fn release(&mut self, txn_num: TxNum) {
-        if let Some(map) = self.locks.get(&txn_num) {
-            for block in map.keys() {
-                self.lock_tbl.unlock(txn_num, block);
-            }
+    pub fn release(&mut self) {
+        for block in self.locks.keys() {
+            self.lock_tbl.unlock(self.txn_num, block);
         }
-        self.locks.remove(&txn_num);
+        self.locks.clear();
     }
 
-    fn has_x_lock(&self, txn_num: TxNum, block: &BlockId) -> bool {
-        matches!(
-            self.locks.get(&txn_num).and_then(|m| m.get(block)),
-            Some(LockType::X)
-        )
+    fn has_x_lock(&self, block: &BlockId) -> bool {
+        matches!(self.locks.get(block), Some(LockType::X))
     }
 }
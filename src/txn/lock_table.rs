@@ -1,115 +1,186 @@
-#![allow(dead_code)]
-
 use std::{
-    collections::HashMap,
-    sync::{Condvar, Mutex, MutexGuard},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
     time::{Duration, Instant},
 };
 
+use tracing::{debug, warn};
+
 use crate::file::BlockId;
 
-use super::transaction::TxNum;
+use super::transaction::{TxNum, TxnError, TxnStats};
+
+/// Default duration a lock request waits before being aborted.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The transactions holding locks on a block. A transaction holding the exclusive lock may
+/// also be among the shared holders, from before it upgraded its lock.
+#[derive(Default)]
+struct Holders {
+    shared: HashSet<TxNum>,
+    exclusive: Option<TxNum>,
+}
 
-const MAX_TIME: Duration = Duration::from_secs(10);
+impl Holders {
+    /// Whether another transaction holds the exclusive lock.
+    fn blocks_shared(&self, txn_num: TxNum) -> bool {
+        self.exclusive.is_some_and(|t| t != txn_num)
+    }
 
-enum Lock {
-    /// Exclusive lock
-    XLock,
-    /// Shared lock with lock count
-    SLock(usize),
+    /// Whether another transaction holds any lock.
+    fn blocks_exclusive(&self, txn_num: TxNum) -> bool {
+        self.blocks_shared(txn_num) || self.shared.iter().any(|&t| t != txn_num)
+    }
 }
 
+/// The locks every transaction holds, by block, shared by all of them so that conflicting
+/// requests wait for each other.
 pub(super) struct LockTable {
-    locks: Mutex<HashMap<TxNum, HashMap<BlockId, Lock>>>,
+    locks: Mutex<HashMap<BlockId, Holders>>,
     cvar: Condvar,
+    timeout: Duration,
+    stats: Arc<TxnStats>,
 }
 
-type LockGuard<'a> = MutexGuard<'a, HashMap<TxNum, HashMap<BlockId, Lock>>>;
-
 impl LockTable {
-    pub fn new() -> Self {
+    pub fn new(timeout: Duration, stats: Arc<TxnStats>) -> Self {
         Self {
             locks: Mutex::new(HashMap::new()),
             cvar: Condvar::new(),
+            timeout,
+            stats,
         }
     }
 
-    /// Tries to acquire a shared lock on the specified block.
-    /// If return value is `true` then lock was acquired.
-    pub fn s_lock(&self, txn_num: TxNum, block: &BlockId) -> Result<(), &str> {
-        let mut map = self.locks.lock().unwrap();
-        let start = Instant::now();
-
-        while Self::has_x_lock(&map, txn_num, block) && !Self::waiting_too_long(start) {
-            let (guard, _) = self.cvar.wait_timeout(map, MAX_TIME).unwrap();
-            map = guard;
-        }
-
-        if Self::has_x_lock(&map, txn_num, block) {
-            return Err("lock aborted");
-        }
-
-        let new_val = match map.get(&txn_num).and_then(|x| x.get(block)) {
-            Some(Lock::SLock(n)) => Lock::SLock(n + 1),
-            _ => Lock::SLock(1),
-        };
-        map.entry(txn_num)
+    /// Acquires a shared lock on `block`, waiting while another transaction holds an
+    /// exclusive one.
+    /// Returns [`TxnError::LockAbort`] if the lock couldn't be acquired within the timeout.
+    pub fn s_lock(&self, txn_num: TxNum, block: &BlockId) -> Result<(), TxnError> {
+        let mut map = self.wait_for(txn_num, block, "shared", Holders::blocks_shared)?;
+        map.entry(block.to_owned())
             .or_default()
-            .insert(block.to_owned(), new_val);
+            .shared
+            .insert(txn_num);
+        Ok(())
+    }
 
+    /// Acquires an exclusive lock on `block`, waiting while another transaction holds any
+    /// lock on it. A shared lock of the same transaction is upgraded.
+    /// Returns [`TxnError::LockAbort`] if the lock couldn't be acquired within the timeout.
+    pub fn x_lock(&self, txn_num: TxNum, block: &BlockId) -> Result<(), TxnError> {
+        let mut map = self.wait_for(txn_num, block, "exclusive", Holders::blocks_exclusive)?;
+        map.entry(block.to_owned()).or_default().exclusive = Some(txn_num);
         Ok(())
     }
 
-    /// Tries to acquire an exclusive lock on the specified block.
-    /// If return value is `true` then lock was acquired.
-    ///
-    /// This method assumes that a shared lock has already been acquired for the block.
-    pub fn x_lock(&self, txn_num: TxNum, block: &BlockId) -> Result<(), &str> {
-        let mut map = self.locks.lock().unwrap();
+    /// Waits until the holders of `block` no longer conflict with `txn_num` and returns the
+    /// table, still locked.
+    fn wait_for(
+        &self,
+        txn_num: TxNum,
+        block: &BlockId,
+        kind: &str,
+        conflicts: impl Fn(&Holders, TxNum) -> bool,
+    ) -> Result<MutexGuard<'_, HashMap<BlockId, Holders>>, TxnError> {
         let start = Instant::now();
-
-        while Self::has_other_s_locks(&map, txn_num, block) && !Self::waiting_too_long(start) {
-            let (guard, _) = self.cvar.wait_timeout(map, MAX_TIME).unwrap();
-            map = guard;
+        let blocked =
+            |map: &HashMap<BlockId, Holders>| map.get(block).is_some_and(|h| conflicts(h, txn_num));
+        let mut map = self.locks.lock().unwrap();
+        if blocked(&map) {
+            debug!(txn_num, %block, kind, "waiting for lock");
+            (map, _) = self
+                .cvar
+                .wait_timeout_while(map, self.timeout, |map| blocked(map))
+                .unwrap();
         }
+        self.stats.lock_wait.observe(start.elapsed());
 
-        if Self::has_other_s_locks(&map, txn_num, block) {
-            return Err("lock aborted");
+        if blocked(&map) {
+            warn!(txn_num, %block, kind, waited = ?start.elapsed(), "lock request aborted");
+            return Err(TxnError::LockAbort(block.clone()));
         }
-
-        map.entry(txn_num)
-            .or_default()
-            .insert(block.to_owned(), Lock::XLock);
-
-        Ok(())
+        Ok(map)
     }
 
-    /// Releases a lock on the specified block.
+    /// Releases the locks `txn_num` holds on `block`.
     pub fn unlock(&self, txn_num: TxNum, block: &BlockId) {
         let mut map = self.locks.lock().unwrap();
-        map.entry(txn_num).and_modify(|x| match x.get(block) {
-            Some(Lock::SLock(n)) if *n > 1 => {
-                let new_val = Lock::SLock(*n - 1);
-                x.insert(block.to_owned(), new_val);
-            }
-            _ => {
-                x.remove(block);
-                self.cvar.notify_all();
-            }
-        });
+        let Some(holders) = map.get_mut(block) else {
+            return;
+        };
+        holders.shared.remove(&txn_num);
+        if holders.exclusive == Some(txn_num) {
+            holders.exclusive = None;
+        }
+        if holders.shared.is_empty() && holders.exclusive.is_none() {
+            map.remove(block);
+        }
+        self.cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn lock_table(timeout: Duration) -> Arc<LockTable> {
+        Arc::new(LockTable::new(timeout, Arc::new(TxnStats::default())))
     }
 
-    fn has_x_lock(map: &LockGuard, txn_num: TxNum, block: &BlockId) -> bool {
-        map.get(&txn_num)
-            .is_some_and(|x| matches!(x.get(block), Some(Lock::XLock)))
+    #[test]
+    fn test_shared_locks() {
+        let lt = lock_table(Duration::from_millis(20));
+        let block = BlockId::new("testfile", 1);
+        lt.s_lock(1, &block).unwrap();
+        lt.s_lock(2, &block).unwrap();
+        // a shared lock held by another transaction blocks an upgrade
+        assert!(matches!(lt.x_lock(1, &block), Err(TxnError::LockAbort(_))));
+        lt.unlock(2, &block);
+        lt.x_lock(1, &block).unwrap();
+        assert!(matches!(lt.s_lock(2, &block), Err(TxnError::LockAbort(_))));
+        // locks on other blocks don't conflict
+        lt.x_lock(2, &BlockId::new("testfile", 2)).unwrap();
     }
 
-    fn has_other_s_locks(map: &LockGuard, txn_num: TxNum, block: &BlockId) -> bool {
-        map.get(&txn_num)
-            .is_some_and(|x| matches!(x.get(block), Some(Lock::SLock(n)) if *n > 1))
+    #[test]
+    fn test_writer_blocks_writer() {
+        let lt = lock_table(Duration::from_secs(5));
+        let block = BlockId::new("testfile", 1);
+        lt.x_lock(1, &block).unwrap();
+
+        let handle = thread::spawn({
+            let lt = Arc::clone(&lt);
+            let block = block.clone();
+            move || {
+                let start = Instant::now();
+                lt.x_lock(2, &block).unwrap();
+                start.elapsed()
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        lt.unlock(1, &block);
+        assert!(handle.join().unwrap() >= Duration::from_millis(50));
     }
 
-    fn waiting_too_long(start: Instant) -> bool {
-        start.elapsed() >= MAX_TIME
+    #[test]
+    fn test_writer_blocks_reader() {
+        let lt = lock_table(Duration::from_secs(5));
+        let block = BlockId::new("testfile", 1);
+        lt.x_lock(1, &block).unwrap();
+
+        let handle = thread::spawn({
+            let lt = Arc::clone(&lt);
+            let block = block.clone();
+            move || {
+                let start = Instant::now();
+                lt.s_lock(2, &block).unwrap();
+                start.elapsed()
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        lt.unlock(1, &block);
+        assert!(handle.join().unwrap() >= Duration::from_millis(50));
     }
 }
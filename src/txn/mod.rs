@@ -3,4 +3,8 @@ mod lock_table;
 mod recovery;
 mod transaction;
 
-pub use transaction::TxNum;
+pub(crate) use lock_table::DEFAULT_LOCK_TIMEOUT;
+pub(crate) use recovery::RecoveryObserver;
+pub use recovery::{LogRecord, RecoveryPhase, RecoveryProgress, RecoveryTarget, UpdateValue};
+pub(crate) use transaction::TransactionManager;
+pub use transaction::{Transaction, TxNum, TxnError};
@@ -1,30 +1,114 @@
 #![allow(dead_code)]
 
 use std::{
+    collections::{HashMap, HashSet},
     fmt,
-    sync::{Arc, RwLockReadGuard},
+    sync::{Arc, Mutex, RwLockReadGuard},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use tracing::{info, warn};
+
 use crate::{
     buffer::{Buffer, BufferManager},
-    constants::SIZE_OF_INT,
+    constants::{SIZE_OF_DOUBLE, SIZE_OF_INT, SIZE_OF_LONG},
     file::{BlockId, Page},
-    log::{LogManager, Lsn},
+    log::{LogError, LogManager, Lsn},
 };
 
-use super::transaction::{Transaction, TxNum};
+use super::transaction::{ActiveTxns, Transaction, TxNum, TxnError};
+
+/// Where point-in-time recovery stops replaying the log, see [`crate::Builder::recovery_target`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Keeps the records up to and including this LSN.
+    Lsn(Lsn),
+    /// Keeps the records before the first commit after this time.
+    Time(SystemTime),
+}
+
+/// The passes recovery makes over the log, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryPhase {
+    /// Reading the log back to find where recovery starts and which transactions committed.
+    Analysis,
+    /// Reapplying committed updates and compensation records, oldest first.
+    Redo,
+    /// Rolling back the transactions that didn't finish, latest first.
+    Undo,
+}
+
+/// How far recovery got, reported after every record it handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub phase: RecoveryPhase,
+    /// Records handled so far in this phase.
+    pub records: usize,
+    /// Records the phase handles in all, once known: not during analysis.
+    pub total: Option<usize>,
+    /// LSN of the record just handled.
+    pub lsn: Lsn,
+}
+
+/// Called with recovery's progress, see [`crate::Builder::recovery_observer`].
+pub(crate) type RecoveryObserver = Box<dyn Fn(&RecoveryProgress) + Send + Sync>;
+
+/// Redo and undo of the operations a subsystem outside the transaction layer logs, e.g. index
+/// splits and merges, as [`LogRecord::Operation`] records tagged with the subsystem's kind.
+///
+/// A subsystem defines its own recoverable operations by registering a handler for its kind with
+/// [`TransactionManager::with_record_handler`](super::transaction::TransactionManager::with_record_handler)
+/// and performing them with [`Transaction::perform`]; `data` is whatever it needs to describe
+/// one.
+pub(crate) trait RecordHandler: Send + Sync {
+    /// Applies the operation `data` logged at `lsn`. Recovery calls it again for operations
+    /// that may already be applied, so it should skip pages whose LSN is at least `lsn`, and
+    /// stamp `lsn` on the pages it changes. Returns whether anything was applied.
+    fn redo(&self, txn: &mut Transaction, lsn: Lsn, data: &[u8]) -> Result<bool, TxnError>;
+
+    /// Describes the operation that reverses `data`, e.g. a merge for a split. It's logged as
+    /// a [`LogRecord::OperationCompensation`] and applied with [`RecordHandler::redo`].
+    fn undo(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// The registered [`RecordHandler`]s by kind.
+pub(crate) type RecordHandlers = Arc<HashMap<u16, Arc<dyn RecordHandler>>>;
+
+/// Microseconds since the Unix epoch, as stored in commit records.
+fn micros_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as i64)
+}
 
 pub(super) struct RecoveryManager {}
 
 impl RecoveryManager {
-    pub fn start(lm: &Arc<LogManager>, txn_num: TxNum) {
-        LogRecord::Start { txn_num }.write_to_log(lm);
+    pub fn start(lm: &Arc<LogManager>, txn_num: TxNum) -> Result<Lsn, LogError> {
+        LogRecord::Start { txn_num }.write_to_log(lm)
     }
 
-    pub fn commit(bm: &Arc<BufferManager>, lm: &Arc<LogManager>, txn_num: TxNum) {
-        bm.flush_all(txn_num);
-        let lsn = LogRecord::Commit { txn_num }.write_to_log(lm);
-        lm.flush(Some(lsn));
+    /// Logs the operation `data` of the subsystem registered for `kind`.
+    pub fn perform(
+        lm: &Arc<LogManager>,
+        txn_num: TxNum,
+        kind: u16,
+        data: &[u8],
+    ) -> Result<Lsn, LogError> {
+        LogRecord::Operation {
+            txn_num,
+            kind,
+            data: data.to_vec(),
+        }
+        .write_to_log(lm)
+    }
+
+    /// Writes a commit record and waits for it to be durable. The transaction's pages aren't
+    /// forced: recovery redoes the changes of committed transactions from the log.
+    pub fn commit(lm: &Arc<LogManager>, txn_num: TxNum) -> Result<(), TxnError> {
+        let time = micros_since_epoch(SystemTime::now());
+        let lsn = LogRecord::Commit { txn_num, time }.write_to_log(lm)?;
+        lm.flush(Some(lsn))?;
+        Ok(())
     }
 
     pub fn rollback(
@@ -32,24 +116,95 @@ impl RecoveryManager {
         lm: &Arc<LogManager>,
         txn_num: TxNum,
         txn: &mut Transaction,
-    ) {
-        Self::do_rollback(lm, txn_num, txn);
+    ) -> Result<(), TxnError> {
+        Self::do_rollback(lm, txn_num, txn)?;
 
-        bm.flush_all(txn_num);
-        let lsn = LogRecord::Rollback { txn_num }.write_to_log(lm);
-        lm.flush(Some(lsn));
+        bm.flush_all(txn_num)?;
+        let lsn = LogRecord::Rollback { txn_num }.write_to_log(lm)?;
+        lm.flush(Some(lsn))?;
+        Ok(())
     }
 
+    /// Recovers from the last checkpoint, or from the oldest record in the log if `whole_log`
+    /// is set, then writes a checkpoint. `observer` is told about every record handled.
     pub fn recover(
         bm: &Arc<BufferManager>,
         lm: &Arc<LogManager>,
         txn_num: TxNum,
         txn: &mut Transaction,
-    ) {
-        Self::do_recover(lm, txn);
-        bm.flush_all(txn_num);
-        let lsn = LogRecord::Checkpoint {}.write_to_log(lm);
-        lm.flush(Some(lsn));
+        whole_log: bool,
+        observer: Option<&RecoveryObserver>,
+    ) -> Result<(), TxnError> {
+        info!(whole_log, "recovery: started");
+        Self::do_recover(lm, txn, whole_log, observer)?;
+        info!("recovery: finished");
+        bm.flush_all(txn_num)?;
+        let lsn = LogRecord::Checkpoint {}.write_to_log(lm)?;
+        lm.flush(Some(lsn))?;
+        info!(lsn, "recovery: checkpoint written");
+        Ok(())
+    }
+
+    /// Drops the log records past `target`. Returns the LSN of the last record kept, or 0 if
+    /// none is.
+    pub fn truncate_to(lm: &Arc<LogManager>, target: RecoveryTarget) -> Result<Lsn, TxnError> {
+        let mut end = 0;
+        for rec in lm.forward_records(0)? {
+            let (lsn, bytes) = rec?;
+            let past = match target {
+                RecoveryTarget::Lsn(target) => lsn > target,
+                RecoveryTarget::Time(target) => matches!(
+                    LogRecord::new(&bytes).ok_or(TxnError::CorruptLogRecord)?,
+                    LogRecord::Commit { time, .. } if time > micros_since_epoch(target)
+                ),
+            };
+            if past {
+                break;
+            }
+            end = lsn;
+        }
+        lm.truncate(end)?;
+        info!(lsn = end, ?target, "recovery: log truncated at the target");
+        Ok(end)
+    }
+
+    /// Writes a fuzzy checkpoint: a begin record with the active transaction table and the
+    /// dirty page table, then an end record. Nothing is flushed and transactions keep running
+    /// meanwhile.
+    ///
+    /// `active` is locked while the begin record is written, so that a transaction either is
+    /// in the table or starts after the begin record.
+    pub fn checkpoint(
+        bm: &Arc<BufferManager>,
+        lm: &Arc<LogManager>,
+        active: &Mutex<ActiveTxns>,
+    ) -> Result<(), TxnError> {
+        let begin = {
+            let active = active.lock().unwrap();
+            LogRecord::CheckpointBegin {
+                active: active.txns.iter().map(|(t, lsn)| (*t, *lsn)).collect(),
+                dirty: bm.dirty_pages(),
+            }
+            .write_to_log(lm)?
+        };
+        let lsn = LogRecord::CheckpointEnd {}.write_to_log(lm)?;
+        lm.flush(Some(lsn))?;
+        info!(begin, lsn, "checkpoint written");
+        Ok(())
+    }
+
+    /// Writes all dirty buffers to disk followed by a checkpoint record.
+    ///
+    /// Callers must ensure that no transaction is active.
+    pub fn quiescent_checkpoint(
+        bm: &Arc<BufferManager>,
+        lm: &Arc<LogManager>,
+    ) -> Result<(), TxnError> {
+        bm.flush_all_dirty()?;
+        let lsn = LogRecord::Checkpoint {}.write_to_log(lm)?;
+        lm.flush(Some(lsn))?;
+        info!(lsn, "quiescent checkpoint written");
+        Ok(())
     }
 
     pub fn set_update(
@@ -58,57 +213,241 @@ impl RecoveryManager {
         buf: RwLockReadGuard<Buffer>,
         offset: usize,
         new_val: UpdateValue,
-    ) -> Lsn {
-        let old_val = match new_val {
-            UpdateValue::INT(_) => UpdateValue::INT(buf.contents().get_int(offset)),
-            UpdateValue::STRING(_) => {
-                UpdateValue::STRING(buf.contents().get_string(offset).into_owned())
-            }
+    ) -> Result<Lsn, TxnError> {
+        let p = buf.contents();
+        let old_val = match &new_val {
+            UpdateValue::INT(_) => UpdateValue::INT(p.try_get_int(offset)?),
+            UpdateValue::STRING(_) => UpdateValue::STRING(p.try_get_str(offset)?.to_owned()),
+            UpdateValue::LONG(_) => UpdateValue::LONG(p.try_get_long(offset)?),
+            UpdateValue::DOUBLE(_) => UpdateValue::DOUBLE(p.try_get_double(offset)?),
+            UpdateValue::BYTES(b) => UpdateValue::BYTES(p.try_get_raw(offset, b.len())?.to_vec()),
+            UpdateValue::DATE(_) => UpdateValue::DATE(p.try_get_date(offset)?),
+            UpdateValue::TIMESTAMP(_) => UpdateValue::TIMESTAMP(p.try_get_timestamp(offset)?),
         };
         let block = buf.block().unwrap().clone();
-        LogRecord::Update {
+        let lsn = LogRecord::Update {
             value: old_val,
+            new_value: new_val,
             txn_num,
             offset,
             block,
         }
-        .write_to_log(lm)
+        .write_to_log(lm)?;
+        Ok(lsn)
     }
 
-    fn do_rollback(lm: &Arc<LogManager>, txn_num: TxNum, txn: &mut Transaction) {
-        let itr = lm.iterator();
-        for bytes in itr {
-            let record = LogRecord::new(bytes).expect("valid record");
+    fn do_rollback(
+        lm: &Arc<LogManager>,
+        txn_num: TxNum,
+        txn: &mut Transaction,
+    ) -> Result<(), TxnError> {
+        let mut undo_next = HashMap::new();
+        for rec in lm.records()? {
+            let (lsn, bytes) = rec?;
+            let record = LogRecord::new(&bytes).ok_or(TxnError::CorruptLogRecord)?;
             if record.txn_num().is_some_and(|x| x == txn_num) {
                 if record.operation() == RecordType::Start {
-                    return;
+                    return Ok(());
                 }
-                record.undo(txn);
+                Self::undo_once(lm, lsn, &record, &mut undo_next, txn)?;
             }
         }
+        Ok(())
     }
 
-    fn do_recover(lm: &Arc<LogManager>, txn: &mut Transaction) {
-        let itr = lm.iterator();
-        let mut finished_txns = Vec::new();
+    /// Undoes the update `record`, found at `lsn` while reading the log latest first, and logs
+    /// a compensation record for it. Skips it if a compensation record read earlier shows it
+    /// was already undone. `undo_next` tracks, per transaction, the LSN before which its
+    /// updates are still to be undone. Returns whether the update was undone.
+    fn undo_once(
+        lm: &Arc<LogManager>,
+        lsn: Lsn,
+        record: &LogRecord,
+        undo_next: &mut HashMap<TxNum, Lsn>,
+        txn: &mut Transaction,
+    ) -> Result<bool, TxnError> {
+        match record {
+            LogRecord::Compensation {
+                txn_num,
+                undo_next: next,
+                ..
+            }
+            | LogRecord::OperationCompensation {
+                txn_num,
+                undo_next: next,
+                ..
+            } => {
+                let entry = undo_next.entry(*txn_num).or_insert(*next);
+                *entry = (*entry).min(*next);
+                Ok(false)
+            }
+            LogRecord::Update { txn_num, .. } | LogRecord::Operation { txn_num, .. } => {
+                if undo_next.get(txn_num).is_some_and(|&next| lsn >= next) {
+                    return Ok(false);
+                }
+                let undone = record.undo(lm, lsn, txn)?;
+                undo_next.insert(*txn_num, lsn);
+                Ok(undone)
+            }
+            _ => Ok(false),
+        }
+    }
 
-        for bytes in itr {
-            let record = LogRecord::new(bytes).expect("valid record");
-            match record.operation() {
-                RecordType::Checkpoint => return,
-                RecordType::Commit | RecordType::Rollback => {
-                    finished_txns.push(record.txn_num().unwrap());
+    /// Redoes the updates of the transactions that committed since the last checkpoint and
+    /// every compensation record, oldest first, then undoes the updates of the transactions
+    /// that didn't finish, latest first, skipping the ones a compensation record shows were
+    /// already undone.
+    ///
+    /// The log is read back to the last quiescent checkpoint, or to the begin record of the
+    /// last complete fuzzy checkpoint. From there, only as far back as the oldest recLSN of its
+    /// dirty pages and the oldest start of its active transactions is read, forwards. Rolled
+    /// back transactions are left alone: their pages were forced after undoing them. Page
+    /// LSNs show which records are already reflected on a page, so running recovery again is
+    /// safe. With `whole_log`, checkpoints are ignored and the whole log is read.
+    fn do_recover(
+        lm: &Arc<LogManager>,
+        txn: &mut Transaction,
+        whole_log: bool,
+        observer: Option<&RecoveryObserver>,
+    ) -> Result<(), TxnError> {
+        let report = |phase, records, total, lsn| {
+            if let Some(observer) = observer {
+                observer(&RecoveryProgress {
+                    phase,
+                    records,
+                    total,
+                    lsn,
+                });
+            }
+        };
+
+        // the records read back, latest first
+        let mut records = Vec::new();
+        // set once an end record is seen; its begin record is the next one
+        let mut ended = false;
+        // the oldest LSN the last complete fuzzy checkpoint needs read and its begin record's
+        let mut checkpoint: Option<(Lsn, Lsn)> = None;
+        for rec in lm.records()? {
+            let (lsn, bytes) = match rec {
+                Err(e @ (LogError::Corrupt { .. } | LogError::InvalidFragment { .. })) => {
+                    warn!(error = %e, "recovery: stopping at a corrupt log block");
+                    break;
                 }
-                _ => {
-                    if !finished_txns.contains(&record.txn_num().unwrap()) {
-                        record.undo(txn);
-                    }
+                rec => rec?,
+            };
+            let Some(record) = LogRecord::new(&bytes) else {
+                warn!("recovery: stopping at a torn or corrupt log record");
+                break;
+            };
+            let last = match &record {
+                LogRecord::Checkpoint {} if !whole_log => break,
+                LogRecord::CheckpointEnd {} => {
+                    ended = true;
+                    false
+                }
+                LogRecord::CheckpointBegin { active, dirty } if ended && !whole_log => {
+                    let starts = active.iter().map(|(_, start)| *start);
+                    let oldest = dirty
+                        .iter()
+                        .map(|(_, rec_lsn)| *rec_lsn)
+                        .chain(starts)
+                        .min();
+                    checkpoint = Some((oldest.map_or(lsn, |oldest| oldest.min(lsn)), lsn));
+                    true
+                }
+                _ => false,
+            };
+            records.push((lsn, record));
+            report(RecoveryPhase::Analysis, records.len(), None, lsn);
+            if last {
+                break;
+            }
+        }
+        if let Some((from, begin)) = checkpoint.filter(|(from, begin)| from < begin) {
+            let mut older = Vec::new();
+            for rec in lm.forward_records(from)? {
+                let (lsn, bytes) = rec?;
+                if lsn >= begin {
+                    break;
+                }
+                older.push((
+                    lsn,
+                    LogRecord::new(&bytes).ok_or(TxnError::CorruptLogRecord)?,
+                ));
+                report(
+                    RecoveryPhase::Analysis,
+                    records.len() + older.len(),
+                    None,
+                    lsn,
+                );
+            }
+            records.extend(older.into_iter().rev());
+        }
+
+        // transaction numbers start over on every open, so a transaction is told apart by its
+        // number and the LSN of its start record, or 0 if that wasn't read
+        let mut starts = HashMap::new();
+        let mut owners: Vec<_> = records
+            .iter()
+            .rev()
+            .map(|(lsn, record)| {
+                if let LogRecord::Start { txn_num } = record {
+                    starts.insert(*txn_num, *lsn);
                 }
+                record
+                    .txn_num()
+                    .map(|t| (t, starts.get(&t).copied().unwrap_or(0)))
+            })
+            .collect();
+        owners.reverse();
+
+        let finished = |op| -> HashSet<(TxNum, Lsn)> {
+            records
+                .iter()
+                .zip(&owners)
+                .filter(|((_, r), _)| r.operation() == op)
+                .filter_map(|(_, owner)| *owner)
+                .collect()
+        };
+        let committed = finished(RecordType::Commit);
+        let rolled_back = finished(RecordType::Rollback);
+
+        let mut redone = 0;
+        let total = Some(records.len());
+        for (i, ((lsn, record), owner)) in records.iter().zip(&owners).rev().enumerate() {
+            let is_committed = owner.is_some_and(|o| committed.contains(&o));
+            let is_clr = matches!(
+                record.operation(),
+                RecordType::Compensation | RecordType::OperationCompensation
+            );
+            if (is_committed || is_clr) && record.redo(*lsn, txn)? {
+                redone += 1;
             }
+            report(RecoveryPhase::Redo, i + 1, total, *lsn);
         }
+        info!(redone, "recovery: redid committed updates");
+
+        let mut undone = 0;
+        let mut undo_next = HashMap::new();
+        for (i, ((lsn, record), owner)) in records.iter().zip(&owners).enumerate() {
+            let incomplete =
+                owner.is_some_and(|o| !committed.contains(&o) && !rolled_back.contains(&o));
+            if incomplete && Self::undo_once(lm, *lsn, record, &mut undo_next, txn)? {
+                undone += 1;
+            }
+            report(RecoveryPhase::Undo, i + 1, total, *lsn);
+        }
+        info!(undone, "recovery: undid incomplete updates");
+        Ok(())
     }
 }
 
+/// Takes the place of the record type in a record whose body is lz4-compressed.
+const COMPRESSED: i32 = -1;
+
+/// Records smaller than this aren't worth compressing.
+const MIN_COMPRESSED_SIZE: usize = 64;
+
 #[derive(PartialEq)]
 enum RecordType {
     Checkpoint = 0,
@@ -116,6 +455,11 @@ enum RecordType {
     Commit = 2,
     Rollback = 3,
     Update = 4,
+    CheckpointBegin = 5,
+    CheckpointEnd = 6,
+    Compensation = 7,
+    Operation = 8,
+    OperationCompensation = 9,
 }
 
 impl TryFrom<i32> for RecordType {
@@ -128,15 +472,26 @@ impl TryFrom<i32> for RecordType {
             2 => Ok(Self::Commit),
             3 => Ok(Self::Rollback),
             4 => Ok(Self::Update),
+            5 => Ok(Self::CheckpointBegin),
+            6 => Ok(Self::CheckpointEnd),
+            7 => Ok(Self::Compensation),
+            8 => Ok(Self::Operation),
+            9 => Ok(Self::OperationCompensation),
             _ => Err(()),
         }
     }
 }
 
+#[derive(Clone, Copy)]
 #[allow(clippy::upper_case_acronyms)]
 enum UpdateValueType {
     INT = 0,
     STRING = 1,
+    LONG = 2,
+    DOUBLE = 3,
+    BYTES = 4,
+    DATE = 5,
+    TIMESTAMP = 6,
 }
 
 impl TryFrom<i32> for UpdateValueType {
@@ -146,6 +501,11 @@ impl TryFrom<i32> for UpdateValueType {
         match value {
             0 => Ok(Self::INT),
             1 => Ok(Self::STRING),
+            2 => Ok(Self::LONG),
+            3 => Ok(Self::DOUBLE),
+            4 => Ok(Self::BYTES),
+            5 => Ok(Self::DATE),
+            6 => Ok(Self::TIMESTAMP),
             _ => Err(()),
         }
     }
@@ -156,6 +516,14 @@ impl TryFrom<i32> for UpdateValueType {
 pub enum UpdateValue {
     INT(i32),
     STRING(String),
+    LONG(i64),
+    DOUBLE(f64),
+    /// Fixed-length bytes, stored in the page without a length.
+    BYTES(Vec<u8>),
+    /// Days since the Unix epoch.
+    DATE(i32),
+    /// Microseconds since the Unix epoch.
+    TIMESTAMP(i64),
 }
 
 impl UpdateValue {
@@ -163,6 +531,11 @@ impl UpdateValue {
         match &self {
             UpdateValue::INT(_) => UpdateValueType::INT,
             UpdateValue::STRING(_) => UpdateValueType::STRING,
+            UpdateValue::LONG(_) => UpdateValueType::LONG,
+            UpdateValue::DOUBLE(_) => UpdateValueType::DOUBLE,
+            UpdateValue::BYTES(_) => UpdateValueType::BYTES,
+            UpdateValue::DATE(_) => UpdateValueType::DATE,
+            UpdateValue::TIMESTAMP(_) => UpdateValueType::TIMESTAMP,
         }
     }
 
@@ -170,6 +543,44 @@ impl UpdateValue {
         match &self {
             UpdateValue::INT(_) => SIZE_OF_INT,
             UpdateValue::STRING(s) => Page::str_size(s),
+            UpdateValue::LONG(_) => SIZE_OF_LONG,
+            UpdateValue::DOUBLE(_) => SIZE_OF_DOUBLE,
+            UpdateValue::BYTES(b) => SIZE_OF_INT + b.len(),
+            UpdateValue::DATE(_) => SIZE_OF_INT,
+            UpdateValue::TIMESTAMP(_) => SIZE_OF_LONG,
+        }
+    }
+
+    /// Bytes the value takes up in a data page, where fixed-length bytes have no length.
+    pub(crate) fn stored_size(&self) -> usize {
+        match &self {
+            UpdateValue::BYTES(b) => b.len(),
+            _ => self.size(),
+        }
+    }
+
+    fn read_from(p: &Page, data_type: UpdateValueType, pos: usize) -> Option<Self> {
+        let value = match data_type {
+            UpdateValueType::INT => UpdateValue::INT(p.try_get_int(pos).ok()?),
+            UpdateValueType::STRING => UpdateValue::STRING(p.try_get_str(pos).ok()?.to_owned()),
+            UpdateValueType::LONG => UpdateValue::LONG(p.try_get_long(pos).ok()?),
+            UpdateValueType::DOUBLE => UpdateValue::DOUBLE(p.try_get_double(pos).ok()?),
+            UpdateValueType::BYTES => UpdateValue::BYTES(p.try_get_bytes(pos).ok()?.to_vec()),
+            UpdateValueType::DATE => UpdateValue::DATE(p.try_get_date(pos).ok()?),
+            UpdateValueType::TIMESTAMP => UpdateValue::TIMESTAMP(p.try_get_timestamp(pos).ok()?),
+        };
+        Some(value)
+    }
+
+    fn write_to(&self, p: &mut Page, pos: usize) {
+        match self {
+            UpdateValue::INT(n) => p.set_int(pos, *n),
+            UpdateValue::STRING(s) => p.set_string(pos, s),
+            UpdateValue::LONG(n) => p.set_long(pos, *n),
+            UpdateValue::DOUBLE(n) => p.set_double(pos, *n),
+            UpdateValue::BYTES(b) => p.set_bytes(pos, b),
+            UpdateValue::DATE(days) => p.set_date(pos, *days),
+            UpdateValue::TIMESTAMP(micros) => p.set_timestamp(pos, *micros),
         }
     }
 }
@@ -179,27 +590,70 @@ impl fmt::Display for UpdateValue {
         let s = match &self {
             UpdateValue::STRING(v) => format!("STRING {}", v),
             UpdateValue::INT(v) => format!("INT {}", v),
+            UpdateValue::LONG(v) => format!("LONG {}", v),
+            UpdateValue::DOUBLE(v) => format!("DOUBLE {}", v),
+            UpdateValue::BYTES(v) => format!("BYTES {:02x?}", v),
+            UpdateValue::DATE(v) => format!("DATE {}", v),
+            UpdateValue::TIMESTAMP(v) => format!("TIMESTAMP {}", v),
         };
         write!(f, "{s}")
     }
 }
 
-enum LogRecord {
+/// A decoded log record, see [`WalReader`](crate::WalReader).
+pub enum LogRecord {
+    /// Every transaction before it finished and its changes were flushed.
     Checkpoint {},
+    /// Start of a fuzzy checkpoint, with the transactions active when it was written and the
+    /// LSNs of their start records, and the dirty blocks with their recLSNs: the LSN of the
+    /// first change that isn't on disk yet.
+    CheckpointBegin {
+        active: Vec<(usize, Lsn)>,
+        dirty: Vec<(BlockId, Lsn)>,
+    },
+    /// End of a fuzzy checkpoint.
+    CheckpointEnd {},
     Start {
         txn_num: usize,
     },
+    /// `time` is when the transaction committed, in microseconds since the Unix epoch.
     Commit {
         txn_num: usize,
+        time: i64,
     },
     Rollback {
         txn_num: usize,
     },
+    /// A change to `block` at `offset` from `value` to `new_value`.
     Update {
+        txn_num: usize,
+        value: UpdateValue,
+        new_value: UpdateValue,
+        offset: usize,
+        block: BlockId,
+    },
+    /// The undo of the update at `undo_next`, which put `value` back at `offset` in `block`.
+    /// Undo carries on with the transaction's updates before `undo_next`.
+    Compensation {
         txn_num: usize,
         value: UpdateValue,
         offset: usize,
         block: BlockId,
+        undo_next: Lsn,
+    },
+    /// An operation of the subsystem registered for `kind`, see [`RecordHandler`].
+    Operation {
+        txn_num: usize,
+        kind: u16,
+        data: Vec<u8>,
+    },
+    /// The reverse of the operation at `undo_next`, logged when undoing it. Undo carries on
+    /// with the transaction's changes before `undo_next`.
+    OperationCompensation {
+        txn_num: usize,
+        kind: u16,
+        data: Vec<u8>,
+        undo_next: Lsn,
     },
 }
 
@@ -207,65 +661,172 @@ impl fmt::Display for LogRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s: String = match &self {
             LogRecord::Checkpoint {} => "<CHECKPOINT>".to_owned(),
+            LogRecord::CheckpointBegin { active, dirty } => {
+                let active: Vec<_> = active.iter().map(|(t, lsn)| format!("{t}@{lsn}")).collect();
+                let dirty: Vec<_> = dirty.iter().map(|(b, lsn)| format!("{b}@{lsn}")).collect();
+                format!(
+                    "<BEGIN CHECKPOINT ACTIVE [{}] DIRTY [{}]>",
+                    active.join(" "),
+                    dirty.join(" ")
+                )
+            }
+            LogRecord::CheckpointEnd {} => "<END CHECKPOINT>".to_owned(),
             LogRecord::Start { txn_num } => format!("<START {}>", txn_num),
-            LogRecord::Commit { txn_num } => format!("<COMMIT {}>", txn_num),
+            LogRecord::Commit { txn_num, .. } => format!("<COMMIT {}>", txn_num),
             LogRecord::Rollback { txn_num } => format!("<ROLLBACK {}>", txn_num),
             LogRecord::Update {
                 value,
+                new_value,
                 txn_num,
                 offset,
                 block,
-            } => format!("<UPDATE {} {} {} {}>", txn_num, block, offset, value),
+            } => format!(
+                "<UPDATE {} {} {} {} -> {}>",
+                txn_num, block, offset, value, new_value
+            ),
+            LogRecord::Compensation {
+                txn_num,
+                value,
+                offset,
+                block,
+                undo_next,
+            } => format!(
+                "<CLR {} {} {} {} UNDO_NEXT {}>",
+                txn_num, block, offset, value, undo_next
+            ),
+            LogRecord::Operation {
+                txn_num,
+                kind,
+                data,
+            } => format!("<OP {} {} {:02x?}>", txn_num, kind, data),
+            LogRecord::OperationCompensation {
+                txn_num,
+                kind,
+                data,
+                undo_next,
+            } => format!(
+                "<OP CLR {} {} {:02x?} UNDO_NEXT {}>",
+                txn_num, kind, data, undo_next
+            ),
         };
         write!(f, "{s}")
     }
 }
 
 impl LogRecord {
-    fn new(bytes: Box<[u8]>) -> Option<Self> {
-        let p: Page = bytes.into();
+    /// Parses a record written by [`LogRecord::write_to_log`].
+    /// Returns `None` if its checksum doesn't match or it can't be parsed.
+    pub(crate) fn new(bytes: &[u8]) -> Option<Self> {
+        let (body, crc) = bytes.split_last_chunk::<SIZE_OF_INT>()?;
+        if u32::from_le_bytes(*crc) != crc32fast::hash(body) {
+            return None;
+        }
+        let p: Page = match body.split_first_chunk::<SIZE_OF_INT>() {
+            Some((op, compressed)) if i32::from_le_bytes(*op) == COMPRESSED => {
+                lz4_flex::block::decompress_size_prepended(compressed)
+                    .ok()?
+                    .into_boxed_slice()
+                    .into()
+            }
+            _ => Box::<[u8]>::from(body).into(),
+        };
 
-        if let Ok(record_type) = RecordType::try_from(p.get_int(0)) {
+        if let Ok(record_type) = RecordType::try_from(p.try_get_int(0).ok()?) {
             let record = match record_type {
                 RecordType::Checkpoint => Self::Checkpoint {},
+                RecordType::CheckpointBegin => {
+                    let mut pos = SIZE_OF_INT;
+                    let n = p.try_get_int(pos).ok()? as usize;
+                    pos += SIZE_OF_INT;
+                    let mut active = Vec::new();
+                    for _ in 0..n {
+                        let txn_num = p.try_get_int(pos).ok()? as usize;
+                        let start = p.try_get_long(pos + SIZE_OF_INT).ok()? as Lsn;
+                        active.push((txn_num, start));
+                        pos += SIZE_OF_INT + SIZE_OF_LONG;
+                    }
+
+                    let n = p.try_get_int(pos).ok()? as usize;
+                    pos += SIZE_OF_INT;
+                    let mut dirty = Vec::new();
+                    for _ in 0..n {
+                        let filename = p.try_get_str(pos).ok()?;
+                        pos += Page::str_size(filename);
+                        let block = BlockId::new(filename, p.try_get_long(pos).ok()? as u64);
+                        let rec_lsn = p.try_get_long(pos + SIZE_OF_LONG).ok()? as Lsn;
+                        dirty.push((block, rec_lsn));
+                        pos += SIZE_OF_LONG * 2;
+                    }
+                    Self::CheckpointBegin { active, dirty }
+                }
+                RecordType::CheckpointEnd => Self::CheckpointEnd {},
                 RecordType::Start => Self::Start {
-                    txn_num: p.get_int(SIZE_OF_INT) as usize,
+                    txn_num: p.try_get_int(SIZE_OF_INT).ok()? as usize,
                 },
                 RecordType::Commit => Self::Commit {
-                    txn_num: p.get_int(SIZE_OF_INT) as usize,
+                    txn_num: p.try_get_int(SIZE_OF_INT).ok()? as usize,
+                    time: p.try_get_long(SIZE_OF_INT * 2).ok()?,
                 },
                 RecordType::Rollback => Self::Rollback {
-                    txn_num: p.get_int(SIZE_OF_INT) as usize,
+                    txn_num: p.try_get_int(SIZE_OF_INT).ok()? as usize,
                 },
-                RecordType::Update => {
+                RecordType::Operation | RecordType::OperationCompensation => {
+                    let txn_num = p.try_get_int(SIZE_OF_INT).ok()? as usize;
+                    let kind = u16::try_from(p.try_get_int(SIZE_OF_INT * 2).ok()?).ok()?;
+                    let dpos = SIZE_OF_INT * 3;
+                    if record_type == RecordType::OperationCompensation {
+                        let undo_next = p.try_get_long(dpos).ok()? as Lsn;
+                        return Some(Self::OperationCompensation {
+                            txn_num,
+                            kind,
+                            data: p.try_get_bytes(dpos + SIZE_OF_LONG).ok()?.to_vec(),
+                            undo_next,
+                        });
+                    }
+                    Self::Operation {
+                        txn_num,
+                        kind,
+                        data: p.try_get_bytes(dpos).ok()?.to_vec(),
+                    }
+                }
+                RecordType::Update | RecordType::Compensation => {
                     let tpos = SIZE_OF_INT;
-                    let txn_num = p.get_int(tpos) as usize;
+                    let txn_num = p.try_get_int(tpos).ok()? as usize;
 
                     let fpos = tpos + SIZE_OF_INT;
-                    let filename = p.get_string(fpos);
+                    let filename = p.try_get_str(fpos).ok()?;
 
-                    let bpos = fpos + Page::str_size(&filename);
-                    let block_num = p.get_int(bpos);
-                    let block = BlockId::new(&filename, block_num as usize);
+                    let bpos = fpos + Page::str_size(filename);
+                    let block_num = p.try_get_long(bpos).ok()?;
+                    let block = BlockId::new(filename, block_num as u64);
 
-                    let dtpos = bpos + SIZE_OF_INT;
-                    let data_type =
-                        UpdateValueType::try_from(p.get_int(dtpos)).expect("valid data type");
+                    let dtpos = bpos + SIZE_OF_LONG;
+                    let data_type = UpdateValueType::try_from(p.try_get_int(dtpos).ok()?).ok()?;
 
                     let opos = dtpos + SIZE_OF_INT;
-                    let offset = p.get_int(opos) as usize;
+                    let offset = p.try_get_int(opos).ok()? as usize;
 
                     let vpos = opos + SIZE_OF_INT;
-                    let value = match data_type {
-                        UpdateValueType::INT => UpdateValue::INT(p.get_int(vpos)),
-                        UpdateValueType::STRING => {
-                            UpdateValue::STRING(p.get_string(vpos).into_owned())
-                        }
-                    };
+                    if record_type == RecordType::Compensation {
+                        let undo_next = p.try_get_long(vpos).ok()? as Lsn;
+                        let value = UpdateValue::read_from(&p, data_type, vpos + SIZE_OF_LONG)?;
+                        return Some(Self::Compensation {
+                            txn_num,
+                            value,
+                            offset,
+                            block,
+                            undo_next,
+                        });
+                    }
+                    let value = UpdateValue::read_from(&p, data_type, vpos)?;
+
+                    let npos = vpos + value.size();
+                    let new_value = UpdateValue::read_from(&p, data_type, npos)?;
 
                     Self::Update {
                         txn_num,
                         value,
+                        new_value,
                         offset,
                         block,
                     }
@@ -280,96 +841,554 @@ impl LogRecord {
     fn operation(&self) -> RecordType {
         match &self {
             LogRecord::Checkpoint { .. } => RecordType::Checkpoint,
+            LogRecord::CheckpointBegin { .. } => RecordType::CheckpointBegin,
+            LogRecord::CheckpointEnd { .. } => RecordType::CheckpointEnd,
             LogRecord::Start { .. } => RecordType::Start,
             LogRecord::Commit { .. } => RecordType::Commit,
             LogRecord::Rollback { .. } => RecordType::Rollback,
             LogRecord::Update { .. } => RecordType::Update,
+            LogRecord::Compensation { .. } => RecordType::Compensation,
+            LogRecord::Operation { .. } => RecordType::Operation,
+            LogRecord::OperationCompensation { .. } => RecordType::OperationCompensation,
         }
     }
 
-    fn txn_num(&self) -> Option<usize> {
+    pub fn txn_num(&self) -> Option<usize> {
         match &self {
-            LogRecord::Checkpoint {} => None,
+            LogRecord::Checkpoint {}
+            | LogRecord::CheckpointBegin { .. }
+            | LogRecord::CheckpointEnd {} => None,
             LogRecord::Start { txn_num }
-            | LogRecord::Commit { txn_num }
+            | LogRecord::Commit { txn_num, .. }
             | LogRecord::Rollback { txn_num }
-            | LogRecord::Update { txn_num, .. } => Some(*txn_num),
+            | LogRecord::Update { txn_num, .. }
+            | LogRecord::Compensation { txn_num, .. }
+            | LogRecord::Operation { txn_num, .. }
+            | LogRecord::OperationCompensation { txn_num, .. } => Some(*txn_num),
         }
     }
 
-    fn undo(&self, txn: &mut Transaction) {
+    /// The block changed by an update or compensation record.
+    pub fn block(&self) -> Option<&BlockId> {
         match &self {
-            LogRecord::Checkpoint {}
-            | LogRecord::Start { .. }
-            | LogRecord::Commit { .. }
-            | LogRecord::Rollback { .. } => {}
+            LogRecord::Update { block, .. } | LogRecord::Compensation { block, .. } => Some(block),
+            _ => None,
+        }
+    }
+
+    /// Restores the old value of the update at `lsn`, logging a compensation record first.
+    /// Returns whether there was anything to undo: not if the page's LSN shows the update
+    /// never reached it.
+    fn undo(
+        &self,
+        lm: &Arc<LogManager>,
+        lsn: Lsn,
+        txn: &mut Transaction,
+    ) -> Result<bool, TxnError> {
+        if let LogRecord::Operation {
+            txn_num,
+            kind,
+            data,
+        } = &self
+        {
+            let handler = txn.record_handler(*kind)?;
+            let reverse = handler.undo(data);
+            let clr = LogRecord::OperationCompensation {
+                txn_num: *txn_num,
+                kind: *kind,
+                data: reverse.clone(),
+                undo_next: lsn,
+            };
+            let clr_lsn = clr.write_to_log(lm)?;
+            return handler.redo(txn, clr_lsn, &reverse);
+        }
+        let LogRecord::Update {
+            txn_num,
+            value,
+            offset,
+            block,
+            ..
+        } = &self
+        else {
+            return Ok(false);
+        };
+        let clr = LogRecord::Compensation {
+            txn_num: *txn_num,
+            value: value.clone(),
+            offset: *offset,
+            block: block.clone(),
+            undo_next: lsn,
+        };
+        txn.pin(block)?;
+        if txn.page_lsn(block)? < lsn {
+            txn.unpin(block);
+            return Ok(false);
+        }
+        txn.set_value_with(block, *offset, value, |_| Ok(Some(clr.write_to_log(lm)?)))?;
+        txn.unpin(block);
+        Ok(true)
+    }
+
+    /// Reapplies an update's new value or a compensation record's restored value, the record
+    /// at `lsn`. Returns whether there was anything to redo: not if the page's LSN shows the
+    /// record is already reflected on it.
+    pub(crate) fn redo(&self, lsn: Lsn, txn: &mut Transaction) -> Result<bool, TxnError> {
+        let (value, offset, block) = match &self {
             LogRecord::Update {
+                new_value,
+                offset,
+                block,
+                ..
+            } => (new_value, offset, block),
+            LogRecord::Compensation {
                 value,
                 offset,
                 block,
                 ..
-            } => {
-                txn.pin(block);
-                txn.set_value(block, *offset, value, false);
-                txn.unpin(block);
+            } => (value, offset, block),
+            LogRecord::Operation { kind, data, .. }
+            | LogRecord::OperationCompensation { kind, data, .. } => {
+                return txn.record_handler(*kind)?.redo(txn, lsn, data);
             }
+            _ => return Ok(false),
+        };
+        txn.pin(block)?;
+        if txn.page_lsn(block)? >= lsn {
+            txn.unpin(block);
+            return Ok(false);
         }
+        txn.set_value_with(block, *offset, value, |_| Ok(Some(lsn)))?;
+        txn.unpin(block);
+        Ok(true)
     }
 
-    fn write_to_log(&self, lm: &Arc<LogManager>) -> Lsn {
+    /// Lays out the fields an update and a compensation record share in a page with `tail`
+    /// more bytes. Returns the page and the offset of the tail.
+    fn change_page(
+        op: RecordType,
+        txn_num: TxNum,
+        block: &BlockId,
+        value: &UpdateValue,
+        offset: usize,
+        tail: usize,
+    ) -> (Page, usize) {
+        let tpos = SIZE_OF_INT;
+        let fpos = tpos + SIZE_OF_INT;
+        let bpos = fpos + Page::str_size(block.filename());
+        let dtpos = bpos + SIZE_OF_LONG;
+        let opos = dtpos + SIZE_OF_INT;
+        let vpos = opos + SIZE_OF_INT;
+
+        let mut p = Page::new(vpos + tail);
+        p.set_int(0, op as i32);
+        p.set_int(tpos, txn_num as i32);
+        p.set_string(fpos, block.filename());
+        p.set_long(bpos, block.number() as i64);
+        p.set_int(dtpos, value.data_type() as i32);
+        p.set_int(opos, offset as i32);
+        (p, vpos)
+    }
+
+    fn write_to_log(&self, lm: &Arc<LogManager>) -> Result<Lsn, LogError> {
         let op = self.operation();
 
-        match &self {
-            LogRecord::Checkpoint {} => {
+        let p = match &self {
+            LogRecord::Checkpoint {} | LogRecord::CheckpointEnd {} => {
                 let mut p = Page::new(SIZE_OF_INT);
                 p.set_int(0, op as i32);
-                lm.append(p.contents())
+                p
             }
-            LogRecord::Start { txn_num }
-            | LogRecord::Commit { txn_num }
-            | LogRecord::Rollback { txn_num } => {
+            LogRecord::CheckpointBegin { active, dirty } => {
+                // op | count | (txn_num | start)... | count | (blk_filename | blk_number | rec_lsn)...
+                let active_size = active.len() * (SIZE_OF_INT + SIZE_OF_LONG);
+                let dirty_size: usize = dirty
+                    .iter()
+                    .map(|(b, _)| Page::str_size(b.filename()) + SIZE_OF_LONG * 2)
+                    .sum();
+                let mut p = Page::new(SIZE_OF_INT * 3 + active_size + dirty_size);
+                p.set_int(0, op as i32);
+                let mut pos = SIZE_OF_INT;
+                p.set_int(pos, active.len() as i32);
+                pos += SIZE_OF_INT;
+                for (txn_num, start) in active {
+                    p.set_int(pos, *txn_num as i32);
+                    p.set_long(pos + SIZE_OF_INT, *start as i64);
+                    pos += SIZE_OF_INT + SIZE_OF_LONG;
+                }
+                p.set_int(pos, dirty.len() as i32);
+                pos += SIZE_OF_INT;
+                for (block, rec_lsn) in dirty {
+                    p.set_string(pos, block.filename());
+                    pos += Page::str_size(block.filename());
+                    p.set_long(pos, block.number() as i64);
+                    p.set_long(pos + SIZE_OF_LONG, *rec_lsn as i64);
+                    pos += SIZE_OF_LONG * 2;
+                }
+                p
+            }
+            LogRecord::Start { txn_num } | LogRecord::Rollback { txn_num } => {
                 let mut p = Page::new(SIZE_OF_INT * 2);
                 p.set_int(0, op as i32);
                 p.set_int(SIZE_OF_INT, *txn_num as i32);
-                lm.append(p.contents())
+                p
+            }
+            LogRecord::Commit { txn_num, time } => {
+                // op | txn_num | time
+                let mut p = Page::new(SIZE_OF_INT * 2 + SIZE_OF_LONG);
+                p.set_int(0, op as i32);
+                p.set_int(SIZE_OF_INT, *txn_num as i32);
+                p.set_long(SIZE_OF_INT * 2, *time);
+                p
             }
             LogRecord::Update {
+                txn_num,
+                value,
+                new_value,
+                offset,
+                block,
+            } => {
+                // Physical Repr:
+                // op | txn_num | blk_filename | blk_number | data type | offset | old | new
+                let tail = value.size() + new_value.size();
+                let (mut p, vpos) = Self::change_page(op, *txn_num, block, value, *offset, tail);
+                value.write_to(&mut p, vpos);
+                new_value.write_to(&mut p, vpos + value.size());
+                p
+            }
+            LogRecord::Compensation {
                 txn_num,
                 value,
                 offset,
                 block,
+                undo_next,
             } => {
                 // Physical Repr:
-                // op | txn_num | blk_filename | blk_number | data type | offset | value
+                // op | txn_num | blk_filename | blk_number | data type | offset | undo_next | value
+                let tail = SIZE_OF_LONG + value.size();
+                let (mut p, vpos) = Self::change_page(op, *txn_num, block, value, *offset, tail);
+                p.set_long(vpos, *undo_next as i64);
+                value.write_to(&mut p, vpos + SIZE_OF_LONG);
+                p
+            }
+            LogRecord::Operation {
+                txn_num,
+                kind,
+                data,
+            } => {
+                // op | txn_num | kind | data
+                let mut p = Page::new(SIZE_OF_INT * 4 + data.len());
+                p.set_int(0, op as i32);
+                p.set_int(SIZE_OF_INT, *txn_num as i32);
+                p.set_int(SIZE_OF_INT * 2, *kind as i32);
+                p.set_bytes(SIZE_OF_INT * 3, data);
+                p
+            }
+            LogRecord::OperationCompensation {
+                txn_num,
+                kind,
+                data,
+                undo_next,
+            } => {
+                // op | txn_num | kind | undo_next | data
+                let mut p = Page::new(SIZE_OF_INT * 4 + SIZE_OF_LONG + data.len());
+                p.set_int(0, op as i32);
+                p.set_int(SIZE_OF_INT, *txn_num as i32);
+                p.set_int(SIZE_OF_INT * 2, *kind as i32);
+                p.set_long(SIZE_OF_INT * 3, *undo_next as i64);
+                p.set_bytes(SIZE_OF_INT * 3 + SIZE_OF_LONG, data);
+                p
+            }
+        };
+
+        let mut bytes = p.contents().to_vec();
+        if lm.compresses_records() && bytes.len() >= MIN_COMPRESSED_SIZE {
+            let compressed = lz4_flex::block::compress_prepend_size(&bytes);
+            if SIZE_OF_INT + compressed.len() < bytes.len() {
+                bytes = [&COMPRESSED.to_le_bytes()[..], &compressed].concat();
+            }
+        }
+        // a checksum after the record, so that recovery can tell a torn record from a valid one
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+        lm.append(&bytes)
+    }
+}
 
-                let tpos = SIZE_OF_INT;
-                let fpos = tpos + SIZE_OF_INT;
-                let bpos = fpos + Page::str_size(block.filename());
-                let dtpos = bpos + SIZE_OF_INT;
-                let opos = dtpos + SIZE_OF_INT;
-                let vpos = opos + SIZE_OF_INT;
+#[cfg(test)]
+mod tests {
+    use crate::{
+        buffer::EvictionPolicy,
+        file::{FileManager, PageType, StorageBackend},
+        txn::{lock_table::DEFAULT_LOCK_TIMEOUT, transaction::TransactionManager},
+    };
 
-                let val_size = value.size();
+    use super::*;
 
-                let mut p = Page::new(vpos + val_size);
-                p.set_int(0, op as i32);
-                p.set_int(tpos, *txn_num as i32);
-                p.set_string(fpos, block.filename());
-                p.set_int(bpos, block.number() as i32);
-                p.set_int(dtpos, value.data_type() as i32);
-                p.set_int(opos, *offset as i32);
-
-                match value {
-                    UpdateValue::INT(n) => {
-                        p.set_int(vpos, *n);
-                    }
-                    UpdateValue::STRING(s) => {
-                        p.set_string(vpos, s);
-                    }
-                };
+    fn open(fm: &Arc<FileManager>) -> (Arc<LogManager>, Arc<BufferManager>, TransactionManager) {
+        let lm = Arc::new(LogManager::new(Arc::clone(fm) as _, "testlog").unwrap());
+        let bm = Arc::new(BufferManager::new(
+            Arc::clone(fm) as _,
+            Arc::clone(&lm),
+            8,
+            EvictionPolicy::default(),
+        ));
+        let tm = TransactionManager::new(
+            Arc::clone(fm) as _,
+            Arc::clone(&lm),
+            Arc::clone(&bm),
+            DEFAULT_LOCK_TIMEOUT,
+        );
+        (lm, bm, tm)
+    }
+
+    #[test]
+    fn test_recover_interrupted_rollback() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let blk = BlockId::new("testfile", 1);
+        {
+            let (lm, bm, tm) = open(&fm);
+            let mut tx = tm.create_txn().unwrap();
+            tx.pin(&blk).unwrap();
+            tx.set_int(&blk, 80, 5, true).unwrap();
+            tx.set_int(&blk, 120, 6, true).unwrap();
+
+            // undo the latest update only, then crash with the undo on disk
+            let (lsn, bytes) = lm.records().unwrap().next().unwrap().unwrap();
+            let record = LogRecord::new(&bytes).unwrap();
+            let mut undo_next = HashMap::new();
+            assert!(
+                RecoveryManager::undo_once(&lm, lsn, &record, &mut undo_next, &mut tx).unwrap()
+            );
+            bm.flush_all(tx.txn_num()).unwrap();
+        }
+
+        let (lm, _bm, tm) = open(&fm);
+        tm.recover().unwrap();
+        let mut page = Page::new(400);
+        fm.read_block(&blk, &mut page).unwrap();
+        assert_eq!((page.get_int(80), page.get_int(120)), (0, 0));
+
+        // each update was compensated exactly once
+        let undone: Vec<_> = lm
+            .iterator()
+            .unwrap()
+            .filter_map(|bytes| match LogRecord::new(&bytes.unwrap()) {
+                Some(LogRecord::Compensation { offset, .. }) => Some(offset),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(undone, [80, 120]);
+    }
+
+    #[test]
+    fn test_recovery_checks_page_lsn() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let (blk, blk2) = (BlockId::new("testfile", 1), BlockId::new("testfile", 2));
+        let committed_lsn = {
+            let (lm, bm, tm) = open(&fm);
+            let mut tx1 = tm.create_txn().unwrap();
+            tx1.pin(&blk).unwrap();
+            tx1.set_int(&blk, 80, 7, true).unwrap();
+            tx1.commit().unwrap();
+            let lsn = lm.latest_lsn();
+            bm.flush_all_dirty().unwrap();
+
+            let mut tx2 = tm.create_txn().unwrap();
+            tx2.pin(&blk2).unwrap();
+            tx2.set_int(&blk2, 80, 9, true).unwrap();
+            // crash before tx2's page is written
+            tx2.crash();
+            lsn
+        };
+
+        let mut page = Page::new(400);
+        fm.read_block(&blk, &mut page).unwrap();
+        assert_eq!(page.header().page_type, PageType::Data);
+        let page_lsn = page.page_lsn();
+        assert!(page_lsn > 0 && page_lsn < committed_lsn);
+
+        for _ in 0..2 {
+            let (lm, _bm, tm) = open(&fm);
+            tm.recover().unwrap();
+            fm.read_block(&blk, &mut page).unwrap();
+            assert_eq!(page.get_int(80), 7);
+            // tx2's update never reached its page, so there was nothing to compensate
+            assert!(!lm.iterator().unwrap().any(|bytes| matches!(
+                LogRecord::new(&bytes.unwrap()),
+                Some(LogRecord::Compensation { .. })
+            )));
+        }
+    }
 
-                lm.append(p.contents())
+    /// Adds to the int at offset 80 of a block: `data` is the block number and the amount.
+    struct AddHandler;
+
+    impl RecordHandler for AddHandler {
+        fn redo(&self, txn: &mut Transaction, lsn: Lsn, data: &[u8]) -> Result<bool, TxnError> {
+            let block = BlockId::new(
+                "testfile",
+                u64::from_le_bytes(data[..8].try_into().unwrap()),
+            );
+            let amount = i32::from_le_bytes(data[8..].try_into().unwrap());
+            txn.pin(&block)?;
+            if txn.page_lsn(&block)? >= lsn {
+                txn.unpin(&block);
+                return Ok(false);
             }
+            let n = txn.get_int(&block, 80)?;
+            txn.set_value_with(&block, 80, &UpdateValue::INT(n + amount), |_| Ok(Some(lsn)))?;
+            txn.unpin(&block);
+            Ok(true)
+        }
+
+        fn undo(&self, data: &[u8]) -> Vec<u8> {
+            let amount = i32::from_le_bytes(data[8..].try_into().unwrap());
+            [&data[..8], &(-amount).to_le_bytes()].concat()
+        }
+    }
+
+    #[test]
+    fn test_operation_records() {
+        const ADD: u16 = 1;
+        let add = |block: u64, amount: i32| -> Vec<u8> {
+            [&block.to_le_bytes()[..], &amount.to_le_bytes()].concat()
+        };
+        let fm = Arc::new(FileManager::in_memory(400));
+        let (blk, blk2) = (BlockId::new("testfile", 1), BlockId::new("testfile", 2));
+        {
+            let (_lm, bm, tm) = open(&fm);
+            let tm = tm.with_record_handler(ADD, AddHandler);
+            // committed but not on disk
+            let mut tx1 = tm.create_txn().unwrap();
+            tx1.perform(ADD, &add(1, 5)).unwrap();
+            tx1.perform(ADD, &add(1, 2)).unwrap();
+            tx1.commit().unwrap();
+
+            let mut tx2 = tm.create_txn().unwrap();
+            tx2.perform(ADD, &add(2, 3)).unwrap();
+            tx2.rollback().unwrap();
+
+            // unfinished, on disk
+            let mut tx3 = tm.create_txn().unwrap();
+            tx3.perform(ADD, &add(2, 4)).unwrap();
+            bm.flush_all(tx3.txn_num()).unwrap();
+            // crash without rolling tx3 back
+            tx3.crash();
+
+            let mut tx4 = tm.create_txn().unwrap();
+            assert!(matches!(
+                tx4.perform(ADD + 1, &add(1, 1)),
+                Err(TxnError::UnknownOperation(2))
+            ));
+        }
+        let mut page = Page::new(400);
+        fm.read_block(&blk2, &mut page).unwrap();
+        assert_eq!(page.get_int(80), 4);
+
+        let (lm, _bm, tm) = open(&fm);
+        let tm = tm.with_record_handler(ADD, AddHandler);
+        tm.recover().unwrap();
+        fm.read_block(&blk, &mut page).unwrap();
+        assert_eq!(page.get_int(80), 7, "committed operations not redone");
+        fm.read_block(&blk2, &mut page).unwrap();
+        assert_eq!(page.get_int(80), 0, "unfinished operation not undone");
+
+        // tx2's and tx3's operations were each undone with a logged reverse operation
+        let reversed: Vec<_> = lm
+            .iterator()
+            .unwrap()
+            .filter_map(|bytes| match LogRecord::new(&bytes.unwrap()) {
+                Some(LogRecord::OperationCompensation { data, .. }) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reversed, [add(2, -4), add(2, -3)]);
+    }
+
+    #[test]
+    fn test_update_record_large_block() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let lm = Arc::new(LogManager::new(fm, "testlog").unwrap());
+
+        // past what an i32 block number could hold
+        let block = BlockId::new("testfile", (1 << 40) + 7);
+        let record = LogRecord::Update {
+            txn_num: 3,
+            value: UpdateValue::INT(42),
+            new_value: UpdateValue::INT(43),
+            offset: 80,
+            block: block.clone(),
+        };
+        record.write_to_log(&lm).unwrap();
+
+        let bytes = lm.iterator().unwrap().next().unwrap().unwrap();
+        let Some(LogRecord::Update { block: read, .. }) = LogRecord::new(&bytes) else {
+            panic!("expected an update record");
+        };
+        assert_eq!(read, block);
+        assert_eq!(read.number(), 1_099_511_627_783);
+    }
+
+    #[test]
+    fn test_record_checksum() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let lm = Arc::new(LogManager::new(fm, "testlog").unwrap());
+        LogRecord::Commit {
+            txn_num: 9,
+            time: 1,
         }
+        .write_to_log(&lm)
+        .unwrap();
+
+        let bytes = lm.iterator().unwrap().next().unwrap().unwrap();
+        assert!(matches!(
+            LogRecord::new(&bytes),
+            Some(LogRecord::Commit {
+                txn_num: 9,
+                time: 1
+            })
+        ));
+
+        let mut flipped = bytes.clone();
+        flipped[SIZE_OF_INT] ^= 1;
+        assert!(LogRecord::new(&flipped).is_none());
+        // a torn record missing its tail
+        assert!(LogRecord::new(&bytes[..bytes.len() - 1]).is_none());
+        assert!(LogRecord::new(&[]).is_none());
+    }
+
+    #[test]
+    fn test_record_compression() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let lm = Arc::new(
+            LogManager::new(fm, "testlog")
+                .unwrap()
+                .with_record_compression(true),
+        );
+        let text = "willow ".repeat(100);
+        let record = LogRecord::Update {
+            txn_num: 4,
+            value: UpdateValue::STRING(text.clone()),
+            new_value: UpdateValue::STRING(text.to_uppercase()),
+            offset: 8,
+            block: BlockId::new("testfile", 2),
+        };
+        record.write_to_log(&lm).unwrap();
+        LogRecord::Start { txn_num: 5 }.write_to_log(&lm).unwrap();
+
+        let records: Vec<_> = lm.iterator().unwrap().map(|r| r.unwrap()).collect();
+        // too small to bother
+        assert!(matches!(
+            LogRecord::new(&records[0]),
+            Some(LogRecord::Start { txn_num: 5 })
+        ));
+        assert!(records[1].len() < text.len());
+        let Some(LogRecord::Update {
+            value: UpdateValue::STRING(s),
+            ..
+        }) = LogRecord::new(&records[1])
+        else {
+            panic!("expected an update record");
+        };
+        assert_eq!(s, text);
     }
 }
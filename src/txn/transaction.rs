@@ -1,202 +1,729 @@
 #![allow(dead_code)]
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock, RwLockReadGuard,
     },
+    time::Duration,
 };
 
+use thiserror::Error;
+use tracing::{debug, info_span, warn, Span};
+
 use crate::{
-    buffer::{Buffer, BufferManager},
-    file::{BlockId, FileManager},
-    log::{LogManager, Lsn},
+    buffer::{AccessHint, Buffer, BufferError, BufferManager, PinnedBuffer},
+    file::{BlockId, PageError, PageHeader, PageType, StorageBackend},
+    log::{LogError, LogManager, Lsn},
+    metrics::Histogram,
 };
 
 use super::{
     concurrency::ConcurrencyManager,
-    recovery::{RecoveryManager, UpdateValue},
+    lock_table::LockTable,
+    recovery::{
+        RecordHandler, RecordHandlers, RecoveryManager, RecoveryObserver, RecoveryTarget,
+        UpdateValue,
+    },
 };
 
 /// Transaction Number
 pub type TxNum = usize;
 
+#[derive(Debug, Error)]
+pub enum TxnError {
+    #[error("timed out waiting for a lock on {0}")]
+    LockAbort(BlockId),
+    #[error("{0} is not pinned by the transaction")]
+    NotPinned(BlockId),
+    #[error("encountered a corrupt log record")]
+    CorruptLogRecord,
+    #[error("transaction is read-only")]
+    ReadOnly,
+    #[error("no handler is registered for log operations of kind {0}")]
+    UnknownOperation(u16),
+    #[error(transparent)]
+    Buffer(#[from] BufferError),
+    #[error(transparent)]
+    Log(#[from] LogError),
+    #[error(transparent)]
+    Page(#[from] PageError),
+}
+
+impl TxnError {
+    /// Whether the transaction gave up waiting for a lock or for a free buffer, so that running
+    /// it again after rolling it back may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::LockAbort(_) | Self::Buffer(BufferError::Abort))
+    }
+}
+
+/// Counters shared by the transaction manager, its transactions and the lock table.
+#[derive(Default)]
+pub(crate) struct TxnStats {
+    pub active: AtomicU64,
+    pub lock_wait: Histogram,
+    pub active_txns: Mutex<ActiveTxns>,
+    /// Notified when a transaction finishes or a quiescent checkpoint ends.
+    pub active_changed: Condvar,
+}
+
+/// What checkpoints need to know about the running transactions.
+#[derive(Default)]
+pub(crate) struct ActiveTxns {
+    /// Transactions that wrote a start record and haven't finished yet, with its LSN.
+    pub txns: BTreeMap<TxNum, Lsn>,
+    /// Set while a quiescent checkpoint waits for the active transactions; none may start.
+    pub quiescing: bool,
+}
+
 struct BufferList {
-    buffers: HashMap<BlockId, Arc<RwLock<Buffer>>>,
-    pins: HashSet<BlockId>,
+    /// One guard per pin, a block pinned twice has to be unpinned twice.
+    buffers: HashMap<BlockId, Vec<PinnedBuffer>>,
     bm: Arc<BufferManager>,
+    hint: AccessHint,
+    txn_num: TxNum,
 }
 
 impl BufferList {
-    fn new(bm: Arc<BufferManager>) -> Self {
+    fn new(bm: Arc<BufferManager>, txn_num: TxNum) -> Self {
         Self {
             buffers: HashMap::new(),
-            pins: HashSet::new(),
             bm,
+            hint: AccessHint::Normal,
+            txn_num,
         }
     }
 
-    fn get(&self, block: &BlockId) -> Option<&Arc<RwLock<Buffer>>> {
-        self.buffers.get(block)
+    fn get(&self, block: &BlockId) -> Result<&RwLock<Buffer>, TxnError> {
+        self.buffers
+            .get(block)
+            .and_then(|pins| pins.first())
+            .map(|buf| &**buf)
+            .ok_or_else(|| TxnError::NotPinned(block.clone()))
     }
 
-    fn pin(&mut self, block: &BlockId) {
-        let lock = self.bm.pin(block).unwrap();
-        self.buffers.insert(block.to_owned(), lock);
-        self.pins.insert(block.to_owned());
+    #[track_caller]
+    fn pin(&mut self, block: &BlockId) -> Result<(), BufferError> {
+        let buf = self.bm.pin_for(block, self.hint, Some(self.txn_num))?;
+        self.buffers.entry(block.to_owned()).or_default().push(buf);
+        Ok(())
     }
 
     fn unpin(&mut self, block: &BlockId) {
-        if let Some(buf) = self.buffers.get(block) {
-            self.bm.unpin(buf.write().unwrap());
-            self.pins.remove(block);
-            if !self.pins.contains(block) {
+        if let Some(pins) = self.buffers.get_mut(block) {
+            pins.pop();
+            if pins.is_empty() {
                 self.buffers.remove(block);
             }
         }
     }
 
     fn unpin_all(&mut self) {
-        for block in &self.pins {
-            if let Some(buf) = self.buffers.get(block) {
-                self.bm.unpin(buf.write().unwrap());
-            };
-        }
         self.buffers.clear();
-        self.pins.clear();
     }
 }
 
 pub struct Transaction {
-    fm: Arc<FileManager>,
+    fm: Arc<dyn StorageBackend>,
     lm: Arc<LogManager>,
     bm: Arc<BufferManager>,
-    cm: Arc<Mutex<ConcurrencyManager>>,
+    cm: Mutex<ConcurrencyManager>,
+    stats: Arc<TxnStats>,
 
     buffers: BufferList,
+    /// Handlers of the operations logged by other subsystems, see [`Transaction::perform`].
+    handlers: RecordHandlers,
     txn_num: TxNum,
+    /// Read-only transactions write no log records and refuse modifications.
+    read_only: bool,
+    /// Replays a primary's changes on a standby, which are already in the log: writes no log
+    /// records, and committing or rolling back only releases its locks and pins.
+    replica: bool,
+    /// Set once the transaction committed, rolled back or finished recovering.
+    finished: bool,
+    /// Every event emitted on behalf of the transaction is recorded inside this span.
+    span: Span,
 }
 
 impl Transaction {
     fn new(
         txn_num: usize,
-        fm: Arc<FileManager>,
+        fm: Arc<dyn StorageBackend>,
         lm: Arc<LogManager>,
         bm: Arc<BufferManager>,
-        cm: Arc<Mutex<ConcurrencyManager>>,
-    ) -> Self {
-        RecoveryManager::start(&lm, txn_num);
-        let buffers = BufferList::new(Arc::clone(&bm));
-        Self {
+        lock_tbl: Arc<LockTable>,
+        stats: Arc<TxnStats>,
+        read_only: bool,
+    ) -> Result<Self, TxnError> {
+        let span = info_span!("txn", txn_num);
+        span.in_scope(|| {
+            if !read_only {
+                // registered under the lock so that a checkpoint can't miss the start record
+                let active = stats.active_txns.lock().unwrap();
+                let mut active = stats
+                    .active_changed
+                    .wait_while(active, |active| active.quiescing)
+                    .unwrap();
+                let lsn = RecoveryManager::start(&lm, txn_num)?;
+                active.txns.insert(txn_num, lsn);
+            }
+            debug!("started");
+            Ok::<_, TxnError>(())
+        })?;
+        let buffers = BufferList::new(Arc::clone(&bm), txn_num);
+        stats.active.fetch_add(1, Ordering::SeqCst);
+        Ok(Self {
             fm,
             lm,
             bm,
-            cm,
+            cm: Mutex::new(ConcurrencyManager::new(txn_num, lock_tbl)),
+            stats,
             txn_num,
             buffers,
+            handlers: RecordHandlers::default(),
+            read_only,
+            replica: false,
+            finished: false,
+            span,
+        })
+    }
+
+    /// Logs the operation `data` of the subsystem registered for `kind` and applies it with the
+    /// subsystem's [`RecordHandler::redo`]. Rollback and recovery undo it logically, with the
+    /// operation its [`RecordHandler::undo`] describes. Returns the LSN of the log record.
+    pub(crate) fn perform(&mut self, kind: u16, data: &[u8]) -> Result<Lsn, TxnError> {
+        if self.read_only {
+            return Err(TxnError::ReadOnly);
         }
+        let handler = self.record_handler(kind)?;
+        let lsn = RecoveryManager::perform(&self.lm, self.txn_num, kind, data)?;
+        handler.redo(self, lsn, data)?;
+        Ok(lsn)
     }
 
-    fn commit(&mut self) {
-        RecoveryManager::commit(&self.bm, &self.lm, self.txn_num);
-        self.cm.lock().unwrap().release(self.txn_num);
-        self.buffers.unpin_all();
-        println!("txn {} committed", self.txn_num)
+    pub(crate) fn record_handler(&self, kind: u16) -> Result<Arc<dyn RecordHandler>, TxnError> {
+        self.handlers
+            .get(&kind)
+            .cloned()
+            .ok_or(TxnError::UnknownOperation(kind))
+    }
+
+    /// Writes a commit record, waits for the log to be durable and releases its locks and pins.
+    pub fn commit(&mut self) -> Result<(), TxnError> {
+        let _guard = self.span.clone().entered();
+        if !self.read_only && !self.replica {
+            RecoveryManager::commit(&self.lm, self.txn_num)?;
+        }
+        self.finish();
+        debug!("committed");
+        Ok(())
     }
 
-    fn rollback(&mut self) {
+    /// Undoes every logged change made by the transaction and releases its locks and pins.
+    pub fn rollback(&mut self) -> Result<(), TxnError> {
+        let _guard = self.span.clone().entered();
+        if !self.read_only && !self.replica {
+            let (bm, lm, txn_num) = (&self.bm.clone(), &self.lm.clone(), self.txn_num);
+            RecoveryManager::rollback(bm, lm, txn_num, self)?;
+        }
+        self.finish();
+        debug!("rolled back");
+        Ok(())
+    }
+
+    fn recover(
+        &mut self,
+        whole_log: bool,
+        observer: Option<&RecoveryObserver>,
+    ) -> Result<(), TxnError> {
+        let _guard = self.span.clone().entered();
+        self.bm.flush_all(self.txn_num)?;
         let (bm, lm, txn_num) = (&self.bm.clone(), &self.lm.clone(), self.txn_num);
-        RecoveryManager::rollback(bm, lm, txn_num, self);
-        self.cm.lock().unwrap().release(self.txn_num);
+        RecoveryManager::recover(bm, lm, txn_num, self, whole_log, observer)?;
+        self.finish();
+        Ok(())
+    }
+
+    /// Drops the transaction the way a crash would: nothing is rolled back or logged.
+    #[cfg(test)]
+    pub(crate) fn crash(mut self) {
+        self.finished = true;
+    }
+
+    /// Releases the transaction's locks and pins once its outcome is logged.
+    fn finish(&mut self) {
+        self.finished = true;
+        self.cm.lock().unwrap().release();
         self.buffers.unpin_all();
-        println!("txn {} rolled back", self.txn_num)
+        self.stats
+            .active_txns
+            .lock()
+            .unwrap()
+            .txns
+            .remove(&self.txn_num);
+        self.stats.active_changed.notify_all();
+        self.stats.active.fetch_sub(1, Ordering::SeqCst);
     }
 
-    fn recover(&mut self) {
-        self.bm.flush_all(self.txn_num);
-        let (bm, lm, txn_num) = (&self.bm.clone(), &self.lm.clone(), self.txn_num);
-        RecoveryManager::recover(bm, lm, txn_num, self);
+    /// LSN of the last logged change to a pinned block, see [`Buffer::page_lsn`].
+    pub(crate) fn page_lsn(&self, block: &BlockId) -> Result<Lsn, TxnError> {
+        Ok(self.buffers.get(block)?.read().unwrap().page_lsn())
     }
 
-    pub fn pin(&mut self, block: &BlockId) {
-        self.buffers.pin(block);
+    #[track_caller]
+    pub fn pin(&mut self, block: &BlockId) -> Result<(), TxnError> {
+        let _guard = self.span.enter();
+        self.buffers.pin(block)?;
+        Ok(())
     }
 
     pub fn unpin(&mut self, block: &BlockId) {
         self.buffers.unpin(block);
     }
 
-    pub fn set_value(&mut self, block: &BlockId, offset: usize, v: &UpdateValue, ok_to_log: bool) {
-        self.cm.lock().unwrap().x_lock(self.txn_num, block);
-        let buf_lock = self.buffers.get(block).unwrap();
+    /// Takes an exclusive lock on `block` until the transaction finishes, as a change to it would.
+    /// Taking it before reading a value that's about to be updated keeps two transactions doing
+    /// the same from both holding a shared lock and waiting for each other to time out.
+    pub fn x_lock(&mut self, block: &BlockId) -> Result<(), TxnError> {
+        self.cm.lock().unwrap().x_lock(block)
+    }
+
+    /// How the blocks pinned from now on are about to be used, e.g.
+    /// [`AccessHint::Sequential`] for the duration of a scan.
+    pub fn set_access_hint(&mut self, hint: AccessHint) {
+        self.buffers.hint = hint;
+    }
+
+    pub fn txn_num(&self) -> TxNum {
+        self.txn_num
+    }
 
-        let lsn: Option<Lsn> = ok_to_log.then_some(RecoveryManager::set_update(
-            &self.lm,
-            self.txn_num,
-            buf_lock.read().unwrap(),
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Writes `n` at `offset` in a pinned block.
+    /// The old value is logged (and restored on rollback) only if `ok_to_log` is set.
+    /// The first [`PageHeader::SIZE`] bytes of the block are reserved for its header.
+    pub fn set_int(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        n: i32,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::INT(n), ok_to_log)
+    }
+
+    /// Writes `s` at `offset` in a pinned block. See [`Transaction::set_int`].
+    pub fn set_string(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        s: &str,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::STRING(s.to_owned()), ok_to_log)
+    }
+
+    /// Writes the 64-bit `n` at `offset` in a pinned block. See [`Transaction::set_int`].
+    pub fn set_long(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        n: i64,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::LONG(n), ok_to_log)
+    }
+
+    /// Writes `n` at `offset` in a pinned block. See [`Transaction::set_int`].
+    pub fn set_double(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        n: f64,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::DOUBLE(n), ok_to_log)
+    }
+
+    /// Writes `b` as a single byte at `offset` in a pinned block. See [`Transaction::set_int`].
+    pub fn set_bool(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        b: bool,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_raw(block, offset, &[b as u8], ok_to_log)
+    }
+
+    /// Writes `bytes` at `offset` in a pinned block without storing their length, e.g. for
+    /// fixed-size values such as UUIDs and hashes. See [`Transaction::set_int`].
+    pub fn set_raw(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        bytes: &[u8],
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(
+            block,
             offset,
-            v.clone(),
-        ));
+            &UpdateValue::BYTES(bytes.to_vec()),
+            ok_to_log,
+        )
+    }
+
+    /// Writes a date, as days since the Unix epoch, at `offset` in a pinned block.
+    /// See [`Transaction::set_int`].
+    pub fn set_date(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        days: i32,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::DATE(days), ok_to_log)
+    }
+
+    /// Writes a timestamp, as microseconds since the Unix epoch, at `offset` in a pinned block.
+    /// See [`Transaction::set_int`].
+    pub fn set_timestamp(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        micros: i64,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        self.set_value(block, offset, &UpdateValue::TIMESTAMP(micros), ok_to_log)
+    }
+
+    pub(crate) fn set_value(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        v: &UpdateValue,
+        ok_to_log: bool,
+    ) -> Result<(), TxnError> {
+        let (lm, txn_num) = (Arc::clone(&self.lm), self.txn_num);
+        self.set_value_with(block, offset, v, |buf| {
+            if !ok_to_log {
+                return Ok(None);
+            }
+            let lsn = RecoveryManager::set_update(&lm, txn_num, buf, offset, v.clone())?;
+            Ok(Some(lsn))
+        })
+    }
+
+    /// Writes `v` at `offset` in a pinned block after `log` wrote the record describing the
+    /// change, if any, and returned its LSN.
+    pub(crate) fn set_value_with(
+        &mut self,
+        block: &BlockId,
+        offset: usize,
+        v: &UpdateValue,
+        log: impl FnOnce(RwLockReadGuard<Buffer>) -> Result<Option<Lsn>, TxnError>,
+    ) -> Result<(), TxnError> {
+        let _guard = self.span.enter();
+        if self.read_only {
+            return Err(TxnError::ReadOnly);
+        }
+        self.cm.lock().unwrap().x_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+
+        // the start of the page holds its header
+        if offset < PageHeader::SIZE {
+            return Err(PageError::InHeader(offset).into());
+        }
+        // checked before anything is logged
+        let size = buf_lock.read().unwrap().contents().contents().len();
+        if offset + v.stored_size() > size {
+            return Err(PageError::OutOfBounds {
+                offset,
+                len: v.stored_size(),
+                size,
+            }
+            .into());
+        }
+        let formatted = match buf_lock.read().unwrap().contents().try_page_type()? {
+            PageType::Empty => false,
+            PageType::Data => true,
+            found => {
+                return Err(PageError::WrongPageType {
+                    expected: PageType::Data,
+                    found,
+                }
+                .into())
+            }
+        };
+
+        let lsn = log(buf_lock.read().unwrap())?;
 
         let mut buf = buf_lock.write().unwrap();
         let p = buf.contents_mut();
+        if !formatted {
+            p.set_header(&PageHeader::new(PageType::Data, PageHeader::SIZE));
+        }
         match v {
-            UpdateValue::INT(n) => p.set_int(offset, *n),
-            UpdateValue::STRING(s) => p.set_string(offset, s),
+            UpdateValue::INT(n) => p.try_set_int(offset, *n)?,
+            UpdateValue::STRING(s) => p.try_set_string(offset, s)?,
+            UpdateValue::LONG(n) => p.try_set_long(offset, *n)?,
+            UpdateValue::DOUBLE(n) => p.try_set_double(offset, *n)?,
+            UpdateValue::BYTES(b) => p.try_set_raw(offset, b)?,
+            UpdateValue::DATE(days) => p.try_set_date(offset, *days)?,
+            UpdateValue::TIMESTAMP(micros) => p.try_set_timestamp(offset, *micros)?,
         }
 
-        buf.set_modified(self.txn_num, lsn);
+        buf.set_modified(self.txn_num, lsn)?;
+        Ok(())
+    }
+
+    pub fn get_string(&self, block: &BlockId, offset: usize) -> Result<String, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_str(offset)?.into())
+    }
+
+    pub fn get_int(&self, block: &BlockId, offset: usize) -> Result<i32, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_int(offset)?)
+    }
+
+    pub fn get_long(&self, block: &BlockId, offset: usize) -> Result<i64, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_long(offset)?)
     }
 
-    fn get_string(&self, block: &BlockId, offset: usize) -> String {
-        self.cm.lock().unwrap().s_lock(self.txn_num, block);
-        let buf_lock = self.buffers.get(block).unwrap();
-        let buf = buf_lock.write().unwrap();
+    pub fn get_double(&self, block: &BlockId, offset: usize) -> Result<f64, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
 
         let p = buf.contents();
-        p.get_string(offset).into()
+        Ok(p.try_get_double(offset)?)
     }
 
-    fn get_int(&self, block: &BlockId, offset: usize) -> i32 {
-        self.cm.lock().unwrap().s_lock(self.txn_num, block);
-        let buf_lock = self.buffers.get(block).unwrap();
-        let buf = buf_lock.write().unwrap();
+    pub fn get_date(&self, block: &BlockId, offset: usize) -> Result<i32, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
 
         let p = buf.contents();
-        p.get_int(offset)
+        Ok(p.try_get_date(offset)?)
+    }
+
+    pub fn get_timestamp(&self, block: &BlockId, offset: usize) -> Result<i64, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_timestamp(offset)?)
+    }
+
+    pub fn get_bool(&self, block: &BlockId, offset: usize) -> Result<bool, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_bool(offset)?)
+    }
+
+    /// Reads the `len` bytes at `offset`, as written by [`Transaction::set_raw`].
+    pub fn get_raw(&self, block: &BlockId, offset: usize, len: usize) -> Result<Vec<u8>, TxnError> {
+        let _guard = self.span.enter();
+        self.cm.lock().unwrap().s_lock(block)?;
+        let buf_lock = self.buffers.get(block)?;
+        let buf = buf_lock.read().unwrap();
+
+        let p = buf.contents();
+        Ok(p.try_get_raw(offset, len)?.to_vec())
+    }
+}
+
+impl Drop for Transaction {
+    /// Rolls back a transaction that was neither committed nor rolled back, so that its
+    /// changes are undone and other transactions can take its locks.
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = self.rollback() {
+            let _guard = self.span.clone().entered();
+            warn!(error = %e, "rollback of dropped transaction failed");
+            self.finish();
+        }
     }
 }
 
-struct TransactionManager {
-    fm: Arc<FileManager>,
+pub(crate) struct TransactionManager {
+    fm: Arc<dyn StorageBackend>,
     lm: Arc<LogManager>,
     bm: Arc<BufferManager>,
 
-    concurrency_mgr: Arc<Mutex<ConcurrencyManager>>,
+    lock_tbl: Arc<LockTable>,
     next_txn_num: AtomicUsize,
+    stats: Arc<TxnStats>,
+    read_only: bool,
+    recovery_observer: Option<RecoveryObserver>,
+    handlers: RecordHandlers,
 }
 
 impl TransactionManager {
-    fn new(fm: Arc<FileManager>, lm: Arc<LogManager>, bm: Arc<BufferManager>) -> Self {
+    pub fn new(
+        fm: Arc<dyn StorageBackend>,
+        lm: Arc<LogManager>,
+        bm: Arc<BufferManager>,
+        lock_timeout: Duration,
+    ) -> Self {
+        let stats = Arc::new(TxnStats::default());
         Self {
             fm,
             lm,
             bm,
-            concurrency_mgr: Arc::new(Mutex::new(ConcurrencyManager::new())),
+            lock_tbl: Arc::new(LockTable::new(lock_timeout, Arc::clone(&stats))),
             next_txn_num: AtomicUsize::new(0),
+            stats,
+            read_only: false,
+            recovery_observer: None,
+            handlers: RecordHandlers::default(),
         }
     }
 
-    fn create_txn(&self) -> Transaction {
+    /// Has `handler` redo and undo the operations of `kind` that transactions perform, see
+    /// [`RecordHandler`].
+    pub(crate) fn with_record_handler(
+        mut self,
+        kind: u16,
+        handler: impl RecordHandler + 'static,
+    ) -> Self {
+        Arc::make_mut(&mut self.handlers).insert(kind, Arc::new(handler));
+        self
+    }
+
+    /// Makes every transaction created from now on read-only.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Reports the progress of every recovery run from now on to `observer`.
+    pub fn with_recovery_observer(mut self, observer: RecoveryObserver) -> Self {
+        self.recovery_observer = Some(observer);
+        self
+    }
+
+    /// Undoes the changes of every transaction that didn't finish before the last shutdown.
+    pub fn recover(&self) -> Result<(), TxnError> {
+        let mut txn = self.create_txn()?;
+        txn.recover(false, self.recovery_observer.as_ref())
+    }
+
+    /// Recovers from the whole log, ignoring checkpoints, e.g. on a standby whose data files
+    /// weren't written in step with the primary's checkpoints.
+    pub fn recover_whole_log(&self) -> Result<(), TxnError> {
+        let mut txn = self.create_txn()?;
+        txn.recover(true, self.recovery_observer.as_ref())
+    }
+
+    /// Point-in-time recovery: drops the log records past `target`, then recovers from the
+    /// whole log that's left. Checkpoints are ignored, since the data files are expected to
+    /// come from a backup older than them.
+    pub fn recover_to(&self, target: RecoveryTarget) -> Result<(), TxnError> {
+        RecoveryManager::truncate_to(&self.lm, target)?;
+        let mut txn = self.create_txn()?;
+        txn.recover(true, self.recovery_observer.as_ref())
+    }
+
+    /// Writes a fuzzy checkpoint, see [`RecoveryManager::checkpoint`]. Transactions may be
+    /// active.
+    pub fn checkpoint(&self) -> Result<(), TxnError> {
+        RecoveryManager::checkpoint(&self.bm, &self.lm, &self.stats.active_txns)
+    }
+
+    /// Writes a quiescent checkpoint: holds back new transactions, waits for the active ones
+    /// to finish, flushes every dirty buffer and writes a checkpoint record, past which
+    /// recovery never reads.
+    ///
+    /// Blocks forever if the calling thread has an active transaction.
+    pub fn quiescent_checkpoint(&self) -> Result<(), TxnError> {
+        let stats = &self.stats;
+        let active = stats.active_txns.lock().unwrap();
+        // one quiescent checkpoint at a time
+        let mut active = stats
+            .active_changed
+            .wait_while(active, |active| active.quiescing)
+            .unwrap();
+        active.quiescing = true;
+        let mut active = stats
+            .active_changed
+            .wait_while(active, |active| !active.txns.is_empty())
+            .unwrap();
+        let res = RecoveryManager::quiescent_checkpoint(&self.bm, &self.lm);
+        active.quiescing = false;
+        stats.active_changed.notify_all();
+        res
+    }
+
+    pub fn create_txn(&self) -> Result<Transaction, TxnError> {
+        self.new_txn(self.read_only)
+    }
+
+    /// Starts a read-only transaction, e.g. on a standby that is still replaying the log.
+    pub fn create_read_only_txn(&self) -> Result<Transaction, TxnError> {
+        self.new_txn(true)
+    }
+
+    /// Starts a transaction that replays the changes of one of a primary's transactions,
+    /// see [`crate::replication`].
+    pub(crate) fn create_replica_txn(&self) -> Result<Transaction, TxnError> {
+        // created read-only so that it writes no start record
+        let mut txn = self.new_txn(true)?;
+        txn.read_only = false;
+        txn.replica = true;
+        Ok(txn)
+    }
+
+    fn new_txn(&self, read_only: bool) -> Result<Transaction, TxnError> {
         let txn_num = self.next_txn_num.fetch_add(1, Ordering::SeqCst);
-        Transaction::new(
+        let mut txn = Transaction::new(
             txn_num,
             self.fm.clone(),
             self.lm.clone(),
             self.bm.clone(),
-            self.concurrency_mgr.clone(),
-        )
+            Arc::clone(&self.lock_tbl),
+            self.stats.clone(),
+            read_only,
+        )?;
+        txn.handlers = Arc::clone(&self.handlers);
+        Ok(txn)
+    }
+
+    pub fn stats(&self) -> &TxnStats {
+        &self.stats
     }
 }
 
@@ -204,28 +731,49 @@ impl TransactionManager {
 mod tests {
     use std::{
         env,
+        path::PathBuf,
+        thread,
         time::{SystemTime, UNIX_EPOCH},
     };
 
-    use crate::buffer::EvictionPolicy;
+    use crate::{
+        buffer::EvictionPolicy,
+        file::{FileManager, Page, StorageBackend, StorageError},
+        txn::{lock_table::DEFAULT_LOCK_TIMEOUT, LogRecord},
+    };
 
     use super::*;
 
     fn setup() -> TransactionManager {
+        with_file_manager(FileManager::in_memory(400))
+    }
+
+    fn test_dir(prefix: &str) -> PathBuf {
         let dirname = format!(
             "{}_{}",
-            "txtest",
+            prefix,
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_millis()
         );
-        let dir_path = env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname);
-        let fm = Arc::new(FileManager::new(&dir_path, 400));
-        let lm = Arc::new(LogManager::new(fm.clone(), "db.log"));
-        let bm = Arc::new(BufferManager::new(fm.clone(), lm.clone(), 20, EvictionPolicy::default()));
+        env::temp_dir().join(env!("CARGO_PKG_NAME")).join(dirname)
+    }
 
-        TransactionManager::new(fm, lm, bm)
+    fn with_file_manager(fm: FileManager) -> TransactionManager {
+        on_storage(Arc::new(fm))
+    }
+
+    fn on_storage(fm: Arc<FileManager>) -> TransactionManager {
+        let lm = Arc::new(LogManager::new(fm.clone(), "db.log").unwrap());
+        let bm = Arc::new(BufferManager::new(
+            fm.clone(),
+            lm.clone(),
+            20,
+            EvictionPolicy::default(),
+        ));
+
+        TransactionManager::new(fm, lm, bm, DEFAULT_LOCK_TIMEOUT)
     }
 
     #[test]
@@ -234,57 +782,301 @@ mod tests {
 
         let blk = BlockId::new("testfile", 1);
 
-        let mut tx1 = tm.create_txn();
-        tx1.pin(&blk);
+        let mut tx1 = tm.create_txn().unwrap();
+        tx1.pin(&blk).unwrap();
 
-        tx1.set_value(&blk, 80, &UpdateValue::INT(1), false);
-        tx1.set_value(&blk, 40, &UpdateValue::STRING("one".into()), false);
+        tx1.set_value(&blk, 80, &UpdateValue::INT(1), false)
+            .unwrap();
+        tx1.set_value(&blk, 40, &UpdateValue::STRING("one".into()), false)
+            .unwrap();
+        tx1.set_long(&blk, 120, 1 << 40, false).unwrap();
+        tx1.set_double(&blk, 140, 2.5, false).unwrap();
+        tx1.set_raw(&blk, 160, &[1, 2, 3, 4], false).unwrap();
+        tx1.set_bool(&blk, 170, true, false).unwrap();
+        tx1.set_date(&blk, 180, 19_000, false).unwrap();
+        tx1.set_timestamp(&blk, 190, 1_700_000_000_000_000, false)
+            .unwrap();
 
-        tx1.commit();
+        tx1.commit().unwrap();
 
         // read-modify-commit
 
-        let mut tx2 = tm.create_txn();
-        tx2.pin(&blk);
+        let mut tx2 = tm.create_txn().unwrap();
+        tx2.pin(&blk).unwrap();
 
-        let start_i = tx2.get_int(&blk, 80);
-        let start_s = tx2.get_string(&blk, 40);
+        let start_i = tx2.get_int(&blk, 80).unwrap();
+        let start_s = tx2.get_string(&blk, 40).unwrap();
 
         assert_eq!(start_i, 1);
         assert_eq!(start_s, "one");
 
-        tx2.set_value(&blk, 80, &UpdateValue::INT(start_i + 1), true);
-        tx2.set_value(&blk, 40, &UpdateValue::STRING(format!("{start_s}!")), true);
+        tx2.set_value(&blk, 80, &UpdateValue::INT(start_i + 1), true)
+            .unwrap();
+        tx2.set_value(&blk, 40, &UpdateValue::STRING(format!("{start_s}!")), true)
+            .unwrap();
 
-        tx2.commit();
+        tx2.commit().unwrap();
 
         // overwrite then roll back
 
-        let mut tx3 = tm.create_txn();
-        tx3.pin(&blk);
+        let mut tx3 = tm.create_txn().unwrap();
+        tx3.pin(&blk).unwrap();
 
-        let post_commit_i = tx3.get_int(&blk, 80);
-        let post_commit_s = tx3.get_string(&blk, 40);
+        let post_commit_i = tx3.get_int(&blk, 80).unwrap();
+        let post_commit_s = tx3.get_string(&blk, 40).unwrap();
 
         assert_eq!(post_commit_i, 2, "commit from tx2 not visible");
         assert_eq!(post_commit_s, "one!", "commit from tx2 not visible");
 
-        tx3.set_value(&blk, 80, &UpdateValue::INT(9999), true);
-        assert_eq!(tx3.get_int(&blk, 80), 9999, "write not visible to tx3");
+        tx3.set_value(&blk, 80, &UpdateValue::INT(9999), true)
+            .unwrap();
+        tx3.set_long(&blk, 120, -1, true).unwrap();
+        tx3.set_double(&blk, 140, f64::NAN, true).unwrap();
+        tx3.set_raw(&blk, 160, &[9, 9, 9, 9], true).unwrap();
+        tx3.set_bool(&blk, 170, false, true).unwrap();
+        assert!(!tx3.get_bool(&blk, 170).unwrap());
+        tx3.set_date(&blk, 180, -1, true).unwrap();
+        tx3.set_timestamp(&blk, 190, 0, true).unwrap();
+        assert_eq!(
+            tx3.get_int(&blk, 80).unwrap(),
+            9999,
+            "write not visible to tx3"
+        );
 
-        tx3.rollback();
+        tx3.rollback().unwrap();
 
         // verify rollback outcome
 
-        let mut tx4 = tm.create_txn();
-        tx4.pin(&blk);
+        let mut tx4 = tm.create_txn().unwrap();
+        tx4.pin(&blk).unwrap();
 
-        let final_i = tx4.get_int(&blk, 80);
-        let final_s = tx4.get_string(&blk, 40);
+        let final_i = tx4.get_int(&blk, 80).unwrap();
+        let final_s = tx4.get_string(&blk, 40).unwrap();
 
         assert_eq!(final_i, 2, "rollback did not restore int");
         assert_eq!(final_s, "one!", "rollback did not restore string");
+        assert_eq!(
+            tx4.get_long(&blk, 120).unwrap(),
+            1 << 40,
+            "rollback did not restore long"
+        );
+        assert_eq!(
+            tx4.get_double(&blk, 140).unwrap(),
+            2.5,
+            "rollback did not restore double"
+        );
+        assert_eq!(tx4.get_raw(&blk, 160, 4).unwrap(), [1, 2, 3, 4]);
+        assert!(tx4.get_bool(&blk, 170).unwrap());
+        assert_eq!(tx4.get_date(&blk, 180).unwrap(), 19_000);
+        assert_eq!(tx4.get_timestamp(&blk, 190).unwrap(), 1_700_000_000_000_000);
+
+        tx4.commit().unwrap();
+    }
+
+    #[test]
+    fn recover_redoes_committed_and_undoes_incomplete() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let blk = BlockId::new("testfile", 1);
+        let blk2 = BlockId::new("testfile", 2);
+        {
+            let tm = on_storage(Arc::clone(&fm));
+            let mut tx1 = tm.create_txn().unwrap();
+            tx1.pin(&blk).unwrap();
+            tx1.set_int(&blk, 80, 7, true).unwrap();
+            tx1.set_string(&blk, 40, "kept", true).unwrap();
+            // commit doesn't force tx1's page
+            tx1.commit().unwrap();
+
+            let mut tx2 = tm.create_txn().unwrap();
+            tx2.pin(&blk2).unwrap();
+            tx2.set_int(&blk2, 120, 9, true).unwrap();
+            // an uncommitted change that reached the disk
+            tm.bm.flush_all(tx2.txn_num()).unwrap();
+            // crash: tx2 isn't rolled back and the buffer pool is dropped without flushing
+            tx2.crash();
+        }
+
+        let (mut page, mut page2) = (Page::new(400), Page::new(400));
+        fm.read_block(&blk, &mut page).unwrap();
+        fm.read_block(&blk2, &mut page2).unwrap();
+        assert_eq!((page.get_int(80), page2.get_int(120)), (0, 9));
+
+        let tm = on_storage(Arc::clone(&fm));
+        tm.recover().unwrap();
+        fm.read_block(&blk, &mut page).unwrap();
+        fm.read_block(&blk2, &mut page2).unwrap();
+        assert_eq!(page.get_int(80), 7, "committed int not redone");
+        assert_eq!(page.get_string(40), "kept", "committed string not redone");
+        assert_eq!(page2.get_int(120), 0, "incomplete change not undone");
+    }
+
+    #[test]
+    fn recover_from_fuzzy_checkpoint() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let blk = BlockId::new("testfile", 1);
+        let blk2 = BlockId::new("testfile", 2);
+        let blk3 = BlockId::new("testfile", 3);
+        {
+            let tm = on_storage(Arc::clone(&fm));
+            // committed but still dirty at the checkpoint
+            let mut tx1 = tm.create_txn().unwrap();
+            tx1.pin(&blk).unwrap();
+            tx1.set_int(&blk, 80, 5, true).unwrap();
+            tx1.commit().unwrap();
+
+            // active at the checkpoint, with its change on disk
+            let mut tx2 = tm.create_txn().unwrap();
+            tx2.pin(&blk2).unwrap();
+            tx2.set_int(&blk2, 80, 7, true).unwrap();
+            tm.bm.flush_all(tx2.txn_num()).unwrap();
+            tm.checkpoint().unwrap();
+
+            let mut tx3 = tm.create_txn().unwrap();
+            tx3.pin(&blk3).unwrap();
+            tx3.set_int(&blk3, 80, 6, true).unwrap();
+            tx3.commit().unwrap();
+            // crash with tx2 unfinished
+            tx2.crash();
+        }
+
+        let mut pages = [Page::new(400), Page::new(400), Page::new(400)];
+        let read = |pages: &mut [Page; 3]| {
+            for (b, page) in [&blk, &blk2, &blk3].into_iter().zip(pages.iter_mut()) {
+                fm.read_block(b, page).unwrap();
+            }
+            pages.each_ref().map(|p| p.get_int(80))
+        };
+        assert_eq!(read(&mut pages), [0, 7, 0]);
+
+        let tm = on_storage(Arc::clone(&fm));
+        tm.recover().unwrap();
+        assert_eq!(
+            read(&mut pages),
+            [5, 0, 6],
+            "dirty page not redone, active txn not undone or later change not redone"
+        );
+    }
+
+    #[test]
+    fn quiescent_checkpoint_waits_for_active_txns() {
+        let tm = Arc::new(setup());
+        let blk = BlockId::new("testfile", 1);
+        let mut tx = tm.create_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 3, true).unwrap();
+
+        let checkpoint = thread::spawn({
+            let tm = Arc::clone(&tm);
+            move || tm.quiescent_checkpoint().unwrap()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!checkpoint.is_finished());
+        let starter = thread::spawn({
+            let tm = Arc::clone(&tm);
+            move || tm.create_txn().unwrap().commit().unwrap()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(
+            !starter.is_finished(),
+            "a transaction started while quiescing"
+        );
+
+        tx.commit().unwrap();
+        checkpoint.join().unwrap();
+        starter.join().unwrap();
+
+        // the started transaction's records come after the checkpoint
+        let records: Vec<_> = tm
+            .lm
+            .iterator()
+            .unwrap()
+            .map(|bytes| LogRecord::new(&bytes.unwrap()).unwrap().to_string())
+            .take(3)
+            .collect();
+        assert_eq!(records[2], "<CHECKPOINT>");
+    }
+
+    #[test]
+    fn data_pages_have_a_header() {
+        let tm = setup();
+        let blk = BlockId::new("testfile", 1);
+        let mut tx = tm.create_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        let lsn = tm.lm.latest_lsn();
+        assert!(matches!(
+            tx.set_int(&blk, PageHeader::SIZE - 1, 1, true),
+            Err(TxnError::Page(PageError::InHeader(_)))
+        ));
+        assert!(matches!(
+            tx.set_long(&blk, 396, 1, true),
+            Err(TxnError::Page(PageError::OutOfBounds { .. }))
+        ));
+        assert_eq!(tm.lm.latest_lsn(), lsn, "rejected writes were logged");
+
+        tx.set_int(&blk, PageHeader::SIZE, 1, true).unwrap();
+        let header = tx
+            .buffers
+            .get(&blk)
+            .unwrap()
+            .read()
+            .unwrap()
+            .contents()
+            .header();
+        assert_eq!(header.page_type, PageType::Data);
+        assert_eq!(header.lsn, tm.lm.latest_lsn());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn drop_rolls_back_unfinished_txn() {
+        let tm = setup();
+        let blk = BlockId::new("testfile", 1);
+        let mut tx1 = tm.create_txn().unwrap();
+        tx1.pin(&blk).unwrap();
+        tx1.set_int(&blk, 80, 5, true).unwrap();
+        drop(tx1);
+        assert_eq!(tm.stats().active.load(Ordering::SeqCst), 0);
+        assert!(tm.stats().active_txns.lock().unwrap().txns.is_empty());
+
+        // tx1's lock is released and its change undone
+        let mut tx2 = tm.create_txn().unwrap();
+        tx2.pin(&blk).unwrap();
+        tx2.set_int(&blk, 120, 6, true).unwrap();
+        assert_eq!(tx2.get_int(&blk, 80).unwrap(), 0);
+        tx2.commit().unwrap();
+        tm.quiescent_checkpoint().unwrap();
+    }
+
+    #[test]
+    fn pin_reports_storage_error() {
+        let dir_path = test_dir("txerrtest");
+        let tm = with_file_manager(FileManager::new(&dir_path, 400).unwrap());
+        // a directory can't be opened as a data file
+        std::fs::create_dir(dir_path.join("notafile")).unwrap();
+
+        let mut tx = tm.create_txn().unwrap();
+        let err = tx.pin(&BlockId::new("notafile", 0)).unwrap_err();
+        assert!(matches!(
+            err,
+            TxnError::Buffer(BufferError::Storage(StorageError::Open { .. }))
+        ));
+        assert!(!err.is_retryable());
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn pin_reports_exhaustion_as_retryable() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let lm = Arc::new(LogManager::new(fm.clone(), "db.log").unwrap());
+        let bm = BufferManager::new(fm.clone(), lm.clone(), 1, EvictionPolicy::default())
+            .with_pin_timeout(Duration::ZERO);
+        let tm = TransactionManager::new(fm, lm, Arc::new(bm), DEFAULT_LOCK_TIMEOUT);
 
-        tx4.commit();
+        let mut tx = tm.create_txn().unwrap();
+        tx.pin(&BlockId::new("testfile", 0)).unwrap();
+        let err = tx.pin(&BlockId::new("testfile", 1)).unwrap_err();
+        assert!(matches!(err, TxnError::Buffer(BufferError::Abort)));
+        assert!(err.is_retryable());
+        tx.rollback().unwrap();
     }
 }
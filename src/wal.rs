@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::{
+    file::StorageBackend,
+    log::{ForwardLogIterator, LogError, LogManager, Lsn},
+    txn::LogRecord,
+};
+
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error(transparent)]
+    Log(#[from] LogError),
+    #[error("log record at LSN {lsn} can't be decoded")]
+    Undecodable { lsn: Lsn },
+}
+
+/// Read-only view of a database's log, decoded into [`LogRecord`]s.
+///
+/// Meant for inspecting the log when debugging, e.g. with the `willow-waldump` binary.
+/// Open one with [`Builder::open_log`](crate::Builder::open_log).
+pub struct WalReader {
+    lm: LogManager,
+}
+
+impl WalReader {
+    pub(crate) fn new(storage: Arc<dyn StorageBackend>, log_file: &str) -> Result<Self, WalError> {
+        Ok(Self {
+            lm: LogManager::new(storage, log_file)?,
+        })
+    }
+
+    /// LSN of the last record in the log.
+    pub fn latest_lsn(&self) -> Lsn {
+        self.lm.latest_lsn()
+    }
+
+    /// Iterates over the records with an LSN of at least `from`, oldest first.
+    ///
+    /// A record that can't be decoded yields [`WalError::Undecodable`] and iteration goes on with
+    /// the next one. A corrupt block ends it after yielding the error.
+    pub fn records(&self, from: Lsn) -> Result<WalRecords, WalError> {
+        Ok(WalRecords {
+            inner: self.lm.forward_records(from)?,
+        })
+    }
+}
+
+/// Iterator returned by [`WalReader::records`].
+pub struct WalRecords {
+    inner: ForwardLogIterator,
+}
+
+impl Iterator for WalRecords {
+    type Item = Result<(Lsn, LogRecord), WalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(
+            self.inner
+                .next()?
+                .map_err(WalError::from)
+                .and_then(|(lsn, bytes)| {
+                    LogRecord::new(&bytes)
+                        .map(|record| (lsn, record))
+                        .ok_or(WalError::Undecodable { lsn })
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{file::FileManager, BlockId, WillowDB};
+
+    use super::*;
+
+    #[test]
+    fn test_wal_reader() {
+        let fm = Arc::new(FileManager::in_memory(400));
+        let db = WillowDB::builder()
+            .open_with_storage(Arc::clone(&fm) as _)
+            .unwrap();
+        let blk = BlockId::new("testfile", 1);
+        let mut tx = db.new_txn().unwrap();
+        tx.pin(&blk).unwrap();
+        tx.set_int(&blk, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+        let txn_num = tx.txn_num();
+
+        let reader = WalReader::new(Arc::clone(&fm) as _, "willowdb.log").unwrap();
+        let records: Vec<_> = reader.records(0).unwrap().map(|r| r.unwrap()).collect();
+        let lsns: Vec<_> = records.iter().map(|(lsn, _)| *lsn).collect();
+        assert!(lsns.is_sorted());
+        assert_eq!(*lsns.last().unwrap(), reader.latest_lsn());
+
+        let ours: Vec<_> = records
+            .iter()
+            .filter(|(_, r)| r.txn_num() == Some(txn_num))
+            .map(|(_, r)| r.to_string())
+            .collect();
+        assert_eq!(
+            ours,
+            [
+                format!("<START {txn_num}>"),
+                format!("<UPDATE {txn_num} {blk} 80 INT 0 -> INT 1>"),
+                format!("<COMMIT {txn_num}>"),
+            ]
+        );
+        let updates = records.iter().filter(|(_, r)| r.block() == Some(&blk));
+        assert_eq!(updates.count(), 1);
+
+        // only what's at or after the given LSN
+        let from = lsns[lsns.len() - 2];
+        assert_eq!(reader.records(from).unwrap().count(), 2);
+    }
+}